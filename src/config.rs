@@ -1,6 +1,12 @@
 //! Configuration handling for the application.
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+use crate::keyring;
+use crate::pan123::Normalization;
+use crate::restic::ResticFileType;
+use crate::server::ListenAddr;
 
 /// Restic REST API server backed by 123pan cloud storage.
 #[derive(Parser, Debug, Clone)]
@@ -8,17 +14,80 @@ use clap::Parser;
 #[command(about = "Restic REST API backend server using 123pan cloud storage")]
 pub struct Config {
     /// 123pan client ID
-    #[arg(long, env = "PAN123_CLIENT_ID")]
-    pub client_id: String,
+    #[arg(
+        long,
+        env = "PAN123_CLIENT_ID",
+        required_unless_present_any = ["client_id_file", "use_keyring"]
+    )]
+    pub client_id: Option<String>,
+
+    /// File containing the 123pan client ID (e.g. a Docker/Kubernetes secret)
+    #[arg(long, env = "PAN123_CLIENT_ID_FILE", conflicts_with = "client_id")]
+    pub client_id_file: Option<PathBuf>,
 
     /// 123pan client secret
-    #[arg(long, env = "PAN123_CLIENT_SECRET")]
-    pub client_secret: String,
+    #[arg(
+        long,
+        env = "PAN123_CLIENT_SECRET",
+        hide_env_values = true,
+        required_unless_present_any = ["client_secret_file", "use_keyring"]
+    )]
+    pub client_secret: Option<String>,
+
+    /// File containing the 123pan client secret (e.g. a Docker/Kubernetes secret)
+    #[arg(
+        long,
+        env = "PAN123_CLIENT_SECRET_FILE",
+        conflicts_with = "client_secret"
+    )]
+    pub client_secret_file: Option<PathBuf>,
+
+    /// Take the client ID and secret not given otherwise from the operating
+    /// system's keyring, where `credentials set` stores them
+    #[arg(long, env = "USE_KEYRING", default_value = "false")]
+    pub use_keyring: bool,
+
+    /// Additional `client_id:client_secret` pairs (comma-separated) to fail
+    /// over to when 123pan rate-limits the active credential
+    #[arg(
+        long,
+        env = "PAN123_EXTRA_CREDENTIALS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    pub extra_credentials: Vec<String>,
 
-    /// Root folder path on 123pan for the repository
-    #[arg(long, env = "PAN123_REPO_PATH", default_value = "/restic-backup")]
+    /// File with additional `client_id:client_secret` pairs, one per line
+    #[arg(long, env = "PAN123_EXTRA_CREDENTIALS_FILE")]
+    pub extra_credentials_file: Option<PathBuf>,
+
+    /// Proxy for requests to 123pan (`http://`, `https://` or `socks5://`);
+    /// when unset, HTTPS_PROXY/ALL_PROXY are honored
+    #[arg(long, env = "PROXY_URL")]
+    pub proxy_url: Option<String>,
+
+    /// Proxy for file downloads/uploads, overriding --proxy-url for that traffic
+    #[arg(long, env = "TRANSFER_PROXY_URL")]
+    pub transfer_proxy_url: Option<String>,
+
+    /// 123pan Open Platform API base URL
+    #[arg(long, env = "PAN123_API_BASE_URL", default_value = crate::pan123::auth::BASE_URL)]
+    pub api_base_url: String,
+
+    /// Root folder path on 123pan for the repository (`--path` as in rest-server)
+    #[arg(
+        long,
+        alias = "path",
+        env = "PAN123_REPO_PATH",
+        default_value = "/restic-backup"
+    )]
     pub repo_path: String,
 
+    /// Unicode normalization form names are compared and created in, so e.g.
+    /// a decomposed path typed on macOS finds the composed folder 123pan lists
+    #[arg(long, env = "UNICODE_NORMALIZATION", value_enum, default_value_t = Normalization::Nfc)]
+    pub unicode_normalization: Normalization,
+
     /// Server listen address (host or IP)
     #[arg(long, env = "LISTEN_ADDR", default_value = "127.0.0.1")]
     pub listen_addr: String,
@@ -27,15 +96,1112 @@ pub struct Config {
     #[arg(long, env = "LISTEN_PORT", default_value_t = 8000)]
     pub listen_port: u16,
 
+    /// Listen address, overriding --listen-addr/--listen-port
+    /// (`host:port`, `:port` for all interfaces or `unix:/path/to.sock`)
+    #[arg(long, env = "LISTEN")]
+    pub listen: Option<ListenAddr>,
+
+    /// Refuse deleting anything but locks, so clients can only add data
+    /// (rest-server's `--append-only`)
+    #[arg(long, env = "APPEND_ONLY", default_value = "false")]
+    pub append_only: bool,
+
+    /// Refuse uploads that would replace existing data packs, snapshots or
+    /// keys (an identical re-upload still succeeds); with `APPEND_ONLY`,
+    /// nothing stored can be destroyed through the server
+    #[arg(long, env = "IMMUTABLE_DATA", default_value = "false")]
+    pub immutable_data: bool,
+
+    /// Serve without authentication on non-loopback addresses; this server
+    /// has no authentication, so it refuses to listen beyond loopback and Unix
+    /// sockets unless this is set (like rest-server without `--htpasswd-file`)
+    #[arg(long, env = "NO_AUTH", default_value = "false")]
+    pub no_auth: bool,
+
+    /// rest-server's per-user repositories; not supported, refuses to start
+    #[arg(long, env = "PRIVATE_REPOS", default_value = "false")]
+    pub private_repos: bool,
+
+    /// Permissions (octal) for the Unix domain socket
+    #[arg(long, env = "SOCKET_MODE", default_value = "660", value_parser = parse_octal_mode)]
+    pub socket_mode: u32,
+
+    /// Accept only IPv6 clients on IPv6 addresses such as `[::]:8000`; by
+    /// default they accept IPv4 clients too (dual-stack)
+    #[arg(long, env = "IPV6_ONLY", default_value = "false")]
+    pub ipv6_only: bool,
+
     /// Log level (trace, debug, info, warn, error)
     #[arg(long, env = "RUST_LOG", default_value = "info")]
     pub log_level: String,
 
+    /// Log output format (text, json)
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Append log lines to this file instead of writing them to stdout, e.g.
+    /// with `--daemon` or as a Windows service
+    #[arg(long, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Bearer token required by the `/admin/*` routes. Without it they are
+    /// only served on listeners only this host can reach
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Sentry DSN to report errors and panics to (disabled if unset)
+    #[arg(long, env = "SENTRY_DSN", hide_env_values = true, value_parser = parse_sentry_dsn)]
+    pub sentry_dsn: Option<String>,
+
+    /// Environment reported to Sentry (e.g. production)
+    #[arg(long, env = "SENTRY_ENVIRONMENT")]
+    pub sentry_environment: Option<String>,
+
     /// Path to the SQLite database file
     #[arg(long, env = "DB_PATH", default_value = "cache-123pan.db")]
     pub db_path: String,
 
+    /// Cache database URL (sqlite:, postgres://, mysql://); overrides DB_PATH.
+    /// A shared PostgreSQL/MySQL database lets replicas share cache state.
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    pub database_url: Option<String>,
+
+    /// Cache namespace for this repository (defaults to REPO_PATH).
+    /// Set when several accounts share a cache database and a repository path.
+    #[arg(long, env = "CACHE_NAMESPACE")]
+    pub cache_namespace: Option<String>,
+
+    /// Entries in the in-memory lookup cache in front of the cache database
+    /// (0 disables; do so when replicas share a database)
+    #[arg(long, env = "LOOKUP_CACHE_ENTRIES", default_value_t = 10_000)]
+    pub lookup_cache_entries: usize,
+
     /// Force rebuild of the file list cache on startup
     #[arg(long, env = "FORCE_CACHE_REBUILD", default_value = "false")]
     pub force_cache_rebuild: bool,
+
+    /// Answer listings, lookups and reads from the 123pan API instead of the
+    /// cache (for debugging the cache and small repositories)
+    #[arg(long, env = "DIRECT_API", default_value = "false")]
+    pub direct_api: bool,
+
+    /// How much of the repository is listed into the cache at startup
+    #[arg(long, env = "CACHE_WARM_UP", value_enum, default_value_t = WarmUpMode::Lazy)]
+    pub cache_warm_up: WarmUpMode,
+
+    /// Seconds after which a cached directory listing is fetched again
+    /// (0 = never, e.g. when only this server writes to the repository)
+    #[arg(long, env = "CACHE_TTL_SECS", default_value = "0")]
+    pub cache_ttl_secs: u64,
+
+    /// When expired directory listings are fetched again
+    #[arg(long, env = "CACHE_REVALIDATION", value_enum, default_value_t = Revalidation::Access)]
+    pub cache_revalidation: Revalidation,
+
+    /// How far HEAD requests trust the directory cache: `metadata` confirms
+    /// the config, keys and snapshots with one 123pan call each, `always`
+    /// every object
+    #[arg(long, env = "HEAD_VERIFY", value_enum, default_value_t = HeadVerify::Cache)]
+    pub head_verify: HeadVerify,
+
+    /// Check credentials, account space, the repository path and that the
+    /// cache database is writable before listening
+    #[arg(long, env = "PREFLIGHT", default_value = "true", action = clap::ArgAction::Set)]
+    pub preflight: bool,
+
+    /// Create the whole repository at startup (as part of the preflight, if
+    /// enabled) if the repository path doesn't exist, for clients that never
+    /// send `POST /?create=true`
+    #[arg(long, env = "AUTO_CREATE_REPO", default_value = "false")]
+    pub auto_create_repo: bool,
+
+    /// Cache database self-check at startup
+    #[arg(long, env = "CACHE_CHECK", value_enum, default_value_t = CacheCheck::Report)]
+    pub cache_check: CacheCheck,
+
+    /// Minutes between removals of cached subtrees whose ancestors are gone
+    /// (0 disables)
+    #[arg(long, env = "ORPHAN_CLEANUP_INTERVAL_MINS", default_value = "60")]
+    pub orphan_cleanup_interval_mins: u64,
+
+    /// What to do when another live instance holds the repository lock
+    #[arg(long, env = "SERVER_LOCK", value_enum, default_value_t = ServerLockMode::Fail)]
+    pub server_lock: ServerLockMode,
+
+    /// Seconds the repository lock stays valid without renewal
+    #[arg(long, env = "SERVER_LOCK_TTL_SECS", default_value = "120")]
+    pub server_lock_ttl_secs: u64,
+
+    /// Record uploads, deletes and repository creation in the cache database
+    /// (queried with `GET /admin/audit`)
+    #[arg(long, env = "AUDIT_LOG", default_value = "true", action = clap::ArgAction::Set)]
+    pub audit_log: bool,
+
+    /// Days audit log entries are kept (0 keeps them forever)
+    #[arg(long, env = "AUDIT_RETENTION_DAYS", default_value = "90")]
+    pub audit_retention_days: u64,
+
+    /// Seed an empty cache from the latest snapshot in `{repo}/.cache-backup`
+    /// instead of crawling the whole repository
+    #[arg(long, env = "RESTORE_CACHE", default_value = "false")]
+    pub restore_cache: bool,
+
+    /// Minutes between SQLite cache maintenance runs: WAL checkpoint,
+    /// incremental vacuum and ANALYZE (0 disables)
+    #[arg(long, env = "DB_MAINTENANCE_INTERVAL_MINS", default_value_t = 1440)]
+    pub db_maintenance_interval_mins: u64,
+
+    /// Minutes between cache database snapshots uploaded to 123pan (0 disables)
+    #[arg(long, env = "CACHE_BACKUP_INTERVAL_MINS", default_value_t = 0)]
+    pub cache_backup_interval_mins: u64,
+
+    /// Minutes between 123pan API usage summaries in the log (0 disables)
+    #[arg(long, env = "METRICS_LOG_INTERVAL_MINS", default_value_t = 60)]
+    pub metrics_log_interval_mins: u64,
+
+    /// Per-client-IP request rate limit in requests per second (0 disables)
+    #[arg(long, env = "RATE_LIMIT_RPS", default_value_t = 0.0)]
+    pub rate_limit_rps: f64,
+
+    /// Per-client-IP burst size for the rate limiter
+    #[arg(long, env = "RATE_LIMIT_BURST", default_value_t = 50)]
+    pub rate_limit_burst: u32,
+
+    /// Maximum concurrent uploads to 123pan (0 = unlimited)
+    #[arg(long, env = "MAX_CONCURRENT_UPLOADS", default_value_t = 4)]
+    pub max_concurrent_uploads: usize,
+
+    /// Maximum concurrent download requests to 123pan, counting each chunk of
+    /// a parallel download (0 = unlimited)
+    #[arg(long, env = "MAX_CONCURRENT_DOWNLOADS", default_value_t = 0)]
+    pub max_concurrent_downloads: usize,
+
+    /// Maximum concurrent 123pan API calls such as listings, mkdir, moves and
+    /// deletes (0 = unlimited)
+    #[arg(long, env = "MAX_CONCURRENT_API_CALLS", default_value_t = 0)]
+    pub max_concurrent_api_calls: usize,
+
+    /// Maximum uploads waiting for a slot before new ones get 503 + Retry-After
+    #[arg(long, env = "UPLOAD_QUEUE_SIZE", default_value_t = 16)]
+    pub upload_queue_size: usize,
+
+    /// MiB of upload bodies buffered in memory at once; further uploads wait
+    /// before reading their body (0 = unlimited)
+    #[arg(long, env = "MAX_BUFFERED_BODY_MB", default_value_t = 0)]
+    pub max_buffered_body_mb: u64,
+
+    /// Local spool directory for write-back uploads; when set, uploads are
+    /// acknowledged once stored locally and sent to 123pan in the background
+    #[arg(long, env = "SPOOL_DIR")]
+    pub spool_dir: Option<String>,
+
+    /// Which uploads go through `SPOOL_DIR`
+    #[arg(long, env = "SPOOL_MODE", value_enum, default_value_t = SpoolMode::WriteBack)]
+    pub spool_mode: SpoolMode,
+
+    /// Local directory caching config/index/snapshot/key contents
+    #[arg(long, env = "METADATA_CACHE_DIR")]
+    pub metadata_cache_dir: Option<String>,
+
+    /// Local directory caching downloaded data packs
+    #[arg(long, env = "PACK_CACHE_DIR")]
+    pub pack_cache_dir: Option<String>,
+
+    /// Maximum size of the pack cache in MiB (least recently used packs are evicted)
+    #[arg(long, env = "PACK_CACHE_SIZE_MB", default_value_t = 1024)]
+    pub pack_cache_size_mb: u64,
+
+    /// Answer ranged reads of uncached packs directly and fetch the whole pack
+    /// into the pack cache in the background
+    #[arg(long, env = "PACK_READAHEAD", default_value = "false")]
+    pub pack_readahead: bool,
+
+    /// MiB fetched ahead once a pack is read sequentially (e.g. by `restic
+    /// check --read-data` or `prune`); bursts of whole-pack reads are then
+    /// also verified against the pack ID (0 disables)
+    #[arg(long, env = "SEQUENTIAL_READAHEAD_MB", default_value_t = 0)]
+    pub sequential_readahead_mb: u64,
+
+    /// Maximum concurrent Range requests when downloading a large file (1 disables)
+    #[arg(long, env = "DOWNLOAD_PARALLELISM", default_value_t = 4)]
+    pub download_parallelism: usize,
+
+    /// Chunk size in MiB for parallel downloads; smaller files use one request
+    #[arg(long, env = "DOWNLOAD_CHUNK_SIZE_MB", default_value_t = 8)]
+    pub download_chunk_size_mb: u64,
+
+    /// Files larger than this many MiB are uploaded in resumable slices
+    /// (capped at 1024, the single-upload limit)
+    #[arg(long, env = "MULTIPART_THRESHOLD_MB", default_value_t = 1024)]
+    pub multipart_threshold_mb: u64,
+
+    /// Length of each data subdirectory name, taken from the pack ID
+    /// (0 stores packs directly in `data/`)
+    #[arg(long, env = "DATA_SHARD_LEN", default_value_t = 2)]
+    pub data_shard_len: usize,
+
+    /// Levels of data subdirectories (0-3); repositories keep the layout
+    /// recorded when they were created
+    #[arg(long, env = "DATA_SHARD_DEPTH", default_value_t = 1)]
+    pub data_shard_depth: usize,
+
+    /// Upload bandwidth limit to 123pan in bytes per second, with optional
+    /// K/M/G suffix (e.g. `2M`); 0 for unlimited
+    #[arg(long, env = "MAX_UPLOAD_RATE", default_value = "0", value_parser = parse_byte_rate)]
+    pub max_upload_rate: u64,
+
+    /// Download bandwidth limit from 123pan, like `MAX_UPLOAD_RATE`
+    #[arg(long, env = "MAX_DOWNLOAD_RATE", default_value = "0", value_parser = parse_byte_rate)]
+    pub max_download_rate: u64,
+
+    /// Transfers of at least this many MiB log their progress (0 disables)
+    #[arg(long, env = "PROGRESS_LOG_THRESHOLD_MB", default_value_t = 256)]
+    pub progress_log_threshold_mb: u64,
+
+    /// Seconds between progress lines of a large transfer
+    #[arg(long, env = "PROGRESS_LOG_INTERVAL_SECS", default_value_t = 30)]
+    pub progress_log_interval_secs: u64,
+
+    /// Seconds to wait for 123pan to finalize a slice upload it verifies
+    /// asynchronously
+    #[arg(long, env = "UPLOAD_COMPLETE_TIMEOUT_SECS", default_value_t = 300)]
+    pub upload_complete_timeout_secs: u64,
+
+    /// Seconds after which the list of upload domains is fetched again
+    #[arg(long, env = "UPLOAD_DOMAIN_TTL_SECS", default_value_t = 3600)]
+    pub upload_domain_ttl_secs: u64,
+
+    /// Idle connections to each 123pan host kept for reuse
+    #[arg(long, env = "POOL_MAX_IDLE_PER_HOST", default_value_t = 32)]
+    pub pool_max_idle_per_host: usize,
+
+    /// Seconds an idle connection to 123pan is kept for reuse
+    #[arg(long, env = "POOL_IDLE_TIMEOUT_SECS", default_value_t = 90)]
+    pub pool_idle_timeout_secs: u64,
+
+    /// Negotiate HTTP/2 with 123pan where offered (`false` for HTTP/1.1 only)
+    #[arg(long, env = "UPSTREAM_HTTP2", default_value = "true", action = clap::ArgAction::Set)]
+    pub upstream_http2: bool,
+
+    /// Seconds between TCP keepalive probes on connections to 123pan (0 disables)
+    #[arg(long, env = "TCP_KEEPALIVE_SECS", default_value_t = 60)]
+    pub tcp_keepalive_secs: u64,
+
+    /// Seconds to wait for a connection to 123pan
+    #[arg(long, env = "CONNECT_TIMEOUT_SECS", default_value_t = 10)]
+    pub connect_timeout_secs: u64,
+
+    /// Seconds to wait for the next bytes of a 123pan response
+    #[arg(long, env = "READ_TIMEOUT_SECS", default_value_t = 30)]
+    pub read_timeout_secs: u64,
+
+    /// Total seconds of a 123pan API call (directory listings are exempt)
+    #[arg(long, env = "API_TIMEOUT_SECS", default_value_t = 30)]
+    pub api_timeout_secs: u64,
+
+    /// Total seconds of an upload, before adding `UPLOAD_TIMEOUT_PER_MIB_SECS`
+    #[arg(long, env = "UPLOAD_TIMEOUT_SECS", default_value_t = 60)]
+    pub upload_timeout_secs: u64,
+
+    /// Seconds added to the upload timeout per MiB uploaded
+    #[arg(long, env = "UPLOAD_TIMEOUT_PER_MIB_SECS", default_value_t = 2)]
+    pub upload_timeout_per_mib_secs: u64,
+
+    /// Total seconds of one download request (a chunk of parallel downloads)
+    #[arg(long, env = "DOWNLOAD_TIMEOUT_SECS", default_value_t = 300)]
+    pub download_timeout_secs: u64,
+
+    /// Milliseconds deletes are collected for before being carried out on
+    /// 123pan in batches; they are acknowledged and hidden from listings
+    /// right away (0 deletes each file before answering)
+    #[arg(long, env = "DELETE_BATCH_WINDOW_MS", default_value_t = 1000)]
+    pub delete_batch_window_ms: u64,
+
+    /// Seconds after which a restic request still without a response is
+    /// answered with 504 (0 disables)
+    #[arg(long, env = "REQUEST_DEADLINE_SECS", default_value_t = 0)]
+    pub request_deadline_secs: u64,
+
+    /// Milliseconds after which a restic request is logged as slow, with the
+    /// 123pan calls it waited on (0 disables)
+    #[arg(long, env = "SLOW_REQUEST_MS", default_value_t = 0)]
+    pub slow_request_ms: u64,
+
+    /// Seconds a client has to send a complete request head (0 disables)
+    #[arg(long, env = "HEADER_READ_TIMEOUT_SECS", default_value_t = 30)]
+    pub header_read_timeout_secs: u64,
+
+    /// Seconds after which a client connection without requests or traffic is
+    /// closed (0 disables)
+    #[arg(long, env = "IDLE_CONNECTION_TIMEOUT_SECS", default_value_t = 300)]
+    pub idle_connection_timeout_secs: u64,
+
+    /// Client connections served at once; further ones wait to be accepted
+    /// (0 = unlimited)
+    #[arg(long, env = "MAX_CONNECTIONS", default_value_t = 0)]
+    pub max_connections: usize,
+
+    /// Maximum seconds to wait for in-flight requests to finish on shutdown
+    #[arg(long, env = "SHUTDOWN_TIMEOUT", default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
+    /// Detach from the terminal and serve in the background (Unix)
+    #[arg(long, env = "DAEMON", default_value = "false")]
+    pub daemon: bool,
+
+    /// File the serving process writes its ID to, removed on exit
+    #[arg(long, env = "PID_FILE")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Environment file (`KEY=VALUE` lines) overriding the environment; it is
+    /// re-read on SIGHUP to apply reloadable settings
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// What to do (serve when omitted)
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Config {
+    /// Resolve the cache database URL: `DATABASE_URL`, or a SQLite file at `DB_PATH`.
+    pub fn database_url(&self) -> String {
+        self.database_url
+            .clone()
+            .unwrap_or_else(|| format!("sqlite:{}?mode=rwc", self.db_path))
+    }
+
+    /// Resolve the effective listen address.
+    pub fn listen_addr(&self) -> ListenAddr {
+        self.listen.clone().unwrap_or_else(|| {
+            let host = &self.listen_addr;
+            if host.contains(':') && !host.starts_with('[') {
+                // An IPv6 address such as `::`
+                ListenAddr::Tcp(format!("[{}]:{}", host, self.listen_port))
+            } else {
+                ListenAddr::Tcp(format!("{}:{}", host, self.listen_port))
+            }
+        })
+    }
+}
+
+impl Config {
+    /// Parse the configuration, applying `CONFIG_FILE` (if set) on top of the
+    /// process environment first, so it can also hold required settings
+    /// such as the credentials.
+    pub fn load() -> anyhow::Result<Self> {
+        if let Some(path) = config_file_arg() {
+            for (key, value) in read_env_file(&path)? {
+                std::env::set_var(key, value);
+            }
+        }
+        Ok(Self::parse())
+    }
+
+    /// Resolve the 123pan client ID and secret, reading secret files if given
+    /// and falling back to the keyring with `USE_KEYRING`.
+    pub fn credentials(&self) -> anyhow::Result<(String, String)> {
+        let (client_id, client_secret) = self.given_credentials()?;
+        let client_id = self.or_keyring(client_id, keyring::CLIENT_ID, "client ID")?;
+        let client_secret =
+            self.or_keyring(client_secret, keyring::CLIENT_SECRET, "client secret")?;
+        Ok((client_id, client_secret))
+    }
+
+    /// The client ID and secret given as options or files, without looking
+    /// in the keyring.
+    pub fn given_credentials(&self) -> anyhow::Result<(Option<String>, Option<String>)> {
+        Ok((
+            read_secret(&self.client_id, &self.client_id_file, "client ID")?,
+            read_secret(
+                &self.client_secret,
+                &self.client_secret_file,
+                "client secret",
+            )?,
+        ))
+    }
+
+    fn or_keyring(
+        &self,
+        secret: Option<String>,
+        account: &str,
+        what: &str,
+    ) -> anyhow::Result<String> {
+        let secret = match secret {
+            Some(secret) => secret,
+            None if self.use_keyring => keyring::get(account)?.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No 123pan {} in the keyring; store it with `restic-123pan credentials set`",
+                    what
+                )
+            })?,
+            None => anyhow::bail!("No 123pan {} configured", what),
+        };
+        if secret.is_empty() {
+            anyhow::bail!("123pan {} is empty", what);
+        }
+        Ok(secret)
+    }
+
+    /// Resolve the additional credential pairs from the option and file.
+    pub fn extra_credentials(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let mut pairs = self.extra_credentials.clone();
+        if let Some(path) = &self.extra_credentials_file {
+            let content = std::fs::read_to_string(path).map_err(|e| {
+                anyhow::anyhow!("Failed to read credentials file {}: {}", path.display(), e)
+            })?;
+            pairs.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+
+        pairs
+            .iter()
+            .map(|pair| {
+                let (client_id, client_secret) = pair
+                    .trim()
+                    .split_once(':')
+                    .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Extra credentials must be client_id:client_secret pairs")
+                    })?;
+                Ok((client_id.to_string(), client_secret.to_string()))
+            })
+            .collect()
+    }
+
+    /// Re-read `CONFIG_FILE` and the secret files and return this
+    /// configuration with the reloadable settings (log level, rate limits,
+    /// credentials) updated. Other settings changed in `CONFIG_FILE` are
+    /// logged as needing a restart.
+    pub fn reloaded(&self) -> anyhow::Result<Self> {
+        let mut config = self.clone();
+        if self.client_id_file.is_some() {
+            config.client_id = read_secret(&None, &self.client_id_file, "client ID")?;
+        }
+        if self.client_secret_file.is_some() {
+            config.client_secret = read_secret(&None, &self.client_secret_file, "client secret")?;
+        }
+        let Some(path) = &self.config_file else {
+            return Ok(config);
+        };
+        for (key, value) in read_env_file(path)? {
+            match key.as_str() {
+                "RUST_LOG" => config.log_level = value,
+                "RATE_LIMIT_RPS" => config.rate_limit_rps = value.parse()?,
+                "RATE_LIMIT_BURST" => config.rate_limit_burst = value.parse()?,
+                "PAN123_CLIENT_ID" => config.client_id = Some(value),
+                "PAN123_CLIENT_SECRET" => config.client_secret = Some(value),
+                "PAN123_EXTRA_CREDENTIALS" => {
+                    config.extra_credentials = value.split(',').map(str::to_string).collect()
+                }
+                // Loaded into the environment at startup
+                _ if std::env::var(&key).ok().as_deref() != Some(value.as_str()) => {
+                    tracing::warn!("{} changed in {}; restart to apply it", key, path.display());
+                }
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Take a secret from its value or, failing that, from the file holding it.
+fn read_secret(
+    value: &Option<String>,
+    file: &Option<PathBuf>,
+    what: &str,
+) -> anyhow::Result<Option<String>> {
+    Ok(match (value, file) {
+        (Some(value), _) => Some(value.clone()),
+        (None, Some(path)) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|e| {
+                    anyhow::anyhow!("Failed to read {} file {}: {}", what, path.display(), e)
+                })?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        ),
+        (None, None) => None,
+    })
+}
+
+/// `--config-file` from the command line, or `CONFIG_FILE`, looked up before
+/// the full parse.
+fn config_file_arg() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config-file" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config-file=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("CONFIG_FILE").map(PathBuf::from)
+}
+
+/// Read `KEY=VALUE` pairs from an environment file. Blank lines, `#`
+/// comments and an `export ` prefix are allowed; values may be quoted.
+pub fn read_env_file(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+
+    let mut vars = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("{}:{}: expected KEY=VALUE", path.display(), lineno + 1)
+        })?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+            .unwrap_or(value);
+        vars.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+fn parse_file_type(s: &str) -> Result<ResticFileType, String> {
+    ResticFileType::from_str(s).ok_or_else(|| format!("Unknown object type '{}'", s))
+}
+
+fn parse_sentry_dsn(s: &str) -> Result<String, String> {
+    s.parse::<sentry::types::Dsn>()
+        .map(|_| s.to_string())
+        .map_err(|e| format!("Invalid Sentry DSN: {}", e))
+}
+
+fn parse_octal_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("Invalid octal mode '{}': {}", s, e))
+}
+
+/// Bytes per second from e.g. `500K` or `2M` (binary multiples).
+fn parse_byte_rate(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim().trim_end_matches("/s");
+    let trimmed = trimmed.strip_suffix(['B', 'b']).unwrap_or(trimmed);
+    let (number, multiplier) = match trimmed.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&trimmed[..i], 1u64 << 10),
+        Some((i, 'M' | 'm')) => (&trimmed[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&trimmed[..i], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid rate '{}', expected e.g. 500K or 2M", s))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("Invalid rate '{}'", s));
+    }
+    Ok((value * multiplier as f64) as u64)
+}
+
+/// Subcommands, all sharing the options above.
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Serve the restic REST API (the default)
+    Serve,
+    /// Bring the cache database schema up to date and move data packs into
+    /// the configured layout (`DATA_SHARD_LEN`, `DATA_SHARD_DEPTH`)
+    Migrate {
+        /// Move data packs back into a flat `data/` and remove the empty
+        /// shard directories
+        #[arg(long)]
+        reverse: bool,
+    },
+    /// Check the cache database for damage, orphaned nodes and duplicate names
+    Fsck {
+        /// Repair what is found
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Print the number and total size of objects per type
+    Stats,
+    /// Test the token, API latency, clock, account space and a scratch file
+    /// round trip, and diagnose what fails
+    Doctor {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Read back every object and check its length and MD5 (and optionally
+    /// SHA-256 against its name), without restic
+    Verify {
+        /// Only check config, keys, snapshots and index, not data packs
+        #[arg(long)]
+        metadata_only: bool,
+        /// Percentage of data packs to read (a different sample each run)
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(0..=100))]
+        data_sample: u32,
+        /// Also compare the SHA-256 of each object with its name
+        #[arg(long)]
+        sha256: bool,
+        /// Objects read at the same time
+        #[arg(long, default_value_t = 4)]
+        parallelism: usize,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Download the repository into a local directory laid out like a restic
+    /// local repository; rerun to resume
+    Export {
+        /// Target directory (created if missing)
+        dir: PathBuf,
+        /// Objects downloaded at the same time
+        #[arg(long, default_value_t = 4)]
+        parallelism: usize,
+    },
+    /// Upload a local restic repository into the repository path, placing
+    /// data packs in the configured layout; rerun to resume
+    Import {
+        /// Local restic repository directory
+        dir: PathBuf,
+        /// Objects uploaded at the same time
+        #[arg(long, default_value_t = 4)]
+        parallelism: usize,
+    },
+    /// Copy new and changed objects to a second repository path, optionally
+    /// on another account (nothing is deleted there)
+    Mirror {
+        /// Repository path to copy to
+        #[arg(long, env = "MIRROR_REPO_PATH")]
+        to_repo_path: String,
+        /// Client ID of the account to copy to (defaults to this one)
+        #[arg(long, env = "MIRROR_CLIENT_ID", requires = "to_client_secret")]
+        to_client_id: Option<String>,
+        /// Client secret of the account to copy to
+        #[arg(long, env = "MIRROR_CLIENT_SECRET", hide_env_values = true)]
+        to_client_secret: Option<String>,
+        /// Objects copied at the same time
+        #[arg(long, default_value_t = 4)]
+        parallelism: usize,
+    },
+    /// Find leftovers of failed uploads (empty objects, duplicate names,
+    /// copies of packs outside their shard) and move them to the trash
+    Gc {
+        /// Trash without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Compare the objects of the repository with those of another
+    /// repository path (or account) or local directory by name, size and MD5
+    Diff {
+        /// Repository path to compare with
+        #[arg(
+            long,
+            required_unless_present = "other_dir",
+            conflicts_with = "other_dir"
+        )]
+        other_repo_path: Option<String>,
+        /// Client ID of the account holding the other repository (defaults
+        /// to this one)
+        #[arg(long, requires_all = ["other_client_secret", "other_repo_path"])]
+        other_client_id: Option<String>,
+        /// Client secret of that account
+        #[arg(long, hide_env_values = true)]
+        other_client_secret: Option<String>,
+        /// Local restic repository directory to compare with
+        #[arg(long)]
+        other_dir: Option<PathBuf>,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the objects of a type with their size and modification time
+    Ls {
+        /// Object type (config, keys, locks, snapshots, index, data)
+        #[arg(value_parser = parse_file_type)]
+        file_type: ResticFileType,
+    },
+    /// Write an object to stdout or a file
+    Cat {
+        /// Object type (config, keys, locks, snapshots, index, data)
+        #[arg(value_parser = parse_file_type)]
+        file_type: ResticFileType,
+        /// Object name (omitted for config)
+        name: Option<String>,
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Delete an object
+    Rm {
+        /// Object type (config, keys, locks, snapshots, index, data)
+        #[arg(value_parser = parse_file_type)]
+        file_type: ResticFileType,
+        /// Object name (omitted for config)
+        name: Option<String>,
+        /// Delete without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Manage the client ID and secret in the operating system's keyring
+    Credentials {
+        #[command(subcommand)]
+        action: CredentialsAction,
+    },
+    /// Register, remove or run as a Windows service serving like `serve`
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// Find files sharing a name in one directory, keep the one matching its
+    /// name (or the newest) and move the others to the trash
+    Dedupe {
+        /// Trash without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+/// Subcommands of `credentials`.
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum CredentialsAction {
+    /// Store the client ID and secret in the keyring, taken from the options
+    /// or asked for
+    Set,
+}
+
+/// Subcommands of `service`.
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum ServiceAction {
+    /// Register the service to start at boot with `--config-file`, which must
+    /// hold all settings (services don't see the user's environment)
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Serve as the service (started by the Service Control Manager)
+    Run,
+}
+
+/// Log output format.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text lines
+    Text,
+    /// Structured JSON objects, one per line
+    Json,
+}
+
+/// Cache warm-up strategy. Metadata directories (keys, snapshots, index,
+/// locks and the config file) are always listed before serving.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmUpMode {
+    /// Load data shards on first use
+    Lazy,
+    /// Crawl data shards in a background task after startup
+    Background,
+    /// Crawl everything before serving
+    Full,
+}
+
+/// Use of the spool directory (see `SPOOL_MODE`).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpoolMode {
+    /// Acknowledge every upload once spooled and upload it in the background
+    WriteBack,
+    /// Upload directly and only queue uploads that failed after all retries
+    Retry,
+}
+
+/// Revalidation of expired directory listings (see `CACHE_TTL_SECS`).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revalidation {
+    /// Fetch the listing again before serving it
+    Access,
+    /// Serve the cached listing and refresh it from a background task
+    Background,
+}
+
+/// Confirmation of HEAD requests with 123pan (see `HEAD_VERIFY`).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadVerify {
+    /// Answer from the directory cache
+    Cache,
+    /// Confirm the config, keys and snapshots with 123pan's file details
+    Metadata,
+    /// Confirm every object, one API call per HEAD
+    Always,
+}
+
+impl HeadVerify {
+    /// Object types whose HEAD is confirmed.
+    pub fn file_types(self) -> Vec<ResticFileType> {
+        match self {
+            HeadVerify::Cache => Vec::new(),
+            HeadVerify::Metadata => vec![
+                ResticFileType::Config,
+                ResticFileType::Keys,
+                ResticFileType::Snapshots,
+            ],
+            HeadVerify::Always => vec![
+                ResticFileType::Config,
+                ResticFileType::Keys,
+                ResticFileType::Snapshots,
+                ResticFileType::Index,
+                ResticFileType::Locks,
+                ResticFileType::Data,
+            ],
+        }
+    }
+}
+
+/// Startup self-check of the cache database (see `CACHE_CHECK`).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheCheck {
+    /// Skip the check
+    Off,
+    /// Log what is found
+    Report,
+    /// Log and repair what is found
+    Repair,
+}
+
+/// Handling of the repository lock held by another instance (see `SERVER_LOCK`).
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerLockMode {
+    /// Don't take the lock
+    Off,
+    /// Refuse to start
+    Fail,
+    /// Serve reads and refuse writes until the lock is released
+    ReadOnly,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_from_env_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            b"# reloadable settings\nexport RUST_LOG=\"debug\"\nRATE_LIMIT_RPS=2.5\n\nRATE_LIMIT_BURST='10'\nPAN123_CLIENT_SECRET=rotated\n",
+        )
+        .unwrap();
+
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--client-id",
+            "id",
+            "--client-secret",
+            "secret",
+            "--config-file",
+            file.path().to_str().unwrap(),
+        ]);
+        let reloaded = config.reloaded().unwrap();
+        assert_eq!(reloaded.log_level, "debug");
+        assert_eq!(reloaded.rate_limit_rps, 2.5);
+        assert_eq!(reloaded.rate_limit_burst, 10);
+        assert_eq!(reloaded.credentials().unwrap().1, "rotated");
+    }
+
+    #[test]
+    fn test_reload_secret_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let id_file = dir.path().join("client_id");
+        let secret_file = dir.path().join("client_secret");
+        std::fs::write(&id_file, "id\n").unwrap();
+        std::fs::write(&secret_file, "secret\n").unwrap();
+
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--client-id-file",
+            id_file.to_str().unwrap(),
+            "--client-secret-file",
+            secret_file.to_str().unwrap(),
+        ]);
+        std::fs::write(&secret_file, "rotated\n").unwrap();
+        let reloaded = config.reloaded().unwrap();
+        assert_eq!(reloaded.client_id.as_deref(), Some("id"));
+        assert_eq!(reloaded.client_secret.as_deref(), Some("rotated"));
+        assert_eq!(
+            reloaded.credentials().unwrap(),
+            ("id".to_string(), "rotated".to_string())
+        );
+    }
+
+    #[test]
+    fn test_credentials_from_files() {
+        let mut secret = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut secret, b"s3cret\n").unwrap();
+
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--client-id",
+            "id",
+            "--client-secret-file",
+            secret.path().to_str().unwrap(),
+        ]);
+        let (client_id, client_secret) = config.credentials().unwrap();
+        assert_eq!(client_id, "id");
+        assert_eq!(client_secret, "s3cret");
+    }
+
+    #[test]
+    fn test_keyring_credentials() {
+        // The keyring is only consulted for what is not given otherwise
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--use-keyring",
+            "--client-id",
+            "id",
+            "--client-secret",
+            "s",
+        ]);
+        assert_eq!(
+            config.credentials().unwrap(),
+            ("id".to_string(), "s".to_string())
+        );
+
+        let config = Config::parse_from(["restic-123pan", "--use-keyring", "credentials", "set"]);
+        assert_eq!(
+            config.command,
+            Some(Command::Credentials {
+                action: CredentialsAction::Set
+            })
+        );
+        assert_eq!(config.given_credentials().unwrap(), (None, None));
+        assert!(Config::try_parse_from(["restic-123pan", "credentials", "set"]).is_err());
+    }
+
+    #[test]
+    fn test_extra_credentials() {
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--client-id",
+            "id",
+            "--client-secret",
+            "secret",
+            "--extra-credentials",
+            "id2:secret2,id3:secret3",
+        ]);
+        assert_eq!(
+            config.extra_credentials().unwrap(),
+            vec![
+                ("id2".to_string(), "secret2".to_string()),
+                ("id3".to_string(), "secret3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_byte_rate() {
+        assert_eq!(parse_byte_rate("0"), Ok(0));
+        assert_eq!(parse_byte_rate("1000"), Ok(1000));
+        assert_eq!(parse_byte_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_byte_rate("2M"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_byte_rate("1.5MB/s"), Ok(3 * 512 * 1024));
+        assert_eq!(parse_byte_rate("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_byte_rate("fast").is_err());
+        assert!(parse_byte_rate("-1M").is_err());
+    }
+
+    #[test]
+    fn test_sentry_dsn() {
+        let args = ["restic-123pan", "--client-id", "id", "--client-secret", "s"];
+        assert_eq!(Config::parse_from(args).sentry_dsn, None);
+
+        let dsn = "https://key@o1.ingest.sentry.io/42";
+        let config = Config::parse_from(args.iter().chain(&["--sentry-dsn", dsn]));
+        assert_eq!(config.sentry_dsn.as_deref(), Some(dsn));
+        assert!(Config::try_parse_from(args.iter().chain(&["--sentry-dsn", "not-a-dsn"])).is_err());
+    }
+
+    #[test]
+    fn test_rest_server_flags() {
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--client-id",
+            "id",
+            "--client-secret",
+            "s",
+            "--path",
+            "/backups",
+            "--listen",
+            ":8080",
+            "--append-only",
+            "--no-auth",
+        ]);
+        assert_eq!(config.repo_path, "/backups");
+        assert_eq!(
+            config.listen_addr(),
+            ListenAddr::Tcp("0.0.0.0:8080".to_string())
+        );
+        assert!(config.append_only);
+        assert!(config.no_auth);
+
+        let config = Config::parse_from([
+            "restic-123pan",
+            "--client-id",
+            "id",
+            "--client-secret",
+            "s",
+            "--listen-addr",
+            "::",
+        ]);
+        assert_eq!(
+            config.listen_addr(),
+            ListenAddr::Tcp("[::]:8000".to_string())
+        );
+        assert!(!config.private_repos);
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let args = ["restic-123pan", "--client-id", "id", "--client-secret", "s"];
+        assert_eq!(Config::parse_from(args).command, None);
+        let config = Config::parse_from(args.iter().chain(&["--repo-path", "/r", "migrate"]));
+        assert_eq!(config.command, Some(Command::Migrate { reverse: false }));
+        assert_eq!(config.repo_path, "/r");
+        let config = Config::parse_from(args.iter().chain(&["migrate", "--reverse"]));
+        assert_eq!(config.command, Some(Command::Migrate { reverse: true }));
+        let config = Config::parse_from(args.iter().chain(&["fsck", "--repair"]));
+        assert_eq!(config.command, Some(Command::Fsck { repair: true }));
+        let config = Config::parse_from(args.iter().chain(&["cat", "keys", "ab", "-o", "key"]));
+        assert_eq!(
+            config.command,
+            Some(Command::Cat {
+                file_type: ResticFileType::Keys,
+                name: Some("ab".to_string()),
+                output: Some(PathBuf::from("key")),
+            })
+        );
+        assert!(Config::try_parse_from(args.iter().chain(&["ls", "packs"])).is_err());
+        assert!(Config::try_parse_from(args.iter().chain(&["diff"])).is_err());
+        assert!(Config::try_parse_from(args.iter().chain(&[
+            "diff",
+            "--other-repo-path",
+            "/a",
+            "--other-dir",
+            "b"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_database_url() {
+        let args = ["restic-123pan", "--client-id", "id", "--client-secret", "s"];
+        let config = Config::parse_from(args.iter().chain(&["--db-path", "/var/lib/cache.db"]));
+        assert_eq!(config.database_url(), "sqlite:/var/lib/cache.db?mode=rwc");
+
+        let config = Config::parse_from(
+            args.iter()
+                .chain(&["--database-url", "postgres://cache@db/restic"]),
+        );
+        assert_eq!(config.database_url(), "postgres://cache@db/restic");
+    }
 }
@@ -0,0 +1,430 @@
+//! Cache database connection and portable schema helpers.
+//!
+//! The cache defaults to a local SQLite file, but any sea-orm backend works:
+//! pointing several replicas at one PostgreSQL or MySQL database lets them
+//! share the file tree cache and access token.
+
+use sea_orm::{
+    sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous},
+    ConnectOptions, ConnectionTrait, Database, DatabaseBackend, DatabaseConnection, DbErr,
+    QueryResult, Statement,
+};
+use serde::Serialize;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::{AppError, Result};
+
+/// How long a SQLite connection waits for another writer's lock before
+/// failing with `SQLITE_BUSY` ("database is locked").
+pub const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lifetime of the single connection holding an in-memory database; it must
+/// outlive the process because the data vanishes when it closes.
+const PINNED_CONNECTION_LIFETIME: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+/// Retries of a cache write that still failed with `SQLITE_BUSY`.
+const BUSY_RETRIES: usize = 5;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Connect to the cache database, applying SQLite tuning when applicable and
+/// bringing the schema up to date.
+pub async fn connect(database_url: &str) -> Result<DatabaseConnection> {
+    let mut opt = ConnectOptions::new(database_url.to_owned());
+    opt.sqlx_logging_level(log::LevelFilter::Debug);
+    // Applied to every pooled connection, not just the first
+    opt.map_sqlx_sqlite_opts(|opts| {
+        opts.journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(SQLITE_BUSY_TIMEOUT)
+            .pragma("cache_size", "-256000") // 256MB; negative means KiB
+            .pragma("temp_store", "MEMORY")
+            .pragma("mmap_size", "30000000000")
+    });
+
+    if is_sqlite_memory(database_url) {
+        // Every connection to `:memory:` opens its own empty database, so
+        // pin exactly one connection and never recycle it
+        opt.max_connections(1)
+            .min_connections(1)
+            .idle_timeout(PINNED_CONNECTION_LIFETIME)
+            .max_lifetime(PINNED_CONNECTION_LIFETIME);
+    }
+
+    let db = Database::connect(opt)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to connect to database: {}", e)))?;
+
+    let applied = crate::migration::run(&db).await?;
+    if !applied.is_empty() {
+        tracing::info!("Applied {} cache database migrations", applied.len());
+    }
+
+    Ok(db)
+}
+
+/// Connect like [`connect`], but if the SQLite database file is damaged, move
+/// it aside (see [`quarantine`]) and start over with an empty one.
+pub async fn connect_or_recreate(database_url: &str) -> Result<DatabaseConnection> {
+    match connect(database_url).await {
+        Err(e) if is_corruption(&e.to_string()) => {
+            let Some(moved) = quarantine(database_url)? else {
+                return Err(e);
+            };
+            tracing::warn!(
+                "Cache database is corrupt ({}); moved it to {} and rebuilding the cache",
+                e,
+                moved.display()
+            );
+            connect(database_url).await
+        }
+        result => result,
+    }
+}
+
+/// Whether an error message means the SQLite database file is damaged
+/// (`SQLITE_CORRUPT` or `SQLITE_NOTADB`).
+pub fn is_corruption(message: &str) -> bool {
+    message.contains("malformed")
+        || message.contains("not a database")
+        || message.contains("(code: 11)")
+        || message.contains("(code: 26)")
+}
+
+/// File behind a SQLite URL; `None` for other backends and in-memory databases.
+pub fn sqlite_path(database_url: &str) -> Option<PathBuf> {
+    if is_sqlite_memory(database_url) {
+        return None;
+    }
+    let rest = database_url.strip_prefix("sqlite:")?;
+    let rest = rest.strip_prefix("//").unwrap_or(rest);
+    let path = rest.split('?').next().unwrap_or_default();
+    (!path.is_empty()).then(|| PathBuf::from(path))
+}
+
+/// Move a SQLite database file and its WAL/SHM files aside so the next
+/// connection creates a fresh one. Returns the new path of the database, or
+/// `None` if there is no file to move. Close all connections first.
+pub fn quarantine(database_url: &str) -> Result<Option<PathBuf>> {
+    let Some(path) = sqlite_path(database_url) else {
+        return Ok(None);
+    };
+    if !path.exists() {
+        return Ok(None);
+    }
+    let target = PathBuf::from(format!(
+        "{}.corrupt-{}",
+        path.display(),
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+    for suffix in ["", "-wal", "-shm"] {
+        let from = PathBuf::from(format!("{}{}", path.display(), suffix));
+        if from.exists() {
+            std::fs::rename(&from, format!("{}{}", target.display(), suffix))?;
+        }
+    }
+    Ok(Some(target))
+}
+
+/// Whether `database_url` names an in-memory SQLite database
+/// (`sqlite::memory:` or a `mode=memory` URI), which keeps no on-disk state.
+pub fn is_sqlite_memory(database_url: &str) -> bool {
+    database_url.starts_with("sqlite:")
+        && (database_url.contains(":memory:") || database_url.contains("mode=memory"))
+}
+
+/// Whether a statement failed because another connection holds a SQLite lock.
+pub(crate) fn is_busy(e: &DbErr) -> bool {
+    let message = e.to_string();
+    message.contains("database is locked")
+        || message.contains("database table is locked")
+        || message.contains("(code: 5)")
+        || message.contains("(code: 6)")
+}
+
+/// Queue for cache writes.
+///
+/// Writers from one process take turns, so concurrent restic connections
+/// don't contend for the SQLite write lock; a write that still finds it
+/// held (e.g. by another process sharing the file) is retried with backoff
+/// instead of surfacing as a 500.
+#[derive(Clone, Default)]
+pub struct WriteQueue {
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl WriteQueue {
+    /// Run `op` once it is this writer's turn, retrying on `SQLITE_BUSY`.
+    pub async fn run<T, F, Fut>(&self, mut op: F) -> std::result::Result<T, DbErr>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, DbErr>>,
+    {
+        let _turn = self.lock.lock().await;
+        let mut delay = BUSY_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Err(e) if is_busy(&e) && attempt < BUSY_RETRIES => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Cache database busy, retrying in {:?} (attempt {}/{})",
+                        delay,
+                        attempt,
+                        BUSY_RETRIES
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Outcome of a SQLite maintenance run.
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceReport {
+    /// WAL frames copied back into the database file
+    pub wal_frames_checkpointed: i64,
+    /// Free pages returned to the filesystem
+    pub pages_freed: i64,
+    /// Whether the database was converted to incremental auto-vacuum (full VACUUM)
+    pub converted: bool,
+    pub duration_ms: u64,
+}
+
+/// Checkpoint the WAL, reclaim free pages and refresh planner statistics.
+///
+/// Constant upserts and deletes leave a growing WAL and free pages behind.
+/// Returns `None` for server databases, which maintain themselves.
+pub async fn maintain(db: &DatabaseConnection) -> Result<Option<MaintenanceReport>> {
+    if db.get_database_backend() != DatabaseBackend::Sqlite {
+        return Ok(None);
+    }
+    let start = std::time::Instant::now();
+
+    let checkpoint = pragma(db, "PRAGMA wal_checkpoint(TRUNCATE)").await?;
+    let wal_frames_checkpointed = checkpoint
+        .as_ref()
+        .and_then(|row| row.try_get_by_index::<i64>(2).ok())
+        .unwrap_or(0)
+        .max(0);
+
+    let free_before = pragma_value(db, "PRAGMA freelist_count").await?;
+    // Incremental vacuum needs auto_vacuum=INCREMENTAL, which only a full
+    // VACUUM can switch an existing database to (once)
+    let converted = pragma_value(db, "PRAGMA auto_vacuum").await? != 2;
+    if converted {
+        tracing::info!("Converting cache database to incremental auto-vacuum");
+        execute(db, "PRAGMA auto_vacuum = INCREMENTAL").await?;
+        execute(db, "VACUUM").await?;
+    } else {
+        execute(db, "PRAGMA incremental_vacuum").await?;
+    }
+    let free_after = pragma_value(db, "PRAGMA freelist_count").await?;
+
+    execute(db, "ANALYZE").await?;
+
+    let report = MaintenanceReport {
+        wal_frames_checkpointed,
+        pages_freed: (free_before - free_after).max(0),
+        converted,
+        duration_ms: start.elapsed().as_millis() as u64,
+    };
+    tracing::info!(
+        "Cache database maintenance: {} WAL frames checkpointed, {} pages freed in {}ms",
+        report.wal_frames_checkpointed,
+        report.pages_freed,
+        report.duration_ms
+    );
+    Ok(Some(report))
+}
+
+/// Run [`maintain`] every `interval` in the background.
+pub fn spawn_maintenance(
+    db: DatabaseConnection,
+    interval: std::time::Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = maintain(&db).await {
+                tracing::error!("Cache database maintenance failed: {}", e);
+            }
+        }
+    })
+}
+
+async fn execute(db: &DatabaseConnection, sql: &str) -> Result<()> {
+    db.execute(Statement::from_string(DatabaseBackend::Sqlite, sql))
+        .await
+        .map_err(|e| AppError::Internal(format!("{} failed: {}", sql, e)))?;
+    Ok(())
+}
+
+async fn pragma(db: &DatabaseConnection, sql: &str) -> Result<Option<QueryResult>> {
+    db.query_one(Statement::from_string(DatabaseBackend::Sqlite, sql))
+        .await
+        .map_err(|e| AppError::Internal(format!("{} failed: {}", sql, e)))
+}
+
+async fn pragma_value(db: &DatabaseConnection, sql: &str) -> Result<i64> {
+    Ok(pragma(db, sql)
+        .await?
+        .and_then(|row| row.try_get_by_index::<i64>(0).ok())
+        .unwrap_or(0))
+}
+
+/// Whether a schema statement failed only because the column or index it
+/// adds already exists. Not every backend supports `IF NOT EXISTS` for
+/// `ALTER TABLE ADD COLUMN` and `CREATE INDEX`, so these errors are ignored.
+pub(crate) fn is_already_exists(e: &DbErr) -> bool {
+    let message = e.to_string().to_lowercase();
+    // SQLite: "duplicate column name"; PostgreSQL: "... already exists";
+    // MySQL: "Duplicate column name" / "Duplicate key name"
+    message.contains("duplicate column")
+        || message.contains("duplicate key name")
+        || message.contains("already exists")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_corrupt_database_is_recreated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        std::fs::write(&path, vec![0x42; 8192]).unwrap();
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        assert_eq!(sqlite_path(&url), Some(path.clone()));
+        assert_eq!(sqlite_path("sqlite::memory:"), None);
+        assert_eq!(sqlite_path("postgres://db/cache"), None);
+
+        let e = connect(&url).await.unwrap_err();
+        assert!(is_corruption(&e.to_string()), "{}", e);
+
+        let db = connect_or_recreate(&url).await.unwrap();
+        db.execute_unprepared("SELECT COUNT(*) FROM file_nodes")
+            .await
+            .unwrap();
+        let moved: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().contains(".corrupt-"))
+            .collect();
+        assert_eq!(moved.len(), 1);
+    }
+
+    #[test]
+    fn test_already_exists_errors() {
+        for message in [
+            "error returned from database: duplicate column name: modified_at",
+            "error returned from database: column \"lease_owner\" of relation \"token_cache\" already exists",
+            "error returned from database: 1060 (42S21): Duplicate column name 'modified_at'",
+            "error returned from database: 1061 (42000): Duplicate key name 'idx_parent_name'",
+            "error returned from database: index idx_parent_name already exists",
+        ] {
+            assert!(is_already_exists(&DbErr::Custom(message.to_string())), "{}", message);
+        }
+        assert!(!is_already_exists(&DbErr::Custom(
+            "no such table: file_nodes".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_maintain_reclaims_free_pages() {
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let db = connect(&format!("sqlite:{}?mode=rwc", db_file.path().display()))
+            .await
+            .unwrap();
+
+        let first = maintain(&db).await.unwrap().unwrap();
+        assert!(first.converted);
+
+        db.execute_unprepared(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000)
+             INSERT INTO file_nodes (repo, file_id, parent_id, name, is_dir, size, updated_at)
+             SELECT 'r', i, 0, printf('%064d', i), 0, 0, '2025-01-01 00:00:00' FROM n;
+             DELETE FROM file_nodes;",
+        )
+        .await
+        .unwrap();
+
+        let second = maintain(&db).await.unwrap().unwrap();
+        assert!(!second.converted);
+        assert!(second.pages_freed > 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_queue_retries_busy() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let queue = WriteQueue::default();
+        let attempts = AtomicUsize::new(0);
+        let result = queue
+            .run(|| async {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(DbErr::Custom(
+                        "error returned from database: (code: 5) database is locked".to_string(),
+                    ))
+                } else {
+                    Ok(7)
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Other errors are returned immediately
+        attempts.store(0, Ordering::SeqCst);
+        let result: std::result::Result<(), DbErr> = queue
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(DbErr::Custom("UNIQUE constraint failed".to_string()))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_sqlite_memory() {
+        assert!(is_sqlite_memory("sqlite::memory:"));
+        assert!(is_sqlite_memory(
+            "sqlite:file:cache?mode=memory&cache=shared"
+        ));
+        assert!(!is_sqlite_memory("sqlite:cache.db?mode=rwc"));
+        assert!(!is_sqlite_memory("postgres://db/memory"));
+
+        // Concurrent queries all see the schema created by the migrations
+        let db = connect("sqlite::memory:").await.unwrap();
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let db = db.clone();
+            tasks.spawn(async move {
+                db.execute_unprepared("SELECT count(*) FROM file_nodes")
+                    .await
+                    .map(|_| ())
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_sqlite() {
+        let db_file = tempfile::NamedTempFile::new().unwrap();
+        let db = connect(&format!("sqlite:{}?mode=rwc", db_file.path().display()))
+            .await
+            .unwrap();
+        assert_eq!(db.get_database_backend(), DatabaseBackend::Sqlite);
+    }
+}
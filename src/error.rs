@@ -1,12 +1,18 @@
 //! Error types for the restic-123pan application.
 
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde_json::json;
 
+use crate::pan123::ErrorKind;
+use crate::redact::redact;
+
+/// Seconds restic is asked to wait after 123pan rate-limited a request.
+const RATE_LIMIT_RETRY_AFTER: u64 = 5;
+
 /// Application-wide error type.
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
@@ -14,8 +20,8 @@ pub enum AppError {
     #[error("123pan API error: {message}")]
     Pan123Api { code: i32, message: String },
 
-    /// HTTP client error
-    #[error("HTTP request failed: {0}")]
+    /// HTTP client error; presigned URLs in it are redacted
+    #[error("HTTP request failed: {}", redact(&.0.to_string()))]
     HttpClient(#[from] reqwest::Error),
 
     /// Authentication error
@@ -30,6 +36,10 @@ pub enum AppError {
     #[error("Invalid request: {0}")]
     BadRequest(String),
 
+    /// Request refused, e.g. overwriting an immutable object
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
     /// IO error
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -38,6 +48,14 @@ pub enum AppError {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
+    /// Server temporarily unable to handle the request
+    #[error("Service unavailable: {message}")]
+    Unavailable { message: String, retry_after: u64 },
+
+    /// No response within the request deadline
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
     /// Internal error
     #[error("Internal error: {0}")]
     Internal(String),
@@ -47,12 +65,24 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, message) = match &self {
             AppError::Pan123Api { code, message } => {
+                let status = match ErrorKind::of(*code, message) {
+                    ErrorKind::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+                    ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+                    ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                    ErrorKind::Upstream => StatusCode::BAD_GATEWAY,
+                };
                 tracing::error!("123pan API error: code={}, message={}", code, message);
-                (StatusCode::BAD_GATEWAY, message.clone())
+                (status, message.clone())
             }
             AppError::HttpClient(e) => {
                 tracing::error!("HTTP client error: {}", e);
-                (StatusCode::BAD_GATEWAY, e.to_string())
+                let status = if e.is_timeout() {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::BAD_GATEWAY
+                };
+                (status, self.to_string())
             }
             AppError::Auth(msg) => {
                 tracing::error!("Auth error: {}", msg);
@@ -66,6 +96,10 @@ impl IntoResponse for AppError {
                 tracing::warn!("Bad request: {}", msg);
                 (StatusCode::BAD_REQUEST, msg.clone())
             }
+            AppError::Forbidden(msg) => {
+                tracing::warn!("Forbidden: {}", msg);
+                (StatusCode::FORBIDDEN, msg.clone())
+            }
             AppError::Io(e) => {
                 tracing::error!("IO error: {}", e);
                 (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
@@ -74,6 +108,14 @@ impl IntoResponse for AppError {
                 tracing::error!("JSON error: {}", e);
                 (StatusCode::BAD_REQUEST, e.to_string())
             }
+            AppError::Unavailable { message, .. } => {
+                tracing::warn!("Service unavailable: {}", message);
+                (StatusCode::SERVICE_UNAVAILABLE, message.clone())
+            }
+            AppError::Timeout(msg) => {
+                tracing::warn!("Timed out: {}", msg);
+                (StatusCode::GATEWAY_TIMEOUT, msg.clone())
+            }
             AppError::Internal(msg) => {
                 tracing::error!("Internal error: {}", msg);
                 (StatusCode::INTERNAL_SERVER_ERROR, msg.clone())
@@ -81,10 +123,21 @@ impl IntoResponse for AppError {
         };
 
         let body = Json(json!({
-            "error": message
+            "error": redact(&message)
         }));
 
-        (status, body).into_response()
+        let retry_after = match &self {
+            AppError::Unavailable { retry_after, .. } => Some(*retry_after),
+            _ if status == StatusCode::TOO_MANY_REQUESTS => Some(RATE_LIMIT_RETRY_AFTER),
+            _ => None,
+        };
+        let mut response = (status, body).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
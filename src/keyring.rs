@@ -0,0 +1,141 @@
+//! Client credentials in the operating system's keyring.
+//!
+//! Secrets are stored with the platform's own tool, so no keyring library is
+//! linked in: `secret-tool` (Secret Service, e.g. GNOME Keyring or KWallet)
+//! on Linux and the BSDs, `security` (the login keychain) on macOS and the
+//! Credential Locker through PowerShell on Windows. Secrets are passed on
+//! standard input, never on the command line.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Service name the credentials are stored under.
+pub const SERVICE: &str = "restic-123pan";
+
+/// Account holding the client ID.
+pub const CLIENT_ID: &str = "client_id";
+
+/// Account holding the client secret.
+pub const CLIENT_SECRET: &str = "client_secret";
+
+/// Exit status of `security` and of the PowerShell scripts below when there
+/// is no such entry (errSecItemNotFound).
+const NOT_FOUND: i32 = 44;
+
+/// Windows: print the password of the entry named by the environment.
+const WINDOWS_GET: &str = "\
+$ErrorActionPreference = 'Stop'
+[void][Windows.Security.Credentials.PasswordVault,Windows.Security.Credentials,ContentType=WindowsRuntime]
+$vault = New-Object Windows.Security.Credentials.PasswordVault
+try { $c = $vault.Retrieve($env:KEYRING_SERVICE, $env:KEYRING_ACCOUNT) } catch { exit 44 }
+$c.RetrievePassword()
+[Console]::Out.Write($c.Password)";
+
+/// Windows: store standard input as the password of the entry named by the
+/// environment, replacing any previous one.
+const WINDOWS_SET: &str = "\
+$ErrorActionPreference = 'Stop'
+[void][Windows.Security.Credentials.PasswordVault,Windows.Security.Credentials,ContentType=WindowsRuntime]
+$vault = New-Object Windows.Security.Credentials.PasswordVault
+$secret = [Console]::In.ReadToEnd()
+try { $vault.Remove($vault.Retrieve($env:KEYRING_SERVICE, $env:KEYRING_ACCOUNT)) } catch { }
+$vault.Add((New-Object Windows.Security.Credentials.PasswordCredential($env:KEYRING_SERVICE, $env:KEYRING_ACCOUNT, $secret)))";
+
+/// Read the secret stored for `account`, if any.
+pub fn get(account: &str) -> anyhow::Result<Option<String>> {
+    let mut command = if cfg!(windows) {
+        powershell(WINDOWS_GET, account)
+    } else if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args(["find-generic-password", "-s", SERVICE, "-a", account, "-w"]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", SERVICE, "account", account]);
+        command
+    };
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| spawn_error(&command, e))?;
+    if output.status.success() {
+        let secret = String::from_utf8(output.stdout)?;
+        return Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    // secret-tool exits with 1 and says nothing when there is no entry
+    let not_found = output.status.code() == Some(NOT_FOUND)
+        || (output.status.code() == Some(1) && output.stderr.is_empty());
+    if not_found {
+        return Ok(None);
+    }
+    anyhow::bail!(
+        "Failed to read {} from the keyring: {}",
+        account,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+/// Store `secret` for `account`, replacing what was stored before.
+pub fn set(account: &str, secret: &str) -> anyhow::Result<()> {
+    let (mut command, input) = if cfg!(windows) {
+        (powershell(WINDOWS_SET, account), secret.to_string())
+    } else if cfg!(target_os = "macos") {
+        // Interactive mode reads the command from standard input, keeping the
+        // secret out of the process list
+        if secret.contains(['"', '\\', '\n']) {
+            anyhow::bail!("The keychain can't store secrets containing quotes or backslashes");
+        }
+        let mut command = Command::new("security");
+        command.arg("-i");
+        let input = format!(
+            "add-generic-password -U -s {} -a {} -w \"{}\"\n",
+            SERVICE, account, secret
+        );
+        (command, input)
+    } else {
+        let mut command = Command::new("secret-tool");
+        let label = format!("{} {}", SERVICE, account);
+        command.args([
+            "store", "--label", &label, "service", SERVICE, "account", account,
+        ]);
+        (command, secret.to_string())
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error(&command, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to store {} in the keyring: {}",
+            account,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// PowerShell running `script` for `account`.
+fn powershell(script: &str, account: &str) -> Command {
+    let mut command = Command::new("powershell");
+    command
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .env("KEYRING_SERVICE", SERVICE)
+        .env("KEYRING_ACCOUNT", account);
+    command
+}
+
+fn spawn_error(command: &Command, e: std::io::Error) -> anyhow::Error {
+    let program = command.get_program().to_string_lossy();
+    let hint = if program == "secret-tool" {
+        " (install libsecret-tools and run a Secret Service such as GNOME Keyring)"
+    } else {
+        ""
+    };
+    anyhow::anyhow!("Failed to run {}{}: {}", program, hint, e)
+}
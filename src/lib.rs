@@ -3,6 +3,13 @@
 //! This library provides the core functionality for the restic-123pan.
 
 pub mod config;
+pub mod db;
 pub mod error;
+pub mod keyring;
+pub mod migration;
 pub mod pan123;
+pub mod redact;
 pub mod restic;
+pub mod server;
+pub mod service;
+pub mod storage;
@@ -3,70 +3,1363 @@
 //! This server implements the Restic REST backend protocol and uses
 //! 123pan as the underlying storage provider.
 
-use clap::Parser;
-use std::net::SocketAddr;
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
+use sentry::integrations::tracing::EventFilter;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter,
+    Registry,
+};
 
-use restic_123pan::config::Config;
-use restic_123pan::pan123::Pan123Client;
-use restic_123pan::restic::create_router;
+use restic_123pan::config::{
+    CacheCheck, Command, Config, CredentialsAction, LogFormat, Revalidation, ServerLockMode,
+    ServiceAction, SpoolMode, WarmUpMode,
+};
+use restic_123pan::db;
+use restic_123pan::keyring;
+use restic_123pan::pan123::gc::Garbage;
+use restic_123pan::pan123::server_lock::ServerLock;
+use restic_123pan::pan123::{
+    ConcurrencyLimits, DirectBackend, Pan123Client, Pan123ClientBuilder, PoolOptions, Timeouts,
+};
+use restic_123pan::redact::{redact, RedactingWriter};
+use restic_123pan::restic::audit::AuditLog;
+use restic_123pan::restic::middleware::{rate_limit, ModeSwitch, RateLimiter};
+use restic_123pan::restic::read_cache::{MetadataCache, PackCache};
+use restic_123pan::restic::spool::WriteBackSpool;
+use restic_123pan::restic::{
+    create_router, AdminAccess, RepoLayout, ResticFileType, ServerOptions,
+};
+use restic_123pan::server::{self, ConnectionOptions, Listener};
+use restic_123pan::service::{self, PidFile};
+use restic_123pan::storage::{
+    self, CopyOptions, CopyReport, LocalBackend, StorageBackend, VerifyOptions,
+};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// First wait before a failed cache warm-up is tried again; doubles per try.
+const WARM_UP_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+const MAX_WARM_UP_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+fn main() -> anyhow::Result<()> {
     // Parse configuration
-    let config = Config::parse();
+    let config = Config::load()?;
 
-    // Initialize logging
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| config.log_level.clone().into()),
+    if let Some(Command::Service { action }) = &config.command {
+        return service_command(config.clone(), action.clone());
+    }
+    if config.daemon {
+        #[cfg(unix)]
+        service::daemonize()?;
+        #[cfg(not(unix))]
+        anyhow::bail!(
+            "--daemon is only supported on Unix; register a service with `service install`"
+        );
+    }
+    let daemon = config.daemon;
+    let result = runtime()?.block_on(run(config));
+    if daemon {
+        // Standard error is gone, so failures can only be logged
+        if let Err(e) = &result {
+            tracing::error!("{:#}", e);
+        }
+    }
+    result
+}
+
+/// The Tokio runtime everything runs on, started only after daemonizing.
+fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+}
+
+/// Register, remove or run as a Windows service.
+fn service_command(config: Config, action: ServiceAction) -> anyhow::Result<()> {
+    #[cfg(windows)]
+    {
+        match action {
+            ServiceAction::Install => {
+                let config_file = config.config_file.as_deref().ok_or_else(|| {
+                    anyhow::anyhow!("The service reads its settings from --config-file; pass one")
+                })?;
+                service::install(config_file)?;
+                println!(
+                    "Registered service {}; start it with `sc start {}`",
+                    service::SERVICE_NAME,
+                    service::SERVICE_NAME
+                );
+                Ok(())
+            }
+            ServiceAction::Uninstall => service::uninstall(),
+            ServiceAction::Run => {
+                // Services start in the system directory; resolve relative
+                // paths such as DB_PATH against the configuration file instead
+                if let Some(dir) = config.config_file.as_deref().and_then(|f| f.parent()) {
+                    if !dir.as_os_str().is_empty() {
+                        std::env::set_current_dir(dir)?;
+                    }
+                }
+                let stop_wait = Duration::from_secs(config.shutdown_timeout + 10);
+                let serve_config = Config {
+                    command: Some(Command::Serve),
+                    ..config
+                };
+                service::run(
+                    move || {
+                        let result = runtime()?.block_on(run(serve_config));
+                        if let Err(e) = &result {
+                            tracing::error!("{:#}", e);
+                        }
+                        result
+                    },
+                    stop_wait,
+                )
+            }
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = (config, action);
+        anyhow::bail!(
+            "Services are a Windows feature; on Unix use --daemon with --pid-file, or a \
+             systemd unit"
         )
-        .with(tracing_subscriber::fmt::layer())
+    }
+}
+
+/// Run the configured subcommand.
+async fn run(config: Config) -> anyhow::Result<()> {
+    let _sentry = init_sentry(&config);
+    let log_filter = init_logging(&config)?;
+
+    match config.command.clone().unwrap_or(Command::Serve) {
+        Command::Serve => serve(config, log_filter).await,
+        Command::Migrate { reverse } => migrate(&config, reverse).await,
+        Command::Fsck { repair } => fsck(&config, repair).await,
+        Command::Stats => stats(&config).await,
+        Command::Doctor { json } => doctor(&config, json).await,
+        Command::Verify {
+            metadata_only,
+            data_sample,
+            sha256,
+            parallelism,
+            json,
+        } => {
+            let options = VerifyOptions {
+                metadata_only,
+                data_sample_percent: data_sample,
+                check_sha256: sha256,
+                parallelism,
+            };
+            verify(&config, &options, json).await
+        }
+        Command::Export { dir, parallelism } => {
+            export(&config, &dir, &CopyOptions { parallelism }).await
+        }
+        Command::Import { dir, parallelism } => {
+            import(&config, &dir, &CopyOptions { parallelism }).await
+        }
+        Command::Mirror {
+            to_repo_path,
+            to_client_id,
+            to_client_secret,
+            parallelism,
+        } => {
+            let account = to_client_id.zip(to_client_secret);
+            mirror(
+                &config,
+                &to_repo_path,
+                account,
+                &CopyOptions { parallelism },
+            )
+            .await
+        }
+        Command::Gc { yes } => gc(&config, yes).await,
+        Command::Diff {
+            other_repo_path,
+            other_client_id,
+            other_client_secret,
+            other_dir,
+            json,
+        } => {
+            let other = match (other_repo_path, other_dir) {
+                (Some(repo_path), _) => {
+                    Other::Repository(repo_path, other_client_id.zip(other_client_secret))
+                }
+                (None, Some(dir)) => Other::Directory(dir),
+                (None, None) => anyhow::bail!("Nothing to compare with"),
+            };
+            diff(&config, other, json).await
+        }
+        Command::Ls { file_type } => ls(&config, file_type).await,
+        Command::Cat {
+            file_type,
+            name,
+            output,
+        } => cat(&config, file_type, name.as_deref(), output.as_deref()).await,
+        Command::Rm {
+            file_type,
+            name,
+            yes,
+        } => rm(&config, file_type, name.as_deref(), yes).await,
+        Command::Dedupe { yes } => dedupe(&config, yes).await,
+        Command::Credentials {
+            action: CredentialsAction::Set,
+        } => credentials_set(&config),
+        Command::Service { .. } => unreachable!("handled before starting the runtime"),
+    }
+}
+
+/// Report panics, and errors logged from here on, to Sentry if a DSN is
+/// configured. Reports are flushed when the guard is dropped.
+fn init_sentry(config: &Config) -> Option<sentry::ClientInitGuard> {
+    let dsn = config.sentry_dsn.as_deref()?;
+    Some(sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: config.sentry_environment.clone().map(Into::into),
+            attach_stacktrace: true,
+            before_send: Some(Arc::new(|mut event| {
+                redact_in_place(&mut event.message);
+                for exception in event.exception.values.iter_mut() {
+                    redact_in_place(&mut exception.value);
+                }
+                Some(event)
+            })),
+            before_breadcrumb: Some(Arc::new(|mut breadcrumb| {
+                redact_in_place(&mut breadcrumb.message);
+                Some(breadcrumb)
+            })),
+            ..Default::default()
+        },
+    )))
+}
+
+/// Mask secrets in a message bound for Sentry.
+fn redact_in_place(message: &mut Option<String>) {
+    if let Some(text) = message {
+        *text = redact(text).into_owned();
+    }
+}
+
+/// Install the log subscriber, returning the handle to change its level.
+fn init_logging(config: &Config) -> anyhow::Result<reload::Handle<EnvFilter, Registry>> {
+    let writer = RedactingWriter::new(match &config.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(std::io::stdout),
+    });
+    let (text_layer, json_layer) = match config.log_format {
+        LogFormat::Text => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(config.log_file.is_none())
+                    .with_writer(writer),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(false),
+            ),
+        ),
+    };
+    // Errors become Sentry events, warnings the breadcrumbs leading up to them
+    let sentry_layer = config.sentry_dsn.is_some().then(|| {
+        sentry::integrations::tracing::layer().event_filter(|metadata| match *metadata.level() {
+            tracing::Level::ERROR => EventFilter::Event,
+            tracing::Level::WARN => EventFilter::Breadcrumb,
+            _ => EventFilter::Ignore,
+        })
+    });
+    let (filter, log_filter) = reload::Layer::new(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| config.log_level.clone().into()),
+    );
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_layer)
+        .with(json_layer)
+        .with(sentry_layer)
         .init();
+    Ok(log_filter)
+}
+
+/// Configure the 123pan client every subcommand uses.
+fn client_builder(config: &Config) -> anyhow::Result<Pan123ClientBuilder> {
+    // Ensure the SQLite database directory exists
+    if config.database_url.is_none() {
+        let db_path = std::path::Path::new(&config.db_path);
+        if let Some(parent) = db_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+    }
+    let database_url = config.database_url();
+    if db::is_sqlite_memory(&database_url) {
+        tracing::warn!("Using an in-memory cache database; the cache is rebuilt on every start");
+    }
+
+    let (client_id, client_secret) = config.credentials()?;
+    let mut builder = Pan123Client::builder(client_id, client_secret)
+        .repo_path(config.repo_path.clone())
+        .database_url(&database_url)
+        .base_url(&config.api_base_url)
+        .extra_credentials(config.extra_credentials()?)
+        .proxies(
+            config.proxy_url.as_deref(),
+            config.transfer_proxy_url.as_deref(),
+        )
+        .parallel_download(
+            config.download_chunk_size_mb * 1024 * 1024,
+            config.download_parallelism,
+        )
+        .lookup_cache_entries(config.lookup_cache_entries)
+        .multipart_threshold(config.multipart_threshold_mb * 1024 * 1024)
+        .unicode_normalization(config.unicode_normalization)
+        .upload_complete_timeout(Duration::from_secs(config.upload_complete_timeout_secs))
+        .upload_domain_ttl(Duration::from_secs(config.upload_domain_ttl_secs))
+        .progress_logging(
+            config.progress_log_threshold_mb * 1024 * 1024,
+            Duration::from_secs(config.progress_log_interval_secs),
+        )
+        .bandwidth_limits(config.max_upload_rate, config.max_download_rate)
+        .concurrency_limits(ConcurrencyLimits {
+            uploads: config.max_concurrent_uploads,
+            downloads: config.max_concurrent_downloads,
+            api_calls: config.max_concurrent_api_calls,
+        })
+        .pool(PoolOptions {
+            max_idle_per_host: config.pool_max_idle_per_host,
+            idle_timeout: Duration::from_secs(config.pool_idle_timeout_secs),
+            http2: config.upstream_http2,
+            tcp_keepalive: (config.tcp_keepalive_secs > 0)
+                .then(|| Duration::from_secs(config.tcp_keepalive_secs)),
+        })
+        .timeouts(Timeouts {
+            connect: Duration::from_secs(config.connect_timeout_secs),
+            read: Duration::from_secs(config.read_timeout_secs),
+            api: Duration::from_secs(config.api_timeout_secs),
+            upload: Duration::from_secs(config.upload_timeout_secs),
+            upload_per_mib: Duration::from_secs(config.upload_timeout_per_mib_secs),
+            download: Duration::from_secs(config.download_timeout_secs),
+        })
+        .data_shard_len(config.data_shard_len)
+        .data_shard_depth(config.data_shard_depth);
+    if let Some(namespace) = &config.cache_namespace {
+        builder = builder.cache_namespace(namespace.clone());
+    }
+    if config.cache_ttl_secs > 0 && config.cache_revalidation == Revalidation::Access {
+        builder = builder.directory_ttl(Duration::from_secs(config.cache_ttl_secs));
+    }
+    Ok(builder)
+}
 
+/// Serve the restic REST API until SIGINT/SIGTERM.
+async fn serve(
+    config: Config,
+    log_filter: reload::Handle<EnvFilter, Registry>,
+) -> anyhow::Result<()> {
     tracing::info!("Starting restic-123pan");
+    let _pid_file = config
+        .pid_file
+        .as_deref()
+        .map(PidFile::create)
+        .transpose()?;
+    if config.private_repos {
+        anyhow::bail!(
+            "--private-repos is not supported: this server serves a single repository \
+             and has no users"
+        );
+    }
+    if !config.no_auth && !config.listen_addr().is_local() {
+        anyhow::bail!(
+            "Refusing to listen on {} without authentication: anyone who can reach it could \
+             read and delete the repository. Listen on a loopback address or Unix socket, or \
+             pass --no-auth (NO_AUTH=true) if access is restricted otherwise",
+            config.listen_addr()
+        );
+    }
+    if config.append_only {
+        tracing::info!("Append-only: deletes are refused except for locks");
+    }
+    if config.immutable_data {
+        tracing::info!("Immutable data: overwriting data, snapshots and keys is refused");
+    }
     tracing::info!("Repository path: {}", config.repo_path);
-    tracing::info!(
-        "Listen address: {}:{}",
-        config.listen_addr,
-        config.listen_port
-    );
+    tracing::info!("Listen address: {}", config.listen_addr());
+
+    let database_url = config.database_url();
+    let builder = client_builder(&config)?;
+    let mut client = builder.clone().build().await?;
+
+    if config.cache_check != CacheCheck::Off {
+        let report = client
+            .check_cache(config.cache_check == CacheCheck::Repair)
+            .await?;
+        for message in report.integrity_errors.iter().take(10) {
+            tracing::error!("Cache database integrity check: {}", message);
+        }
+        if !report.integrity_errors.is_empty() {
+            // A damaged file can't be fixed in place; start over with an empty cache
+            client.database().close().await?;
+            drop(client);
+            match db::quarantine(&database_url)? {
+                Some(moved) => tracing::warn!(
+                    "Cache database is corrupt; moved it to {} and rebuilding the cache",
+                    moved.display()
+                ),
+                None => anyhow::bail!("Cache database is corrupt and can't be recreated"),
+            }
+            client = builder.build().await?;
+        } else if !report.is_clean() {
+            tracing::warn!(
+                "Cache check found {} orphaned nodes and {} duplicate names{}",
+                report.orphans.len(),
+                report.duplicates.len(),
+                if report.repaired {
+                    " (repaired)"
+                } else {
+                    "; set CACHE_CHECK=repair to fix"
+                }
+            );
+        }
+    }
+
+    // Fail now rather than on restic's first request
+    if config.preflight {
+        let report = client.preflight(config.auto_create_repo).await?;
+        let total = report.account.space_total();
+        if total > 0 {
+            tracing::info!(
+                "123pan account {} has {} of {} MiB free",
+                report.account.uid,
+                (total - report.account.space_used) / (1024 * 1024),
+                total / (1024 * 1024)
+            );
+        }
+        if report.repo_created {
+            tracing::info!("Created repository directory {}", config.repo_path);
+        }
+    } else if config.auto_create_repo && client.find_path_id(&config.repo_path).await?.is_none() {
+        client.init_repository().await?;
+    }
 
-    // Ensure database directory exists
-    let db_path = std::path::Path::new(&config.db_path);
-    if let Some(parent) = db_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            std::fs::create_dir_all(parent)?;
+    // Existing repositories keep the layout they were created with
+    if client.load_layout().await?.is_none() {
+        tracing::info!("Using data layout {}", client.layout());
+    }
+
+    // Only one instance may write to the repository at a time
+    let server_lock = if config.server_lock == ServerLockMode::Off {
+        None
+    } else {
+        let lock = ServerLock::new(
+            client.clone(),
+            Duration::from_secs(config.server_lock_ttl_secs),
+        );
+        match lock.acquire().await? {
+            None => tracing::info!("Acquired repository lock as instance {}", lock.instance()),
+            Some(holder) if config.server_lock == ServerLockMode::Fail => anyhow::bail!(
+                "Repository is locked by {}; stop that instance, wait for the lock \
+                 to expire or set SERVER_LOCK=read-only",
+                holder
+            ),
+            Some(holder) => {
+                tracing::warn!("Repository is locked by {}; serving read-only", holder)
+            }
+        }
+        lock.spawn_renewal();
+        Some(lock)
+    };
+    let read_only = server_lock
+        .as_ref()
+        .map(|lock| lock.read_only_flag())
+        .unwrap_or_default();
+
+    // Deletes queued before a restart are carried out by the writer only
+    let writer = !read_only.load(Ordering::Relaxed);
+    if writer && config.delete_batch_window_ms > 0 && !config.direct_api {
+        client.spawn_delete_batching(Duration::from_millis(config.delete_batch_window_ms));
+    } else if writer {
+        if let Err(e) = client.flush_deletes().await {
+            tracing::warn!("Failed to carry out queued deletes: {}", e);
+        }
+    }
+
+    let backend: Arc<dyn StorageBackend> = if config.direct_api {
+        tracing::warn!("Direct API mode: listings and reads bypass the cache");
+        Arc::new(DirectBackend::new(client.clone()))
+    } else {
+        // Warm up the cache while serving; paths not cached yet get 503 until it completes
+        let warm_up_client = client.clone();
+        let warm_up_config = config.clone();
+        tokio::spawn(async move {
+            // A failure keeps the server not ready; it is retried with backoff
+            // rather than exiting, which would skip the graceful shutdown
+            let mut delay = WARM_UP_RETRY_DELAY;
+            while let Err(e) = warm_up(&warm_up_client, &warm_up_config).await {
+                tracing::error!("Cache warm-up failed, retrying in {:?}: {:#}", delay, e);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_WARM_UP_RETRY_DELAY);
+            }
+        });
+        Arc::new(client.clone())
+    };
+
+    // Open the write-back spool and resume any pending uploads
+    let spool = match &config.spool_dir {
+        Some(dir) => {
+            let spool = WriteBackSpool::open(dir, client.database(), backend.clone())
+                .await?
+                .with_layout(client.layout());
+            if read_only.load(Ordering::Relaxed) {
+                tracing::warn!("Read-only: pending spooled uploads resume on a later start");
+            } else {
+                spool.spawn_worker(config.max_concurrent_uploads);
+            }
+            Some(spool)
+        }
+        None => None,
+    };
+
+    let metadata_cache = match &config.metadata_cache_dir {
+        Some(dir) => Some(MetadataCache::open(dir).await?),
+        None => None,
+    };
+
+    let pack_cache = match &config.pack_cache_dir {
+        Some(dir) => Some(PackCache::open(dir, config.pack_cache_size_mb * 1024 * 1024).await?),
+        None => None,
+    };
+
+    let audit = config.audit_log.then(|| {
+        let audit = AuditLog::new(client.database(), client.cache_namespace());
+        if config.audit_retention_days > 0 {
+            audit.spawn_pruning(Duration::from_secs(config.audit_retention_days * 86400));
+        }
+        audit
+    });
+
+    // Use the systemd-activated socket if present, otherwise bind ourselves
+    let listener = match Listener::from_systemd()? {
+        Some(listener) => {
+            if !config.no_auth && !listener.is_local() {
+                anyhow::bail!(
+                    "Refusing to serve the non-loopback socket passed by systemd without \
+                     authentication; pass --no-auth (NO_AUTH=true) if access is restricted \
+                     otherwise"
+                );
+            }
+            tracing::info!("Server listening on {}, passed by systemd", listener);
+            listener
+        }
+        None => {
+            let listener =
+                Listener::bind(&config.listen_addr(), config.socket_mode, config.ipv6_only).await?;
+            tracing::info!("Server listening on {}", listener);
+            listener
         }
+    };
+
+    // The admin routes can switch the server read-only; without a token they
+    // are only served to this host
+    let admin = match &config.admin_token {
+        Some(token) => AdminAccess::Token(token.clone()),
+        None if listener.is_local() => AdminAccess::Open,
+        None => {
+            tracing::warn!(
+                "/admin/* disabled: set ADMIN_TOKEN to use it on {}",
+                listener
+            );
+            AdminAccess::Disabled
+        }
+    };
+
+    let options = ServerOptions {
+        max_concurrent_uploads: config.max_concurrent_uploads,
+        upload_queue_size: config.upload_queue_size,
+        body_memory_budget: config.max_buffered_body_mb * 1024 * 1024,
+        spool,
+        write_back: config.spool_mode == SpoolMode::WriteBack,
+        metadata_cache,
+        pack_cache,
+        pack_readahead: config.pack_readahead,
+        readahead_window: config.sequential_readahead_mb * 1024 * 1024,
+        layout: client.layout(),
+        read_only,
+        append_only: config.append_only,
+        immutable: config.immutable_data,
+        verified_heads: config.head_verify.file_types(),
+        mode: ModeSwitch::default(),
+        audit,
+        slow_request: (config.slow_request_ms > 0)
+            .then(|| Duration::from_millis(config.slow_request_ms)),
+        deadline: (config.request_deadline_secs > 0)
+            .then(|| Duration::from_secs(config.request_deadline_secs)),
+        admin,
+    };
+    // The rate limiter is always installed so a reload can enable it
+    if config.rate_limit_rps > 0.0 {
+        tracing::info!(
+            "Rate limiting clients to {} req/s (burst {})",
+            config.rate_limit_rps,
+            config.rate_limit_burst
+        );
+    }
+    let limiter = Arc::new(RateLimiter::new(
+        config.rate_limit_rps,
+        config.rate_limit_burst,
+    ));
+    let mut app = create_router(backend, options).layer(axum::middleware::from_fn_with_state(
+        limiter.clone(),
+        rate_limit,
+    ));
+    if config.sentry_dsn.is_some() {
+        // A hub per request, so reports carry the request they happened in
+        app = app
+            .layer(SentryHttpLayer::new())
+            .layer(NewSentryLayer::<axum::extract::Request>::new_from_top());
     }
-    let database_url = format!("sqlite:{}?mode=rwc", config.db_path);
 
-    // Create 123pan client
-    let client = Pan123Client::new(
-        config.client_id.clone(),
-        config.client_secret.clone(),
-        config.repo_path.clone(),
-        &database_url,
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(
+        config.clone(),
+        log_filter,
+        limiter,
+        client.clone(),
+    ));
+    #[cfg(not(unix))]
+    let _ = log_filter;
+
+    // Start server; on SIGINT/SIGTERM stop accepting connections and drain
+    // in-flight requests, bounded by the shutdown timeout.
+    server::serve(
+        listener,
+        app,
+        shutdown_signal(),
+        Duration::from_secs(config.shutdown_timeout),
+        ConnectionOptions {
+            header_read_timeout: (config.header_read_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.header_read_timeout_secs)),
+            idle_timeout: (config.idle_connection_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.idle_connection_timeout_secs)),
+            max_connections: config.max_connections,
+        },
     )
     .await?;
 
-    // Warm up the cache before starting the server
-    tracing::info!("Checking file list cache...");
-    client.warm_cache(config.force_cache_rebuild).await?;
+    // Carry out queued deletes and flush pending cache writes before exiting
+    if writer {
+        if let Err(e) = client.flush_deletes().await {
+            tracing::warn!("Queued deletes are carried out on the next start: {}", e);
+        }
+    }
+    if let Err(e) = client.flush_cache().await {
+        tracing::error!("Failed to flush cache on shutdown: {}", e);
+    }
 
-    // Create router
-    let app = create_router(client).layer(TraceLayer::new_for_http());
+    if let Some(lock) = &server_lock {
+        if let Err(e) = lock.release().await {
+            tracing::error!("Failed to release repository lock: {}", e);
+        }
+    }
 
-    // Parse listen address
-    let addr: SocketAddr = format!("{}:{}", config.listen_addr, config.listen_port).parse()?;
+    tracing::info!("Server stopped");
+    Ok(())
+}
 
-    tracing::info!("Server listening on http://{}", addr);
+/// Upgrade the cache database and move data packs into the configured layout,
+/// or back into a flat `data/` with `reverse`.
+async fn migrate(config: &Config, reverse: bool) -> anyhow::Result<()> {
+    let mut builder = client_builder(config)?;
+    if reverse {
+        builder = builder.data_shard_len(0);
+    }
+    // Connecting applies pending schema migrations
+    let client = builder.build().await?;
+    println!("Cache database schema is up to date");
+    let moved = client.migrate_data_structure().await?;
+    println!("Moved {} data packs into layout {}", moved, client.layout());
+    if reverse {
+        let removed = client.remove_empty_shard_dirs().await?;
+        println!("Removed {} empty data shard directories", removed);
+    }
+    client.flush_cache().await?;
+    Ok(())
+}
 
-    // Start server
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+/// Check the cache database, optionally repairing it. Fails if problems
+/// remain, so it can be scripted.
+async fn fsck(config: &Config, repair: bool) -> anyhow::Result<()> {
+    let client = client_builder(config)?.build().await?;
+    let report = client.check_cache(repair).await?;
+    for message in &report.integrity_errors {
+        println!("integrity: {}", message);
+    }
+    for file_id in &report.orphans {
+        println!("orphaned node: {}", file_id);
+    }
+    for (parent_id, name) in &report.duplicates {
+        println!("duplicate name: '{}' in directory {}", name, parent_id);
+    }
 
+    if !report.integrity_errors.is_empty() {
+        if !repair {
+            anyhow::bail!("Cache database is corrupt; run with --repair to recreate it");
+        }
+        client.database().close().await?;
+        drop(client);
+        match db::quarantine(&config.database_url())? {
+            Some(moved) => println!(
+                "Moved the corrupt cache database to {}; it is rebuilt on the next start",
+                moved.display()
+            ),
+            None => anyhow::bail!("Cache database is corrupt and can't be recreated"),
+        }
+        return Ok(());
+    }
+    if report.is_clean() {
+        println!("Cache database is consistent");
+    } else if report.repaired {
+        client.flush_cache().await?;
+        println!("Repaired the problems found");
+    } else {
+        anyhow::bail!("Cache check found problems; run with --repair to fix them");
+    }
     Ok(())
 }
+
+/// Print the number and total size of the repository's objects per type.
+async fn stats(config: &Config) -> anyhow::Result<()> {
+    let mut client = client_builder(config)?.build().await?;
+    client.load_layout().await?;
+    client.warm_cache(false).await?;
+
+    println!("{:<10} {:>10} {:>16}", "type", "objects", "bytes");
+    let (mut total_count, mut total_size) = (0, 0);
+    for file_type in [
+        ResticFileType::Config,
+        ResticFileType::Keys,
+        ResticFileType::Locks,
+        ResticFileType::Snapshots,
+        ResticFileType::Index,
+        ResticFileType::Data,
+    ] {
+        let objects = if file_type.is_config() {
+            client.head("config").await?.into_iter().collect()
+        } else {
+            client.list(file_type.dirname()).await?
+        };
+        let size: i64 = objects.iter().map(|o| o.size).sum();
+        println!(
+            "{:<10} {:>10} {:>16}",
+            file_type.dirname(),
+            objects.len(),
+            size
+        );
+        total_count += objects.len();
+        total_size += size;
+    }
+    println!("{:<10} {:>10} {:>16}", "total", total_count, total_size);
+    client.flush_cache().await?;
+    Ok(())
+}
+
+/// Run the self-test and print a diagnosis; fails if any check fails.
+async fn doctor(config: &Config, json: bool) -> anyhow::Result<()> {
+    let client = client_builder(config)?.build().await?;
+    let report = client.doctor().await;
+    client.flush_cache().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for check in &report.checks {
+            println!(
+                "[{}] {:<11} {} ({} ms)",
+                if check.ok { " ok " } else { "FAIL" },
+                check.name,
+                check.detail,
+                check.millis
+            );
+            if let Some(diagnosis) = &check.diagnosis {
+                println!("       {:<11} {}", "", diagnosis);
+            }
+        }
+    }
+    if !report.is_ok() {
+        anyhow::bail!("Some checks failed");
+    }
+    Ok(())
+}
+
+/// Check every object of the repository; fails if any problem is found.
+async fn verify(config: &Config, options: &VerifyOptions, json: bool) -> anyhow::Result<()> {
+    let mut client = client_builder(config)?.build().await?;
+    client.load_layout().await?;
+    client.warm_cache(false).await?;
+    let report = storage::verify(&client, client.layout(), options).await?;
+    client.flush_cache().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for problem in &report.problems {
+            println!("{}: {}", problem.path, problem.problem);
+        }
+        println!(
+            "Checked {} objects ({} MiB), skipped {} data packs, {} problems",
+            report.checked,
+            report.bytes / (1024 * 1024),
+            report.skipped,
+            report.problems.len()
+        );
+    }
+    if !report.is_ok() {
+        anyhow::bail!("Verification found {} problems", report.problems.len());
+    }
+    Ok(())
+}
+
+/// Download the repository into `dir` in restic's local layout.
+async fn export(
+    config: &Config,
+    dir: &std::path::Path,
+    options: &CopyOptions,
+) -> anyhow::Result<()> {
+    let mut client = client_builder(config)?.build().await?;
+    client.load_layout().await?;
+    client.warm_cache(false).await?;
+    let target = LocalBackend::open(dir).await?;
+    // restic's local backend stores packs in data/<first two characters>/
+    let report = storage::copy_repository(
+        &client,
+        client.layout(),
+        &target,
+        RepoLayout::default(),
+        options,
+    )
+    .await?;
+    client.flush_cache().await?;
+    print_copy_report(&report)
+}
+
+/// Upload the local restic repository at `dir`, then check that every object
+/// arrived with its size.
+async fn import(
+    config: &Config,
+    dir: &std::path::Path,
+    options: &CopyOptions,
+) -> anyhow::Result<()> {
+    if !dir.join("config").is_file() {
+        anyhow::bail!(
+            "{} is not a restic repository (no config file)",
+            dir.display()
+        );
+    }
+    let source = LocalBackend::open(dir).await?;
+    let mut client = client_builder(config)?.build().await?;
+    client.load_layout().await?;
+    client.warm_cache(false).await?;
+    client.init_repository().await?;
+
+    let report = storage::copy_repository(
+        &source,
+        RepoLayout::default(),
+        &client,
+        client.layout(),
+        options,
+    )
+    .await?;
+    print_copy_report(&report)?;
+
+    let check =
+        storage::check_copy(&source, RepoLayout::default(), &client, client.layout()).await?;
+    client.flush_cache().await?;
+    for path in &check.mismatched {
+        println!("missing or incomplete: {}", path);
+    }
+    if !check.is_ok() {
+        anyhow::bail!(
+            "{} of {} objects did not arrive intact; run again to retry them",
+            check.mismatched.len(),
+            check.objects
+        );
+    }
+    println!(
+        "Verified {} objects ({} MiB) in {}",
+        check.objects,
+        check.bytes / (1024 * 1024),
+        config.repo_path
+    );
+    Ok(())
+}
+
+/// Copy what is new or changed to `to_repo_path`, on the account given or
+/// this one.
+async fn mirror(
+    config: &Config,
+    to_repo_path: &str,
+    account: Option<(String, String)>,
+    options: &CopyOptions,
+) -> anyhow::Result<()> {
+    let mut source = client_builder(config)?.build().await?;
+    source.load_layout().await?;
+    source.warm_cache(false).await?;
+    let target = open_other_repository(config, to_repo_path, account).await?;
+    target.init_repository().await?;
+
+    let report =
+        storage::copy_repository(&source, source.layout(), &target, target.layout(), options)
+            .await?;
+    source.flush_cache().await?;
+    target.flush_cache().await?;
+    print_copy_report(&report)
+}
+
+/// Connect to `repo_path` on the account given or this one, sharing the
+/// configured cache database, and load its layout and cache.
+async fn open_other_repository(
+    config: &Config,
+    repo_path: &str,
+    account: Option<(String, String)>,
+) -> anyhow::Result<Pan123Client> {
+    let mut builder = client_builder(config)?.repo_path(repo_path);
+    match account {
+        // The same path on another account needs its own cache entries
+        Some((client_id, client_secret)) => {
+            builder = builder
+                .cache_namespace(format!("{}:{}", client_id, repo_path))
+                .credentials(client_id, client_secret);
+        }
+        None if repo_path == config.repo_path => {
+            anyhow::bail!("The other repository must be another path or account")
+        }
+        None => {
+            if let Some(namespace) = &config.cache_namespace {
+                builder = builder.cache_namespace(format!("{}:{}", namespace, repo_path));
+            }
+        }
+    }
+    let mut client = builder.build().await?;
+    client.load_layout().await?;
+    client.warm_cache(false).await?;
+    Ok(client)
+}
+
+/// What [`diff`] compares the repository with.
+enum Other {
+    /// A repository path, on the account given or this one
+    Repository(String, Option<(String, String)>),
+    /// A local restic repository
+    Directory(std::path::PathBuf),
+}
+
+/// Compare the repository's objects with another repository's; fails if
+/// they differ.
+async fn diff(config: &Config, other: Other, json: bool) -> anyhow::Result<()> {
+    let client = open_repository(config).await?;
+    let diff = match &other {
+        Other::Repository(repo_path, account) => {
+            let other = open_other_repository(config, repo_path, account.clone()).await?;
+            let diff = storage::diff_repositories(&client, client.layout(), &other, other.layout())
+                .await?;
+            other.flush_cache().await?;
+            diff
+        }
+        Other::Directory(dir) => {
+            if !dir.is_dir() {
+                anyhow::bail!("{} is not a directory", dir.display());
+            }
+            let local = LocalBackend::open(dir).await?;
+            storage::diff_repositories(&client, client.layout(), &local, RepoLayout::default())
+                .await?
+        }
+    };
+    client.flush_cache().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        for name in &diff.missing {
+            println!("missing: {}", name);
+        }
+        for name in &diff.extra {
+            println!("extra:   {}", name);
+        }
+        for entry in &diff.differing {
+            println!("differs: {} ({})", entry.name, entry.problem);
+        }
+        println!(
+            "{} objects identical, {} missing, {} extra, {} differing",
+            diff.same,
+            diff.missing.len(),
+            diff.extra.len(),
+            diff.differing.len()
+        );
+    }
+    if !diff.is_identical() {
+        anyhow::bail!("The repositories differ");
+    }
+    Ok(())
+}
+
+/// Summarize a copy; fails if any object could not be copied.
+fn print_copy_report(report: &CopyReport) -> anyhow::Result<()> {
+    for (path, error) in &report.failed {
+        println!("{}: {}", path, error);
+    }
+    println!(
+        "Copied {} objects ({} MiB), {} already present, {} failed",
+        report.copied,
+        report.bytes / (1024 * 1024),
+        report.skipped,
+        report.failed.len()
+    );
+    if !report.is_ok() {
+        anyhow::bail!(
+            "{} objects could not be copied; run again to retry them",
+            report.failed.len()
+        );
+    }
+    Ok(())
+}
+
+/// List the objects of `file_type`.
+async fn ls(config: &Config, file_type: ResticFileType) -> anyhow::Result<()> {
+    let client = open_repository(config).await?;
+    let objects = if file_type.is_config() {
+        client.head("config").await?.into_iter().collect()
+    } else {
+        client.list(file_type.dirname()).await?
+    };
+    for object in &objects {
+        let modified = object
+            .modified_at
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!("{:>12} {:<19} {}", object.size, modified, object.name);
+    }
+    client.flush_cache().await?;
+    Ok(())
+}
+
+/// Write an object to `output`, or stdout.
+async fn cat(
+    config: &Config,
+    file_type: ResticFileType,
+    name: Option<&str>,
+    output: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let client = open_repository(config).await?;
+    let path = object_path(&client, file_type, name)?;
+    let data = client.get_range(&path, None).await?;
+    match output {
+        Some(output) => std::fs::write(output, &data)?,
+        None => std::io::stdout().write_all(&data)?,
+    }
+    client.flush_cache().await?;
+    Ok(())
+}
+
+/// Delete an object after confirmation.
+async fn rm(
+    config: &Config,
+    file_type: ResticFileType,
+    name: Option<&str>,
+    yes: bool,
+) -> anyhow::Result<()> {
+    let client = open_repository(config).await?;
+    let path = object_path(&client, file_type, name)?;
+    let Some(object) = client.head(&path).await? else {
+        anyhow::bail!("{} not found", path);
+    };
+    if !yes && !confirm(&format!("Delete {} ({} bytes)?", path, object.size))? {
+        println!("Nothing deleted");
+        return Ok(());
+    }
+    client.delete(&path).await?;
+    client.flush_cache().await?;
+    println!("Deleted {}", path);
+    Ok(())
+}
+
+/// Connect and load the layout and cache for the object commands.
+async fn open_repository(config: &Config) -> anyhow::Result<Pan123Client> {
+    let mut client = client_builder(config)?.build().await?;
+    client.load_layout().await?;
+    client.warm_cache(false).await?;
+    Ok(client)
+}
+
+/// Repository-relative path of the object named on the command line.
+fn object_path(
+    client: &Pan123Client,
+    file_type: ResticFileType,
+    name: Option<&str>,
+) -> anyhow::Result<String> {
+    match name {
+        None if file_type.is_config() => Ok("config".to_string()),
+        None => anyhow::bail!("An object name is required for {}", file_type.dirname()),
+        Some(_) if file_type.is_config() => anyhow::bail!("config has no named objects"),
+        Some(name) if !file_type.is_valid_name(name) => {
+            anyhow::bail!("Invalid {} object name '{}'", file_type.dirname(), name)
+        }
+        Some(name) => Ok(client.layout().object_path(file_type, name)),
+    }
+}
+
+/// Trash leftovers of failed uploads after listing them and asking.
+async fn gc(config: &Config, yes: bool) -> anyhow::Result<()> {
+    let mut client = client_builder(config)?.build().await?;
+    client.load_layout().await?;
+    let garbage = client.find_garbage().await?;
+    if garbage.is_empty() {
+        println!("No garbage found");
+        return Ok(());
+    }
+    trash_garbage(&client, &garbage, yes).await
+}
+
+/// Keep one file of each name per directory and trash the other copies
+/// after confirmation.
+async fn dedupe(config: &Config, yes: bool) -> anyhow::Result<()> {
+    let client = client_builder(config)?.build().await?;
+    let duplicates = client.find_duplicates().await?;
+    if duplicates.is_empty() {
+        println!("No duplicate names found");
+        return Ok(());
+    }
+    trash_garbage(&client, &duplicates, yes).await
+}
+
+/// List `garbage` and move it to the trash once confirmed.
+async fn trash_garbage(
+    client: &Pan123Client,
+    garbage: &[Garbage],
+    yes: bool,
+) -> anyhow::Result<()> {
+    for file in garbage {
+        println!("{:<10} {} ({} bytes)", file.reason, file.path, file.size);
+    }
+    if !yes
+        && !confirm(&format!(
+            "Move {} files to the 123pan trash?",
+            garbage.len()
+        ))?
+    {
+        println!("Nothing trashed");
+        return Ok(());
+    }
+    client.collect_garbage(garbage).await?;
+    client.flush_cache().await?;
+    println!("Trashed {} files", garbage.len());
+    Ok(())
+}
+
+/// Ask `question` on the terminal; only an explicit yes counts.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Store the client ID and secret in the keyring, asking for what the
+/// options don't give.
+fn credentials_set(config: &Config) -> anyhow::Result<()> {
+    let (client_id, client_secret) = config.given_credentials()?;
+    let client_id = match client_id {
+        Some(client_id) => client_id,
+        None => prompt("123pan client ID", false)?,
+    };
+    let client_secret = match client_secret {
+        Some(client_secret) => client_secret,
+        None => prompt("123pan client secret", true)?,
+    };
+    if client_id.is_empty() || client_secret.is_empty() {
+        anyhow::bail!("The client ID and secret must not be empty");
+    }
+    keyring::set(keyring::CLIENT_ID, &client_id)?;
+    keyring::set(keyring::CLIENT_SECRET, &client_secret)?;
+    println!(
+        "Stored the credentials in the keyring; start the server with --use-keyring (USE_KEYRING=true)"
+    );
+    Ok(())
+}
+
+/// Ask for a line on the terminal, not echoing it if `secret` (on Unix).
+fn prompt(what: &str, secret: bool) -> anyhow::Result<String> {
+    use std::io::IsTerminal;
+
+    print!("{}: ", what);
+    std::io::stdout().flush()?;
+    let hide = secret && cfg!(unix) && std::io::stdin().is_terminal();
+    let stty = |arg: &str| std::process::Command::new("stty").arg(arg).status();
+    if hide {
+        stty("-echo")?;
+    }
+    let mut answer = String::new();
+    let read = std::io::stdin().read_line(&mut answer);
+    if hide {
+        stty("echo")?;
+        println!();
+    }
+    read?;
+    Ok(answer.trim().to_string())
+}
+
+/// Populate the cache, then start the background cache tasks.
+async fn warm_up(client: &Pan123Client, config: &Config) -> anyhow::Result<()> {
+    if config.restore_cache && !config.force_cache_rebuild {
+        match client.restore_cache().await? {
+            Some(name) => tracing::info!("Cache restored from snapshot {}", name),
+            None => tracing::warn!("No cache snapshot found, crawling the repository"),
+        }
+    }
+
+    tracing::info!("Checking file list cache...");
+    match config.cache_warm_up {
+        WarmUpMode::Lazy => client.warm_cache(config.force_cache_rebuild).await?,
+        WarmUpMode::Background => {
+            client.warm_cache(config.force_cache_rebuild).await?;
+            client.spawn_cache_crawl();
+        }
+        WarmUpMode::Full => client.warm_cache_fully(config.force_cache_rebuild).await?,
+    }
+
+    if config.db_maintenance_interval_mins > 0 {
+        db::spawn_maintenance(
+            client.database(),
+            Duration::from_secs(config.db_maintenance_interval_mins * 60),
+        );
+    }
+    if config.cache_backup_interval_mins > 0 {
+        client.spawn_cache_backup(Duration::from_secs(config.cache_backup_interval_mins * 60));
+    }
+    if config.metrics_log_interval_mins > 0 {
+        client
+            .api_metrics()
+            .spawn_summary(Duration::from_secs(config.metrics_log_interval_mins * 60));
+    }
+    if config.orphan_cleanup_interval_mins > 0 {
+        client.spawn_orphan_cleanup(Duration::from_secs(
+            config.orphan_cleanup_interval_mins * 60,
+        ));
+    }
+    if config.cache_ttl_secs > 0 && config.cache_revalidation == Revalidation::Background {
+        client.spawn_revalidation(Duration::from_secs(config.cache_ttl_secs));
+    }
+    Ok(())
+}
+
+/// Re-read `CONFIG_FILE` on SIGHUP and apply the log level and rate limits,
+/// and re-read the credentials (options, files, keyring) and rotate changed
+/// ones, without touching in-flight connections or the cache.
+#[cfg(unix)]
+async fn reload_on_sighup<S>(
+    mut config: Config,
+    log_filter: reload::Handle<EnvFilter, S>,
+    limiter: Arc<RateLimiter>,
+    client: Pan123Client,
+) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            tracing::error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        let new_config = match config.reloaded() {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                tracing::error!("Failed to reload configuration: {}", e);
+                continue;
+            }
+        };
+        match rotate_credentials(&client, &new_config).await {
+            Ok(0) => tracing::info!("Credentials unchanged"),
+            Ok(n) => tracing::info!("Rotated {} credential(s)", n),
+            Err(e) => tracing::error!("Failed to rotate credentials: {}", e),
+        }
+        if config.config_file.is_none() {
+            continue;
+        }
+
+        if new_config.log_level != config.log_level {
+            match EnvFilter::try_new(&new_config.log_level) {
+                Ok(filter) => {
+                    if let Err(e) = log_filter.reload(filter) {
+                        tracing::error!("Failed to apply log level: {}", e);
+                    }
+                }
+                Err(e) => tracing::error!("Invalid log level '{}': {}", new_config.log_level, e),
+            }
+        }
+        limiter.update(new_config.rate_limit_rps, new_config.rate_limit_burst);
+
+        tracing::info!(
+            "Configuration reloaded (log level {}, rate limit {} req/s, burst {})",
+            new_config.log_level,
+            new_config.rate_limit_rps,
+            new_config.rate_limit_burst
+        );
+        config = new_config;
+    }
+}
+
+/// Swap the credentials `config` resolves to into the client.
+#[cfg(unix)]
+async fn rotate_credentials(client: &Pan123Client, config: &Config) -> anyhow::Result<usize> {
+    let mut credentials = vec![config.credentials()?];
+    credentials.extend(config.extra_credentials()?);
+    Ok(client.rotate_credentials(credentials).await?)
+}
+
+/// Wait for SIGINT (Ctrl+C) or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+        _ = service::stop_requested() => {},
+    }
+
+    tracing::info!("Shutdown signal received, draining in-flight requests");
+}
@@ -0,0 +1,51 @@
+//! File tree cache, keyed by 123pan file ID.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        // Databases predating migrations already have the table, possibly
+        // in a later shape; leave it to the migrations that follow.
+        if super::has_column(db, "file_nodes", "file_id").await {
+            return Ok(());
+        }
+
+        let builder = db.get_database_backend();
+        let stmt = Table::create()
+            .table("file_nodes")
+            .col(
+                ColumnDef::new("file_id")
+                    .big_integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new("parent_id").big_integer().not_null())
+            .col(ColumnDef::new("name").string().not_null())
+            .col(ColumnDef::new("is_dir").boolean().not_null())
+            .col(ColumnDef::new("size").big_integer().not_null())
+            .col(ColumnDef::new("etag").string().null())
+            .col(ColumnDef::new("updated_at").date_time().not_null())
+            .to_owned();
+        db.execute(builder.build(&stmt)).await?;
+
+        super::create_index(
+            db,
+            &super::index("idx_parent_name", "file_nodes")
+                .col("parent_id")
+                .col("name")
+                .unique()
+                .to_owned(),
+        )
+        .await
+    }
+}
@@ -0,0 +1,27 @@
+//! Access token shared by every process using the database.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let stmt = Table::create()
+            .table("token_cache")
+            .if_not_exists()
+            .col(ColumnDef::new("id").integer().not_null().primary_key())
+            .col(ColumnDef::new("access_token").text().not_null())
+            .col(ColumnDef::new("expires_at").string().not_null())
+            .to_owned();
+        db.execute(db.get_database_backend().build(&stmt)).await?;
+        Ok(())
+    }
+}
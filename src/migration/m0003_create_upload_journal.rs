@@ -0,0 +1,41 @@
+//! Journal of uploads pending in the write-back spool.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Index, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let stmt = Table::create()
+            .table("upload_journal")
+            .if_not_exists()
+            .col(ColumnDef::new("file_type").string().not_null())
+            .col(ColumnDef::new("name").string().not_null())
+            .col(ColumnDef::new("seq").big_integer().not_null())
+            .col(ColumnDef::new("size").big_integer().not_null())
+            .col(
+                ColumnDef::new("attempts")
+                    .big_integer()
+                    .not_null()
+                    .default(0),
+            )
+            .col(
+                ColumnDef::new("retry_at")
+                    .big_integer()
+                    .not_null()
+                    .default(0),
+            )
+            .primary_key(Index::create().col("file_type").col("name"))
+            .to_owned();
+        db.execute(db.get_database_backend().build(&stmt)).await?;
+        Ok(())
+    }
+}
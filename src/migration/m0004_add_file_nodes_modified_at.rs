@@ -0,0 +1,21 @@
+//! Last modification time reported by 123pan.
+
+use async_trait::async_trait;
+use sea_orm::{sea_query::ColumnDef, DbErr, DeriveMigrationName};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        super::add_column(
+            db,
+            "file_nodes",
+            ColumnDef::new("modified_at").date_time().null(),
+        )
+        .await
+    }
+}
@@ -0,0 +1,19 @@
+//! Lease that lets one process refresh the shared token at a time.
+
+use async_trait::async_trait;
+use sea_orm::{sea_query::ColumnDef, DbErr, DeriveMigrationName};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        for column in ["lease_owner", "lease_until"] {
+            super::add_column(db, "token_cache", ColumnDef::new(column).string().null()).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! Key file nodes by repository namespace so one database can serve several.
+//!
+//! Existing entries move to the empty namespace; the first client to open
+//! the database adopts them (see `Pan123Client::claim_legacy_nodes`).
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Index, Query, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const COLUMNS: [&str; 8] = [
+    "file_id",
+    "parent_id",
+    "name",
+    "is_dir",
+    "size",
+    "etag",
+    "updated_at",
+    "modified_at",
+];
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        if super::has_column(db, "file_nodes", "repo").await {
+            return Ok(());
+        }
+
+        let builder = db.get_database_backend();
+        let rename = Table::rename()
+            .table("file_nodes", "file_nodes_legacy")
+            .to_owned();
+        db.execute(builder.build(&rename)).await?;
+
+        let create = Table::create()
+            .table("file_nodes")
+            .col(ColumnDef::new("repo").string().not_null())
+            .col(ColumnDef::new("file_id").big_integer().not_null())
+            .col(ColumnDef::new("parent_id").big_integer().not_null())
+            .col(ColumnDef::new("name").string().not_null())
+            .col(ColumnDef::new("is_dir").boolean().not_null())
+            .col(ColumnDef::new("size").big_integer().not_null())
+            .col(ColumnDef::new("etag").string().null())
+            .col(ColumnDef::new("updated_at").date_time().not_null())
+            .col(ColumnDef::new("modified_at").date_time().null())
+            .primary_key(Index::create().col("repo").col("file_id"))
+            .to_owned();
+        db.execute(builder.build(&create)).await?;
+
+        let copy = Query::insert()
+            .into_table("file_nodes")
+            .columns(std::iter::once("repo").chain(COLUMNS))
+            .select_from(super::select_with_constant(
+                "",
+                &COLUMNS,
+                "file_nodes_legacy",
+            ))
+            .map_err(|e| DbErr::Custom(e.to_string()))?
+            .to_owned();
+        db.execute(builder.build(&copy)).await?;
+
+        let drop = Table::drop().table("file_nodes_legacy").to_owned();
+        db.execute(builder.build(&drop)).await?;
+
+        super::create_index(
+            db,
+            &super::index("idx_repo_parent_name", "file_nodes")
+                .col("repo")
+                .col("parent_id")
+                .col("name")
+                .unique()
+                .to_owned(),
+        )
+        .await
+    }
+}
@@ -0,0 +1,54 @@
+//! Directories whose listing has been fetched into the cache.
+//!
+//! Earlier versions crawled the whole repository at startup and treated any
+//! directory with cached children as listed; those are marked as loaded.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Expr, Index, OnConflict, Query, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let builder = db.get_database_backend();
+        let create = Table::create()
+            .table("loaded_dirs")
+            .if_not_exists()
+            .col(ColumnDef::new("repo").string().not_null())
+            .col(ColumnDef::new("dir_id").big_integer().not_null())
+            .col(ColumnDef::new("loaded_at").big_integer().not_null())
+            .primary_key(Index::create().col("repo").col("dir_id"))
+            .to_owned();
+        db.execute(builder.build(&create)).await?;
+
+        let seed = Query::insert()
+            .into_table("loaded_dirs")
+            .columns(["repo", "dir_id", "loaded_at"])
+            .select_from(
+                Query::select()
+                    .distinct()
+                    .columns(["repo", "parent_id"])
+                    .expr(Expr::val(chrono::Utc::now().timestamp()))
+                    .from("file_nodes")
+                    // SQLite can't parse ON CONFLICT after a SELECT without WHERE
+                    .and_where(Expr::cust("1 = 1"))
+                    .to_owned(),
+            )
+            .map_err(|e| DbErr::Custom(e.to_string()))?
+            .on_conflict(
+                OnConflict::columns(["repo", "dir_id"])
+                    .do_nothing_on(["repo"])
+                    .to_owned(),
+            )
+            .to_owned();
+        db.execute(builder.build(&seed)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+//! Recently deleted files, kept out of listings that still show them.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Index, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let stmt = Table::create()
+            .table("tombstones")
+            .if_not_exists()
+            .col(ColumnDef::new("repo").string().not_null())
+            .col(ColumnDef::new("file_id").big_integer().not_null())
+            .col(ColumnDef::new("deleted_at").big_integer().not_null())
+            .primary_key(Index::create().col("repo").col("file_id"))
+            .to_owned();
+        db.execute(db.get_database_backend().build(&stmt)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Audit log of requests that modify the repository.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Index, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let builder = db.get_database_backend();
+        let stmt = Table::create()
+            .table("audit_log")
+            .if_not_exists()
+            .col(
+                ColumnDef::new("id")
+                    .big_integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new("repo").string().not_null())
+            .col(ColumnDef::new("at").big_integer().not_null())
+            .col(ColumnDef::new("operation").string().not_null())
+            .col(ColumnDef::new("file_type").string().not_null())
+            .col(ColumnDef::new("name").string().not_null())
+            .col(ColumnDef::new("size").big_integer())
+            .col(ColumnDef::new("client_ip").string())
+            .col(ColumnDef::new("user").string())
+            .col(ColumnDef::new("status").integer().not_null())
+            .to_owned();
+        db.execute(builder.build(&stmt)).await?;
+
+        let index = Index::create()
+            .name("idx_audit_log_repo_at")
+            .table("audit_log")
+            .col("repo")
+            .col("at")
+            .if_not_exists()
+            .to_owned();
+        db.execute(builder.build(&index)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! State of slice uploads, so they can resume after a restart.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Index, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let stmt = Table::create()
+            .table("upload_sessions")
+            .if_not_exists()
+            .col(ColumnDef::new("repo").string().not_null())
+            .col(ColumnDef::new("parent_id").big_integer().not_null())
+            .col(ColumnDef::new("name").string().not_null())
+            .col(ColumnDef::new("etag").string().not_null())
+            .col(ColumnDef::new("size").big_integer().not_null())
+            .col(ColumnDef::new("preupload_id").string().not_null())
+            .col(ColumnDef::new("slice_size").big_integer().not_null())
+            .col(ColumnDef::new("server").string().not_null())
+            .col(ColumnDef::new("completed_parts").text().not_null())
+            .col(ColumnDef::new("created_at").big_integer().not_null())
+            .primary_key(Index::create().col("repo").col("parent_id").col("name"))
+            .to_owned();
+        db.execute(db.get_database_backend().build(&stmt)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+//! Deletions accepted from clients but not yet carried out on 123pan.
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::{ColumnDef, Index, Table},
+    ConnectionTrait, DbErr, DeriveMigrationName,
+};
+use sea_orm_migration::{MigrationTrait, SchemaManager};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let stmt = Table::create()
+            .table("pending_deletes")
+            .if_not_exists()
+            .col(ColumnDef::new("repo").string().not_null())
+            .col(ColumnDef::new("file_id").big_integer().not_null())
+            .col(ColumnDef::new("queued_at").big_integer().not_null())
+            .primary_key(Index::create().col("repo").col("file_id"))
+            .to_owned();
+        db.execute(db.get_database_backend().build(&stmt)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! Versioned schema migrations for the cache database.
+//!
+//! Run with `sea-orm-migration`: migrations run once, in order, at startup
+//! and are recorded in the `seaql_migrations` table. Each one only extends
+//! the schema left by its predecessors, so databases created by any earlier
+//! version (including those predating this framework) are upgraded in place.
+
+mod m0001_create_file_nodes;
+mod m0002_create_token_cache;
+mod m0003_create_upload_journal;
+mod m0004_add_file_nodes_modified_at;
+mod m0005_add_token_refresh_lease;
+mod m0006_namespace_file_nodes;
+mod m0007_create_loaded_dirs;
+mod m0008_create_tombstones;
+mod m0009_create_audit_log;
+mod m0010_create_upload_sessions;
+mod m0011_create_pending_deletes;
+
+#[cfg(test)]
+mod tests;
+
+use sea_orm::{
+    sea_query::{ColumnDef, Expr, Index, IndexCreateStatement, Query, Table},
+    ConnectionTrait, DatabaseConnection, DbErr, Statement, TransactionTrait,
+};
+use sea_orm_migration::{MigrationTrait, MigratorTrait, SchemaManagerConnection};
+
+use crate::error::{AppError, Result};
+
+/// The cache database migrations.
+pub struct Migrator;
+
+impl MigratorTrait for Migrator {
+    /// All migrations, in the order they are applied.
+    fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+        vec![
+            Box::new(m0001_create_file_nodes::Migration),
+            Box::new(m0002_create_token_cache::Migration),
+            Box::new(m0003_create_upload_journal::Migration),
+            Box::new(m0004_add_file_nodes_modified_at::Migration),
+            Box::new(m0005_add_token_refresh_lease::Migration),
+            Box::new(m0006_namespace_file_nodes::Migration),
+            Box::new(m0007_create_loaded_dirs::Migration),
+            Box::new(m0008_create_tombstones::Migration),
+            Box::new(m0009_create_audit_log::Migration),
+            Box::new(m0010_create_upload_sessions::Migration),
+            Box::new(m0011_create_pending_deletes::Migration),
+        ]
+    }
+}
+
+/// Apply all pending migrations, returning the names of those applied.
+///
+/// Each migration runs in a transaction of its own, on every backend.
+pub async fn run(db: &DatabaseConnection) -> Result<Vec<String>> {
+    let mut newly_applied = Vec::new();
+    loop {
+        let Some(next) = Migrator::get_pending_migrations(db)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to read applied migrations: {}", e)))?
+            .into_iter()
+            .next()
+        else {
+            return Ok(newly_applied);
+        };
+        let name = next.name().to_string();
+
+        let txn = db.begin().await.map_err(|e| migration_error(&name, e))?;
+        let result = match Migrator::up(&txn, Some(1)).await {
+            Ok(()) => txn.commit().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            // Another replica may have applied it concurrently; every step is idempotent
+            if applied(db).await?.contains(&name) {
+                continue;
+            }
+            return Err(migration_error(&name, e));
+        }
+
+        newly_applied.push(name);
+    }
+}
+
+/// Names of the migrations already applied to the database.
+pub async fn applied(db: &DatabaseConnection) -> Result<Vec<String>> {
+    Ok(Migrator::get_applied_migrations(db)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to read applied migrations: {}", e)))?
+        .iter()
+        .map(|migration| migration.name().to_string())
+        .collect())
+}
+
+fn migration_error(name: &str, e: DbErr) -> AppError {
+    AppError::Internal(format!("Migration {} failed: {}", name, e))
+}
+
+// ============================================================================
+// Helpers shared by migrations
+// ============================================================================
+
+/// Whether `table.column` exists.
+///
+/// The column is qualified so SQLite can't read a missing one as a string
+/// literal. The probe runs in a savepoint because a failed statement aborts
+/// the surrounding transaction on PostgreSQL.
+async fn has_column(
+    db: &SchemaManagerConnection<'_>,
+    table: &'static str,
+    column: &'static str,
+) -> bool {
+    let stmt = Query::select()
+        .column((table, column))
+        .from(table)
+        .limit(1)
+        .to_owned();
+    let Ok(savepoint) = db.begin().await else {
+        return false;
+    };
+    let exists = savepoint
+        .query_one(db.get_database_backend().build(&stmt))
+        .await
+        .is_ok();
+    let _ = savepoint.rollback().await;
+    exists
+}
+
+/// Add a nullable column, tolerating databases that already have it.
+async fn add_column(
+    db: &SchemaManagerConnection<'_>,
+    table: &'static str,
+    column: &mut ColumnDef,
+) -> std::result::Result<(), DbErr> {
+    let stmt = Table::alter().table(table).add_column(column).to_owned();
+    execute_unless_exists(db, db.get_database_backend().build(&stmt)).await
+}
+
+/// Create an index, tolerating databases that already have it.
+/// MySQL has no `CREATE INDEX IF NOT EXISTS`.
+async fn create_index(
+    db: &SchemaManagerConnection<'_>,
+    index: &IndexCreateStatement,
+) -> std::result::Result<(), DbErr> {
+    let stmt = index.clone().if_not_exists().to_owned();
+    execute_unless_exists(db, db.get_database_backend().build(&stmt)).await
+}
+
+/// Execute a schema statement in a savepoint, ignoring "already exists" errors.
+async fn execute_unless_exists(
+    db: &SchemaManagerConnection<'_>,
+    stmt: Statement,
+) -> std::result::Result<(), DbErr> {
+    let savepoint = db.begin().await?;
+    match savepoint.execute(stmt).await {
+        Ok(_) => savepoint.commit().await,
+        Err(e) => {
+            savepoint.rollback().await?;
+            if crate::db::is_already_exists(&e) {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+/// `SELECT` all of `columns` from `table`, prefixed with a constant value.
+fn select_with_constant(
+    constant: &str,
+    columns: &[&'static str],
+    table: &'static str,
+) -> sea_orm::sea_query::SelectStatement {
+    Query::select()
+        .expr(Expr::val(constant))
+        .columns(columns.iter().copied())
+        .from(table)
+        .to_owned()
+}
+
+/// Shorthand for a named index on `table`.
+fn index(name: &str, table: &'static str) -> IndexCreateStatement {
+    Index::create().name(name).table(table).to_owned()
+}
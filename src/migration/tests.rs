@@ -0,0 +1,93 @@
+use super::*;
+use sea_orm::{Database, Statement};
+use tempfile::NamedTempFile;
+
+async fn connect(db_file: &NamedTempFile) -> DatabaseConnection {
+    Database::connect(format!("sqlite:{}?mode=rwc", db_file.path().display()))
+        .await
+        .unwrap()
+}
+
+async fn execute(db: &DatabaseConnection, sql: &str) {
+    db.execute(Statement::from_string(db.get_database_backend(), sql))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_fresh_database_migrates_once() {
+    let db_file = NamedTempFile::new().unwrap();
+    let db = connect(&db_file).await;
+
+    let names: Vec<String> = Migrator::migrations()
+        .iter()
+        .map(|m| m.name().to_string())
+        .collect();
+    assert!(
+        names.windows(2).all(|w| w[0] < w[1]),
+        "unsorted: {:?}",
+        names
+    );
+
+    assert_eq!(run(&db).await.unwrap(), names);
+    assert!(run(&db).await.unwrap().is_empty());
+    assert_eq!(applied(&db).await.unwrap(), names);
+
+    execute(
+        &db,
+        "INSERT INTO file_nodes (repo, file_id, parent_id, name, is_dir, size, updated_at) \
+         VALUES ('a', 1, 0, 'x', 1, 0, '2025-01-01 00:00:00'), \
+                ('b', 1, 0, 'x', 1, 0, '2025-01-01 00:00:00')",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_pre_migration_database_is_upgraded() {
+    let db_file = NamedTempFile::new().unwrap();
+    let db = connect(&db_file).await;
+    for sql in [
+        "CREATE TABLE file_nodes (file_id INTEGER PRIMARY KEY, parent_id INTEGER NOT NULL, \
+         name TEXT NOT NULL, is_dir BOOLEAN NOT NULL, size INTEGER NOT NULL, etag TEXT, \
+         updated_at TEXT NOT NULL)",
+        "CREATE UNIQUE INDEX idx_parent_name ON file_nodes (parent_id, name)",
+        "INSERT INTO file_nodes VALUES (7, 0, 'repo', 1, 0, NULL, '2025-01-01 00:00:00')",
+        "CREATE TABLE token_cache (id INTEGER PRIMARY KEY, access_token TEXT NOT NULL, \
+         expires_at TEXT NOT NULL)",
+    ] {
+        execute(&db, sql).await;
+    }
+
+    run(&db).await.unwrap();
+
+    let row = db
+        .query_one(Statement::from_string(
+            db.get_database_backend(),
+            "SELECT repo, modified_at FROM file_nodes WHERE file_id = 7",
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(row.try_get::<String>("", "repo").unwrap(), "");
+    assert_eq!(
+        row.try_get::<Option<String>>("", "modified_at").unwrap(),
+        None
+    );
+    execute(
+        &db,
+        "UPDATE token_cache SET lease_owner = NULL, lease_until = NULL",
+    )
+    .await;
+    execute(&db, "SELECT seq, attempts FROM upload_journal").await;
+
+    // The old startup crawl listed every directory that has cached children
+    let row = db
+        .query_one(Statement::from_string(
+            db.get_database_backend(),
+            "SELECT COUNT(*) AS n FROM loaded_dirs WHERE repo = '' AND dir_id = 0",
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(row.try_get::<i64>("", "n").unwrap(), 1);
+}
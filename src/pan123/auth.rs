@@ -1,19 +1,22 @@
 //! Token management for 123pan API authentication.
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
 use parking_lot::RwLock;
 use reqwest::Client;
 use sea_orm::{
-    sea_query::{ColumnDef, Expr, OnConflict, Query, Table},
+    sea_query::{Expr, OnConflict, Query},
     ConnectionTrait, DatabaseConnection,
 };
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
+use super::metrics::{ApiMetrics, UpstreamTimings};
+use super::rate_limit;
 use super::types::{AccessTokenData, AccessTokenRequest, ApiResponse};
-use super::{MAX_RETRIES, RETRY_DELAY};
+use super::{PoolOptions, Timeouts, MAX_RETRIES, RETRY_DELAY};
 use crate::error::{AppError, Result};
 
-/// Base URL for 123pan Open Platform API.
+/// Default base URL for 123pan Open Platform API.
 pub const BASE_URL: &str = "https://open-api.123pan.com";
 
 /// Token with expiry information.
@@ -30,68 +33,321 @@ impl TokenInfo {
     }
 }
 
+/// A client ID/secret pair with its own token and usage counters.
+struct Credential {
+    /// Client ID and secret, replaced by [`TokenManager::rotate_credentials`]
+    keys: RwLock<(String, String)>,
+    token: RwLock<Option<TokenInfo>>,
+    last_refresh_time: RwLock<Option<DateTime<Utc>>>,
+    /// API requests issued with this credential
+    requests: AtomicU64,
+    /// Requests rejected with 429
+    rate_limited: AtomicU64,
+}
+
+impl Credential {
+    fn new(client_id: String, client_secret: String) -> Self {
+        Self {
+            keys: RwLock::new((client_id, client_secret)),
+            token: RwLock::new(None),
+            last_refresh_time: RwLock::new(None),
+            requests: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Usage counters for one configured credential.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CredentialStats {
+    pub index: usize,
+    pub active: bool,
+    pub requests: u64,
+    pub rate_limited: u64,
+}
+
 /// Token manager that handles automatic token refresh.
+///
+/// Several credentials may be configured; the active one is used until
+/// 123pan rate-limits it, then requests fail over to the next one.
 #[derive(Clone)]
 pub struct TokenManager {
-    client_id: String,
-    client_secret: String,
+    credentials: Arc<Vec<Credential>>,
+    /// Index of the active credential
+    current: Arc<AtomicUsize>,
     http_client: Client,
+    /// Client for presigned download URLs and upload domains
+    transfer_client: Client,
+    api_proxy: Option<String>,
+    transfer_proxy: Option<String>,
+    /// Timeouts of HTTP requests
+    timeouts: Timeouts,
+    /// Connection reuse of both clients
+    pool: PoolOptions,
+    /// Retries of a rate-limited token request
+    max_retries: usize,
+    retry_delay: std::time::Duration,
+    /// 123pan Open Platform API base URL (without trailing slash)
+    base_url: String,
     db: DatabaseConnection,
-    token: Arc<RwLock<Option<TokenInfo>>>,
-    last_refresh_time: Arc<RwLock<Option<DateTime<Utc>>>>,
+    /// Identifies this process when holding a token refresh lease
+    instance_id: String,
+    /// Usage counters of all API calls made with these credentials
+    metrics: ApiMetrics,
 }
 
 const TOKEN_CACHE_TABLE: &str = "token_cache";
 const TOKEN_CACHE_ID: &str = "id";
 const TOKEN_CACHE_ACCESS_TOKEN: &str = "access_token";
 const TOKEN_CACHE_EXPIRES_AT: &str = "expires_at";
+const TOKEN_CACHE_LEASE_OWNER: &str = "lease_owner";
+const TOKEN_CACHE_LEASE_UNTIL: &str = "lease_until";
+
+/// How long a refresh lease is held before other instances may take over.
+const REFRESH_LEASE_SECS: i64 = 30;
+/// How often to poll for a token refreshed by the lease holder.
+const LEASE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+/// Polls before giving up on the lease holder and refreshing locally.
+const LEASE_WAIT_POLLS: usize = 20;
+
+/// Build an HTTP client with connect and read `timeouts`, a `total` timeout
+/// per request if given and `pool` settings, optionally routed through `proxy`.
+fn build_http_client(
+    proxy: Option<&str>,
+    timeouts: &Timeouts,
+    total: Option<std::time::Duration>,
+    pool: &PoolOptions,
+) -> Result<Client> {
+    let mut builder = pool.apply(
+        Client::builder()
+            .connect_timeout(timeouts.connect)
+            .read_timeout(timeouts.read),
+    );
+    if let Some(total) = total {
+        builder = builder.timeout(total);
+    }
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| AppError::Internal(format!("Invalid proxy URL '{}': {}", proxy, e)))?;
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| AppError::Internal(format!("Failed to create HTTP client: {}", e)))
+}
+
+/// Timestamp format stored in the token cache (sorts lexicographically).
+fn db_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
 
 impl TokenManager {
     /// Create a new token manager.
     pub fn new(client_id: String, client_secret: String, db: DatabaseConnection) -> Self {
-        let http_client = Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
-            .build()
+        let timeouts = Timeouts::default();
+        let pool = PoolOptions::default();
+        let http_client = build_http_client(None, &timeouts, Some(timeouts.api), &pool)
             .expect("Failed to create HTTP client");
+        let transfer_client =
+            build_http_client(None, &timeouts, None, &pool).expect("Failed to create HTTP client");
 
         Self {
-            client_id,
-            client_secret,
+            credentials: Arc::new(vec![Credential::new(client_id, client_secret)]),
+            current: Arc::new(AtomicUsize::new(0)),
+            transfer_client,
             http_client,
+            api_proxy: None,
+            transfer_proxy: None,
+            timeouts,
+            pool,
+            max_retries: MAX_RETRIES,
+            retry_delay: RETRY_DELAY,
+            base_url: BASE_URL.to_string(),
             db,
-            token: Arc::new(RwLock::new(None)),
-            last_refresh_time: Arc::new(RwLock::new(None)),
+            instance_id: format!(
+                "{}-{}",
+                std::process::id(),
+                Utc::now().timestamp_nanos_opt().unwrap_or_default()
+            ),
+            metrics: ApiMetrics::default(),
         }
     }
 
-    /// Initialize token cache table.
-    pub async fn init_db(&self) -> Result<()> {
-        let builder = self.db.get_database_backend();
-        let stmt = Table::create()
-            .table(TOKEN_CACHE_TABLE)
-            .if_not_exists()
-            .col(
-                ColumnDef::new(TOKEN_CACHE_ID)
-                    .integer()
-                    .not_null()
-                    .primary_key(),
-            )
-            .col(ColumnDef::new(TOKEN_CACHE_ACCESS_TOKEN).string().not_null())
-            .col(ColumnDef::new(TOKEN_CACHE_EXPIRES_AT).string().not_null())
-            .to_owned();
+    /// Add further credentials to fail over to when the active one is rate limited.
+    pub fn with_extra_credentials(mut self, extra: Vec<(String, String)>) -> Self {
+        let mut credentials: Vec<Credential> = self
+            .credentials
+            .iter()
+            .map(|c| {
+                let (client_id, client_secret) = c.keys.read().clone();
+                Credential::new(client_id, client_secret)
+            })
+            .collect();
+        credentials.extend(
+            extra
+                .into_iter()
+                .map(|(client_id, client_secret)| Credential::new(client_id, client_secret)),
+        );
+        self.credentials = Arc::new(credentials);
+        self
+    }
 
-        self.db.execute(builder.build(&stmt)).await.map_err(|e| {
-            AppError::Internal(format!("Failed to initialize token cache table: {}", e))
-        })?;
+    /// Route API requests through `api_proxy` and download/upload traffic
+    /// through `transfer_proxy` (HTTP, HTTPS or SOCKS5 URLs). Without an
+    /// explicit proxy the standard `HTTPS_PROXY`/`ALL_PROXY` variables apply.
+    pub fn with_proxies(
+        mut self,
+        api_proxy: Option<&str>,
+        transfer_proxy: Option<&str>,
+    ) -> Result<Self> {
+        self.api_proxy = api_proxy.map(str::to_string);
+        self.transfer_proxy = transfer_proxy.map(str::to_string);
+        self.rebuild_http_clients()?;
+        Ok(self)
+    }
 
+    /// Bound connecting, reading and API calls by `timeouts`. Transfers get
+    /// their total timeout per request.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Result<Self> {
+        self.timeouts = timeouts;
+        self.rebuild_http_clients()?;
+        Ok(self)
+    }
+
+    /// Keep connections of both clients alive and reuse them as `pool` says.
+    pub fn with_pool(mut self, pool: PoolOptions) -> Result<Self> {
+        self.pool = pool;
+        self.rebuild_http_clients()?;
+        Ok(self)
+    }
+
+    /// Retry rate-limited token requests up to `max_retries` times, `delay` apart.
+    pub fn with_retries(mut self, max_retries: usize, delay: std::time::Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    fn rebuild_http_clients(&mut self) -> Result<()> {
+        self.http_client = build_http_client(
+            self.api_proxy.as_deref(),
+            &self.timeouts,
+            Some(self.timeouts.api),
+            &self.pool,
+        )?;
+        self.transfer_client = build_http_client(
+            self.transfer_proxy.as_deref(),
+            &self.timeouts,
+            None,
+            &self.pool,
+        )?;
         Ok(())
     }
 
+    /// Use a different API base URL.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Usage counters of the 123pan API.
+    pub fn metrics(&self) -> &ApiMetrics {
+        &self.metrics
+    }
+
+    /// API base URL.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Index of the active credential.
+    fn active(&self) -> usize {
+        self.current.load(Ordering::Acquire) % self.credentials.len()
+    }
+
+    /// Record a 429 for the active credential and fail over to the next one.
+    /// Returns true if another credential is now active.
+    pub fn report_rate_limited(&self) -> bool {
+        let index = self.active();
+        self.credentials[index]
+            .rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+        if self.credentials.len() < 2 {
+            return false;
+        }
+
+        let next = (index + 1) % self.credentials.len();
+        if self
+            .current
+            .compare_exchange(index, next, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            tracing::warn!(
+                "Credential #{} rate limited, failing over to credential #{}",
+                index,
+                next
+            );
+        }
+        true
+    }
+
+    /// Per-credential request and rate-limit counters.
+    pub fn stats(&self) -> Vec<CredentialStats> {
+        let active = self.active();
+        self.credentials
+            .iter()
+            .enumerate()
+            .map(|(index, c)| CredentialStats {
+                index,
+                active: index == active,
+                requests: c.requests.load(Ordering::Relaxed),
+                rate_limited: c.rate_limited.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Replace the client IDs and secrets, e.g. after a secret leaked, without
+    /// interrupting requests. `credentials` lists the primary credential and
+    /// the extra ones in configuration order; their number can't change. Each
+    /// changed credential first gets a token with its new secret, and keeps
+    /// the old secret if that fails. Returns how many were replaced.
+    pub async fn rotate_credentials(&self, credentials: Vec<(String, String)>) -> Result<usize> {
+        if credentials.len() != self.credentials.len() {
+            return Err(AppError::BadRequest(format!(
+                "{} credentials configured, {} given; restart to add or remove credentials",
+                self.credentials.len(),
+                credentials.len()
+            )));
+        }
+        let mut rotated = 0;
+        for (index, keys) in credentials.into_iter().enumerate() {
+            let credential = &self.credentials[index];
+            if *credential.keys.read() == keys {
+                continue;
+            }
+            let old = std::mem::replace(&mut *credential.keys.write(), keys);
+            if let Err(e) = self.fetch_token(index).await {
+                *credential.keys.write() = old;
+                return Err(AppError::Auth(format!(
+                    "New credential #{} was refused, keeping the old one: {}",
+                    index, e
+                )));
+            }
+            tracing::info!("Credential #{} rotated", index);
+            rotated += 1;
+        }
+        Ok(rotated)
+    }
+
     /// Get a valid access token, refreshing if necessary.
     pub async fn get_token(&self) -> Result<String> {
+        let index = self.active();
+        let credential = &self.credentials[index];
+        credential.requests.fetch_add(1, Ordering::Relaxed);
+
         // Check if we have a valid token
         {
-            let token_guard = self.token.read();
+            let token_guard = credential.token.read();
             if let Some(ref token_info) = *token_guard {
                 if !token_info.is_expired() {
                     return Ok(token_info.access_token.clone());
@@ -100,23 +356,42 @@ impl TokenManager {
         }
 
         // Check cached token in DB
-        if let Some(token_info) = self.load_cached_token().await? {
-            let mut token_guard = self.token.write();
+        if let Some(token_info) = self.load_cached_token(index).await? {
+            let mut token_guard = credential.token.write();
             *token_guard = Some(token_info.clone());
             return Ok(token_info.access_token);
         }
 
         // Need to refresh token
-        self.refresh_token().await
+        self.refresh_credential(index).await
     }
 
     /// Force refresh the access token.
     /// Includes 429 retry support.
     /// Rate limited to once per minute.
     pub async fn refresh_token(&self) -> Result<String> {
+        self.refresh_credential(self.active()).await
+    }
+
+    /// Refresh the token of credential `index`, coordinating with other
+    /// instances sharing the database: if one of them already stored a newer
+    /// token it is adopted, and only the holder of the refresh lease calls
+    /// the token API while the others wait for its result.
+    async fn refresh_credential(&self, index: usize) -> Result<String> {
+        let credential = &self.credentials[index];
+
+        let stale = credential
+            .token
+            .read()
+            .as_ref()
+            .map(|t| t.access_token.clone());
+        if let Some(token) = self.adopt_shared_token(index, stale.as_deref()).await? {
+            return Ok(token);
+        }
+
         // Rate limit check
         {
-            let last_refresh = self.last_refresh_time.read();
+            let last_refresh = credential.last_refresh_time.read();
             if let Some(last_time) = *last_refresh {
                 let now = Utc::now();
                 if now - last_time < Duration::minutes(1) {
@@ -127,7 +402,7 @@ impl TokenManager {
                     // Try to return existing token even if potentially expired,
                     // or just return what we have to avoid spamming API.
                     // Ideally we should check if we really have a token.
-                    let token_guard = self.token.read();
+                    let token_guard = credential.token.read();
                     if let Some(ref token_info) = *token_guard {
                         return Ok(token_info.access_token.clone());
                     }
@@ -140,13 +415,36 @@ impl TokenManager {
             }
         }
 
-        tracing::info!("Refreshing 123pan access token");
+        if !self.acquire_refresh_lease(index).await? {
+            tracing::info!("Another instance is refreshing the access token, waiting");
+            for _ in 0..LEASE_WAIT_POLLS {
+                tokio::time::sleep(LEASE_POLL_INTERVAL).await;
+                if let Some(token) = self.adopt_shared_token(index, stale.as_deref()).await? {
+                    return Ok(token);
+                }
+            }
+            tracing::warn!("Timed out waiting for another instance to refresh the token");
+        }
+
+        let result = self.fetch_token(index).await;
+        if let Err(e) = self.release_refresh_lease(index).await {
+            tracing::warn!("Failed to release token refresh lease: {}", e);
+        }
+        result
+    }
+
+    /// Request a new access token for credential `index` from the API.
+    async fn fetch_token(&self, index: usize) -> Result<String> {
+        let credential = &self.credentials[index];
 
-        let url = format!("{}/api/v1/access_token", BASE_URL);
+        tracing::info!("Refreshing 123pan access token (credential #{})", index);
 
+        let url = format!("{}/api/v1/access_token", self.base_url);
+
+        let (client_id, client_secret) = credential.keys.read().clone();
         let request = AccessTokenRequest {
-            client_id: self.client_id.clone(),
-            client_secret: self.client_secret.clone(),
+            client_id,
+            client_secret,
         };
 
         // Serialize request once for reuse in retries
@@ -154,7 +452,9 @@ impl TokenManager {
             AppError::Auth(format!("Failed to serialize access token request: {}", e))
         })?;
 
-        for attempt in 0..=MAX_RETRIES {
+        const ENDPOINT: &str = "/api/v1/access_token";
+        for attempt in 0..=self.max_retries {
+            let call = UpstreamTimings::start(ENDPOINT);
             let response = self
                 .http_client
                 .post(&url)
@@ -162,25 +462,35 @@ impl TokenManager {
                 .header("Content-Type", "application/json")
                 .body(request_json.clone())
                 .send()
-                .await?;
+                .await
+                .inspect_err(|_| self.metrics.record_transport_error(ENDPOINT))?;
 
-            let api_response: ApiResponse<AccessTokenData> = response.json().await?;
+            let headers = response.headers().clone();
+            let api_response: ApiResponse<AccessTokenData> = response
+                .json()
+                .await
+                .inspect_err(|_| self.metrics.record_transport_error(ENDPOINT))?;
+            drop(call);
+            self.metrics.record_response(ENDPOINT, api_response.code);
 
             // Check for 429 rate limit error
             if api_response.code == 429 {
-                if attempt < MAX_RETRIES {
+                if attempt < self.max_retries {
+                    self.metrics.record_retry(ENDPOINT);
+                    let wait = rate_limit::retry_after(&headers, &api_response.message)
+                        .unwrap_or(self.retry_delay);
                     tracing::warn!(
-                        "Rate limited (429) when refreshing access token, waiting {}s before retry (attempt {}/{})",
-                        RETRY_DELAY.as_secs(),
+                        "Rate limited (429) when refreshing access token, waiting {:?} before retry (attempt {}/{})",
+                        wait,
                         attempt + 1,
-                        MAX_RETRIES
+                        self.max_retries
                     );
-                    tokio::time::sleep(RETRY_DELAY).await;
+                    tokio::time::sleep(wait).await;
                     continue;
                 } else {
                     tracing::error!(
                         "Rate limited (429) after {} retries when refreshing access token, giving up",
-                        MAX_RETRIES
+                        self.max_retries
                     );
                     return Err(AppError::Auth(format!(
                         "Failed to get access token after retries: {} (code: {})",
@@ -216,11 +526,11 @@ impl TokenManager {
 
             // Update stored token
             {
-                let mut token_guard = self.token.write();
+                let mut token_guard = credential.token.write();
                 *token_guard = Some(token_info.clone());
             }
 
-            self.store_cached_token(&token_info).await?;
+            self.store_cached_token(index, &token_info).await?;
 
             tracing::info!(
                 "Successfully refreshed access token, expires at {}",
@@ -229,7 +539,7 @@ impl TokenManager {
 
             // Update last refresh time
             {
-                let mut last_refresh = self.last_refresh_time.write();
+                let mut last_refresh = credential.last_refresh_time.write();
                 *last_refresh = Some(Utc::now());
             }
 
@@ -239,17 +549,124 @@ impl TokenManager {
         unreachable!()
     }
 
+    /// Timeouts of HTTP requests.
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
     /// Get the HTTP client.
     pub fn http_client(&self) -> &Client {
         &self.http_client
     }
 
-    async fn load_cached_token(&self) -> Result<Option<TokenInfo>> {
+    /// Get the HTTP client for file transfers.
+    pub fn transfer_client(&self) -> &Client {
+        &self.transfer_client
+    }
+
+    /// Use a valid token stored by another instance if it differs from `stale`.
+    async fn adopt_shared_token(
+        &self,
+        index: usize,
+        stale: Option<&str>,
+    ) -> Result<Option<String>> {
+        let Some(token_info) = self.load_cached_token(index).await? else {
+            return Ok(None);
+        };
+        if Some(token_info.access_token.as_str()) == stale {
+            return Ok(None);
+        }
+        tracing::info!("Using access token refreshed by another instance");
+        let token = token_info.access_token.clone();
+        *self.credentials[index].token.write() = Some(token_info);
+        Ok(Some(token))
+    }
+
+    /// Try to take the refresh lease for credential `index`.
+    pub(crate) async fn acquire_refresh_lease(&self, index: usize) -> Result<bool> {
+        let builder = self.db.get_database_backend();
+        let now = Utc::now();
+
+        // Make sure the row exists so the lease can be taken with an UPDATE
+        let placeholder = Query::insert()
+            .into_table(TOKEN_CACHE_TABLE)
+            .columns([
+                TOKEN_CACHE_ID,
+                TOKEN_CACHE_ACCESS_TOKEN,
+                TOKEN_CACHE_EXPIRES_AT,
+            ])
+            .values_panic([
+                Self::cache_row(index).into(),
+                "".into(),
+                db_timestamp(DateTime::UNIX_EPOCH).into(),
+            ])
+            .on_conflict(
+                OnConflict::column(TOKEN_CACHE_ID)
+                    .do_nothing_on([TOKEN_CACHE_ID])
+                    .to_owned(),
+            )
+            .to_owned();
+        self.db
+            .execute(builder.build(&placeholder))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to prepare token lease: {}", e)))?;
+
+        let stmt = Query::update()
+            .table(TOKEN_CACHE_TABLE)
+            .values([
+                (TOKEN_CACHE_LEASE_OWNER, self.instance_id.clone().into()),
+                (
+                    TOKEN_CACHE_LEASE_UNTIL,
+                    db_timestamp(now + Duration::seconds(REFRESH_LEASE_SECS)).into(),
+                ),
+            ])
+            .and_where(Expr::col(TOKEN_CACHE_ID).eq(Self::cache_row(index)))
+            .cond_where(
+                Expr::col(TOKEN_CACHE_LEASE_UNTIL)
+                    .is_null()
+                    .or(Expr::col(TOKEN_CACHE_LEASE_UNTIL).lt(db_timestamp(now)))
+                    .or(Expr::col(TOKEN_CACHE_LEASE_OWNER).eq(self.instance_id.clone())),
+            )
+            .to_owned();
+        let result = self
+            .db
+            .execute(builder.build(&stmt))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to acquire token lease: {}", e)))?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Release the refresh lease for credential `index` if this instance holds it.
+    pub(crate) async fn release_refresh_lease(&self, index: usize) -> Result<()> {
+        let builder = self.db.get_database_backend();
+        let stmt = Query::update()
+            .table(TOKEN_CACHE_TABLE)
+            .values([
+                (TOKEN_CACHE_LEASE_OWNER, Option::<String>::None.into()),
+                (TOKEN_CACHE_LEASE_UNTIL, Option::<String>::None.into()),
+            ])
+            .and_where(Expr::col(TOKEN_CACHE_ID).eq(Self::cache_row(index)))
+            .and_where(Expr::col(TOKEN_CACHE_LEASE_OWNER).eq(self.instance_id.clone()))
+            .to_owned();
+        self.db
+            .execute(builder.build(&stmt))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to release token lease: {}", e)))?;
+        Ok(())
+    }
+
+    /// Token cache row for a credential (the primary credential uses row 1).
+    fn cache_row(index: usize) -> i64 {
+        index as i64 + 1
+    }
+
+    async fn load_cached_token(&self, index: usize) -> Result<Option<TokenInfo>> {
         let builder = self.db.get_database_backend();
         let stmt = Query::select()
             .columns([TOKEN_CACHE_ACCESS_TOKEN, TOKEN_CACHE_EXPIRES_AT])
             .from(TOKEN_CACHE_TABLE)
-            .and_where(Expr::col(TOKEN_CACHE_ID).eq(1))
+            .and_where(Expr::col(TOKEN_CACHE_ID).eq(Self::cache_row(index)))
             .to_owned();
 
         let row = self
@@ -289,7 +706,7 @@ impl TokenManager {
         Ok(Some(token_info))
     }
 
-    async fn store_cached_token(&self, token_info: &TokenInfo) -> Result<()> {
+    async fn store_cached_token(&self, index: usize, token_info: &TokenInfo) -> Result<()> {
         let builder = self.db.get_database_backend();
         let stmt = Query::insert()
             .into_table(TOKEN_CACHE_TABLE)
@@ -299,7 +716,7 @@ impl TokenManager {
                 TOKEN_CACHE_EXPIRES_AT,
             ])
             .values_panic([
-                1.into(),
+                Self::cache_row(index).into(),
                 token_info.access_token.clone().into(),
                 token_info.expires_at.to_rfc3339().into(),
             ])
@@ -321,9 +738,14 @@ impl TokenManager {
 
 impl std::fmt::Debug for TokenManager {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let client_ids: Vec<String> = self
+            .credentials
+            .iter()
+            .map(|c| c.keys.read().0.clone())
+            .collect();
         f.debug_struct("TokenManager")
-            .field("client_id", &self.client_id)
-            .field("client_secret", &"[REDACTED]")
+            .field("client_ids", &client_ids)
+            .field("client_secrets", &"[REDACTED]")
             .finish()
     }
 }
@@ -0,0 +1,279 @@
+//! `StorageBackend` implementation for 123pan.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use sea_orm::{ColumnTrait, QueryFilter, QueryOrder, QuerySelect};
+use serde_json::json;
+
+use super::client::IN_CLAUSE_CHUNK;
+use super::{encode_name, entity, FileInfo, Pan123Client};
+use crate::error::{AppError, Result};
+use crate::storage::{split_path, ObjectInfo, Readiness, StorageBackend};
+
+/// Seconds restic is asked to wait when a path isn't cached during warm-up.
+const WARM_UP_RETRY_AFTER: u64 = 10;
+
+/// Rows read from the cache per query while streaming a listing.
+const LIST_PAGE_ROWS: u64 = 1000;
+
+impl Pan123Client {
+    /// Absolute 123pan path of a repository-relative path.
+    pub(super) fn repo_full_path(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            self.repo_path.clone()
+        } else {
+            format!("{}/{}", self.repo_path.trim_end_matches('/'), path)
+        }
+    }
+
+    /// Until warm-up completes, only directories already in the cache are
+    /// served; others get 503 + Retry-After instead of being listed on demand.
+    async fn require_loaded(&self, dir_id: i64) -> Result<()> {
+        if self.is_cache_ready() || self.is_loaded(dir_id).await? {
+            return Ok(());
+        }
+        Err(AppError::Unavailable {
+            message: "Cache warm-up in progress".to_string(),
+            retry_after: WARM_UP_RETRY_AFTER,
+        })
+    }
+
+    /// Check that every directory on a repository-relative path is cached
+    /// (see [`Self::require_loaded`]).
+    async fn require_synced(&self, dir: &str) -> Result<()> {
+        if self.is_cache_ready() {
+            return Ok(());
+        }
+        let mut current_id = 0;
+        for part in self
+            .repo_full_path(dir)
+            .split('/')
+            .filter(|p| !p.is_empty())
+        {
+            self.require_loaded(current_id).await?;
+            match self.find_file(current_id, &encode_name(part)?).await? {
+                Some(node) if node.is_folder() => current_id = node.file_id,
+                // Missing directories are created (or reported missing) as usual
+                _ => return Ok(()),
+            }
+        }
+        self.require_loaded(current_id).await
+    }
+
+    /// IDs of `dir` and all directories below it, loading each on first use.
+    /// A missing directory has none.
+    async fn list_dirs(&self, dir: &str) -> Result<Vec<i64>> {
+        self.require_synced(dir).await?;
+        let Some(dir_id) = self.find_path_id(&self.repo_full_path(dir)).await? else {
+            return Ok(Vec::new());
+        };
+
+        // Walk the cached tree one level at a time, loading shards on first use
+        let mut dir_ids = Vec::new();
+        let mut level = vec![dir_id];
+        let mut hit = true;
+        while !level.is_empty() {
+            for &dir_id in &level {
+                self.require_loaded(dir_id).await?;
+                hit &= self.is_loaded(dir_id).await?;
+                self.ensure_loaded(dir_id).await?;
+            }
+            let mut subdirs = Vec::new();
+            for chunk in level.chunks(IN_CLAUSE_CHUNK) {
+                subdirs.extend(
+                    self.nodes()
+                        .filter(entity::Column::ParentId.is_in(chunk.to_vec()))
+                        .filter(entity::Column::IsDir.eq(true))
+                        .all(&self.db)
+                        .await
+                        .map_err(|e| AppError::Internal(format!("DB error in list: {}", e)))?
+                        .into_iter()
+                        .map(|node| node.file_id),
+                );
+            }
+            dir_ids.append(&mut level);
+            level = subdirs;
+        }
+        self.cache_metrics.record("listing", dir, hit);
+        Ok(dir_ids)
+    }
+
+    /// Look up a file by repository-relative path without creating directories.
+    async fn find_object(&self, path: &str) -> Result<Option<FileInfo>> {
+        let (parent, name) = split_path(path);
+        self.require_synced(parent).await?;
+        let Some(dir_id) = self.find_path_id(&self.repo_full_path(parent)).await? else {
+            return Ok(None);
+        };
+        let hit = self.is_loaded(dir_id).await?;
+        let file = self.get_file_info(dir_id, name).await?;
+        self.cache_metrics.record("lookup", path, hit);
+        Ok(file.filter(|f| f.file_type == 0))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Pan123Client {
+    async fn list(&self, dir: &str) -> Result<Vec<ObjectInfo>> {
+        self.list_stream(dir).await?.try_collect().await
+    }
+
+    async fn list_stream(&self, dir: &str) -> Result<BoxStream<'static, Result<ObjectInfo>>> {
+        let dir_ids = self.list_dirs(dir).await?;
+        let client = self.clone();
+        let pages = stream::iter(
+            dir_ids
+                .chunks(IN_CLAUSE_CHUNK)
+                .map(<[i64]>::to_vec)
+                .collect::<Vec<_>>(),
+        )
+        .flat_map(move |chunk| {
+            let client = client.clone();
+            // Keyset pagination by file ID, one page of rows at a time
+            stream::try_unfold(Some(0), move |after| {
+                let client = client.clone();
+                let chunk = chunk.clone();
+                async move {
+                    let Some(after) = after else {
+                        return Ok::<_, AppError>(None);
+                    };
+                    let nodes = client
+                        .nodes()
+                        .filter(entity::Column::ParentId.is_in(chunk))
+                        .filter(entity::Column::IsDir.eq(false))
+                        .filter(entity::Column::FileId.gt(after))
+                        .order_by_asc(entity::Column::FileId)
+                        .limit(LIST_PAGE_ROWS)
+                        .all(&client.db)
+                        .await
+                        .map_err(|e| AppError::Internal(format!("DB error in list: {}", e)))?;
+                    let next = (nodes.len() as u64 == LIST_PAGE_ROWS)
+                        .then(|| nodes.last().map(|n| n.file_id))
+                        .flatten();
+                    Ok(Some((nodes, next)))
+                }
+            })
+        });
+        Ok(pages
+            .map_ok(|nodes| {
+                stream::iter(
+                    nodes
+                        .into_iter()
+                        .map(|node| Ok(ObjectInfo::from(FileInfo::from(node)))),
+                )
+            })
+            .try_flatten()
+            .boxed())
+    }
+
+    async fn head(&self, path: &str) -> Result<Option<ObjectInfo>> {
+        Ok(self.find_object(path).await?.map(ObjectInfo::from))
+    }
+
+    async fn head_verified(&self, path: &str) -> Result<Option<ObjectInfo>> {
+        let Some(file) = self.find_object(path).await? else {
+            return Ok(None);
+        };
+        let confirmed = self.file_detail(file.file_id).await?.is_some_and(|detail| {
+            detail.trashed == 0
+                && detail.filename == file.filename
+                && detail.parent_file_id == file.parent_file_id
+                && detail.size == file.size
+        });
+        if confirmed {
+            return Ok(Some(ObjectInfo::from(file)));
+        }
+        tracing::warn!("Cached entry of {} differs from 123pan", path);
+        self.reconcile_dir(file.parent_file_id).await?;
+        Ok(self.find_object(path).await?.map(ObjectInfo::from))
+    }
+
+    async fn get_range(&self, path: &str, range: Option<(u64, u64)>) -> Result<Bytes> {
+        let file = self
+            .find_object(path)
+            .await?
+            .ok_or_else(|| AppError::NotFound(path.to_string()))?;
+        self.download_file(file.file_id, range).await
+    }
+
+    async fn put(&self, path: &str, data: Bytes) -> Result<()> {
+        let (parent, name) = split_path(path);
+        self.require_synced(parent).await?;
+        let dir_id = self.ensure_path(&self.repo_full_path(parent)).await?;
+        // With duplicate=2, upload will overwrite existing file atomically
+        let file_id = self.upload_file(dir_id, name, data).await?;
+        // An overwritten file queued for deletion may keep its ID
+        if self.delete_batcher.get().is_some() {
+            self.dequeue_deletes(&[file_id]).await?;
+        }
+        Ok(())
+    }
+
+    async fn create(&self, path: &str, data: Bytes) -> Result<()> {
+        if self.find_object(path).await?.is_some() {
+            return Err(AppError::Forbidden(format!("{} already exists", path)));
+        }
+        let (parent, name) = split_path(path);
+        let dir_id = self.ensure_path(&self.repo_full_path(parent)).await?;
+        // With duplicate=1, 123pan refuses a file created since the lookup
+        match self.upload_new_file(dir_id, name, data.clone()).await {
+            // The name may still be taken by a file queued for deletion
+            Err(AppError::Forbidden(_)) if self.delete_batcher.get().is_some() => {
+                self.flush_deletes().await?;
+                self.upload_new_file(dir_id, name, data).await?;
+            }
+            result => {
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        if let Some(file) = self.find_object(path).await? {
+            if !self.queue_delete(file.file_id).await? {
+                self.delete_file(file.parent_file_id, file.file_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn ensure_dir(&self, path: &str) -> Result<()> {
+        self.require_synced(path).await?;
+        self.ensure_path(&self.repo_full_path(path)).await?;
+        Ok(())
+    }
+
+    async fn readiness(&self) -> Readiness {
+        let db = self.ping_db().await.is_ok();
+        let token = self.check_token().await.is_ok();
+        let cache = self.is_cache_ready();
+
+        Readiness {
+            ready: db && token && cache,
+            details: json!({
+                "db": db,
+                "token": token,
+                "cache": cache,
+                "credentials": self.credential_stats(),
+                "lookup_cache": self.lookup_cache_stats(),
+                "pending_deletes": self.pending_deletes().await.ok(),
+            }),
+        }
+    }
+
+    async fn maintain(&self) -> Result<serde_json::Value> {
+        let report = crate::db::maintain(&self.db).await?;
+        Ok(serde_json::to_value(report).unwrap_or_default())
+    }
+
+    fn metrics(&self) -> String {
+        self.api_metrics().render_prometheus()
+            + &self.cache_metrics.render_prometheus(
+                "pan123_cache_requests_total",
+                "Listings and lookups answered from the directory cache (hit) or after listing from 123pan (miss)",
+            )
+    }
+}
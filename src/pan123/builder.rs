@@ -0,0 +1,309 @@
+//! Builder for `Pan123Client` with tunable behavior.
+
+use std::time::Duration;
+
+use super::auth::BASE_URL;
+use super::lookup_cache::DEFAULT_LOOKUP_CACHE_ENTRIES;
+use super::{
+    ConcurrencyLimits, Normalization, Pan123Client, PoolOptions, Timeouts, MAX_LIST_PAGE_SIZE,
+    MAX_RETRIES, MAX_SINGLE_UPLOAD_SIZE, RETRY_DELAY, UPLOAD_COMPLETE_TIMEOUT, UPLOAD_DOMAIN_TTL,
+};
+use crate::error::{AppError, Result};
+use crate::restic::RepoLayout;
+
+/// Configures and creates a [`Pan123Client`].
+///
+/// ```no_run
+/// # async fn example() -> restic_123pan::error::Result<()> {
+/// use restic_123pan::pan123::Pan123Client;
+///
+/// let client = Pan123Client::builder("client_id", "client_secret")
+///     .repo_path("/backups/laptop")
+///     .database_url("sqlite:cache.db?mode=rwc")
+///     .max_retries(5)
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Pan123ClientBuilder {
+    pub(super) client_id: String,
+    pub(super) client_secret: String,
+    pub(super) repo_path: String,
+    pub(super) database_url: String,
+    pub(super) cache_namespace: Option<String>,
+    pub(super) base_url: String,
+    pub(super) extra_credentials: Vec<(String, String)>,
+    pub(super) api_proxy: Option<String>,
+    pub(super) transfer_proxy: Option<String>,
+    pub(super) timeouts: Timeouts,
+    pub(super) pool: PoolOptions,
+    pub(super) max_retries: usize,
+    pub(super) retry_delay: Duration,
+    pub(super) page_size: u32,
+    pub(super) layout: RepoLayout,
+    pub(super) download_chunk_size: u64,
+    pub(super) download_parallelism: usize,
+    pub(super) lookup_cache_entries: usize,
+    pub(super) directory_ttl: Option<Duration>,
+    pub(super) multipart_threshold: u64,
+    pub(super) normalization: Normalization,
+    pub(super) upload_domain_ttl: Duration,
+    pub(super) upload_complete_timeout: Duration,
+    pub(super) concurrency_limits: ConcurrencyLimits,
+    pub(super) max_upload_rate: u64,
+    pub(super) max_download_rate: u64,
+    pub(super) progress_threshold: u64,
+    pub(super) progress_interval: Duration,
+}
+
+impl Pan123ClientBuilder {
+    pub(super) fn new(client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            repo_path: "/restic-backup".to_string(),
+            database_url: "sqlite:cache-123pan.db?mode=rwc".to_string(),
+            cache_namespace: None,
+            base_url: BASE_URL.to_string(),
+            extra_credentials: Vec::new(),
+            api_proxy: None,
+            transfer_proxy: None,
+            timeouts: Timeouts::default(),
+            pool: PoolOptions::default(),
+            max_retries: MAX_RETRIES,
+            retry_delay: RETRY_DELAY,
+            page_size: MAX_LIST_PAGE_SIZE,
+            layout: RepoLayout::default(),
+            download_chunk_size: 8 * 1024 * 1024,
+            download_parallelism: 1,
+            lookup_cache_entries: DEFAULT_LOOKUP_CACHE_ENTRIES,
+            directory_ttl: None,
+            multipart_threshold: MAX_SINGLE_UPLOAD_SIZE,
+            normalization: Normalization::default(),
+            upload_domain_ttl: UPLOAD_DOMAIN_TTL,
+            upload_complete_timeout: UPLOAD_COMPLETE_TIMEOUT,
+            concurrency_limits: ConcurrencyLimits::default(),
+            max_upload_rate: 0,
+            max_download_rate: 0,
+            progress_threshold: 0,
+            progress_interval: Duration::from_secs(30),
+        }
+    }
+
+    /// Root folder of the repository on 123pan.
+    pub fn repo_path(mut self, repo_path: impl Into<String>) -> Self {
+        self.repo_path = repo_path.into();
+        self
+    }
+
+    /// Database holding the file tree cache and shared token.
+    pub fn database_url(mut self, database_url: impl Into<String>) -> Self {
+        self.database_url = database_url.into();
+        self
+    }
+
+    /// Key scoping this repository's entries in a shared cache database.
+    ///
+    /// Defaults to the repository path; set it when several accounts share
+    /// one database and use the same path.
+    pub fn cache_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.cache_namespace = Some(namespace.into());
+        self
+    }
+
+    /// 123pan API endpoint (e.g. a mock server or regional endpoint).
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Use another account, dropping the extra credentials of the previous one.
+    pub fn credentials(
+        mut self,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        self.client_id = client_id.into();
+        self.client_secret = client_secret.into();
+        self.extra_credentials.clear();
+        self
+    }
+
+    /// Credentials to fail over to when the primary one is rate limited.
+    pub fn extra_credentials(mut self, extra: Vec<(String, String)>) -> Self {
+        self.extra_credentials = extra;
+        self
+    }
+
+    /// Proxies for API calls and for downloads/uploads; the transfer proxy
+    /// defaults to the API proxy.
+    pub fn proxies(mut self, api_proxy: Option<&str>, transfer_proxy: Option<&str>) -> Self {
+        self.api_proxy = api_proxy.map(str::to_string);
+        self.transfer_proxy = transfer_proxy.or(api_proxy).map(str::to_string);
+        self
+    }
+
+    /// Total timeout of a single upstream API call (listings are exempt).
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.api = timeout;
+        self
+    }
+
+    /// Connect, read, API call, upload and download timeouts.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Connection pool, HTTP/2 and TCP keepalive settings of the clients for
+    /// the API and for upload and download domains.
+    pub fn pool(mut self, pool: PoolOptions) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Retries of a rate-limited or unauthorized API call.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Wait between retries and download resumptions.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Entries requested per file list page (1-100).
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Length of the ID prefix naming data subdirectories (0 = no subdirectories).
+    pub fn data_shard_len(mut self, len: usize) -> Self {
+        self.layout.data_shard_len = len;
+        self
+    }
+
+    /// Levels of data subdirectories (0-3), each named by the next
+    /// `data_shard_len` characters of the ID.
+    pub fn data_shard_depth(mut self, depth: usize) -> Self {
+        self.layout.data_shard_depth = depth;
+        self
+    }
+
+    /// Download files larger than `chunk_size` as up to `parallelism`
+    /// concurrent Range requests.
+    pub fn parallel_download(mut self, chunk_size: u64, parallelism: usize) -> Self {
+        self.download_chunk_size = chunk_size.max(1);
+        self.download_parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Entries kept in the in-memory lookup cache in front of the database
+    /// (0 disables it, e.g. when replicas share a database and write to it).
+    pub fn lookup_cache_entries(mut self, entries: usize) -> Self {
+        self.lookup_cache_entries = entries;
+        self
+    }
+
+    /// Fetch a cached directory listing again when it is used more than
+    /// `ttl` after it was listed, bounding how long changes made outside this
+    /// server go unnoticed. By default listings never expire.
+    pub fn directory_ttl(mut self, ttl: Duration) -> Self {
+        self.directory_ttl = Some(ttl);
+        self
+    }
+
+    /// Upload files larger than `threshold` bytes in slices, which resume
+    /// after a restart (at most 1 GiB, the limit of single uploads).
+    pub fn multipart_threshold(mut self, threshold: u64) -> Self {
+        self.multipart_threshold = threshold.min(MAX_SINGLE_UPLOAD_SIZE);
+        self
+    }
+
+    /// Unicode normalization form names are compared and uploaded in, so a
+    /// path typed in one form finds names 123pan lists in another (NFC by
+    /// default).
+    pub fn unicode_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Longest wait for 123pan to finalize a slice upload it verifies
+    /// asynchronously.
+    pub fn upload_complete_timeout(mut self, timeout: Duration) -> Self {
+        self.upload_complete_timeout = timeout;
+        self
+    }
+
+    /// Age after which the list of upload domains is fetched again.
+    pub fn upload_domain_ttl(mut self, ttl: Duration) -> Self {
+        self.upload_domain_ttl = ttl;
+        self
+    }
+
+    /// Concurrent uploads, download requests and API calls to 123pan, each
+    /// limited separately (0 for unlimited).
+    pub fn concurrency_limits(mut self, limits: ConcurrencyLimits) -> Self {
+        self.concurrency_limits = limits;
+        self
+    }
+
+    /// Limit uploads and downloads to 123pan, in bytes per second across all
+    /// transfers in that direction (0 for unlimited).
+    pub fn bandwidth_limits(mut self, upload: u64, download: u64) -> Self {
+        self.max_upload_rate = upload;
+        self.max_download_rate = download;
+        self
+    }
+
+    /// Log the progress of transfers of at least `threshold` bytes every
+    /// `interval` (0 disables).
+    pub fn progress_logging(mut self, threshold: u64, interval: Duration) -> Self {
+        self.progress_threshold = threshold;
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Connect to the database and create the client.
+    pub async fn build(self) -> Result<Pan123Client> {
+        if !(1..=MAX_LIST_PAGE_SIZE).contains(&self.page_size) {
+            return Err(AppError::BadRequest(format!(
+                "List page size must be between 1 and {}, got {}",
+                MAX_LIST_PAGE_SIZE, self.page_size
+            )));
+        }
+        self.layout.validate()?;
+        Pan123Client::from_builder(self).await
+    }
+}
+
+impl std::fmt::Debug for Pan123ClientBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pan123ClientBuilder")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"[REDACTED]")
+            .field("repo_path", &self.repo_path)
+            .field("cache_namespace", &self.cache_namespace)
+            .field("base_url", &self.base_url)
+            .field("timeouts", &self.timeouts)
+            .field("pool", &self.pool)
+            .field("max_retries", &self.max_retries)
+            .field("page_size", &self.page_size)
+            .field("directory_ttl", &self.directory_ttl)
+            .field("multipart_threshold", &self.multipart_threshold)
+            .field("normalization", &self.normalization)
+            .field("upload_domain_ttl", &self.upload_domain_ttl)
+            .field("upload_complete_timeout", &self.upload_complete_timeout)
+            .field("concurrency_limits", &self.concurrency_limits)
+            .field("max_upload_rate", &self.max_upload_rate)
+            .field("max_download_rate", &self.max_download_rate)
+            .field("progress_threshold", &self.progress_threshold)
+            .field("layout", &self.layout)
+            .finish()
+    }
+}
@@ -0,0 +1,217 @@
+//! Snapshots of the cache database stored with the repository on 123pan.
+//!
+//! A full crawl of a large repository takes a long time. Periodic snapshots
+//! in `{repo_path}/.cache-backup` let a replacement host seed its cache from
+//! the latest one instead (`--restore-cache`).
+
+use bytes::Bytes;
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DatabaseBackend, DbErr, EntityTrait, IntoActiveModel,
+    QueryFilter, Statement, TransactionTrait,
+};
+use std::time::Duration;
+
+use super::{encode_name, entity, loaded_dir, FileInfo, Pan123Client};
+use crate::error::{AppError, Result};
+
+/// Repository-relative folder holding cache snapshots.
+pub const CACHE_BACKUP_DIR: &str = ".cache-backup";
+
+/// Number of snapshots kept; older ones are deleted after each backup.
+const KEEP_SNAPSHOTS: usize = 3;
+
+/// Rows inserted per statement when restoring (stays under SQLite's bind limit).
+const RESTORE_BATCH: usize = 1000;
+
+impl Pan123Client {
+    /// Snapshot the cache database and upload it, returning the snapshot name.
+    ///
+    /// Only SQLite caches are backed up; shared databases have their own
+    /// backup tooling.
+    pub async fn backup_cache(&self) -> Result<String> {
+        if self.db.get_database_backend() != DatabaseBackend::Sqlite {
+            return Err(AppError::BadRequest(
+                "Cache backups require a SQLite cache database".to_string(),
+            ));
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("snapshot.db");
+
+        // VACUUM INTO writes a consistent, compacted copy without blocking writers
+        self.db
+            .execute(Statement::from_sql_and_values(
+                DatabaseBackend::Sqlite,
+                "VACUUM INTO ?",
+                [path.to_string_lossy().to_string().into()],
+            ))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to snapshot cache: {}", e)))?;
+
+        // Access tokens, local upload state and the audit log don't belong in the copy
+        let snapshot = crate::db::connect(&format!("sqlite:{}?mode=rw", path.display())).await?;
+        snapshot
+            .execute_unprepared(
+                "DELETE FROM token_cache; DELETE FROM upload_journal; DELETE FROM audit_log; \
+                 DELETE FROM upload_sessions; PRAGMA journal_mode=DELETE;",
+            )
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to prepare cache snapshot: {}", e)))?;
+        snapshot
+            .close()
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to close cache snapshot: {}", e)))?;
+
+        let data = Bytes::from(tokio::fs::read(&path).await?);
+        let name = format!("cache-{}.db", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+        let dir_id = self
+            .ensure_path(&self.repo_full_path(CACHE_BACKUP_DIR))
+            .await?;
+        self.upload_file(dir_id, &name, data.clone()).await?;
+        tracing::info!("Uploaded cache snapshot {} ({} bytes)", name, data.len());
+
+        let snapshots = self.cache_snapshots(dir_id).await?;
+        for old in snapshots.iter().skip(KEEP_SNAPSHOTS) {
+            if let Err(e) = self.delete_file(dir_id, old.file_id).await {
+                tracing::warn!(
+                    "Failed to delete old cache snapshot {}: {}",
+                    old.filename,
+                    e
+                );
+            }
+        }
+
+        Ok(name)
+    }
+
+    /// Seed this namespace's cache from the latest snapshot on 123pan.
+    ///
+    /// Returns the name of the restored snapshot, or `None` if there is none.
+    /// Files written after the snapshot was taken are only picked up by a
+    /// rebuild, so snapshots should be taken often relative to write activity.
+    pub async fn restore_cache(&self) -> Result<Option<String>> {
+        // The cache is empty on a new host, so resolve the folder via the API
+        let mut dir_id = 0;
+        let backup_path = self.repo_full_path(CACHE_BACKUP_DIR);
+        for part in backup_path.split('/').filter(|p| !p.is_empty()) {
+            let part = encode_name(part)?;
+            let found = self
+                .fetch_files_from_api(dir_id)
+                .await?
+                .into_iter()
+                .find(|f| self.names_match(&f.filename, &part) && f.is_folder());
+            match found {
+                Some(folder) => dir_id = folder.file_id,
+                None => return Ok(None),
+            }
+        }
+
+        let Some(latest) = self.cache_snapshots(dir_id).await?.into_iter().next() else {
+            return Ok(None);
+        };
+        tracing::info!("Restoring cache from snapshot {}", latest.filename);
+
+        let data = self.download_file(latest.file_id, None).await?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("snapshot.db");
+        tokio::fs::write(&path, &data).await?;
+
+        // Connecting migrates snapshots taken by older versions
+        let snapshot = crate::db::connect(&format!("sqlite:{}?mode=rw", path.display())).await?;
+        let nodes = entity::Entity::find()
+            .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+            .all(&snapshot)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to read cache snapshot: {}", e)))?;
+        let markers = loaded_dir::Entity::find()
+            .filter(loaded_dir::Column::Repo.eq(self.namespace.as_str()))
+            .all(&snapshot)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to read cache snapshot: {}", e)))?;
+        let _ = snapshot.close().await;
+
+        self.writes
+            .run(|| self.replace_namespace(&nodes, &markers))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to restore cache: {}", e)))?;
+        self.lookups.clear();
+        self.loaded.write().clear();
+
+        tracing::info!(
+            "Restored {} cached nodes from {}",
+            nodes.len(),
+            latest.filename
+        );
+        Ok(Some(latest.filename))
+    }
+
+    /// Replace every cached node and loaded directory of this namespace in
+    /// one transaction.
+    async fn replace_namespace(
+        &self,
+        nodes: &[entity::Model],
+        markers: &[loaded_dir::Model],
+    ) -> std::result::Result<(), DbErr> {
+        let txn = self.db.begin().await?;
+        entity::Entity::delete_many()
+            .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+            .exec(&txn)
+            .await?;
+        loaded_dir::Entity::delete_many()
+            .filter(loaded_dir::Column::Repo.eq(self.namespace.as_str()))
+            .exec(&txn)
+            .await?;
+        for batch in nodes.chunks(RESTORE_BATCH) {
+            entity::Entity::insert_many(
+                batch
+                    .iter()
+                    .cloned()
+                    .map(IntoActiveModel::into_active_model),
+            )
+            .exec(&txn)
+            .await?;
+        }
+        for batch in markers.chunks(RESTORE_BATCH) {
+            loaded_dir::Entity::insert_many(
+                batch
+                    .iter()
+                    .cloned()
+                    .map(IntoActiveModel::into_active_model),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+        }
+        txn.commit().await
+    }
+
+    /// Back up the cache every `interval` in the background.
+    pub fn spawn_cache_backup(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The first tick completes immediately; the cache was just warmed
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = client.backup_cache().await {
+                    tracing::error!("Cache backup failed: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Snapshots in the backup folder, newest first.
+    async fn cache_snapshots(&self, dir_id: i64) -> Result<Vec<FileInfo>> {
+        let mut snapshots: Vec<FileInfo> = self
+            .fetch_files_from_api(dir_id)
+            .await?
+            .into_iter()
+            .filter(|f| {
+                !f.is_folder() && f.filename.starts_with("cache-") && f.filename.ends_with(".db")
+            })
+            .collect();
+        snapshots.sort_by(|a, b| b.filename.cmp(&a.filename));
+        Ok(snapshots)
+    }
+}
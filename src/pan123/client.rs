@@ -3,51 +3,142 @@
 use bytes::Bytes;
 use parking_lot::RwLock;
 use reqwest::multipart::{Form, Part};
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::Instrument;
 
-use super::auth::{TokenManager, BASE_URL};
+use super::auth::{CredentialStats, TokenManager};
+use super::builder::Pan123ClientBuilder;
 use super::entity;
+use super::limits::ClassLimit;
+use super::loaded_dir;
+use super::lookup_cache::{LookupCache, LookupCacheStats};
+use super::metrics::{ApiMetrics, CacheMetrics, UpstreamTimings};
+use super::names::{encode_name, validate_filename, Normalization};
+use super::pending_delete;
+use super::progress::{upload_body, Progress};
+use super::rate_limit;
+use super::singleflight::SingleFlight;
+use super::throttle::Throttle;
+use super::tombstone;
 use super::types::{
-    ApiResponse, CreateDirData, CreateDirRequest, DeleteRequest, DownloadInfoData, FileInfo,
-    FileListData, MoveRequest, SingleUploadData, TrashRequest,
+    ApiResponse, CreateDirData, CreateDirRequest, DeleteRequest, DownloadInfoData, ErrorKind,
+    FileDetailData, FileInfo, FileListData, MoveRequest, SingleUploadData, TrashRequest,
 };
-use super::{MAX_RETRIES, RETRY_DELAY};
+use super::upload_domains::UploadDomains;
+use super::{MAX_DOWNLOAD_RESUMES, REVALIDATION_INTERVAL, TOMBSTONE_WINDOW};
+use crate::db::WriteQueue;
 use crate::error::{AppError, Result};
-use crate::restic::ResticFileType;
+use crate::restic::types::LAYOUT_FILE;
+use crate::restic::{RepoLayout, ResticFileType};
 
-use sea_orm::{
-    entity::*,
-    query::*,
-    sea_query::{Expr, Index},
-    *,
-};
+use sea_orm::{entity::*, query::*, sea_query::Expr, *};
+
+/// IDs per `IN (...)` query, well below the bind parameter limits of the databases.
+pub(super) const IN_CLAUSE_CHUNK: usize = 500;
+
+/// Rows per multi-row INSERT of cached nodes (9 bind parameters each).
+const INSERT_CHUNK: usize = 500;
+
+/// Directories listed from the API per cache transaction during a crawl.
+const CRAWL_BATCH_DIRS: usize = 32;
+
+/// Upload `duplicate` policies: fail if the name is taken, or replace the
+/// existing file.
+pub(super) const DUPLICATE_FAIL: i32 = 1;
+pub(super) const DUPLICATE_OVERWRITE: i32 = 2;
 
 /// Client for interacting with 123pan API.
 #[derive(Clone)]
 pub struct Pan123Client {
-    token_manager: TokenManager,
-    repo_path: String,
+    pub(crate) token_manager: TokenManager,
+    pub(crate) repo_path: String,
     /// Database connection for persistent cache
     pub(crate) db: DatabaseConnection,
-    /// Upload domain (fetched dynamically)
-    upload_domain: Arc<RwLock<Option<String>>>,
+    /// Scopes cached nodes so one database can serve several repositories
+    pub(crate) namespace: String,
+    /// Serializes cache writes and retries them on `SQLITE_BUSY`
+    pub(crate) writes: WriteQueue,
+    /// Hot `(parent_id, name)` lookups in front of the database
+    pub(crate) lookups: LookupCache,
+    /// Listings and lookups answered without listing a directory from the API
+    pub(crate) cache_metrics: CacheMetrics,
+    /// Upload domains (fetched dynamically) with failover
+    upload_domains: UploadDomains,
+    /// Set once the startup cache warm-up has completed
+    cache_ready: Arc<AtomicBool>,
+    /// Directories known to be fully cached, with the Unix time they were listed
+    /// (see `ensure_loaded`)
+    pub(crate) loaded: Arc<RwLock<HashMap<i64, i64>>>,
+    /// Age after which a directory listing is fetched again before being served
+    directory_ttl: Option<Duration>,
+    /// Coalesces concurrent listings of the same directory
+    listings: SingleFlight<i64, ()>,
+    /// Coalesces concurrent downloads of the same file and range
+    downloads: SingleFlight<(i64, Option<(u64, u64)>), Bytes>,
+    /// Size of each Range request when downloading large files in parallel
+    download_chunk_size: u64,
+    /// Maximum concurrent Range requests per download (1 disables splitting)
+    download_parallelism: usize,
+    /// Files larger than this are uploaded in slices
+    pub(crate) multipart_threshold: u64,
+    /// Unicode normalization form names are compared and created in
+    normalization: Normalization,
+    /// Longest wait for 123pan to finalize a slice upload
+    pub(super) upload_complete_timeout: Duration,
+    /// Concurrency limits per request class, shared by clones
+    pub(super) upload_limit: ClassLimit,
+    download_limit: ClassLimit,
+    api_limit: ClassLimit,
+    /// Bandwidth limits shared by all uploads and all downloads
+    pub(crate) upload_throttle: Option<Throttle>,
+    download_throttle: Option<Throttle>,
+    /// Transfers of at least this many bytes log their progress (0 disables)
+    progress_threshold: u64,
+    progress_interval: Duration,
+    /// Retries of a rate-limited or unauthorized API call
+    pub(crate) max_retries: usize,
+    retry_delay: std::time::Duration,
+    /// Entries requested per file list page
+    pub(crate) page_size: u32,
+    /// Arrangement of objects in the repository directory
+    layout: RepoLayout,
+    /// Wakes the delete batcher; set once it runs (see `spawn_delete_batching`)
+    pub(super) delete_batcher: Arc<OnceLock<Notify>>,
 }
 
 impl Pan123Client {
-    async fn retry_api<T, F, Fut>(&self, request_maker: F) -> Result<ApiResponse<T>>
+    pub(super) async fn retry_api<T, F, Fut>(&self, request_maker: F) -> Result<ApiResponse<T>>
     where
         T: serde::de::DeserializeOwned,
         F: Fn(&str) -> Fut,
         Fut: std::future::Future<Output = std::result::Result<reqwest::Response, reqwest::Error>>,
     {
-        for attempt in 0..=MAX_RETRIES {
+        for attempt in 0..=self.max_retries {
             let token = self.token_manager.get_token().await?;
-            let response = request_maker(&token).await?;
-            let text = response.text().await?;
+            let metrics = self.token_manager.metrics();
+            let start = Instant::now();
+            let response = request_maker(&token).await.inspect_err(|e| {
+                let endpoint = e.url().map_or("unknown", |u| u.path());
+                metrics.record_transport_error(endpoint);
+                UpstreamTimings::record(endpoint, start.elapsed());
+            })?;
+            let endpoint = response.url().path().to_string();
+            let headers = response.headers().clone();
+            let text = response.text().await;
+            UpstreamTimings::record(&endpoint, start.elapsed());
+            let text = text.inspect_err(|_| metrics.record_transport_error(&endpoint))?;
 
             let api_response: ApiResponse<T> = match serde_json::from_str(&text) {
                 Ok(v) => v,
                 Err(e) => {
+                    metrics.record_response(&endpoint, -1);
                     return Err(AppError::Pan123Api {
                         code: -1,
                         message: format!("Failed to parse response JSON: {}", e),
@@ -55,24 +146,33 @@ impl Pan123Client {
                 }
             };
 
+            metrics.record_response(&endpoint, api_response.code);
             if !api_response.is_success() {
                 tracing::warn!("123pan API error response: {}", text);
             }
 
             if api_response.code == 429 {
-                if attempt < MAX_RETRIES {
+                // Fail over to another credential right away if one is configured
+                if self.token_manager.report_rate_limited() && attempt < self.max_retries {
+                    metrics.record_retry(&endpoint);
+                    continue;
+                }
+                if attempt < self.max_retries {
+                    let wait = rate_limit::retry_after(&headers, &api_response.message)
+                        .unwrap_or(self.retry_delay);
                     tracing::warn!(
-                        "Rate limited (429), waiting {}s before retry (attempt {}/{})",
-                        RETRY_DELAY.as_secs(),
+                        "Rate limited (429), waiting {:?} before retry (attempt {}/{})",
+                        wait,
                         attempt + 1,
-                        MAX_RETRIES
+                        self.max_retries
                     );
-                    tokio::time::sleep(RETRY_DELAY).await;
+                    tokio::time::sleep(wait).await;
+                    metrics.record_retry(&endpoint);
                     continue;
                 }
                 tracing::error!(
                     "Rate limited (429) after {} retries, giving up",
-                    MAX_RETRIES
+                    self.max_retries
                 );
                 return Err(AppError::Pan123Api {
                     code: api_response.code,
@@ -80,18 +180,17 @@ impl Pan123Client {
                 });
             }
 
-            if api_response.code == 401 {
-                if attempt < MAX_RETRIES {
-                    tracing::warn!(
-                        "Token expired (401), refreshing token and retrying (attempt {}/{})",
-                        attempt + 1,
-                        MAX_RETRIES
-                    );
-                    if let Err(e) = self.token_manager.refresh_token().await {
-                        tracing::error!("Failed to refresh token on 401: {}", e);
-                    }
-                    continue;
+            if api_response.code == 401 && attempt < self.max_retries {
+                tracing::warn!(
+                    "Token expired (401), refreshing token and retrying (attempt {}/{})",
+                    attempt + 1,
+                    self.max_retries
+                );
+                if let Err(e) = self.token_manager.refresh_token().await {
+                    tracing::error!("Failed to refresh token on 401: {}", e);
                 }
+                metrics.record_retry(&endpoint);
+                continue;
             }
 
             return Ok(api_response);
@@ -102,95 +201,242 @@ impl Pan123Client {
         ))
     }
 
-    /// Create a new 123pan client.
+    /// Create a new 123pan client with default settings.
     pub async fn new(
         client_id: String,
         client_secret: String,
         repo_path: String,
         database_url: &str,
     ) -> Result<Self> {
-        let mut opt = ConnectOptions::new(database_url.to_owned());
-        opt.sqlx_logging_level(log::LevelFilter::Debug);
-
-        let db = Database::connect(opt)
+        Self::builder(client_id, client_secret)
+            .repo_path(repo_path)
+            .database_url(database_url)
+            .build()
             .await
-            .map_err(|e| AppError::Internal(format!("Failed to connect to database: {}", e)))?;
-
-        // Enable SQLite performance optimizations
-        db.execute(sea_orm::Statement::from_string(
-            sea_orm::DatabaseBackend::Sqlite,
-            "PRAGMA journal_mode=WAL;
-             PRAGMA synchronous=NORMAL;
-             PRAGMA cache_size=-256000; -- 256MB negative means pages
-             PRAGMA temp_store=MEMORY;
-             PRAGMA mmap_size=30000000000;",
-        ))
-        .await
-        .map_err(|e| AppError::Internal(format!("Failed to set SQLite pragmas: {}", e)))?;
+    }
+
+    /// Start configuring a client for the given credential.
+    pub fn builder(
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Pan123ClientBuilder {
+        Pan123ClientBuilder::new(client_id, client_secret)
+    }
+
+    pub(super) async fn from_builder(builder: Pan123ClientBuilder) -> Result<Self> {
+        let db = crate::db::connect_or_recreate(&builder.database_url).await?;
+
+        let token_manager = TokenManager::new(builder.client_id, builder.client_secret, db.clone())
+            .with_base_url(builder.base_url)
+            .with_extra_credentials(builder.extra_credentials)
+            .with_retries(builder.max_retries, builder.retry_delay)
+            .with_timeouts(builder.timeouts)?
+            .with_pool(builder.pool)?
+            .with_proxies(
+                builder.api_proxy.as_deref(),
+                builder.transfer_proxy.as_deref(),
+            )?;
 
         let client = Self {
-            token_manager: TokenManager::new(client_id, client_secret, db.clone()),
-            repo_path,
+            token_manager,
+            namespace: builder
+                .cache_namespace
+                .unwrap_or_else(|| builder.repo_path.clone()),
+            repo_path: builder.repo_path,
             db,
-            upload_domain: Arc::new(RwLock::new(None)),
+            writes: WriteQueue::default(),
+            lookups: LookupCache::new(builder.lookup_cache_entries),
+            cache_metrics: CacheMetrics::default(),
+            upload_domains: UploadDomains::new(builder.upload_domain_ttl),
+            cache_ready: Arc::new(AtomicBool::new(false)),
+            loaded: Arc::new(RwLock::new(HashMap::new())),
+            directory_ttl: builder.directory_ttl,
+            listings: SingleFlight::default(),
+            downloads: SingleFlight::default(),
+            download_chunk_size: builder.download_chunk_size,
+            download_parallelism: builder.download_parallelism,
+            multipart_threshold: builder.multipart_threshold,
+            normalization: builder.normalization,
+            upload_complete_timeout: builder.upload_complete_timeout,
+            upload_limit: ClassLimit::new(builder.concurrency_limits.uploads),
+            download_limit: ClassLimit::new(builder.concurrency_limits.downloads),
+            api_limit: ClassLimit::new(builder.concurrency_limits.api_calls),
+            upload_throttle: Throttle::new(builder.max_upload_rate),
+            download_throttle: Throttle::new(builder.max_download_rate),
+            progress_threshold: builder.progress_threshold,
+            progress_interval: builder.progress_interval,
+            max_retries: builder.max_retries,
+            retry_delay: builder.retry_delay,
+            page_size: builder.page_size,
+            layout: builder.layout,
+            delete_batcher: Arc::default(),
         };
 
-        client.init_db().await?;
-        client.token_manager.init_db().await?;
+        client.claim_legacy_nodes().await?;
 
         Ok(client)
     }
 
-    /// Initialize database schema.
-    async fn init_db(&self) -> Result<()> {
-        let builder = self.db.get_database_backend();
-        let schema = Schema::new(builder);
+    /// Arrangement of objects in the repository directory.
+    pub fn layout(&self) -> RepoLayout {
+        self.layout
+    }
 
-        let stmt = schema
-            .create_table_from_entity(entity::Entity)
-            .if_not_exists()
-            .to_owned();
-        self.db
-            .execute(builder.build(&stmt))
-            .await
-            .map_err(|e| AppError::Internal(format!("Failed to initialize database: {}", e)))?;
-
-        // Create indexes from entity definitions (#[sea_orm(indexed)] attributes)
-        // create_index_from_entity generates CREATE INDEX statements, but doesn't support IF NOT EXISTS,
-        // so we ignore "already exists" errors.
-        for index_stmt in schema.create_index_from_entity(entity::Entity) {
-            let sql = builder.build(&index_stmt);
-            if let Err(e) = self.db.execute(sql).await {
-                // Ignore "index already exists" errors (SQLite error code for this)
-                let err_str = e.to_string();
-                if !err_str.contains("already exists") {
-                    return Err(AppError::Internal(format!(
-                        "Failed to create index from entity: {}",
-                        e
-                    )));
-                }
-            }
+    /// Adopt the layout recorded in the repository's layout file, if it has
+    /// one, so existing repositories keep the layout they were created with.
+    /// Returns the recorded layout.
+    pub async fn load_layout(&mut self) -> Result<Option<RepoLayout>> {
+        let Some(repo_id) = self.find_path_id(&self.repo_path).await? else {
+            return Ok(None);
+        };
+        let Some(file) = self
+            .fetch_files_from_api(repo_id)
+            .await?
+            .into_iter()
+            .find(|f| f.filename == LAYOUT_FILE && !f.is_folder())
+        else {
+            return Ok(None);
+        };
+        let data = self.download_file(file.file_id, None).await?;
+        let layout: RepoLayout = serde_json::from_slice(&data)?;
+        layout.validate()?;
+        if layout != self.layout {
+            tracing::info!(
+                "Repository was created with data layout {}, using it instead of {}",
+                layout,
+                self.layout
+            );
         }
+        self.layout = layout;
+        Ok(Some(layout))
+    }
 
-        // Add composite unique index for lookup efficiency and name uniqueness
-        let index_stmt = Index::create()
-            .name("idx_parent_name")
-            .table(entity::Entity)
-            .col(entity::Column::ParentId)
-            .col(entity::Column::Name)
-            .unique()
-            .if_not_exists()
-            .to_owned();
+    /// Add credentials to fail over to when the primary one is rate limited.
+    pub fn with_extra_credentials(mut self, extra: Vec<(String, String)>) -> Self {
+        self.token_manager = self.token_manager.with_extra_credentials(extra);
+        self
+    }
 
-        self.db
-            .execute(builder.build(&index_stmt))
-            .await
-            .map_err(|e| AppError::Internal(format!("Failed to create index: {}", e)))?;
+    /// Route upstream traffic through proxies; `transfer_proxy` applies to
+    /// downloads and uploads and defaults to `api_proxy`.
+    pub fn with_proxies(
+        mut self,
+        api_proxy: Option<&str>,
+        transfer_proxy: Option<&str>,
+    ) -> Result<Self> {
+        self.token_manager = self
+            .token_manager
+            .with_proxies(api_proxy, transfer_proxy.or(api_proxy))?;
+        Ok(self)
+    }
+
+    /// Use a different 123pan API endpoint (e.g. a mock server or regional endpoint).
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.token_manager = self.token_manager.with_base_url(base_url);
+        self
+    }
+
+    /// Connection to the cache database, for tables kept alongside the file cache.
+    pub fn database(&self) -> DatabaseConnection {
+        self.db.clone()
+    }
+
+    /// Hit/miss counters of the in-memory lookup cache.
+    pub fn lookup_cache_stats(&self) -> LookupCacheStats {
+        self.lookups.stats()
+    }
+
+    /// Namespace separating this repository's rows in a shared cache database.
+    pub fn cache_namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Cached nodes belonging to this client's namespace.
+    pub(crate) fn nodes(&self) -> Select<entity::Entity> {
+        entity::Entity::find().filter(entity::Column::Repo.eq(self.namespace.as_str()))
+    }
+
+    /// Progress tracker for a transfer of `total` bytes, if it is large
+    /// enough to be logged.
+    pub(crate) fn progress(&self, verb: &'static str, name: &str, total: u64) -> Option<Progress> {
+        (self.progress_threshold > 0 && total >= self.progress_threshold)
+            .then(|| Progress::new(verb, name, total, self.progress_interval))
+    }
+
+    /// Request body uploading `data` within the bandwidth limit, logging the
+    /// progress of large uploads of `name`.
+    pub(crate) fn upload_body(&self, name: &str, data: Bytes) -> reqwest::Body {
+        let progress = self.progress("Uploading", name, data.len() as u64);
+        upload_body(data, self.upload_throttle.clone(), progress)
+    }
+
+    /// Calls, errors and transferred bytes of the 123pan API.
+    pub fn api_metrics(&self) -> &ApiMetrics {
+        self.token_manager.metrics()
+    }
+
+    /// Hits and misses of the directory cache: `listing` for listings of
+    /// type directories, `lookup` for single objects.
+    pub fn cache_metrics(&self) -> &CacheMetrics {
+        &self.cache_metrics
+    }
+
+    /// Per-credential request and rate-limit counters.
+    pub fn credential_stats(&self) -> Vec<CredentialStats> {
+        self.token_manager.stats()
+    }
 
+    /// Swap in new client IDs and secrets, see [`TokenManager::rotate_credentials`].
+    pub async fn rotate_credentials(&self, credentials: Vec<(String, String)>) -> Result<usize> {
+        self.token_manager.rotate_credentials(credentials).await
+    }
+
+    /// Download files larger than `chunk_size` as up to `parallelism`
+    /// concurrent Range requests.
+    pub fn with_parallel_download(mut self, chunk_size: u64, parallelism: usize) -> Self {
+        self.download_chunk_size = chunk_size.max(1);
+        self.download_parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Adopt entries migrated from a database that predates cache namespacing.
+    async fn claim_legacy_nodes(&self) -> Result<()> {
+        let claimed = self
+            .writes
+            .run(|| {
+                entity::Entity::update_many()
+                    .col_expr(entity::Column::Repo, Expr::value(self.namespace.as_str()))
+                    .filter(entity::Column::Repo.eq(""))
+                    .exec(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to claim cached nodes: {}", e)))?;
+        self.writes
+            .run(|| {
+                loaded_dir::Entity::update_many()
+                    .col_expr(
+                        loaded_dir::Column::Repo,
+                        Expr::value(self.namespace.as_str()),
+                    )
+                    .filter(loaded_dir::Column::Repo.eq(""))
+                    .exec(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to claim cached nodes: {}", e)))?;
+        if claimed.rows_affected > 0 {
+            tracing::info!(
+                "Assigned {} previously cached nodes to namespace '{}'",
+                claimed.rows_affected,
+                self.namespace
+            );
+        }
         Ok(())
     }
 
-    async fn get<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<ApiResponse<T>> {
+    pub(super) async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<ApiResponse<T>> {
         self.get_with_timeout::<T>(url, None).await
     }
 
@@ -207,6 +453,7 @@ impl Pan123Client {
         url: &str,
         timeout: Option<std::time::Duration>,
     ) -> Result<ApiResponse<T>> {
+        let _permit = self.api_limit.acquire().await;
         self.retry_api(|token| {
             let mut request = self
                 .token_manager
@@ -224,13 +471,14 @@ impl Pan123Client {
         .await
     }
 
-    async fn post<T: serde::de::DeserializeOwned, B: serde::Serialize>(
+    pub(super) async fn post<T: serde::de::DeserializeOwned, B: serde::Serialize>(
         &self,
         url: &str,
         body: &B,
     ) -> Result<ApiResponse<T>> {
         let body_json = serde_json::to_string(body)?;
 
+        let _permit = self.api_limit.acquire().await;
         self.retry_api(|token| {
             self.token_manager
                 .http_client()
@@ -244,24 +492,59 @@ impl Pan123Client {
         .await
     }
 
+    // ========================================================================
+    // Health
+    // ========================================================================
+
+    /// Check that the cache database is reachable.
+    pub async fn ping_db(&self) -> Result<()> {
+        self.db
+            .ping()
+            .await
+            .map_err(|e| AppError::Internal(format!("DB ping failed: {}", e)))
+    }
+
+    /// Check that a valid access token is available, refreshing if necessary.
+    pub async fn check_token(&self) -> Result<()> {
+        self.token_manager.get_token().await.map(|_| ())
+    }
+
+    /// Flush the SQLite WAL into the main database file before shutdown.
+    pub async fn flush_cache(&self) -> Result<()> {
+        if self.db.get_database_backend() != sea_orm::DatabaseBackend::Sqlite {
+            return Ok(());
+        }
+        self.db
+            .execute(sea_orm::Statement::from_string(
+                sea_orm::DatabaseBackend::Sqlite,
+                "PRAGMA wal_checkpoint(TRUNCATE);",
+            ))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to checkpoint WAL: {}", e)))?;
+        Ok(())
+    }
+
+    /// Whether the startup cache warm-up has completed.
+    pub fn is_cache_ready(&self) -> bool {
+        self.cache_ready.load(Ordering::Acquire)
+    }
+
     // ========================================================================
     // Upload Domain
     // ========================================================================
 
-    /// Get upload domain, fetching dynamically if not cached.
+    /// Get the upload domain in use, fetching the domain list if it is not
+    /// cached or due for a refresh.
     /// Includes 429 retry support.
-    async fn get_upload_domain(&self) -> Result<String> {
-        // Check cache first
-        {
-            let cache = self.upload_domain.read();
-            if let Some(domain) = cache.as_ref() {
-                return Ok(domain.clone());
-            }
+    pub(super) async fn get_upload_domain(&self) -> Result<String> {
+        if let Some(domain) = self.upload_domains.current() {
+            return Ok(domain);
         }
 
         // Fetch from API with 429 retry support
-        let url = format!("{}/upload/v2/file/domain", BASE_URL);
+        let url = format!("{}/upload/v2/file/domain", self.token_manager.base_url());
 
+        let _permit = self.api_limit.acquire().await;
         let api_response: ApiResponse<Vec<String>> = self
             .retry_api(|token| {
                 self.token_manager
@@ -283,21 +566,27 @@ impl Pan123Client {
         let domains = api_response
             .data
             .ok_or_else(|| AppError::Internal("No upload domain in response".to_string()))?;
-
         let domain = domains
-            .into_iter()
-            .next()
+            .first()
+            .cloned()
             .ok_or_else(|| AppError::Internal("Empty upload domain list".to_string()))?;
 
-        tracing::info!("Fetched upload domain: {}", domain);
+        tracing::info!("Fetched upload domains: {}", domains.join(", "));
+        self.upload_domains.set(domains);
+        Ok(domain)
+    }
 
-        // Cache the domain
-        {
-            let mut cache = self.upload_domain.write();
-            *cache = Some(domain.clone());
+    /// Record whether an upload to `domain` got through, so repeated
+    /// connection failures move uploads to the next domain and a domain
+    /// answering with something other than the API (code -1) gets the domain
+    /// list fetched again.
+    pub(super) fn report_upload<T>(&self, domain: &str, result: &Result<T>) {
+        match result {
+            Err(AppError::HttpClient(_)) => self.upload_domains.report_failure(domain),
+            Err(AppError::Pan123Api { code: -1, .. }) => self.upload_domains.invalidate(domain),
+            Err(_) => {}
+            Ok(_) => self.upload_domains.report_success(domain),
         }
-
-        Ok(domain)
     }
 
     // ========================================================================
@@ -305,9 +594,11 @@ impl Pan123Client {
     // ========================================================================
 
     /// List files in a directory.
-    /// Returns files from the persistent cache.
+    /// Returns files from the persistent cache, loading the directory on first use.
     pub async fn list_files(&self, parent_id: i64) -> Result<Vec<FileInfo>> {
-        let nodes = entity::Entity::find()
+        self.ensure_loaded(parent_id).await?;
+        let nodes = self
+            .nodes()
             .filter(entity::Column::ParentId.eq(parent_id))
             .all(&self.db)
             .await
@@ -318,15 +609,17 @@ impl Pan123Client {
 
     /// Fetch files from 123pan API (internal, bypasses cache).
     /// Uses no timeout to handle large directories with hundreds of thousands of files.
-    async fn fetch_files_from_api(&self, parent_id: i64) -> Result<Vec<FileInfo>> {
+    pub(super) async fn fetch_files_from_api(&self, parent_id: i64) -> Result<Vec<FileInfo>> {
         let mut all_files = Vec::new();
         let mut last_file_id: Option<i64> = None;
         let mut page_count = 0;
 
         loop {
             let mut url = format!(
-                "{}/api/v2/file/list?parentFileId={}&limit=100",
-                BASE_URL, parent_id
+                "{}/api/v2/file/list?parentFileId={}&limit={}",
+                self.token_manager.base_url(),
+                parent_id,
+                self.page_size
             );
 
             if let Some(id) = last_file_id {
@@ -374,16 +667,39 @@ impl Pan123Client {
         Ok(all_files)
     }
 
-    /// Find a file by exact name in a directory.
-    /// Uses directory listing instead of search (search has index delay issues).
+    /// Find a file by name in a directory, the name as stored on 123pan
+    /// (see [`encode_name`]). Unless normalization is off, a file 123pan
+    /// lists composed or decomposed is found under the other form too; an
+    /// exact match wins.
+    /// Uses the cache instead of search (search has index delay issues).
     pub async fn find_file(&self, parent_id: i64, name: &str) -> Result<Option<FileInfo>> {
-        let files = self.list_files(parent_id).await?;
-        Ok(files.into_iter().find(|f| f.filename == name))
+        // Revalidating an expired listing also drops its lookup cache entries
+        self.ensure_loaded(parent_id).await?;
+        let generation = match self.lookups.get(parent_id, name) {
+            Ok(file) => return Ok(Some(file)),
+            Err(generation) => generation,
+        };
+
+        let variants = self.normalization.variants(name);
+        let mut found = self
+            .nodes()
+            .filter(entity::Column::ParentId.eq(parent_id))
+            .filter(entity::Column::Name.is_in(variants.iter().map(|v| v.as_ref())))
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error in find_file: {}", e)))?;
+        found.sort_by_key(|node| variants.iter().position(|v| *v == node.name));
+        let file = found.into_iter().next().map(FileInfo::from);
+        if let Some(file) = &file {
+            self.lookups.fill(generation, parent_id, file.clone());
+        }
+        Ok(file)
     }
 
     /// Create a directory. Returns the directory ID.
     /// If the directory already exists, returns its ID.
     async fn create_directory(&self, parent_id: i64, name: &str) -> Result<i64> {
+        validate_filename(name)?;
         tracing::debug!("Creating directory '{}' in parent {}", name, parent_id);
 
         let request = CreateDirRequest {
@@ -391,8 +707,8 @@ impl Pan123Client {
             parent_id,
         };
 
-        // mkdir uses BASE_URL, not upload domain
-        let url = format!("{}/upload/v1/file/mkdir", BASE_URL);
+        // mkdir uses the API base URL, not upload domain
+        let url = format!("{}/upload/v1/file/mkdir", self.token_manager.base_url());
 
         let response: ApiResponse<CreateDirData> = self.post(&url, &request).await?;
 
@@ -402,87 +718,19 @@ impl Pan123Client {
                 response.code,
                 response.message
             );
-            // Code 1: directory already exists (message: 该目录下已经有同名文件夹,无法进行创建)
-            if response.code == 1 {
+            // The cache missed a directory created elsewhere; list it again
+            if response.is_name_conflict() {
+                self.reconcile_dir(parent_id).await?;
                 if let Some(existing) = self.find_file(parent_id, name).await? {
                     if existing.is_folder() {
-                        let cached = entity::Entity::find()
-                            .filter(entity::Column::ParentId.eq(parent_id))
-                            .filter(entity::Column::Name.eq(name.to_string()))
-                            .filter(entity::Column::IsDir.eq(true))
-                            .one(&self.db)
-                            .await
-                            .map_err(|e| {
-                                AppError::Internal(format!(
-                                    "DB error checking existing directory: {}",
-                                    e
-                                ))
-                            })?;
-
-                        if cached.is_none() {
-                            let existing_dir = entity::ActiveModel {
-                                file_id: Set(existing.file_id),
-                                parent_id: Set(parent_id),
-                                name: Set(name.to_string()),
-                                is_dir: Set(true),
-                                size: Set(existing.size),
-                                etag: Set(None),
-                                updated_at: Set(chrono::Utc::now().naive_utc()),
-                            };
-                            existing_dir.insert(&self.db).await.map_err(|e| {
-                                AppError::Internal(format!(
-                                    "Failed to insert existing directory into DB: {}",
-                                    e
-                                ))
-                            })?;
-                        }
-
                         tracing::info!(
-                            "Directory '{}' already exists with id {}",
+                            "Directory '{}' already exists with id {} (refreshed)",
                             name,
                             existing.file_id
                         );
                         return Ok(existing.file_id);
                     }
                 }
-                // Cache may be stale; refresh this directory from API and retry.
-                let files = self.fetch_files_from_api(parent_id).await?;
-                for f in &files {
-                    entity::Entity::insert(entity::ActiveModel {
-                        file_id: Set(f.file_id),
-                        parent_id: Set(parent_id),
-                        name: Set(f.filename.clone()),
-                        is_dir: Set(f.is_folder()),
-                        size: Set(f.size),
-                        etag: Set(None),
-                        updated_at: Set(chrono::Utc::now().naive_utc()),
-                    })
-                    .on_conflict(
-                        sea_orm::sea_query::OnConflict::column(entity::Column::FileId)
-                            .do_nothing()
-                            .to_owned(),
-                    )
-                    .exec(&self.db)
-                    .await
-                    .map_err(|e| {
-                        AppError::Internal(format!(
-                            "Failed to refresh directory cache in mkdir fallback: {}",
-                            e
-                        ))
-                    })?;
-                }
-
-                if let Some(existing) = files
-                    .into_iter()
-                    .find(|f| f.filename == name && f.is_folder())
-                {
-                    tracing::info!(
-                        "Directory '{}' already exists with id {} (refreshed)",
-                        name,
-                        existing.file_id
-                    );
-                    return Ok(existing.file_id);
-                }
             }
             return Err(AppError::Pan123Api {
                 code: response.code,
@@ -494,8 +742,11 @@ impl Pan123Client {
             .data
             .ok_or_else(|| AppError::Internal("No data in mkdir response".to_string()))?;
 
+        self.reconcile_moved(data.dir_id, parent_id, name).await?;
+
         // Add newly created directory to DB
         let new_dir = entity::ActiveModel {
+            repo: Set(self.namespace.clone()),
             file_id: Set(data.dir_id),
             parent_id: Set(parent_id),
             name: Set(name.to_string()),
@@ -503,10 +754,18 @@ impl Pan123Client {
             size: Set(0),
             etag: Set(None),
             updated_at: Set(chrono::Utc::now().naive_utc()),
+            modified_at: Set(Some(chrono::Utc::now().naive_utc())),
         };
-        new_dir.insert(&self.db).await.map_err(|e| {
-            AppError::Internal(format!("Failed to insert new directory into DB: {}", e))
-        })?;
+        self.writes
+            .run(|| entity::Entity::insert(new_dir.clone()).exec(&self.db))
+            .await
+            .map_err(|e| {
+                AppError::Internal(format!("Failed to insert new directory into DB: {}", e))
+            })?;
+        if let Ok(node) = new_dir.try_into_model() {
+            self.lookups.put(parent_id, FileInfo::from(node));
+        }
+        self.mark_loaded(data.dir_id).await?;
 
         tracing::info!("Created directory '{}' with id {}", name, data.dir_id);
         Ok(data.dir_id)
@@ -515,23 +774,15 @@ impl Pan123Client {
     /// Find directory ID for a path by traversing from root.
     /// Uses SQLite queries.
     pub async fn find_path_id(&self, path: &str) -> Result<Option<i64>> {
-        let parts: Vec<&str> = path
-            .trim_start_matches('/')
-            .trim_end_matches('/')
-            .split('/')
-            .filter(|s| !s.is_empty())
-            .collect();
+        let parts = path_names(path)?;
 
         let mut current_id: i64 = 0; // Root directory
 
         for part in parts {
-            let node = entity::Entity::find()
-                .filter(entity::Column::ParentId.eq(current_id))
-                .filter(entity::Column::Name.eq(part.to_string()))
-                .filter(entity::Column::IsDir.eq(true))
-                .one(&self.db)
-                .await
-                .map_err(|e| AppError::Internal(format!("DB error in find_path_id: {}", e)))?;
+            let node = self
+                .find_file(current_id, &part)
+                .await?
+                .filter(FileInfo::is_folder);
 
             if let Some(node) = node {
                 current_id = node.file_id;
@@ -551,24 +802,14 @@ impl Pan123Client {
         }
 
         // Path doesn't exist, create it using mkdir API
-        let parts: Vec<&str> = path
-            .trim_start_matches('/')
-            .trim_end_matches('/')
-            .split('/')
-            .filter(|s| !s.is_empty())
-            .collect();
-
         let mut current_id: i64 = 0; // Root directory
 
-        for part in parts {
+        for part in path.split('/').filter(|s| !s.is_empty()) {
             // Check if this segment already exists
-            let node = entity::Entity::find()
-                .filter(entity::Column::ParentId.eq(current_id))
-                .filter(entity::Column::Name.eq(part.to_string()))
-                .filter(entity::Column::IsDir.eq(true))
-                .one(&self.db)
-                .await
-                .map_err(|e| AppError::Internal(format!("DB error in ensure_path: {}", e)))?;
+            let node = self
+                .find_file(current_id, &encode_name(part)?)
+                .await?
+                .filter(FileInfo::is_folder);
 
             if let Some(node) = node {
                 current_id = node.file_id;
@@ -576,25 +817,23 @@ impl Pan123Client {
             }
 
             // Create the directory
-            current_id = self.create_directory(current_id, part).await?;
+            current_id = self
+                .create_directory(current_id, &self.new_name(part)?)
+                .await?;
         }
 
         Ok(current_id)
     }
 
-    /// Extract the 2-character prefix from a filename for data subdirectory.
-    /// Data files in restic are named by their hash, so the first 2 characters
-    /// are used to create subdirectories for better listing performance.
-    fn data_subdir_prefix(filename: &str) -> &str {
-        // Data files are always hex hashes with at least 2 characters
-        &filename[..2.min(filename.len())]
-    }
-
-    /// Get the directory ID for a data file, creating the 2-char subdirectory if needed.
-    /// Data files are stored in `{repo_path}/data/{prefix}/` where prefix is the first 2 chars.
+    /// Get the directory ID for a data file, creating its shard subdirectory if needed.
+    /// Data files are stored in `{repo_path}/data/{shard}/`, where shard is one
+    /// to three levels of `data_shard_len`-character pieces of the name
+    /// (restic names packs by hash), e.g. `data/ab/` or `data/ab/cd/`.
     pub async fn get_data_file_dir_id(&self, filename: &str) -> Result<i64> {
-        let prefix = Self::data_subdir_prefix(filename);
-        let path = format!("{}/data/{}", self.repo_path, prefix);
+        let path = match self.layout.data_shard(filename) {
+            Some(prefix) => format!("{}/data/{}", self.repo_path, prefix),
+            None => format!("{}/data", self.repo_path),
+        };
         self.ensure_path(&path).await
     }
 
@@ -618,6 +857,44 @@ impl Pan123Client {
     /// Updates the persistent cache.
     /// Includes 429 retry support.
     pub async fn upload_file(&self, parent_id: i64, filename: &str, data: Bytes) -> Result<i64> {
+        self.upload(parent_id, filename, data, DUPLICATE_OVERWRITE)
+            .await
+    }
+
+    /// Like [`Self::upload_file`], but with duplicate=1: fails with
+    /// [`AppError::Forbidden`] instead of replacing an existing file.
+    pub async fn upload_new_file(
+        &self,
+        parent_id: i64,
+        filename: &str,
+        data: Bytes,
+    ) -> Result<i64> {
+        self.upload(parent_id, filename, data, DUPLICATE_FAIL).await
+    }
+
+    async fn upload(
+        &self,
+        parent_id: i64,
+        filename: &str,
+        data: Bytes,
+        duplicate: i32,
+    ) -> Result<i64> {
+        let stored = encode_name(filename)?;
+        // The node is upserted below; the rest of the directory must be cached too
+        self.ensure_loaded(parent_id).await?;
+        // Replace a file 123pan lists in another normalization form rather
+        // than adding a second one
+        let existing = if self.normalization.variants(&stored).len() > 1 {
+            self.find_file(parent_id, &stored)
+                .await?
+                .filter(|file| !file.is_folder())
+        } else {
+            None
+        };
+        let filename = &match existing {
+            Some(file) => file.filename,
+            None => self.new_name(filename)?,
+        };
         let file_size = data.len() as i64;
         tracing::debug!(
             "Uploading file '{}' ({} bytes) to parent {}",
@@ -629,36 +906,115 @@ impl Pan123Client {
         // Calculate MD5 hash
         let md5_hash = format!("{:x}", md5::compute(&data));
 
+        let file_id = if file_size as u64 > self.multipart_threshold {
+            self.upload_sliced(parent_id, filename, &md5_hash, &data, duplicate)
+                .await?
+        } else {
+            self.upload_single(parent_id, filename, &md5_hash, data, duplicate)
+                .await?
+        };
+        self.reconcile_moved(file_id, parent_id, filename).await?;
+
+        // Sync with DB (insert or replace by parent/name)
+        let model = entity::ActiveModel {
+            repo: Set(self.namespace.clone()),
+            file_id: Set(file_id),
+            parent_id: Set(parent_id),
+            name: Set(filename.to_string()),
+            is_dir: Set(false),
+            size: Set(file_size),
+            etag: Set(Some(md5_hash.clone())),
+            updated_at: Set(chrono::Utc::now().naive_utc()),
+            modified_at: Set(Some(chrono::Utc::now().naive_utc())),
+        };
+        self.writes
+            .run(|| {
+                entity::Entity::insert(model.clone())
+                    .on_conflict(
+                        sea_orm::sea_query::OnConflict::columns([
+                            entity::Column::Repo,
+                            entity::Column::ParentId,
+                            entity::Column::Name,
+                        ])
+                        .update_columns([
+                            entity::Column::FileId,
+                            entity::Column::ParentId,
+                            entity::Column::Name,
+                            entity::Column::Size,
+                            entity::Column::Etag,
+                            entity::Column::UpdatedAt,
+                            entity::Column::ModifiedAt,
+                        ])
+                        .to_owned(),
+                    )
+                    .exec(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to sync file to DB: {}", e)))?;
+        if let Ok(node) = model.try_into_model() {
+            self.lookups.put(parent_id, FileInfo::from(node));
+        }
+
+        tracing::info!("Uploaded file '{}' with id {}", filename, file_id);
+        Ok(file_id)
+    }
+
+    /// Upload a file in one request. Returns the file ID.
+    async fn upload_single(
+        &self,
+        parent_id: i64,
+        filename: &str,
+        md5_hash: &str,
+        data: Bytes,
+        duplicate: i32,
+    ) -> Result<i64> {
+        let file_size = data.len() as i64;
         let upload_domain = self.get_upload_domain().await?;
         let upload_url = format!("{}/upload/v2/file/single/create", upload_domain);
 
-        // Store data as Vec<u8> for reuse in retries
-        let data_vec = data.to_vec();
-
-        let api_response: ApiResponse<SingleUploadData> = self
+        let permit = self.upload_limit.acquire().await;
+        let result: Result<ApiResponse<SingleUploadData>> = self
             .retry_api(|token| {
                 let form = Form::new()
                     .text("parentFileID", parent_id.to_string())
                     .text("filename", filename.to_string())
-                    .text("etag", md5_hash.clone())
+                    .text("etag", md5_hash.to_string())
                     .text("size", file_size.to_string())
-                    .text("duplicate", "2")
+                    .text("duplicate", duplicate.to_string())
                     .part(
                         "file",
-                        Part::bytes(data_vec.clone()).file_name(filename.to_string()),
+                        // Cloning `Bytes` shares the buffer, so retries don't copy it
+                        Part::stream_with_length(
+                            self.upload_body(filename, data.clone()),
+                            file_size as u64,
+                        )
+                        .file_name(filename.to_string()),
                     );
 
                 self.token_manager
-                    .http_client()
+                    .transfer_client()
                     .post(&upload_url)
+                    .timeout(self.token_manager.timeouts().upload_for(file_size as u64))
                     .header("Authorization", format!("Bearer {}", token))
                     .header("Platform", "open_platform")
                     .multipart(form)
                     .send()
             })
-            .await?;
+            .await;
+        drop(permit);
+        self.report_upload(&upload_domain, &result);
+        let api_response = result?;
 
         if !api_response.is_success() {
+            if api_response.is_name_conflict() {
+                self.reconcile_dir(parent_id).await?;
+                if duplicate == DUPLICATE_FAIL {
+                    return Err(AppError::Forbidden(format!(
+                        "'{}' already exists",
+                        filename
+                    )));
+                }
+            }
             return Err(AppError::Pan123Api {
                 code: api_response.code,
                 message: api_response.message,
@@ -673,44 +1029,37 @@ impl Pan123Client {
             return Err(AppError::Internal("Upload not completed".to_string()));
         }
 
-        let file_id = upload_data.file_id;
-
-        // Sync with DB (insert or replace by parent/name)
-        entity::Entity::insert(entity::ActiveModel {
-            file_id: Set(file_id),
-            parent_id: Set(parent_id),
-            name: Set(filename.to_string()),
-            is_dir: Set(false),
-            size: Set(file_size),
-            etag: Set(Some(md5_hash.clone())),
-            updated_at: Set(chrono::Utc::now().naive_utc()),
-        })
-        .on_conflict(
-            sea_orm::sea_query::OnConflict::columns([
-                entity::Column::ParentId,
-                entity::Column::Name,
-            ])
-            .update_columns([
-                entity::Column::FileId,
-                entity::Column::ParentId,
-                entity::Column::Name,
-                entity::Column::Size,
-                entity::Column::Etag,
-                entity::Column::UpdatedAt,
-            ])
-            .to_owned(),
-        )
-        .exec(&self.db)
-        .await
-        .map_err(|e| AppError::Internal(format!("Failed to sync file to DB: {}", e)))?;
+        self.token_manager.metrics().add_uploaded(file_size as u64);
+        Ok(upload_data.file_id)
+    }
 
-        tracing::info!("Uploaded file '{}' with id {}", filename, file_id);
-        return Ok(file_id);
+    /// Details of a file as 123pan has them, or `None` if it doesn't exist.
+    pub async fn file_detail(&self, file_id: i64) -> Result<Option<FileDetailData>> {
+        let url = format!(
+            "{}/api/v1/file/detail?fileID={}",
+            self.token_manager.base_url(),
+            file_id
+        );
+        let response: ApiResponse<FileDetailData> = self.get(&url).await?;
+        if !response.is_success() {
+            if ErrorKind::of(response.code, &response.message) == ErrorKind::NotFound {
+                return Ok(None);
+            }
+            return Err(AppError::Pan123Api {
+                code: response.code,
+                message: response.message,
+            });
+        }
+        Ok(response.data)
     }
 
     /// Get download URL for a file.
     pub async fn get_download_url(&self, file_id: i64) -> Result<String> {
-        let url = format!("{}/api/v1/file/download_info?fileId={}", BASE_URL, file_id);
+        let url = format!(
+            "{}/api/v1/file/download_info?fileId={}",
+            self.token_manager.base_url(),
+            file_id
+        );
         let response: ApiResponse<DownloadInfoData> = self.get(&url).await?;
 
         if !response.is_success() {
@@ -731,18 +1080,166 @@ impl Pan123Client {
     }
 
     /// Download a file's content with optional range support.
-    /// Uses 123pan's native range download capability.
+    /// Concurrent requests for the same file and range share one download.
     pub async fn download_file(&self, file_id: i64, range: Option<(u64, u64)>) -> Result<Bytes> {
+        self.downloads
+            .run((file_id, range), || self.fetch_file(file_id, range))
+            .await
+    }
+
+    /// Fetch a file's content using 123pan's native range download capability.
+    /// Large transfers are split into chunks fetched concurrently.
+    async fn fetch_file(&self, file_id: i64, range: Option<(u64, u64)>) -> Result<Bytes> {
         let download_url = self.get_download_url(file_id).await?;
 
-        let mut request = self.token_manager.http_client().get(&download_url);
+        let span = match range {
+            Some(range) => Some(range),
+            None => self.cached_size(file_id).await?.map(|size| (0, size - 1)),
+        };
+        let progress = span.and_then(|(start, end)| {
+            self.progress("Downloading", &format!("file {}", file_id), end - start + 1)
+        });
+        let Some((start, end)) = span.filter(|(start, end)| {
+            self.download_parallelism > 1 && end - start + 1 > self.download_chunk_size
+        }) else {
+            return self
+                .fetch_url(&download_url, range, progress.as_ref())
+                .await;
+        };
+
+        let chunks = chunk_ranges(start, end, self.download_chunk_size);
+        tracing::debug!(
+            "Downloading file {} bytes {}-{} in {} chunks",
+            file_id,
+            start,
+            end,
+            chunks.len()
+        );
+
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(self.download_parallelism));
+        let mut tasks = tokio::task::JoinSet::new();
+        for (index, chunk) in chunks.iter().copied().enumerate() {
+            let client = self.clone();
+            let url = download_url.clone();
+            let semaphore = semaphore.clone();
+            let progress = progress.clone();
+            tasks.spawn(
+                UpstreamTimings::propagate(async move {
+                    let _permit = semaphore.acquire_owned().await;
+                    let data = client
+                        .fetch_url(&url, Some(chunk), progress.as_ref())
+                        .await?;
+                    if data.len() as u64 != chunk.1 - chunk.0 + 1 {
+                        return Err(AppError::Internal(format!(
+                            "Chunk {}-{} returned {} bytes",
+                            chunk.0,
+                            chunk.1,
+                            data.len()
+                        )));
+                    }
+                    Ok((index, data))
+                })
+                .in_current_span(),
+            );
+        }
+
+        let mut parts = vec![Bytes::new(); chunks.len()];
+        while let Some(joined) = tasks.join_next().await {
+            let (index, data) = joined
+                .map_err(|e| AppError::Internal(format!("Download task failed: {}", e)))??;
+            parts[index] = data;
+        }
+
+        let mut data = bytes::BytesMut::with_capacity((end - start + 1) as usize);
+        for part in parts {
+            data.extend_from_slice(&part);
+        }
+        Ok(data.freeze())
+    }
+
+    /// GET a presigned download URL, optionally restricted to a byte range.
+    /// A body that ends early is resumed with a Range request from the break
+    /// point, up to `MAX_DOWNLOAD_RESUMES` times.
+    async fn fetch_url(
+        &self,
+        url: &str,
+        range: Option<(u64, u64)>,
+        progress: Option<&Progress>,
+    ) -> Result<Bytes> {
+        let offset = range.map_or(0, |(start, _)| start);
+        let mut expected = range.map(|(start, end)| end - start + 1);
+        let mut data = bytes::BytesMut::new();
+        let mut resumes = 0;
+
+        loop {
+            let received = data.len() as u64;
+            let request_range = match expected {
+                Some(len) if received > 0 => Some((offset + received, offset + len - 1)),
+                _ => range,
+            };
+
+            let result = self
+                .read_body(
+                    url,
+                    request_range,
+                    received > 0,
+                    &mut data,
+                    &mut expected,
+                    progress,
+                )
+                .await;
+            let received = data.len() as u64;
+            let error = match (result, expected) {
+                (Ok(()), Some(len)) if received >= len => return Ok(data.freeze()),
+                (Ok(()), None) => return Ok(data.freeze()),
+                (Ok(()), Some(len)) => AppError::Internal(format!(
+                    "Download ended after {} of {} bytes",
+                    received, len
+                )),
+                (Err(e), _) => e,
+            };
+
+            // Resuming needs a known length and some progress to build on
+            if expected.is_none() || received == 0 || resumes >= MAX_DOWNLOAD_RESUMES {
+                return Err(error);
+            }
+            resumes += 1;
+            tracing::warn!(
+                "Download interrupted after {} bytes ({}), resuming (attempt {}/{})",
+                received,
+                error,
+                resumes,
+                MAX_DOWNLOAD_RESUMES
+            );
+            tokio::time::sleep(self.retry_delay).await;
+        }
+    }
+
+    /// Append the response body for `range` to `data`. Learns the expected
+    /// length from Content-Length when it is not known yet.
+    async fn read_body(
+        &self,
+        url: &str,
+        range: Option<(u64, u64)>,
+        resuming: bool,
+        data: &mut bytes::BytesMut,
+        expected: &mut Option<u64>,
+        progress: Option<&Progress>,
+    ) -> Result<()> {
+        let _permit = self.download_limit.acquire().await;
+        let _call = UpstreamTimings::start("download");
+        let mut request = self
+            .token_manager
+            .transfer_client()
+            .get(url)
+            .timeout(self.token_manager.timeouts().download);
 
         // Pass Range header to 123pan for native range support
         if let Some((start, end)) = range {
             request = request.header("Range", format!("bytes={}-{}", start, end));
         }
 
-        let response = request.send().await?;
+        let mut response = request.send().await?;
 
         if !response.status().is_success() && response.status().as_u16() != 206 {
             return Err(AppError::Internal(format!(
@@ -750,8 +1247,39 @@ impl Pan123Client {
                 response.status()
             )));
         }
+        if resuming && response.status().as_u16() != 206 {
+            return Err(AppError::Internal(
+                "Server ignored Range request while resuming download".to_string(),
+            ));
+        }
+        if expected.is_none() {
+            *expected = response.content_length();
+        }
+
+        while let Some(chunk) = response.chunk().await? {
+            if let Some(throttle) = &self.download_throttle {
+                throttle.consume(chunk.len()).await;
+            }
+            self.token_manager
+                .metrics()
+                .add_downloaded(chunk.len() as u64);
+            if let Some(progress) = progress {
+                progress.advance(chunk.len());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(())
+    }
 
-        Ok(response.bytes().await?)
+    /// Size of a non-empty file as recorded in the cache.
+    async fn cached_size(&self, file_id: i64) -> Result<Option<u64>> {
+        let model = entity::Entity::find_by_id((self.namespace.clone(), file_id))
+            .one(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to query file size: {}", e)))?;
+        Ok(model
+            .filter(|m| !m.is_dir && m.size > 0)
+            .map(|m| m.size as u64))
     }
 
     pub async fn trash_file(&self, file_id: i64) -> Result<()> {
@@ -762,7 +1290,10 @@ impl Pan123Client {
         };
 
         let response: ApiResponse<()> = self
-            .post(&format!("{}/api/v1/file/trash", BASE_URL), &request)
+            .post(
+                &format!("{}/api/v1/file/trash", self.token_manager.base_url()),
+                &request,
+            )
             .await?;
 
         if !response.is_success() {
@@ -773,22 +1304,91 @@ impl Pan123Client {
         }
 
         // Sync with DB: remove trashed file
-        entity::Entity::delete_by_id(file_id)
-            .exec(&self.db)
+        self.writes
+            .run(|| self.remove_node(file_id, false))
             .await
             .map_err(|e| {
                 AppError::Internal(format!("Failed to delete trashed file from DB: {}", e))
             })?;
+        self.lookups.remove_ids(&[file_id]);
 
         Ok(())
     }
 
+    /// Remove a node and leave a tombstone in one transaction, dropping
+    /// tombstones older than [`TOMBSTONE_WINDOW`]. With `queue`, its removal
+    /// from 123pan is recorded as pending in the same transaction.
+    pub(super) async fn remove_node(
+        &self,
+        file_id: i64,
+        queue: bool,
+    ) -> std::result::Result<(), DbErr> {
+        let now = chrono::Utc::now().timestamp();
+        let txn = self.db.begin().await?;
+        entity::Entity::delete_by_id((self.namespace.clone(), file_id))
+            .exec(&txn)
+            .await?;
+        if queue {
+            pending_delete::Entity::insert(pending_delete::ActiveModel {
+                repo: Set(self.namespace.clone()),
+                file_id: Set(file_id),
+                queued_at: Set(now),
+            })
+            .on_conflict(
+                sea_orm::sea_query::OnConflict::columns([
+                    pending_delete::Column::Repo,
+                    pending_delete::Column::FileId,
+                ])
+                .do_nothing()
+                .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+        }
+        tombstone::Entity::insert(tombstone::ActiveModel {
+            repo: Set(self.namespace.clone()),
+            file_id: Set(file_id),
+            deleted_at: Set(now),
+        })
+        .on_conflict(
+            sea_orm::sea_query::OnConflict::columns([
+                tombstone::Column::Repo,
+                tombstone::Column::FileId,
+            ])
+            .update_column(tombstone::Column::DeletedAt)
+            .to_owned(),
+        )
+        .exec_without_returning(&txn)
+        .await?;
+        tombstone::Entity::delete_many()
+            .filter(tombstone::Column::Repo.eq(self.namespace.as_str()))
+            .filter(tombstone::Column::DeletedAt.lt(now - TOMBSTONE_WINDOW.as_secs() as i64))
+            .exec(&txn)
+            .await?;
+        txn.commit().await
+    }
+
+    /// IDs of files deleted within [`TOMBSTONE_WINDOW`] or still queued for
+    /// deletion.
+    async fn recent_tombstones(&self) -> Result<HashSet<i64>> {
+        let cutoff = chrono::Utc::now().timestamp() - TOMBSTONE_WINDOW.as_secs() as i64;
+        let tombstones = tombstone::Entity::find()
+            .filter(tombstone::Column::Repo.eq(self.namespace.as_str()))
+            .filter(tombstone::Column::DeletedAt.gte(cutoff))
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error reading tombstones: {}", e)))?;
+        let mut ids: HashSet<i64> = tombstones.into_iter().map(|t| t.file_id).collect();
+        ids.extend(self.pending_delete_ids().await?);
+        Ok(ids)
+    }
+
     /// Delete a file.
     pub async fn delete_file(&self, _parent_id: i64, file_id: i64) -> Result<()> {
         // First move to trash (required by 123pan for permanent deletion)
         self.trash_file(file_id).await?;
 
-        let url = format!("{}/api/v1/file/delete", BASE_URL);
+        let url = format!("{}/api/v1/file/delete", self.token_manager.base_url());
         let request = DeleteRequest {
             file_ids: vec![file_id],
         };
@@ -821,10 +1421,16 @@ impl Pan123Client {
         };
 
         let response: ApiResponse<()> = self
-            .post(&format!("{}/api/v1/file/move", BASE_URL), &request)
+            .post(
+                &format!("{}/api/v1/file/move", self.token_manager.base_url()),
+                &request,
+            )
             .await?;
 
         if !response.is_success() {
+            if response.is_name_conflict() {
+                self.reconcile_dir(to_parent_id).await?;
+            }
             return Err(AppError::Pan123Api {
                 code: response.code,
                 message: response.message,
@@ -832,22 +1438,39 @@ impl Pan123Client {
         }
 
         // Sync with DB: update parent_id for all moved files
-        entity::Entity::update_many()
-            .col_expr(entity::Column::ParentId, Expr::value(to_parent_id))
-            .filter(entity::Column::FileId.is_in(file_ids.clone()))
-            .exec(&self.db)
+        self.writes
+            .run(|| {
+                entity::Entity::update_many()
+                    .col_expr(entity::Column::ParentId, Expr::value(to_parent_id))
+                    .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+                    .filter(entity::Column::FileId.is_in(file_ids.clone()))
+                    .exec(&self.db)
+            })
             .await
             .map_err(|e| {
                 AppError::Internal(format!("Failed to update moved files in DB: {}", e))
             })?;
+        self.lookups.remove_ids(&file_ids);
 
         tracing::info!("Moved {} files to parent {}", file_ids.len(), to_parent_id);
         Ok(())
     }
 
+    /// Name a new file or folder `name` is created under: normalized, then
+    /// encoded (see [`encode_name`]).
+    fn new_name(&self, name: &str) -> Result<String> {
+        encode_name(&self.normalization.apply(name)).map(Cow::into_owned)
+    }
+
+    /// Whether a name 123pan lists stands for the stored name `name` (see
+    /// [`encode_name`]), as is or in the normalization form.
+    pub(crate) fn names_match(&self, listed: &str, name: &str) -> bool {
+        self.normalization.matches(listed, name)
+    }
+
     /// Check if a file exists and get its info using precise search.
     pub async fn get_file_info(&self, parent_id: i64, filename: &str) -> Result<Option<FileInfo>> {
-        self.find_file(parent_id, filename).await
+        self.find_file(parent_id, &encode_name(filename)?).await
     }
 
     /// Initialize the repository structure.
@@ -869,14 +1492,31 @@ impl Pan123Client {
             self.ensure_path(&path).await?;
         }
 
+        let repo_id = self.ensure_path(&self.repo_path.clone()).await?;
+        if self.find_file(repo_id, LAYOUT_FILE).await?.is_none() {
+            let data = Bytes::from(serde_json::to_vec(&self.layout)?);
+            self.upload_file(repo_id, LAYOUT_FILE, data).await?;
+        }
+
         tracing::info!("Repository initialized successfully");
         Ok(())
     }
 
-    /// Warm up the cache by pre-fetching all directory IDs and file listings.
-    /// This should be called during startup before the server starts accepting requests.
-    /// Resumes from where it left off if interrupted.
+    /// Resolve the repository and cache its type directories, then mark the
+    /// cache ready. Until then the backend only serves directories already cached.
+    /// Data shards and anything below are loaded on first use (see [`Self::ensure_loaded`]);
+    /// with `force_rebuild` every directory is listed again when next used.
     pub async fn warm_cache(&self, force_rebuild: bool) -> Result<()> {
+        self.warm_up(force_rebuild, false).await
+    }
+
+    /// Like [`Self::warm_cache`], but crawl the whole repository before
+    /// marking the cache ready.
+    pub async fn warm_cache_fully(&self, force_rebuild: bool) -> Result<()> {
+        self.warm_up(force_rebuild, true).await
+    }
+
+    async fn warm_up(&self, force_rebuild: bool, crawl: bool) -> Result<()> {
         let start = std::time::Instant::now();
 
         tracing::info!(
@@ -884,147 +1524,375 @@ impl Pan123Client {
             if force_rebuild {
                 "Rebuilding"
             } else {
-                "Warming up (resumable)"
+                "Warming up"
             },
             self.repo_path
         );
+        if force_rebuild {
+            self.forget_loaded().await?;
+        }
 
-        // 1. Resolve repo_path root
-        let parts: Vec<&str> = self
-            .repo_path
-            .trim_start_matches('/')
-            .trim_end_matches('/')
-            .split('/')
-            .filter(|s| !s.is_empty())
-            .collect();
+        // 1. Resolve repo_path root (lists each ancestor not yet cached)
+        let Some(repo_id) = self.find_path_id(&self.repo_path).await? else {
+            tracing::warn!(
+                "Repository path {} not found during warm-up. Repository might not exist yet.",
+                self.repo_path
+            );
+            self.cache_ready.store(true, Ordering::Release);
+            return Ok(());
+        };
 
-        let mut current_id: i64 = 0;
-        for part in parts {
-            // We use fetch_or_use_cache here to avoid API calls if the path is already cached
-            let (files, cached) = self.fetch_or_use_cache(current_id, force_rebuild).await?;
-            
-            let found = files
-                .into_iter()
-                .find(|f| f.filename == part && f.is_folder());
-
-            match found {
-                Some(found) => {
-                    if !cached {
-                         tracing::debug!("Found path component '{}' via API", part);
-                    }
-                    current_id = found.file_id;
-                }
-                None => {
-                    tracing::warn!(
-                        "Path component {} not found during warm-up. Repository might not exist yet.",
-                        part
-                    );
-                    return Ok(());
-                }
+        // 2. List the type directories; data shards are loaded lazily
+        for file_type in [
+            ResticFileType::Data,
+            ResticFileType::Keys,
+            ResticFileType::Locks,
+            ResticFileType::Snapshots,
+            ResticFileType::Index,
+        ] {
+            if let Some(dir) = self
+                .find_file(repo_id, file_type.dirname())
+                .await?
+                .filter(FileInfo::is_folder)
+            {
+                self.ensure_loaded(dir.file_id).await?;
             }
         }
+        if crawl {
+            self.crawl_cache().await?;
+        }
 
-        // 2. Recursively crawl everything under repo_path
-        let mut queue = vec![(current_id, self.repo_path.clone())];
-        let mut fetched_count = 0;
-        let mut cached_count = 0;
-
-        while let Some((parent_id, path)) = queue.pop() {
-            let (files, cached) = self.fetch_or_use_cache(parent_id, force_rebuild).await?;
-            
-            if cached {
-                cached_count += 1;
-                tracing::debug!("Cache hit for directory: {}", path);
-            } else {
-                fetched_count += 1;
-                tracing::info!("Fetched directory: {} ({} files)", path, files.len());
-            }
+        tracing::info!("Cache warm-up completed in {:?}", start.elapsed());
+        self.cache_ready.store(true, Ordering::Release);
+        Ok(())
+    }
 
-            for f in files {
-                if f.is_folder() {
-                    queue.push((f.file_id, format!("{}/{}", path, f.filename)));
+    /// Load every directory under the repository into the cache.
+    /// Resumes from where it left off if interrupted.
+    pub async fn crawl_cache(&self) -> Result<()> {
+        let start = std::time::Instant::now();
+        let Some(repo_id) = self.find_path_id(&self.repo_path).await? else {
+            return Ok(());
+        };
+
+        let mut queue = vec![repo_id];
+        let mut dir_count = 0;
+        while !queue.is_empty() {
+            // Directories cached already are walked from the database; the
+            // others are listed and saved together in one transaction
+            let mut fetched = Vec::new();
+            while fetched.len() < CRAWL_BATCH_DIRS {
+                let Some(dir_id) = queue.pop() else { break };
+                dir_count += 1;
+                if self.is_loaded(dir_id).await? {
+                    for f in self.list_files(dir_id).await? {
+                        if f.is_folder() {
+                            queue.push(f.file_id);
+                        }
+                    }
+                } else {
+                    fetched.push((dir_id, self.fetch_files_from_api(dir_id).await?));
                 }
             }
+            if fetched.is_empty() {
+                continue;
+            }
+
+            let listings: Vec<(i64, &[FileInfo])> = fetched
+                .iter()
+                .map(|(dir_id, files)| (*dir_id, files.as_slice()))
+                .collect();
+            self.save_listings(&listings).await?;
+            let now = chrono::Utc::now().timestamp();
+            let mut loaded = self.loaded.write();
+            for (dir_id, files) in &fetched {
+                loaded.insert(*dir_id, now);
+                queue.extend(files.iter().filter(|f| f.is_folder()).map(|f| f.file_id));
+            }
         }
 
         tracing::info!(
-            "Cache warm-up completed in {:?}. Fetched {} dirs, cached {} dirs.", 
+            "Cache crawl completed in {:?} ({} dirs)",
             start.elapsed(),
-            fetched_count,
-            cached_count
+            dir_count
         );
         Ok(())
     }
 
-    async fn cache_has_children(&self, parent_id: i64) -> Result<bool> {
-        let count = entity::Entity::find()
-            .filter(entity::Column::ParentId.eq(parent_id))
-            .count(&self.db)
+    /// Crawl the repository in the background (see [`Self::crawl_cache`]).
+    /// Requests for directories not reached yet load them on demand.
+    pub fn spawn_cache_crawl(&self) -> tokio::task::JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client.crawl_cache().await {
+                tracing::error!("Background cache crawl failed: {}", e);
+            }
+        })
+    }
+
+    /// Make sure the listing of `dir_id` is cached, fetching it from the API
+    /// the first time the directory is used.
+    /// Listings older than the directory TTL are fetched again.
+    pub async fn ensure_loaded(&self, dir_id: i64) -> Result<()> {
+        let loaded_at = self.loaded.read().get(&dir_id).copied();
+        if loaded_at.is_some_and(|at| self.is_fresh(at)) {
+            return Ok(());
+        }
+        self.listings.run(dir_id, || self.load_dir(dir_id)).await
+    }
+
+    /// Whether a fresh listing of `dir_id` is cached, without fetching it.
+    pub async fn is_loaded(&self, dir_id: i64) -> Result<bool> {
+        let loaded_at = self.loaded.read().get(&dir_id).copied();
+        if loaded_at.is_some_and(|at| self.is_fresh(at)) {
+            return Ok(true);
+        }
+        // Loaded by an earlier run or another process sharing the database
+        let marker = loaded_dir::Entity::find_by_id((self.namespace.clone(), dir_id))
+            .one(&self.db)
             .await
-            .map_err(|e| AppError::Internal(format!("DB count fail: {}", e)))?;
-        Ok(count > 0)
+            .map_err(|e| AppError::Internal(format!("DB error in is_loaded: {}", e)))?;
+        match marker {
+            Some(marker) if self.is_fresh(marker.loaded_at) => {
+                self.loaded.write().insert(dir_id, marker.loaded_at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
     }
 
-    async fn fetch_or_use_cache(
-        &self,
-        parent_id: i64,
-        force_rebuild: bool,
-    ) -> Result<(Vec<FileInfo>, bool)> {
-        if !force_rebuild && self.cache_has_children(parent_id).await? {
-            let files = self.list_files(parent_id).await?;
-            return Ok((files, true));
+    fn is_fresh(&self, loaded_at: i64) -> bool {
+        self.directory_ttl
+            .is_none_or(|ttl| chrono::Utc::now().timestamp() - loaded_at < ttl.as_secs() as i64)
+    }
+
+    async fn load_dir(&self, dir_id: i64) -> Result<()> {
+        if self.is_loaded(dir_id).await? {
+            return Ok(());
         }
+        self.refresh_dir(dir_id).await
+    }
 
-        let files = self.fetch_files_from_api(parent_id).await?;
-        self.save_files_to_db(parent_id, &files).await?;
-        Ok((files, false))
+    /// Fetch the listing of `dir_id` from the API and replace the cached one.
+    async fn refresh_dir(&self, dir_id: i64) -> Result<()> {
+        let files = self.fetch_files_from_api(dir_id).await?;
+        self.save_files_to_db(dir_id, &files).await?;
+        self.loaded
+            .write()
+            .insert(dir_id, chrono::Utc::now().timestamp());
+        tracing::debug!("Loaded directory {} ({} files)", dir_id, files.len());
+        Ok(())
     }
 
-    async fn save_files_to_db(&self, parent_id: i64, files: &[FileInfo]) -> Result<()> {
-        let txn = self
-            .db
-            .begin()
+    /// List `parent_id` again after a write found the cache out of step with
+    /// 123pan, e.g. an entry created, replaced or moved by another client.
+    pub async fn reconcile_dir(&self, parent_id: i64) -> Result<()> {
+        tracing::info!(
+            "Cached listing of directory {} is out of date, listing it again",
+            parent_id
+        );
+        self.refresh_dir(parent_id).await
+    }
+
+    /// Reconcile the directory a node was cached in if 123pan reports it at
+    /// another place, before the node is recorded at `parent_id`/`name`.
+    async fn reconcile_moved(&self, file_id: i64, parent_id: i64, name: &str) -> Result<()> {
+        let cached = entity::Entity::find_by_id((self.namespace.clone(), file_id))
+            .one(&self.db)
             .await
-            .map_err(|e| AppError::Internal(format!("DB begin fail: {}", e)))?;
+            .map_err(|e| AppError::Internal(format!("DB error checking file id: {}", e)))?;
+        match cached {
+            Some(node) if node.parent_id != parent_id || node.name != name => {
+                self.reconcile_dir(node.parent_id).await
+            }
+            _ => Ok(()),
+        }
+    }
 
-        // Delete existing entries for this parent to avoid stale entries
-        entity::Entity::delete_many()
-            .filter(entity::Column::ParentId.eq(parent_id))
-            .exec(&txn)
+    /// Fetch again every directory listed more than `ttl` ago, returning how
+    /// many were refreshed.
+    pub async fn revalidate_expired(&self, ttl: Duration) -> Result<usize> {
+        let cutoff = chrono::Utc::now().timestamp() - ttl.as_secs() as i64;
+        let expired = loaded_dir::Entity::find()
+            .filter(loaded_dir::Column::Repo.eq(self.namespace.as_str()))
+            .filter(loaded_dir::Column::LoadedAt.lte(cutoff))
+            .all(&self.db)
             .await
-            .map_err(|e| AppError::Internal(format!("DB delete fail: {}", e)))?;
-
-        if !files.is_empty() {
-             let mut models = Vec::with_capacity(files.len());
-             for f in files {
-                models.push(entity::ActiveModel {
-                    file_id: Set(f.file_id),
-                    parent_id: Set(parent_id),
-                    name: Set(f.filename.clone()),
-                    is_dir: Set(f.is_folder()),
-                    size: Set(f.size),
-                    etag: Set(None),
-                    updated_at: Set(chrono::Utc::now().naive_utc()),
-                });
+            .map_err(|e| AppError::Internal(format!("DB error in revalidate_expired: {}", e)))?;
+
+        let mut refreshed = 0;
+        for marker in expired {
+            match self
+                .listings
+                .run(marker.dir_id, || self.refresh_dir(marker.dir_id))
+                .await
+            {
+                Ok(()) => refreshed += 1,
+                Err(e) => tracing::warn!("Failed to revalidate directory {}: {}", marker.dir_id, e),
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// Revalidate expired directory listings in the background, so they are
+    /// refreshed without delaying requests (see [`Self::revalidate_expired`]).
+    pub fn spawn_revalidation(&self, ttl: Duration) -> tokio::task::JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(ttl.clamp(Duration::from_secs(1), REVALIDATION_INTERVAL));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match client.revalidate_expired(ttl).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Revalidated {} cached directories", n),
+                    Err(e) => tracing::error!("Cache revalidation failed: {}", e),
+                }
             }
+        })
+    }
+
+    /// Record that `dir_id` is fully cached without listing it (it was just created).
+    async fn mark_loaded(&self, dir_id: i64) -> Result<()> {
+        self.writes
+            .run(|| self.insert_loaded_marker(&self.db, dir_id))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to mark directory loaded: {}", e)))?;
+        self.loaded
+            .write()
+            .insert(dir_id, chrono::Utc::now().timestamp());
+        Ok(())
+    }
+
+    async fn insert_loaded_marker<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        dir_id: i64,
+    ) -> std::result::Result<(), DbErr> {
+        let marker = loaded_dir::ActiveModel {
+            repo: Set(self.namespace.clone()),
+            dir_id: Set(dir_id),
+            loaded_at: Set(chrono::Utc::now().timestamp()),
+        };
+        loaded_dir::Entity::insert(marker)
+            .on_conflict(
+                sea_orm::sea_query::OnConflict::columns([
+                    loaded_dir::Column::Repo,
+                    loaded_dir::Column::DirId,
+                ])
+                .update_column(loaded_dir::Column::LoadedAt)
+                .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Forget which directories were loaded so each is listed again on next use.
+    async fn forget_loaded(&self) -> Result<()> {
+        self.writes
+            .run(|| {
+                loaded_dir::Entity::delete_many()
+                    .filter(loaded_dir::Column::Repo.eq(self.namespace.as_str()))
+                    .exec(&self.db)
+            })
+            .await
+            .map_err(|e| {
+                AppError::Internal(format!("Failed to reset loaded directories: {}", e))
+            })?;
+        self.loaded.write().clear();
+        Ok(())
+    }
+
+    async fn save_files_to_db(&self, parent_id: i64, files: &[FileInfo]) -> Result<()> {
+        self.save_listings(&[(parent_id, files)]).await
+    }
+
+    /// Replace the cached listings of several directories in one transaction.
+    async fn save_listings(&self, listings: &[(i64, &[FileInfo])]) -> Result<()> {
+        // The listing may still show files deleted moments ago
+        let tombstones = self.recent_tombstones().await?;
+        let now = chrono::Utc::now().naive_utc();
+        let listings: Vec<(i64, Vec<entity::ActiveModel>)> = listings
+            .iter()
+            .map(|(parent_id, files)| {
+                let models = files
+                    .iter()
+                    .filter(|f| !tombstones.contains(&f.file_id))
+                    .map(|f| entity::ActiveModel {
+                        repo: Set(self.namespace.clone()),
+                        file_id: Set(f.file_id),
+                        parent_id: Set(*parent_id),
+                        name: Set(f.filename.clone()),
+                        is_dir: Set(f.is_folder()),
+                        size: Set(f.size),
+                        etag: Set(f.etag.clone()),
+                        updated_at: Set(now),
+                        modified_at: Set(f.modified_at),
+                    })
+                    .collect();
+                (*parent_id, models)
+            })
+            .collect();
+
+        self.writes
+            .run(|| self.replace_children(&listings))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to save directory listing: {}", e)))?;
+        for (parent_id, _) in &listings {
+            self.lookups.remove_children(*parent_id);
+        }
+        // Entries moved in may be cached under their old parent
+        for (_, models) in &listings {
+            let ids: Vec<i64> = models
+                .iter()
+                .filter_map(|m| m.file_id.try_as_ref().copied())
+                .collect();
+            self.lookups.remove_ids(&ids);
+        }
+        Ok(())
+    }
 
-            // Chunking for SQLite limits
-            for chunk in models.chunks(50) {
+    /// Replace the cached children of each directory and mark them loaded in
+    /// one transaction.
+    async fn replace_children(
+        &self,
+        listings: &[(i64, Vec<entity::ActiveModel>)],
+    ) -> std::result::Result<(), DbErr> {
+        let txn = self.db.begin().await?;
+
+        for (parent_id, models) in listings {
+            // Delete existing entries for this parent to avoid stale entries
+            entity::Entity::delete_many()
+                .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+                .filter(entity::Column::ParentId.eq(*parent_id))
+                .exec(&txn)
+                .await?;
+
+            // Chunking for bind parameter limits
+            for chunk in models.chunks(INSERT_CHUNK) {
+                // Entries moved in are still cached under their old parent
+                let ids: Vec<i64> = chunk
+                    .iter()
+                    .filter_map(|m| m.file_id.try_as_ref().copied())
+                    .collect();
+                entity::Entity::delete_many()
+                    .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+                    .filter(entity::Column::FileId.is_in(ids))
+                    .exec(&txn)
+                    .await?;
                 entity::Entity::insert_many(chunk.to_vec())
                     .exec(&txn)
-                    .await
-                    .map_err(|e| {
-                        AppError::Internal(format!("DB batch insert fail: {}", e))
-                    })?;
+                    .await?;
             }
+            self.insert_loaded_marker(&txn, *parent_id).await?;
         }
 
-        txn.commit()
-            .await
-            .map_err(|e| AppError::Internal(format!("DB commit fail: {}", e)))?;
-        Ok(())
+        txn.commit().await
     }
-    /// List all data files across all 2-char subdirectories.
+
+    /// List all data files across all shard subdirectories.
     /// Returns aggregated file list from all subdirectories under data/.
     pub async fn list_all_data_files(&self) -> Result<Vec<FileInfo>> {
         // Find data directory ID
@@ -1035,29 +1903,56 @@ impl Pan123Client {
             return Ok(Vec::new());
         };
 
-        // Find all subdirectories under /data
-        let subdirs = entity::Entity::find()
-            .filter(entity::Column::ParentId.eq(data_dir_id))
-            .filter(entity::Column::IsDir.eq(true))
-            .all(&self.db)
-            .await
-            .map_err(|e| {
-                AppError::Internal(format!("DB error in list_all_data_files (subdirs): {}", e))
-            })?;
-
-        let subdir_ids: Vec<i64> = subdirs.into_iter().map(|n| n.file_id).collect();
+        // Walk the shard levels below /data; without sharding, packs live
+        // directly in data/
+        let mut dir_ids = vec![data_dir_id];
+        let mut level = vec![data_dir_id];
+        while !level.is_empty() {
+            for &dir_id in &level {
+                self.ensure_loaded(dir_id).await?;
+            }
+            let mut subdirs = Vec::new();
+            for chunk in level.chunks(IN_CLAUSE_CHUNK) {
+                subdirs.extend(
+                    self.nodes()
+                        .filter(entity::Column::ParentId.is_in(chunk.to_vec()))
+                        .filter(entity::Column::IsDir.eq(true))
+                        .all(&self.db)
+                        .await
+                        .map_err(|e| {
+                            AppError::Internal(format!(
+                                "DB error in list_all_data_files (subdirs): {}",
+                                e
+                            ))
+                        })?
+                        .into_iter()
+                        .map(|n| n.file_id),
+                );
+            }
+            dir_ids.extend(&subdirs);
+            level = subdirs;
+        }
 
         // Find all files in those subdirectories
-        let files = entity::Entity::find()
-            .filter(entity::Column::ParentId.is_in(subdir_ids))
-            .filter(entity::Column::IsDir.eq(false))
-            .all(&self.db)
-            .await
-            .map_err(|e| {
-                AppError::Internal(format!("DB error in list_all_data_files (files): {}", e))
-            })?;
-
-        Ok(files.into_iter().map(FileInfo::from).collect())
+        let mut files = Vec::new();
+        for chunk in dir_ids.chunks(IN_CLAUSE_CHUNK) {
+            files.extend(
+                self.nodes()
+                    .filter(entity::Column::ParentId.is_in(chunk.to_vec()))
+                    .filter(entity::Column::IsDir.eq(false))
+                    .all(&self.db)
+                    .await
+                    .map_err(|e| {
+                        AppError::Internal(format!(
+                            "DB error in list_all_data_files (files): {}",
+                            e
+                        ))
+                    })?
+                    .into_iter()
+                    .map(FileInfo::from),
+            );
+        }
+        Ok(files)
     }
 }
 
@@ -1069,3 +1964,19 @@ impl std::fmt::Debug for Pan123Client {
             .finish()
     }
 }
+
+/// Names of the directories on an absolute path as stored on 123pan.
+fn path_names(path: &str) -> Result<Vec<String>> {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|part| encode_name(part).map(String::from))
+        .collect()
+}
+
+/// Split the inclusive byte range `start..=end` into consecutive chunks.
+pub(crate) fn chunk_ranges(start: u64, end: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    (start..=end)
+        .step_by(chunk_size as usize)
+        .map(|chunk_start| (chunk_start, (chunk_start + chunk_size - 1).min(end)))
+        .collect()
+}
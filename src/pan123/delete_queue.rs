@@ -0,0 +1,213 @@
+//! Batched deletion, for the bursts of DELETEs sent by `restic prune`.
+//!
+//! A queued delete removes the file from the cache and records it as pending
+//! in one transaction, so the next listing no longer shows it, and returns.
+//! The delete batcher then trashes and deletes the pending files on 123pan
+//! up to [`MAX_BATCH`] at a time. Pending deletes live in the database, so
+//! those not carried out before a restart are picked up again.
+
+use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect};
+use std::time::Duration;
+
+use super::pending_delete;
+use super::types::{ApiResponse, DeleteRequest, ErrorKind, TrashRequest};
+use super::Pan123Client;
+use crate::error::{AppError, Result};
+
+/// Files per trash and delete call (API limit).
+const MAX_BATCH: u64 = 100;
+
+/// Wait before pending deletes that failed are tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+impl Pan123Client {
+    /// Queue the removal of a file from 123pan if the delete batcher runs;
+    /// returns whether it was queued. The file is gone from the cache either
+    /// way once this returns `true`.
+    pub(super) async fn queue_delete(&self, file_id: i64) -> Result<bool> {
+        let Some(wakeup) = self.delete_batcher.get() else {
+            return Ok(false);
+        };
+        self.writes
+            .run(|| self.remove_node(file_id, true))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to queue delete: {}", e)))?;
+        self.lookups.remove_ids(&[file_id]);
+        wakeup.notify_one();
+        Ok(true)
+    }
+
+    /// Carry out queued deletes in the background, waiting `window` after
+    /// the first of a burst so the rest join its batch. Deletes are queued
+    /// only once this runs.
+    pub fn spawn_delete_batching(&self, window: Duration) -> tokio::task::JoinHandle<()> {
+        let client = self.clone();
+        let _ = self.delete_batcher.set(Default::default());
+        tokio::spawn(async move {
+            let Some(wakeup) = client.delete_batcher.get() else {
+                return;
+            };
+            loop {
+                match client.flush_deletes().await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Deleted {} queued files from 123pan", n),
+                    Err(e) => {
+                        tracing::warn!(
+                            "Queued deletes failed, retrying in {:?}: {}",
+                            RETRY_INTERVAL,
+                            e
+                        );
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                        continue;
+                    }
+                }
+                wakeup.notified().await;
+                tokio::time::sleep(window).await;
+            }
+        })
+    }
+
+    /// Trash and delete all queued files now, returning how many were
+    /// removed. Files 123pan no longer has are dropped from the queue; any
+    /// other failure keeps the batch queued and stops here.
+    pub async fn flush_deletes(&self) -> Result<usize> {
+        let mut removed = 0;
+        loop {
+            let batch: Vec<i64> = pending_delete::Entity::find()
+                .select_only()
+                .column(pending_delete::Column::FileId)
+                .filter(pending_delete::Column::Repo.eq(self.namespace.as_str()))
+                .order_by_asc(pending_delete::Column::QueuedAt)
+                .limit(MAX_BATCH)
+                .into_tuple()
+                .all(&self.db)
+                .await
+                .map_err(|e| {
+                    AppError::Internal(format!("DB error reading queued deletes: {}", e))
+                })?;
+            if batch.is_empty() {
+                return Ok(removed);
+            }
+
+            let done = match self.remove_files(&batch).await {
+                Ok(()) => batch,
+                // One file 123pan refuses (e.g. already gone) fails the whole
+                // call; find out which one
+                Err(AppError::Pan123Api { code, message })
+                    if batch.len() > 1
+                        && ErrorKind::of(code, &message) != ErrorKind::RateLimited =>
+                {
+                    let mut done = Vec::with_capacity(batch.len());
+                    for &file_id in &batch {
+                        match self.remove_files(&[file_id]).await {
+                            Ok(()) => done.push(file_id),
+                            Err(AppError::Pan123Api { code, message })
+                                if ErrorKind::of(code, &message) == ErrorKind::NotFound =>
+                            {
+                                tracing::warn!(
+                                    "Dropping queued delete of file {}: {} (code {})",
+                                    file_id,
+                                    message,
+                                    code
+                                );
+                                done.push(file_id);
+                            }
+                            Err(e) => {
+                                self.dequeue_deletes(&done).await?;
+                                return Err(e);
+                            }
+                        }
+                    }
+                    done
+                }
+                // Already gone from 123pan
+                Err(AppError::Pan123Api { code, message })
+                    if ErrorKind::of(code, &message) == ErrorKind::NotFound =>
+                {
+                    tracing::warn!(
+                        "Dropping queued delete of file {}: {} (code {})",
+                        batch[0],
+                        message,
+                        code
+                    );
+                    batch
+                }
+                Err(e) => return Err(e),
+            };
+            removed += done.len();
+            self.dequeue_deletes(&done).await?;
+        }
+    }
+
+    /// Number of files queued for deletion.
+    pub async fn pending_deletes(&self) -> Result<u64> {
+        pending_delete::Entity::find()
+            .filter(pending_delete::Column::Repo.eq(self.namespace.as_str()))
+            .count(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error counting queued deletes: {}", e)))
+    }
+
+    /// IDs of files queued for deletion.
+    pub(super) async fn pending_delete_ids(&self) -> Result<Vec<i64>> {
+        pending_delete::Entity::find()
+            .select_only()
+            .column(pending_delete::Column::FileId)
+            .filter(pending_delete::Column::Repo.eq(self.namespace.as_str()))
+            .into_tuple()
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error reading queued deletes: {}", e)))
+    }
+
+    /// Trash, then permanently delete files on 123pan, in one call each.
+    async fn remove_files(&self, file_ids: &[i64]) -> Result<()> {
+        let base_url = self.token_manager.base_url();
+        let response: ApiResponse<()> = self
+            .post(
+                &format!("{}/api/v1/file/trash", base_url),
+                &TrashRequest {
+                    file_ids: file_ids.to_vec(),
+                },
+            )
+            .await?;
+        if !response.is_success() {
+            return Err(AppError::Pan123Api {
+                code: response.code,
+                message: response.message,
+            });
+        }
+        let response: ApiResponse<serde_json::Value> = self
+            .post(
+                &format!("{}/api/v1/file/delete", base_url),
+                &DeleteRequest {
+                    file_ids: file_ids.to_vec(),
+                },
+            )
+            .await?;
+        if !response.is_success() {
+            return Err(AppError::Pan123Api {
+                code: response.code,
+                message: response.message,
+            });
+        }
+        Ok(())
+    }
+
+    /// Drop files from the delete queue.
+    pub(super) async fn dequeue_deletes(&self, file_ids: &[i64]) -> Result<()> {
+        if file_ids.is_empty() {
+            return Ok(());
+        }
+        self.writes
+            .run(|| {
+                pending_delete::Entity::delete_many()
+                    .filter(pending_delete::Column::Repo.eq(self.namespace.as_str()))
+                    .filter(pending_delete::Column::FileId.is_in(file_ids.iter().copied()))
+                    .exec(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to dequeue deletes: {}", e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! Serving straight from the 123pan API, bypassing the cache database.
+//!
+//! Every listing, lookup and read lists the directory involved from the API,
+//! so what is served is always what 123pan holds. Only directory IDs are
+//! remembered. Meant for debugging suspected cache bugs and for small
+//! repositories where consistency matters more than the number of API calls.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde_json::json;
+use std::collections::HashMap;
+
+use super::{encode_name, FileInfo, Pan123Client};
+use crate::error::{AppError, Result};
+use crate::storage::{split_path, ObjectInfo, Readiness, StorageBackend};
+
+/// [`StorageBackend`] answering reads from the 123pan API instead of the
+/// cache; writes go through the client as usual.
+pub struct DirectBackend {
+    client: Pan123Client,
+    /// Directory IDs by absolute path
+    dir_ids: Mutex<HashMap<String, i64>>,
+}
+
+impl DirectBackend {
+    pub fn new(client: Pan123Client) -> Self {
+        Self {
+            client,
+            dir_ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// ID of the directory at a repository-relative path, listing each level
+    /// from the API the first time.
+    async fn dir_id(&self, dir: &str) -> Result<Option<i64>> {
+        let full_path = self.client.repo_full_path(dir);
+        if let Some(&id) = self.dir_ids.lock().get(&full_path) {
+            return Ok(Some(id));
+        }
+        let mut current_id = 0;
+        let mut path = String::new();
+        for part in full_path.split('/').filter(|p| !p.is_empty()) {
+            path = format!("{}/{}", path, part);
+            let part = encode_name(part)?;
+            let known = self.dir_ids.lock().get(&path).copied();
+            current_id = match known {
+                Some(id) => id,
+                None => {
+                    let Some(folder) = self
+                        .client
+                        .fetch_files_from_api(current_id)
+                        .await?
+                        .into_iter()
+                        .find(|f| f.is_folder() && self.client.names_match(&f.filename, &part))
+                    else {
+                        return Ok(None);
+                    };
+                    self.dir_ids.lock().insert(path.clone(), folder.file_id);
+                    folder.file_id
+                }
+            };
+        }
+        Ok(Some(current_id))
+    }
+
+    /// Files of a directory as the API lists them. A directory that can't be
+    /// listed is forgotten, in case it was removed.
+    async fn list_dir(&self, dir_id: i64) -> Result<Vec<FileInfo>> {
+        let result = self.client.fetch_files_from_api(dir_id).await;
+        if result.is_err() {
+            self.dir_ids.lock().retain(|_, id| *id != dir_id);
+        }
+        result
+    }
+
+    /// Look up a file by repository-relative path. Of several files with the
+    /// name, the newest non-empty one is returned.
+    async fn find_object(&self, path: &str) -> Result<Option<FileInfo>> {
+        let (parent, name) = split_path(path);
+        let Some(dir_id) = self.dir_id(parent).await? else {
+            return Ok(None);
+        };
+        let name = encode_name(name)?;
+        Ok(self
+            .list_dir(dir_id)
+            .await?
+            .into_iter()
+            .filter(|f| !f.is_folder() && self.client.names_match(&f.filename, &name))
+            .max_by_key(|f| (f.size > 0, f.modified_at, f.file_id)))
+    }
+
+    /// ID of a directory, creating it and its parents if missing.
+    async fn ensure_dir_id(&self, dir: &str) -> Result<i64> {
+        match self.dir_id(dir).await? {
+            Some(id) => Ok(id),
+            None => {
+                let full_path = self.client.repo_full_path(dir);
+                let id = self.client.ensure_path(&full_path).await?;
+                self.dir_ids.lock().insert(full_path, id);
+                Ok(id)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DirectBackend {
+    async fn list(&self, dir: &str) -> Result<Vec<ObjectInfo>> {
+        let Some(dir_id) = self.dir_id(dir).await? else {
+            return Ok(Vec::new());
+        };
+        let mut objects = Vec::new();
+        let mut pending = vec![dir_id];
+        while let Some(dir_id) = pending.pop() {
+            for file in self.list_dir(dir_id).await? {
+                if file.is_folder() {
+                    pending.push(file.file_id);
+                } else {
+                    objects.push(ObjectInfo::from(file));
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn head(&self, path: &str) -> Result<Option<ObjectInfo>> {
+        Ok(self.find_object(path).await?.map(ObjectInfo::from))
+    }
+
+    async fn get_range(&self, path: &str, range: Option<(u64, u64)>) -> Result<Bytes> {
+        let file = self
+            .find_object(path)
+            .await?
+            .ok_or_else(|| AppError::NotFound(path.to_string()))?;
+        self.client.download_file(file.file_id, range).await
+    }
+
+    async fn put(&self, path: &str, data: Bytes) -> Result<()> {
+        let (parent, name) = split_path(path);
+        let dir_id = self.ensure_dir_id(parent).await?;
+        self.client.upload_file(dir_id, name, data).await?;
+        Ok(())
+    }
+
+    async fn create(&self, path: &str, data: Bytes) -> Result<()> {
+        let (parent, name) = split_path(path);
+        let dir_id = self.ensure_dir_id(parent).await?;
+        self.client.upload_new_file(dir_id, name, data).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        if let Some(file) = self.find_object(path).await? {
+            self.client
+                .delete_file(file.parent_file_id, file.file_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn ensure_dir(&self, path: &str) -> Result<()> {
+        self.ensure_dir_id(path).await?;
+        Ok(())
+    }
+
+    async fn readiness(&self) -> Readiness {
+        let db = self.client.ping_db().await.is_ok();
+        let token = self.client.check_token().await.is_ok();
+        Readiness {
+            ready: db && token,
+            details: json!({
+                "db": db,
+                "token": token,
+                "mode": "direct",
+                "credentials": self.client.credential_stats(),
+            }),
+        }
+    }
+
+    async fn maintain(&self) -> Result<serde_json::Value> {
+        self.client.maintain().await
+    }
+
+    fn metrics(&self) -> String {
+        self.client.api_metrics().render_prometheus()
+    }
+}
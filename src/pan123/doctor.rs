@@ -0,0 +1,248 @@
+//! Self-test of the connection to 123pan.
+//!
+//! Walks through what the server needs, in the order it needs it: an access
+//! token, a reachable API, a correct clock, space on the account and a
+//! scratch file round trip in the repository. Each failure comes with a
+//! diagnosis of the usual misconfiguration behind it.
+
+use bytes::Bytes;
+use serde::Serialize;
+use std::future::Future;
+use std::time::Instant;
+
+use super::Pan123Client;
+use crate::error::{AppError, Result};
+
+/// Clock difference to the API beyond which the local clock is reported.
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+/// Name of the scratch directory created below the repository.
+const SCRATCH_DIR: &str = ".doctor";
+
+/// Outcome of one check of [`Pan123Client::doctor`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    /// Time the check took
+    pub millis: u64,
+    /// What was found, or the error
+    pub detail: String,
+    /// Likely cause and fix of a failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnosis: Option<String>,
+}
+
+/// Outcome of [`Pan123Client::doctor`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DoctorReport {
+    pub checks: Vec<DoctorCheck>,
+}
+
+impl DoctorReport {
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|c| c.ok)
+    }
+
+    /// Run `check`, recording its outcome and duration; returns its result
+    /// if it passed.
+    async fn run<T, F, Fut>(&mut self, name: &'static str, check: F) -> Option<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = std::result::Result<(T, String), Failure>>,
+    {
+        let start = Instant::now();
+        let result = check().await;
+        let millis = start.elapsed().as_millis() as u64;
+        let (ok, detail, diagnosis, value) = match result {
+            Ok((value, detail)) => (true, detail, None, Some(value)),
+            Err(failure) => (false, failure.detail, failure.diagnosis, None),
+        };
+        self.checks.push(DoctorCheck {
+            name,
+            ok,
+            millis,
+            detail,
+            diagnosis,
+        });
+        value
+    }
+}
+
+/// A failed check.
+struct Failure {
+    detail: String,
+    diagnosis: Option<String>,
+}
+
+impl Failure {
+    fn new(detail: impl Into<String>, diagnosis: impl Into<String>) -> Self {
+        Self {
+            detail: detail.into(),
+            diagnosis: Some(diagnosis.into()),
+        }
+    }
+}
+
+impl From<AppError> for Failure {
+    fn from(e: AppError) -> Self {
+        Self {
+            diagnosis: diagnose(&e),
+            detail: e.to_string(),
+        }
+    }
+}
+
+/// The usual cause of an error talking to 123pan.
+fn diagnose(e: &AppError) -> Option<String> {
+    match e {
+        AppError::Auth(_) => Some(
+            "Bad credentials: check PAN123_CLIENT_ID and PAN123_CLIENT_SECRET \
+             (or their _FILE variants)"
+                .to_string(),
+        ),
+        AppError::HttpClient(e) if e.is_connect() || e.is_timeout() => Some(
+            "The 123pan API is unreachable: check DNS, firewalls and PROXY_URL, or \
+             PAN123_API_BASE_URL"
+                .to_string(),
+        ),
+        AppError::Pan123Api { code: 429, .. } => {
+            Some("Rate limited by 123pan: wait a minute and try again".to_string())
+        }
+        AppError::Pan123Api { code: 401, .. } => {
+            Some("The access token was refused: the credentials may have been revoked".to_string())
+        }
+        _ => None,
+    }
+}
+
+impl Pan123Client {
+    /// Check the access token, API latency, clock skew, account space and a
+    /// mkdir/upload/download/delete round trip of a scratch file below the
+    /// repository. Checks that depend on a failed one are skipped.
+    pub async fn doctor(&self) -> DoctorReport {
+        let mut report = DoctorReport::default();
+
+        let token = report
+            .run("token", || async {
+                self.check_token().await?;
+                Ok(((), "Access token obtained".to_string()))
+            })
+            .await;
+        if token.is_none() {
+            return report;
+        }
+
+        report
+            .run("api latency", || async {
+                let start = Instant::now();
+                self.user_info().await?;
+                let millis = start.elapsed().as_millis();
+                Ok(((), format!("User info answered in {} ms", millis)))
+            })
+            .await;
+
+        report
+            .run("clock", || async {
+                let skew = self.clock_skew().await?;
+                let detail = format!("Local clock is {}s off the API's", skew);
+                if skew.abs() > MAX_CLOCK_SKEW_SECS {
+                    return Err(Failure::new(
+                        detail,
+                        "The local clock is wrong: token expiry and cache ages are \
+                         misjudged; enable NTP",
+                    ));
+                }
+                Ok(((), detail))
+            })
+            .await;
+
+        report
+            .run("space", || async {
+                let account = self.user_info().await?;
+                let total = account.space_total();
+                let free = total.saturating_sub(account.space_used);
+                let detail = format!(
+                    "{} of {} MiB free",
+                    free / (1024 * 1024),
+                    total / (1024 * 1024)
+                );
+                if total > 0 && free == 0 {
+                    return Err(Failure::new(
+                        detail,
+                        "The account is full: uploads will fail until space is freed",
+                    ));
+                }
+                Ok(((), detail))
+            })
+            .await;
+
+        report
+            .run("round trip", || async {
+                self.scratch_round_trip().await.map_err(Failure::from)
+            })
+            .await;
+        report
+    }
+
+    /// Seconds the local clock is ahead of the API server's `Date` header.
+    async fn clock_skew(&self) -> std::result::Result<i64, Failure> {
+        let response = self
+            .token_manager
+            .http_client()
+            .head(self.token_manager.base_url())
+            .send()
+            .await
+            .map_err(AppError::from)?;
+        let date = response
+            .headers()
+            .get(reqwest::header::DATE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+            .ok_or_else(|| Failure {
+                detail: "The API sent no Date header".to_string(),
+                diagnosis: None,
+            })?;
+        Ok((chrono::Utc::now() - date.with_timezone(&chrono::Utc)).num_seconds())
+    }
+
+    /// Create a scratch directory below the repository, upload, download and
+    /// delete a file in it, then trash the directory.
+    async fn scratch_round_trip(&self) -> Result<((), String)> {
+        let start = Instant::now();
+        let dir_id = self
+            .ensure_path(&format!("{}/{}", self.repo_path, SCRATCH_DIR))
+            .await?;
+        let mkdir = start.elapsed();
+
+        let data = Bytes::from(format!("restic-123pan doctor {}", chrono::Utc::now()));
+        let name = format!("scratch-{}", std::process::id());
+        let start = Instant::now();
+        let file_id = self.upload_file(dir_id, &name, data.clone()).await?;
+        let upload = start.elapsed();
+
+        let start = Instant::now();
+        let downloaded = self.download_file(file_id, None).await?;
+        let download = start.elapsed();
+        if downloaded != data {
+            return Err(AppError::Internal(
+                "The scratch file came back with other content".to_string(),
+            ));
+        }
+
+        let start = Instant::now();
+        self.delete_file(dir_id, file_id).await?;
+        self.trash_file(dir_id).await?;
+        let delete = start.elapsed();
+        Ok((
+            (),
+            format!(
+                "mkdir {} ms, upload {} ms, download {} ms, delete {} ms",
+                mkdir.as_millis(),
+                upload.as_millis(),
+                download.as_millis(),
+                delete.as_millis()
+            ),
+        ))
+    }
+}
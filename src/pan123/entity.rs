@@ -1,19 +1,23 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
+/// Cached file tree node. The table is defined by `crate::migration`.
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "file_nodes")]
 pub struct Model {
+    /// Cache namespace of the repository this node was cached for
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repo: String,
     #[sea_orm(primary_key, auto_increment = false)]
     pub file_id: i64,
-    #[sea_orm(indexed)]
     pub parent_id: i64,
-    #[sea_orm(indexed)]
     pub name: String,
     pub is_dir: bool,
     pub size: i64,
     pub etag: Option<String>,
     pub updated_at: DateTime,
+    /// Last modification time on 123pan (UTC)
+    pub modified_at: Option<DateTime>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
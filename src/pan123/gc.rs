@@ -0,0 +1,222 @@
+//! Finding and removing leftovers of failed uploads.
+//!
+//! Interrupted or racing uploads can leave zero-byte objects, several files
+//! of the same name in one directory, or copies of packs outside their shard
+//! directory. The repository is walked through the API rather than the cache,
+//! which can't hold two entries of the same name.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::{FileInfo, Pan123Client};
+use crate::error::{AppError, Result};
+use crate::restic::types::OBJECT_ID_LEN;
+use crate::restic::ResticFileType;
+
+/// Why a file is garbage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GarbageReason {
+    /// An object without content
+    Empty,
+    /// Another file of the same name in the directory is kept
+    Duplicate,
+    /// A pack outside its shard directory, where a copy is stored
+    Misplaced,
+}
+
+impl std::fmt::Display for GarbageReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            GarbageReason::Empty => "empty",
+            GarbageReason::Duplicate => "duplicate",
+            GarbageReason::Misplaced => "misplaced",
+        })
+    }
+}
+
+/// A file found by [`Pan123Client::find_garbage`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Garbage {
+    pub file_id: i64,
+    pub parent_id: i64,
+    /// Repository-relative path
+    pub path: String,
+    pub size: i64,
+    pub reason: GarbageReason,
+}
+
+impl Pan123Client {
+    /// Walk the repository on 123pan and collect leftovers of failed uploads.
+    ///
+    /// Of several files with one name, a non-empty one is kept, then the
+    /// newest. A pack outside its shard directory only counts as garbage if
+    /// the shard directory holds the same content; otherwise it is left for
+    /// `migrate` to move.
+    pub async fn find_garbage(&self) -> Result<Vec<Garbage>> {
+        let mut garbage = Vec::new();
+        // Etags of the files that are kept, by path
+        let mut kept: HashMap<String, Option<String>> = HashMap::new();
+        let mut stray_packs = Vec::new();
+        for (dir_path, files) in self.walk_objects().await? {
+            let in_data = dir_path == "data" || dir_path.starts_with("data/");
+            let mut seen = HashSet::new();
+            for file in files {
+                let path = join_path(&dir_path, &file.filename);
+                let is_object = !dir_path.is_empty() || file.filename == "config";
+                let reason = if !seen.insert(file.filename.clone()) {
+                    Some(GarbageReason::Duplicate)
+                } else if is_object && file.size == 0 {
+                    Some(GarbageReason::Empty)
+                } else {
+                    None
+                };
+                match reason {
+                    Some(reason) => garbage.push(to_garbage(&file, path, reason)),
+                    None => {
+                        let expected = self
+                            .layout()
+                            .object_path(ResticFileType::Data, &file.filename);
+                        if in_data && path != expected {
+                            stray_packs.push((file, path, expected));
+                        } else {
+                            kept.insert(path, file.etag);
+                        }
+                    }
+                }
+            }
+        }
+
+        for (file, path, expected) in stray_packs {
+            if kept.get(&expected).is_some_and(|etag| *etag == file.etag) {
+                garbage.push(to_garbage(&file, path, GarbageReason::Misplaced));
+            }
+        }
+        garbage.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(garbage)
+    }
+
+    /// Walk the repository on 123pan and collect every file sharing its name
+    /// with another one in the same directory, except the copy to keep.
+    ///
+    /// Data, index and snapshot objects are named by the SHA-256 of their
+    /// content, so the copies of those are downloaded and the newest one
+    /// matching its name is kept. Otherwise, or if none matches, a non-empty
+    /// copy is kept, then the newest.
+    pub async fn find_duplicates(&self) -> Result<Vec<Garbage>> {
+        let mut duplicates = Vec::new();
+        for (dir_path, files) in self.walk_objects().await? {
+            let mut by_name: BTreeMap<&str, Vec<&FileInfo>> = BTreeMap::new();
+            for file in &files {
+                by_name.entry(&file.filename).or_default().push(file);
+            }
+            for (name, copies) in by_name.into_iter().filter(|(_, c)| c.len() > 1) {
+                let path = join_path(&dir_path, name);
+                let keep = self.pick_copy(&dir_path, name, &copies).await?;
+                tracing::info!(
+                    "Keeping file {} of {} copies of {}",
+                    copies[keep].file_id,
+                    copies.len(),
+                    path
+                );
+                for (_, file) in copies.iter().enumerate().filter(|(i, _)| *i != keep) {
+                    duplicates.push(to_garbage(file, path.clone(), GarbageReason::Duplicate));
+                }
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Index of the copy of `name` to keep among `copies`, which are ordered
+    /// by preference.
+    async fn pick_copy(&self, dir_path: &str, name: &str, copies: &[&FileInfo]) -> Result<usize> {
+        let type_dir = dir_path.split('/').next().unwrap_or_default();
+        let content_addressed = matches!(
+            ResticFileType::from_str(type_dir),
+            Some(ResticFileType::Data | ResticFileType::Index | ResticFileType::Snapshots)
+        ) && name.len() == OBJECT_ID_LEN;
+        if content_addressed {
+            for (i, file) in copies.iter().enumerate().filter(|(_, f)| f.size > 0) {
+                let data = self.download_file(file.file_id, None).await?;
+                if format!("{:x}", Sha256::digest(&data)) == name {
+                    return Ok(i);
+                }
+            }
+            tracing::warn!(
+                "No copy of {} matches its name, keeping the newest",
+                join_path(dir_path, name)
+            );
+        }
+        Ok(0)
+    }
+
+    /// Files of the type directories (and the shard directories below
+    /// `data/`) and the repository root, listed through the API, by
+    /// repository-relative directory path. Files of the same name are ordered
+    /// non-empty first, then newest first.
+    async fn walk_objects(&self) -> Result<Vec<(String, Vec<FileInfo>)>> {
+        let Some(repo_id) = self.find_path_id(&self.repo_path).await? else {
+            return Err(AppError::NotFound(format!(
+                "Repository {} not found",
+                self.repo_path
+            )));
+        };
+
+        let mut listings = Vec::new();
+        let mut dirs = vec![(repo_id, String::new())];
+        while let Some((dir_id, dir_path)) = dirs.pop() {
+            let (folders, mut files): (Vec<_>, Vec<_>) = self
+                .fetch_files_from_api(dir_id)
+                .await?
+                .into_iter()
+                .partition(|f| f.is_folder());
+            let in_data = dir_path == "data" || dir_path.starts_with("data/");
+            for folder in folders {
+                // Type directories and the shard directories below data/
+                let type_dir = dir_path.is_empty()
+                    && ResticFileType::from_str(&folder.filename).is_some_and(|t| !t.is_config());
+                if type_dir || in_data {
+                    dirs.push((folder.file_id, join_path(&dir_path, &folder.filename)));
+                }
+            }
+            files.sort_by(|a, b| {
+                (b.size > 0, b.modified_at, b.file_id).cmp(&(a.size > 0, a.modified_at, a.file_id))
+            });
+            listings.push((dir_path, files));
+        }
+        Ok(listings)
+    }
+
+    /// Move `garbage` to the 123pan trash and list the directories it was
+    /// in again, so the cache holds the files that were kept.
+    pub async fn collect_garbage(&self, garbage: &[Garbage]) -> Result<()> {
+        for file in garbage {
+            self.trash_file(file.file_id).await?;
+            tracing::info!("Trashed {} file {}", file.reason, file.path);
+        }
+        let parents: BTreeSet<i64> = garbage.iter().map(|g| g.parent_id).collect();
+        for parent_id in parents {
+            self.reconcile_dir(parent_id).await?;
+        }
+        Ok(())
+    }
+}
+
+fn join_path(dir_path: &str, name: &str) -> String {
+    if dir_path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir_path, name)
+    }
+}
+
+fn to_garbage(file: &FileInfo, path: String, reason: GarbageReason) -> Garbage {
+    Garbage {
+        file_id: file.file_id,
+        parent_id: file.parent_file_id,
+        path,
+        size: file.size,
+        reason,
+    }
+}
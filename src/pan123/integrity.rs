@@ -0,0 +1,215 @@
+//! Self-checks and cleanup of the cache database.
+//!
+//! A damaged database file, or rows left behind by an interrupted write, would
+//! otherwise surface as subtly wrong listings served to restic. Subtrees
+//! removed outside this server are cleaned up periodically.
+
+use sea_orm::{
+    sea_query::{Expr, Query},
+    ColumnTrait, ConnectionTrait, DatabaseBackend, DbErr, EntityTrait, QueryFilter, QuerySelect,
+    Statement, TransactionTrait,
+};
+use serde::Serialize;
+use std::time::Duration;
+
+use super::{entity, loaded_dir, Pan123Client};
+use crate::error::{AppError, Result};
+
+/// Findings of [`Pan123Client::check_cache`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntegrityReport {
+    /// Problems reported by `PRAGMA integrity_check` (SQLite only)
+    pub integrity_errors: Vec<String>,
+    /// Nodes whose parent is not cached (and is not the root)
+    pub orphans: Vec<i64>,
+    /// Directories holding several cached entries with the same name
+    pub duplicates: Vec<(i64, String)>,
+    /// Whether the problems found were repaired
+    pub repaired: bool,
+}
+
+impl IntegrityReport {
+    /// Whether nothing was found.
+    pub fn is_clean(&self) -> bool {
+        self.integrity_errors.is_empty() && self.orphans.is_empty() && self.duplicates.is_empty()
+    }
+}
+
+impl Pan123Client {
+    /// Check the cache database and this namespace's cached tree.
+    ///
+    /// With `repair`, orphaned nodes are deleted and directories with
+    /// duplicate entries are listed again on next use. A damaged database
+    /// file can't be repaired in place; the tree is not checked then, and the
+    /// caller should recreate the database (see [`crate::db::quarantine`]).
+    pub async fn check_cache(&self, repair: bool) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport {
+            integrity_errors: self.integrity_errors().await?,
+            ..IntegrityReport::default()
+        };
+        if !report.integrity_errors.is_empty() {
+            return Ok(report);
+        }
+
+        report.orphans = self.find_orphans().await?;
+
+        report.duplicates = self
+            .nodes()
+            .select_only()
+            .column(entity::Column::ParentId)
+            .column(entity::Column::Name)
+            .group_by(entity::Column::ParentId)
+            .group_by(entity::Column::Name)
+            .having(Expr::expr(Expr::col(entity::Column::FileId).count()).gt(1))
+            .into_tuple()
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error finding duplicates: {}", e)))?;
+
+        if repair && !report.is_clean() {
+            self.writes
+                .run(|| self.repair_tree(&report))
+                .await
+                .map_err(|e| AppError::Internal(format!("Failed to repair cache: {}", e)))?;
+            self.lookups.remove_ids(&report.orphans);
+            for (parent_id, _) in &report.duplicates {
+                self.lookups.remove_children(*parent_id);
+                self.loaded.write().remove(parent_id);
+            }
+            report.repaired = true;
+        }
+        Ok(report)
+    }
+
+    /// Delete cached subtrees whose ancestors are no longer cached, e.g. of
+    /// directories removed outside this server and dropped from a refreshed
+    /// listing. Returns the number of rows deleted.
+    pub async fn cleanup_orphans(&self) -> Result<u64> {
+        let mut removed = 0;
+        // Each pass removes the top level of the dead subtrees
+        loop {
+            let orphans = self.find_orphans().await?;
+            if orphans.is_empty() {
+                break;
+            }
+            let report = IntegrityReport {
+                orphans,
+                ..IntegrityReport::default()
+            };
+            self.writes
+                .run(|| self.repair_tree(&report))
+                .await
+                .map_err(|e| AppError::Internal(format!("Failed to delete orphans: {}", e)))?;
+            self.lookups.remove_ids(&report.orphans);
+            removed += report.orphans.len() as u64;
+        }
+
+        // Loaded markers of directories that are gone
+        let dirs = Query::select()
+            .column(entity::Column::FileId)
+            .from(entity::Entity)
+            .and_where(entity::Column::Repo.eq(self.namespace.as_str()))
+            .to_owned();
+        let markers = self
+            .writes
+            .run(|| {
+                loaded_dir::Entity::delete_many()
+                    .filter(loaded_dir::Column::Repo.eq(self.namespace.as_str()))
+                    .filter(loaded_dir::Column::DirId.ne(0))
+                    .filter(loaded_dir::Column::DirId.not_in_subquery(dirs.clone()))
+                    .exec(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to delete loaded markers: {}", e)))?;
+        if markers.rows_affected > 0 {
+            self.loaded.write().clear();
+        }
+        Ok(removed + markers.rows_affected)
+    }
+
+    /// Remove orphaned rows every `interval` in the background.
+    pub fn spawn_orphan_cleanup(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match client.cleanup_orphans().await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Removed {} orphaned cache rows", n),
+                    Err(e) => tracing::error!("Orphan cleanup failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Cached nodes whose parent is neither cached nor the root.
+    async fn find_orphans(&self) -> Result<Vec<i64>> {
+        let parents = Query::select()
+            .column(entity::Column::FileId)
+            .from(entity::Entity)
+            .and_where(entity::Column::Repo.eq(self.namespace.as_str()))
+            .to_owned();
+        self.nodes()
+            .select_only()
+            .column(entity::Column::FileId)
+            .filter(entity::Column::ParentId.ne(0))
+            .filter(entity::Column::ParentId.not_in_subquery(parents))
+            .into_tuple()
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error finding orphans: {}", e)))
+    }
+
+    /// Messages from `PRAGMA integrity_check`, empty if the database is fine.
+    async fn integrity_errors(&self) -> Result<Vec<String>> {
+        if self.db.get_database_backend() != DatabaseBackend::Sqlite {
+            return Ok(Vec::new());
+        }
+        let rows = self
+            .db
+            .query_all(Statement::from_string(
+                DatabaseBackend::Sqlite,
+                "PRAGMA integrity_check",
+            ))
+            .await
+            .map_err(|e| AppError::Internal(format!("Integrity check failed to run: {}", e)));
+        let rows = match rows {
+            Ok(rows) => rows,
+            // Badly damaged files fail the check itself
+            Err(e) if crate::db::is_corruption(&e.to_string()) => return Ok(vec![e.to_string()]),
+            Err(e) => return Err(e),
+        };
+        let messages = rows
+            .iter()
+            .filter_map(|row| row.try_get_by_index::<String>(0).ok())
+            .filter(|message| message != "ok")
+            .collect();
+        Ok(messages)
+    }
+
+    /// Delete orphans and the entries of directories with duplicates, which
+    /// are listed again on next use.
+    async fn repair_tree(&self, report: &IntegrityReport) -> std::result::Result<(), DbErr> {
+        let txn = self.db.begin().await?;
+        for batch in report.orphans.chunks(500) {
+            entity::Entity::delete_many()
+                .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+                .filter(entity::Column::FileId.is_in(batch.iter().copied()))
+                .exec(&txn)
+                .await?;
+        }
+        for (parent_id, _) in &report.duplicates {
+            entity::Entity::delete_many()
+                .filter(entity::Column::Repo.eq(self.namespace.as_str()))
+                .filter(entity::Column::ParentId.eq(*parent_id))
+                .exec(&txn)
+                .await?;
+            loaded_dir::Entity::delete_by_id((self.namespace.clone(), *parent_id))
+                .exec(&txn)
+                .await?;
+        }
+        txn.commit().await
+    }
+}
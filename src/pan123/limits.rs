@@ -0,0 +1,36 @@
+//! Concurrency limits per class of upstream request.
+//!
+//! Uploads, downloads and API calls (listings, mkdir, moves, deletes, upload
+//! bookkeeping) each get their own semaphore, so a large restore saturating
+//! the download slots cannot hold up the API calls a concurrent backup needs.
+
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limit on concurrent requests of one class, shared by clones.
+#[derive(Debug, Clone, Default)]
+pub struct ClassLimit(Option<Arc<Semaphore>>);
+
+impl ClassLimit {
+    /// Allow `max_concurrent` requests at once; 0 for unlimited.
+    pub fn new(max_concurrent: usize) -> Self {
+        Self((max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))))
+    }
+
+    /// Wait for a slot, held until the returned permit is dropped.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.0 {
+            // The semaphore is never closed
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+}
+
+/// Concurrency limits of a [`super::Pan123Client`]; 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    pub uploads: usize,
+    pub downloads: usize,
+    pub api_calls: usize,
+}
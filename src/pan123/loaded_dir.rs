@@ -0,0 +1,19 @@
+use sea_orm::entity::prelude::*;
+
+/// Directory whose listing has been fetched into the cache. The table is
+/// defined by `crate::migration`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "loaded_dirs")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repo: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub dir_id: i64,
+    /// Unix time the listing was fetched
+    pub loaded_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
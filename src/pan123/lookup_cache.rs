@@ -0,0 +1,166 @@
+//! In-process LRU of cached nodes in front of the SQLite cache.
+//!
+//! HEAD/GET of small objects (lock files, keys) is dominated by the database
+//! lookups resolving each path segment. Hot `(parent_id, name)` lookups are
+//! answered from memory instead; the database stays the source of truth and
+//! every cache mutation updates or invalidates the affected entries.
+//! Only existing nodes are kept, so a miss always falls through to the
+//! database.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use super::FileInfo;
+
+/// Default number of entries kept.
+pub const DEFAULT_LOOKUP_CACHE_ENTRIES: usize = 10_000;
+
+type Key = (i64, String);
+
+/// Bounded map of `(parent_id, name)` to node.
+#[derive(Debug, Clone)]
+pub struct LookupCache {
+    inner: Arc<Mutex<Inner>>,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// key -> (node, last use tick)
+    entries: HashMap<Key, (FileInfo, u64)>,
+    /// last use tick -> key, oldest first
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    /// Bumped by every mutation, so a lookup that raced with one doesn't
+    /// insert what it read before the change
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Lookup cache counters.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LookupCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl LookupCache {
+    /// Create a cache holding up to `capacity` entries (0 disables it).
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::default())),
+            capacity,
+        }
+    }
+
+    /// Look up a node, returning the generation to pass to [`Self::fill`]
+    /// on a miss.
+    pub fn get(&self, parent_id: i64, name: &str) -> Result<FileInfo, u64> {
+        let mut guard = self.inner.lock();
+        let inner = &mut *guard;
+        let key = (parent_id, name.to_string());
+        inner.tick += 1;
+        let tick = inner.tick;
+        match inner.entries.get_mut(&key) {
+            Some((file, last_used)) => {
+                let file = file.clone();
+                let old_tick = std::mem::replace(last_used, tick);
+                inner.order.remove(&old_tick);
+                inner.order.insert(tick, key);
+                inner.hits += 1;
+                Ok(file)
+            }
+            None => {
+                inner.misses += 1;
+                Err(inner.generation)
+            }
+        }
+    }
+
+    /// Insert a node read from the database, unless the cache was mutated
+    /// since `generation` was returned by [`Self::get`].
+    pub fn fill(&self, generation: u64, parent_id: i64, file: FileInfo) {
+        let mut inner = self.inner.lock();
+        if inner.generation == generation {
+            inner.insert(parent_id, file, self.capacity);
+        }
+    }
+
+    /// Record a node written to the database.
+    pub fn put(&self, parent_id: i64, file: FileInfo) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.insert(parent_id, file, self.capacity);
+    }
+
+    /// Forget nodes with any of `file_ids` (deleted or moved).
+    pub fn remove_ids(&self, file_ids: &[i64]) {
+        let file_ids: HashSet<i64> = file_ids.iter().copied().collect();
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.retain(|_, cached| !file_ids.contains(&cached.file_id));
+    }
+
+    /// Forget the children of `parent_id` (its listing was replaced).
+    pub fn remove_children(&self, parent_id: i64) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.retain(|key, _| key.0 != parent_id);
+    }
+
+    /// Forget everything.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.generation += 1;
+        inner.entries.clear();
+        inner.order.clear();
+    }
+
+    pub fn stats(&self) -> LookupCacheStats {
+        let inner = self.inner.lock();
+        LookupCacheStats {
+            entries: inner.entries.len(),
+            capacity: self.capacity,
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, parent_id: i64, file: FileInfo, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let key = (parent_id, file.filename.clone());
+        if let Some((_, old_tick)) = self.entries.insert(key.clone(), (file, tick)) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(tick, key);
+
+        while self.entries.len() > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Key, &FileInfo) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (file, tick)| {
+            let kept = keep(key, file);
+            if !kept {
+                order.remove(tick);
+            }
+            kept
+        });
+    }
+}
@@ -0,0 +1,375 @@
+//! Usage counters for the 123pan API.
+//!
+//! Calls, rate limiting, retries and error codes are counted per endpoint
+//! (the request path), transfers in bytes, so it is visible how close the
+//! server runs to 123pan's rate limits. Exposed at `/metrics` and logged
+//! periodically (see [`ApiMetrics::spawn_summary`]).
+//!
+//! [`CacheMetrics`] counts cache hits and misses per restic type directory,
+//! to check whether caching actually saves API calls.
+//!
+//! [`UpstreamTimings`] collects the time one REST request spent waiting on
+//! 123pan, so slow requests can be attributed to the calls behind them.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counters of one endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EndpointStats {
+    pub calls: u64,
+    /// Responses with code 429
+    pub rate_limited: u64,
+    /// Calls repeated after a 429 or 401
+    pub retries: u64,
+    /// Requests that got no API response (connection errors, timeouts)
+    pub transport_errors: u64,
+    /// Responses by non-zero API code
+    pub errors: BTreeMap<i32, u64>,
+}
+
+/// Counters at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ApiMetricsSnapshot {
+    pub endpoints: BTreeMap<String, EndpointStats>,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+}
+
+impl ApiMetricsSnapshot {
+    fn total(&self, field: impl Fn(&EndpointStats) -> u64) -> u64 {
+        self.endpoints.values().map(field).sum()
+    }
+}
+
+/// Per-endpoint Prometheus counter: name, help text and value.
+type Counter = (&'static str, &'static str, fn(&EndpointStats) -> u64);
+
+#[derive(Debug, Default)]
+struct Inner {
+    endpoints: Mutex<BTreeMap<String, EndpointStats>>,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+}
+
+/// 123pan API usage counters, shared by clones.
+#[derive(Debug, Clone, Default)]
+pub struct ApiMetrics {
+    inner: Arc<Inner>,
+}
+
+impl ApiMetrics {
+    fn update(&self, endpoint: &str, f: impl FnOnce(&mut EndpointStats)) {
+        let mut endpoints = self.inner.endpoints.lock();
+        match endpoints.get_mut(endpoint) {
+            Some(stats) => f(stats),
+            None => f(endpoints.entry(endpoint.to_string()).or_default()),
+        }
+    }
+
+    /// Count a response with API `code` from `endpoint`.
+    pub fn record_response(&self, endpoint: &str, code: i32) {
+        self.update(endpoint, |stats| {
+            stats.calls += 1;
+            if code == 429 {
+                stats.rate_limited += 1;
+            }
+            if code != 0 {
+                *stats.errors.entry(code).or_default() += 1;
+            }
+        });
+    }
+
+    /// Count a request to `endpoint` that got no API response.
+    pub fn record_transport_error(&self, endpoint: &str) {
+        self.update(endpoint, |stats| {
+            stats.calls += 1;
+            stats.transport_errors += 1;
+        });
+    }
+
+    /// Count a call to `endpoint` being repeated.
+    pub fn record_retry(&self, endpoint: &str) {
+        self.update(endpoint, |stats| stats.retries += 1);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.inner
+            .bytes_uploaded
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.inner
+            .bytes_downloaded
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ApiMetricsSnapshot {
+        ApiMetricsSnapshot {
+            endpoints: self.inner.endpoints.lock().clone(),
+            bytes_uploaded: self.inner.bytes_uploaded.load(Ordering::Relaxed),
+            bytes_downloaded: self.inner.bytes_downloaded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counters in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        let counters: [Counter; 4] = [
+            ("pan123_api_calls_total", "123pan API calls", |s| s.calls),
+            (
+                "pan123_api_rate_limited_total",
+                "123pan API calls answered with 429",
+                |s| s.rate_limited,
+            ),
+            (
+                "pan123_api_retries_total",
+                "123pan API calls repeated after 429 or 401",
+                |s| s.retries,
+            ),
+            (
+                "pan123_api_transport_errors_total",
+                "123pan API calls without a response",
+                |s| s.transport_errors,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for (endpoint, stats) in &snapshot.endpoints {
+                let _ = writeln!(
+                    out,
+                    "{}{{endpoint=\"{}\"}} {}",
+                    name,
+                    endpoint,
+                    value(stats)
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "# HELP pan123_api_errors_total 123pan API responses by error code\n\
+             # TYPE pan123_api_errors_total counter"
+        );
+        for (endpoint, stats) in &snapshot.endpoints {
+            for (code, count) in &stats.errors {
+                let _ = writeln!(
+                    out,
+                    "pan123_api_errors_total{{endpoint=\"{}\",code=\"{}\"}} {}",
+                    endpoint, code, count
+                );
+            }
+        }
+        for (name, help, value) in [
+            (
+                "pan123_uploaded_bytes_total",
+                "Bytes uploaded to 123pan",
+                snapshot.bytes_uploaded,
+            ),
+            (
+                "pan123_downloaded_bytes_total",
+                "Bytes downloaded from 123pan",
+                snapshot.bytes_downloaded,
+            ),
+        ] {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                name, help, name, name, value
+            );
+        }
+        out
+    }
+
+    /// Log the usage of each `interval` in the background.
+    pub fn spawn_summary(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let metrics = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+            let mut previous = metrics.snapshot();
+            loop {
+                ticker.tick().await;
+                let current = metrics.snapshot();
+                let calls = current.total(|s| s.calls) - previous.total(|s| s.calls);
+                if calls > 0 {
+                    tracing::info!(
+                        "123pan API usage in the last {:?}: {} calls ({} rate limited, {} retries, {} transport errors), {} MiB up, {} MiB down",
+                        interval,
+                        calls,
+                        current.total(|s| s.rate_limited) - previous.total(|s| s.rate_limited),
+                        current.total(|s| s.retries) - previous.total(|s| s.retries),
+                        current.total(|s| s.transport_errors)
+                            - previous.total(|s| s.transport_errors),
+                        (current.bytes_uploaded - previous.bytes_uploaded) / (1024 * 1024),
+                        (current.bytes_downloaded - previous.bytes_downloaded) / (1024 * 1024),
+                    );
+                }
+                previous = current;
+            }
+        })
+    }
+}
+
+tokio::task_local! {
+    /// Timings of the REST request the current task works for
+    static REQUEST_TIMINGS: Arc<UpstreamTimings>;
+}
+
+/// Calls to 123pan made on behalf of one REST request: count and summed
+/// duration by endpoint. Calls made in parallel add up, so the total can
+/// exceed the request's own duration.
+#[derive(Debug, Default)]
+pub struct UpstreamTimings {
+    endpoints: Mutex<BTreeMap<String, (u64, Duration)>>,
+}
+
+impl UpstreamTimings {
+    /// Run `fut`, recording the 123pan calls it makes in `self`.
+    pub async fn scope<F: Future>(self: Arc<Self>, fut: F) -> F::Output {
+        REQUEST_TIMINGS.scope(self, fut).await
+    }
+
+    /// Make `fut`, about to be spawned, record its calls for the request the
+    /// current task works for, if any.
+    pub fn propagate<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+        let timings = REQUEST_TIMINGS.try_with(Arc::clone).ok();
+        async move {
+            match timings {
+                Some(timings) => timings.scope(fut).await,
+                None => fut.await,
+            }
+        }
+    }
+
+    /// Record a call to `endpoint` that took `elapsed`. Does nothing outside
+    /// a request, e.g. in background jobs.
+    pub fn record(endpoint: &str, elapsed: Duration) {
+        let _ = REQUEST_TIMINGS.try_with(|timings| {
+            let mut endpoints = timings.endpoints.lock();
+            let entry = match endpoints.get_mut(endpoint) {
+                Some(entry) => entry,
+                None => endpoints.entry(endpoint.to_string()).or_default(),
+            };
+            entry.0 += 1;
+            entry.1 += elapsed;
+        });
+    }
+
+    /// Record the time until the returned guard is dropped as a call to
+    /// `endpoint`.
+    pub fn start(endpoint: &'static str) -> UpstreamCall {
+        UpstreamCall {
+            endpoint,
+            start: Instant::now(),
+        }
+    }
+
+    /// Number of calls made.
+    pub fn calls(&self) -> u64 {
+        self.endpoints.lock().values().map(|(calls, _)| calls).sum()
+    }
+
+    /// Summed duration of all calls.
+    pub fn total(&self) -> Duration {
+        self.endpoints.lock().values().map(|(_, time)| *time).sum()
+    }
+
+    /// Calls by endpoint, slowest first, e.g.
+    /// `/api/v2/file/list 3x 120ms, download 1x 800ms`.
+    pub fn summary(&self) -> String {
+        let mut endpoints: Vec<_> = self
+            .endpoints
+            .lock()
+            .iter()
+            .map(|(endpoint, &(calls, time))| (endpoint.clone(), calls, time))
+            .collect();
+        endpoints.sort_by_key(|&(_, _, time)| std::cmp::Reverse(time));
+        endpoints
+            .iter()
+            .map(|(endpoint, calls, time)| {
+                format!("{} {}x {}ms", endpoint, calls, time.as_millis())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A call in progress, see [`UpstreamTimings::start`].
+#[derive(Debug)]
+pub struct UpstreamCall {
+    endpoint: &'static str,
+    start: Instant,
+}
+
+impl Drop for UpstreamCall {
+    fn drop(&mut self) {
+        UpstreamTimings::record(self.endpoint, self.start.elapsed());
+    }
+}
+
+/// Hits and misses of one cache for one type directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cache hit/miss counters by cache and type directory, shared by clones.
+#[derive(Debug, Clone, Default)]
+pub struct CacheMetrics {
+    counters: Arc<Mutex<BTreeMap<(&'static str, String), CacheStats>>>,
+}
+
+impl CacheMetrics {
+    /// Count a request to `cache` for an object under `path`, a
+    /// repository-relative path whose first component is the type directory.
+    pub fn record(&self, cache: &'static str, path: &str, hit: bool) {
+        let type_dir = path.trim_start_matches('/').split('/').next().unwrap_or("");
+        let mut counters = self.counters.lock();
+        let stats = match counters.get_mut(&(cache, type_dir.to_string())) {
+            Some(stats) => stats,
+            None => counters.entry((cache, type_dir.to_string())).or_default(),
+        };
+        if hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+
+    /// Counters of `cache` by type directory.
+    pub fn stats(&self, cache: &str) -> BTreeMap<String, CacheStats> {
+        self.counters
+            .lock()
+            .iter()
+            .filter(|((name, _), _)| *name == cache)
+            .map(|((_, type_dir), stats)| (type_dir.clone(), *stats))
+            .collect()
+    }
+
+    /// Counters as the Prometheus counter `name`, labeled by cache, type
+    /// directory and result.
+    pub fn render_prometheus(&self, name: &str, help: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for ((cache, type_dir), stats) in self.counters.lock().iter() {
+            for (result, value) in [("hit", stats.hits), ("miss", stats.misses)] {
+                let _ = writeln!(
+                    out,
+                    "{}{{cache=\"{}\",type=\"{}\",result=\"{}\"}} {}",
+                    name, cache, type_dir, result, value
+                );
+            }
+        }
+        out
+    }
+}
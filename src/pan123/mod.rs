@@ -2,18 +2,67 @@ use std::time::Duration;
 
 pub const MAX_RETRIES: usize = 3;
 pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait honored when 123pan asks to retry a rate-limited call later.
+pub const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+pub const MAX_DOWNLOAD_RESUMES: usize = 5;
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest file accepted by the single-request upload API.
+pub const MAX_SINGLE_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+/// Default longest wait for 123pan to finalize a slice upload.
+pub const UPLOAD_COMPLETE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Maximum page size accepted by the 123pan file list API.
+pub const MAX_LIST_PAGE_SIZE: u32 = 100;
+/// How long deleted files are kept out of listings that still show them.
+pub const TOMBSTONE_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Default age after which the list of upload domains is fetched again.
+pub const UPLOAD_DOMAIN_TTL: Duration = Duration::from_secs(60 * 60);
+/// Longest wait between scans for expired directory listings.
+pub const REVALIDATION_INTERVAL: Duration = Duration::from_secs(60);
 
 pub mod auth;
+mod backend;
+pub mod builder;
+pub mod cache_backup;
 pub mod client;
+mod delete_queue;
+pub mod direct;
+pub mod doctor;
 pub mod entity;
+pub mod gc;
+pub mod integrity;
+pub mod limits;
+pub mod loaded_dir;
+pub mod lookup_cache;
+pub mod metrics;
+mod multipart;
+pub mod names;
+pub mod pending_delete;
+pub mod pool;
+pub mod preflight;
+pub mod progress;
+pub mod rate_limit;
+mod relayout;
+pub mod server_lock;
+pub mod singleflight;
+pub mod throttle;
+pub mod timeouts;
+pub mod tombstone;
 pub mod types;
+pub mod upload_domains;
+pub mod upload_session;
 
 #[cfg(test)]
 mod tests;
 
+pub use builder::Pan123ClientBuilder;
 pub use client::Pan123Client;
+pub use direct::DirectBackend;
+pub use limits::ConcurrencyLimits;
+pub use names::{decode_name, encode_name, validate_filename, Normalization};
+pub use pool::PoolOptions;
+pub use timeouts::Timeouts;
 pub use types::{
     AccessTokenData, AccessTokenRequest, ApiResponse, CreateDirData, CreateDirRequest,
-    DeleteRequest, DownloadInfoData, FileInfo, FileListData, MoveRequest, SingleUploadData,
-    TrashRequest,
+    DeleteRequest, DownloadInfoData, ErrorKind, FileDetailData, FileInfo, FileListData,
+    MoveRequest, SingleUploadData, TrashRequest, UserInfoData,
 };
@@ -0,0 +1,338 @@
+//! Slice uploads for large files, resumable across restarts.
+//!
+//! 123pan accepts a file in numbered slices under a preupload ID. The ID and
+//! the slices already accepted are recorded in `upload_sessions`, so when the
+//! same content is uploaded again after a failure or restart (restic retries
+//! the POST, the spool re-sends its copy), only the missing slices are sent.
+//! Completion may be asynchronous; it is polled for until 123pan has
+//! finalized the file, and only then is the file cached.
+
+use bytes::Bytes;
+use reqwest::multipart::{Form, Part};
+use sea_orm::{sea_query::OnConflict, ColumnTrait, EntityTrait, QueryFilter};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use super::client::DUPLICATE_FAIL;
+use super::types::{
+    ApiResponse, CreateUploadData, CreateUploadRequest, UploadCompleteData, UploadCompleteRequest,
+};
+use super::{upload_session, Pan123Client};
+use crate::error::{AppError, Result};
+
+/// Age after which a recorded upload is started over instead of resumed.
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// First wait before asking again whether an upload has been finalized;
+/// doubled after each poll up to [`MAX_COMPLETE_POLL_INTERVAL`].
+const COMPLETE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_COMPLETE_POLL_INTERVAL: Duration = Duration::from_secs(8);
+
+impl Pan123Client {
+    /// Upload `data` in slices, resuming an earlier upload of the same
+    /// content to the same place if one is recorded. Returns the file ID.
+    pub(super) async fn upload_sliced(
+        &self,
+        parent_id: i64,
+        filename: &str,
+        etag: &str,
+        data: &Bytes,
+        duplicate: i32,
+    ) -> Result<i64> {
+        if let Some(session) = self
+            .resumable_session(parent_id, filename, etag, data.len() as i64)
+            .await?
+        {
+            tracing::info!(
+                "Resuming upload of '{}' ({} slices already uploaded)",
+                filename,
+                completed_parts(&session).len()
+            );
+            match self.upload_slices(session, data).await {
+                // 123pan rejected the session, e.g. the preupload ID expired
+                Err(e @ AppError::Pan123Api { code, .. }) if code > 0 => {
+                    tracing::warn!(
+                        "Resuming upload of '{}' failed, restarting: {}",
+                        filename,
+                        e
+                    );
+                    self.forget_session(parent_id, filename).await?;
+                }
+                result => return result,
+            }
+        }
+
+        let create = CreateUploadRequest {
+            parent_file_id: parent_id,
+            filename: filename.to_string(),
+            etag: etag.to_string(),
+            size: data.len() as i64,
+            duplicate,
+        };
+        let url = format!("{}/upload/v2/file/create", self.token_manager.base_url());
+        let response: ApiResponse<CreateUploadData> = self.post(&url, &create).await?;
+        if !response.is_success() {
+            if response.is_name_conflict() {
+                self.reconcile_dir(parent_id).await?;
+                if duplicate == DUPLICATE_FAIL {
+                    return Err(AppError::Forbidden(format!(
+                        "'{}' already exists",
+                        filename
+                    )));
+                }
+            }
+            return Err(AppError::Pan123Api {
+                code: response.code,
+                message: response.message,
+            });
+        }
+        let created = response
+            .data
+            .ok_or_else(|| AppError::Internal("No data in upload create response".to_string()))?;
+        if created.reuse {
+            tracing::info!("123pan already has the content of '{}'", filename);
+            return Ok(created.file_id);
+        }
+        if created.slice_size <= 0 {
+            return Err(AppError::Internal(format!(
+                "Invalid slice size {} in upload create response",
+                created.slice_size
+            )));
+        }
+        let server = match created.servers.into_iter().next() {
+            Some(server) => server,
+            None => self.get_upload_domain().await?,
+        };
+
+        let session = upload_session::Model {
+            repo: self.namespace.clone(),
+            parent_id,
+            name: filename.to_string(),
+            etag: etag.to_string(),
+            size: data.len() as i64,
+            preupload_id: created.preupload_id,
+            slice_size: created.slice_size,
+            server,
+            completed_parts: String::new(),
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        let model = upload_session::ActiveModel::from(session.clone());
+        self.writes
+            .run(|| {
+                upload_session::Entity::insert(model.clone())
+                    .on_conflict(
+                        OnConflict::columns([
+                            upload_session::Column::Repo,
+                            upload_session::Column::ParentId,
+                            upload_session::Column::Name,
+                        ])
+                        .update_columns([
+                            upload_session::Column::Etag,
+                            upload_session::Column::Size,
+                            upload_session::Column::PreuploadId,
+                            upload_session::Column::SliceSize,
+                            upload_session::Column::Server,
+                            upload_session::Column::CompletedParts,
+                            upload_session::Column::CreatedAt,
+                        ])
+                        .to_owned(),
+                    )
+                    .exec_without_returning(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to record upload: {}", e)))?;
+
+        self.upload_slices(session, data).await
+    }
+
+    /// Send the slices not uploaded yet and finish the upload.
+    async fn upload_slices(&self, mut session: upload_session::Model, data: &Bytes) -> Result<i64> {
+        let mut done = completed_parts(&session);
+        let slice_size = session.slice_size as usize;
+        let slices = data.len().div_ceil(slice_size);
+        for index in 0..slices {
+            let part = index as i64 + 1;
+            if done.contains(&part) {
+                continue;
+            }
+            let start = index * slice_size;
+            let slice = data.slice(start..(start + slice_size).min(data.len()));
+            self.upload_slice(&session, part, slice).await?;
+
+            done.insert(part);
+            session.completed_parts = done
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            self.writes
+                .run(|| {
+                    upload_session::Entity::update_many()
+                        .col_expr(
+                            upload_session::Column::CompletedParts,
+                            session.completed_parts.clone().into(),
+                        )
+                        .filter(upload_session::Column::Repo.eq(session.repo.as_str()))
+                        .filter(upload_session::Column::ParentId.eq(session.parent_id))
+                        .filter(upload_session::Column::Name.eq(session.name.as_str()))
+                        .exec(&self.db)
+                })
+                .await
+                .map_err(|e| AppError::Internal(format!("Failed to record slice: {}", e)))?;
+        }
+
+        let file_id = self.complete_upload(&session).await?;
+        self.forget_session(session.parent_id, &session.name)
+            .await?;
+        Ok(file_id)
+    }
+
+    /// Finish the upload. 123pan verifies large files asynchronously and
+    /// answers `completed: false` until it is done, so ask again with growing
+    /// intervals for up to `upload_complete_timeout`. The session is kept on
+    /// failure, so the next attempt only asks again.
+    async fn complete_upload(&self, session: &upload_session::Model) -> Result<i64> {
+        let url = format!(
+            "{}/upload/v2/file/upload_complete",
+            self.token_manager.base_url()
+        );
+        let request = UploadCompleteRequest {
+            preupload_id: session.preupload_id.clone(),
+        };
+        let deadline = Instant::now() + self.upload_complete_timeout;
+        let mut interval = COMPLETE_POLL_INTERVAL;
+        loop {
+            let response: ApiResponse<UploadCompleteData> = self.post(&url, &request).await?;
+            if !response.is_success() {
+                return Err(AppError::Pan123Api {
+                    code: response.code,
+                    message: response.message,
+                });
+            }
+            let completed = response.data.ok_or_else(|| {
+                AppError::Internal("No data in upload complete response".to_string())
+            })?;
+            if completed.completed {
+                return Ok(completed.file_id);
+            }
+            if Instant::now() + interval > deadline {
+                return Err(AppError::Timeout(format!(
+                    "123pan did not finalize the upload of '{}' within {:?}",
+                    session.name, self.upload_complete_timeout
+                )));
+            }
+            tracing::debug!(
+                "Upload of '{}' is being finalized, asking again in {:?}",
+                session.name,
+                interval
+            );
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(MAX_COMPLETE_POLL_INTERVAL);
+        }
+    }
+
+    async fn upload_slice(
+        &self,
+        session: &upload_session::Model,
+        part: i64,
+        slice: Bytes,
+    ) -> Result<()> {
+        let url = format!("{}/upload/v2/file/slice", session.server);
+        let slice_md5 = format!("{:x}", md5::compute(&slice));
+        let permit = self.upload_limit.acquire().await;
+        let result: Result<ApiResponse<serde_json::Value>> = self
+            .retry_api(|token| {
+                let form = Form::new()
+                    .text("preuploadID", session.preupload_id.clone())
+                    .text("sliceNo", part.to_string())
+                    .text("sliceMD5", slice_md5.clone())
+                    .part(
+                        "slice",
+                        Part::stream_with_length(
+                            self.upload_body(&session.name, slice.clone()),
+                            slice.len() as u64,
+                        )
+                        .file_name(format!("{}.part{}", session.name, part)),
+                    );
+                self.token_manager
+                    .transfer_client()
+                    .post(&url)
+                    .timeout(self.token_manager.timeouts().upload_for(slice.len() as u64))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .header("Platform", "open_platform")
+                    .multipart(form)
+                    .send()
+            })
+            .await;
+        drop(permit);
+        self.report_upload(&session.server, &result);
+        let response = result?;
+        if !response.is_success() {
+            return Err(AppError::Pan123Api {
+                code: response.code,
+                message: response.message,
+            });
+        }
+        self.token_manager
+            .metrics()
+            .add_uploaded(slice.len() as u64);
+        tracing::debug!("Uploaded slice {} of '{}'", part, session.name);
+        Ok(())
+    }
+
+    /// The recorded upload of this content, unless it is stale or was for
+    /// different content (which is started over).
+    async fn resumable_session(
+        &self,
+        parent_id: i64,
+        filename: &str,
+        etag: &str,
+        size: i64,
+    ) -> Result<Option<upload_session::Model>> {
+        let session = upload_session::Entity::find_by_id((
+            self.namespace.clone(),
+            parent_id,
+            filename.to_string(),
+        ))
+        .one(&self.db)
+        .await
+        .map_err(|e| AppError::Internal(format!("DB error reading upload state: {}", e)))?;
+        let Some(session) = session else {
+            return Ok(None);
+        };
+        let age = chrono::Utc::now().timestamp() - session.created_at;
+        if session.etag != etag
+            || session.size != size
+            || session.slice_size <= 0
+            || age > SESSION_TTL.as_secs() as i64
+        {
+            self.forget_session(parent_id, filename).await?;
+            return Ok(None);
+        }
+        Ok(Some(session))
+    }
+
+    async fn forget_session(&self, parent_id: i64, filename: &str) -> Result<()> {
+        self.writes
+            .run(|| {
+                upload_session::Entity::delete_by_id((
+                    self.namespace.clone(),
+                    parent_id,
+                    filename.to_string(),
+                ))
+                .exec(&self.db)
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to delete upload state: {}", e)))?;
+        Ok(())
+    }
+}
+
+/// Numbers of the slices recorded as uploaded.
+fn completed_parts(session: &upload_session::Model) -> BTreeSet<i64> {
+    session
+        .completed_parts
+        .split(',')
+        .filter_map(|p| p.parse().ok())
+        .collect()
+}
@@ -0,0 +1,190 @@
+//! Names as stored on 123pan.
+//!
+//! 123pan rejects some names that are fine for restic and in `REPO_PATH`:
+//! reserved characters, control characters and trailing dots or spaces.
+//! Those are stored percent-encoded (`%3A` for `:`) and decoded again when
+//! listed, so they round-trip. Every other name, including all restic object
+//! names and names that already contain `%XX`, is stored as is, so existing
+//! folders and files are still found. Only escapes of characters that would
+//! have been encoded are decoded; a `%41` stays `%41`.
+//!
+//! The cache holds names as stored on 123pan; the client encodes the paths
+//! and file names it is given, and [`ObjectInfo`](crate::storage::ObjectInfo)
+//! carries decoded names.
+//!
+//! Names are compared in one Unicode normalization form, so e.g. a
+//! decomposed `REPO_PATH` typed on macOS finds the composed directory 123pan
+//! lists. The cache keeps names as 123pan lists them; only names created on
+//! 123pan are normalized.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+use crate::error::{AppError, Result};
+
+/// Unicode normalization form names are compared and created in.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Normalization {
+    /// Names are taken as they are
+    None,
+    /// Canonical composition, as 123pan lists names
+    #[default]
+    Nfc,
+    /// Canonical decomposition
+    Nfd,
+}
+
+impl Normalization {
+    /// `name` in this form.
+    pub fn apply(self, name: &str) -> Cow<'_, str> {
+        match self {
+            Normalization::Nfc if !is_nfc(name) => Cow::Owned(name.nfc().collect()),
+            Normalization::Nfd if !is_nfd(name) => Cow::Owned(name.nfd().collect()),
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    /// `name` and the forms of it a name 123pan lists may be in to compare
+    /// equal, `name` first.
+    pub fn variants(self, name: &str) -> Vec<Cow<'_, str>> {
+        let mut variants = vec![Cow::Borrowed(name)];
+        if self != Normalization::None {
+            for variant in [
+                Normalization::Nfc.apply(name),
+                Normalization::Nfd.apply(name),
+            ] {
+                if !variants.contains(&variant) {
+                    variants.push(variant);
+                }
+            }
+        }
+        variants
+    }
+
+    /// Whether `a` and `b` are the same name in this form.
+    pub fn matches(self, a: &str, b: &str) -> bool {
+        a == b || (self != Normalization::None && self.apply(a) == self.apply(b))
+    }
+}
+
+/// Characters 123pan does not allow in file names.
+const RESERVED_FILENAME_CHARS: &[char] = &['"', '\\', '/', ':', '*', '?', '|', '<', '>'];
+
+/// Maximum file name length accepted by 123pan.
+const MAX_FILENAME_LEN: usize = 255;
+
+/// Reject names that could escape the repository directory or create
+/// entries 123pan can't address: empty, `.`/`..`, path separators, control
+/// characters, and 123pan-reserved characters.
+pub fn validate_filename(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.chars().count() > MAX_FILENAME_LEN
+        || name.chars().any(needs_escape);
+    if invalid {
+        return Err(AppError::BadRequest(format!(
+            "Invalid file name: {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn needs_escape(c: char) -> bool {
+    c.is_control() || RESERVED_FILENAME_CHARS.contains(&c)
+}
+
+/// Whether `s` starts with an escape sequence, `%` and two hex digits.
+fn starts_escape(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    bytes.len() >= 3
+        && bytes[0] == b'%'
+        && bytes[1].is_ascii_hexdigit()
+        && bytes[2].is_ascii_hexdigit()
+}
+
+/// The character encoded by the escape sequences `s` starts with, and the
+/// length of those sequences.
+fn decode_escape(s: &str) -> Option<(char, usize)> {
+    let mut bytes = [0; 4];
+    for n in 0..bytes.len() {
+        let escape = s.get(n * 3..n * 3 + 3).filter(|e| starts_escape(e))?;
+        bytes[n] = u8::from_str_radix(&escape[1..], 16).ok()?;
+        if let Ok(decoded) = std::str::from_utf8(&bytes[..=n]) {
+            return decoded.chars().next().map(|c| (c, (n + 1) * 3));
+        }
+    }
+    None
+}
+
+/// Whether `s` is nothing but escaped dots and spaces, as [`encode_name`]
+/// writes the end of a name.
+fn only_escaped_trailer(s: &str) -> bool {
+    s.len().is_multiple_of(3)
+        && s.as_bytes()
+            .chunks(3)
+            .all(|e| e.eq_ignore_ascii_case(b"%2E") || e == b"%20")
+}
+
+/// Name to store `name` under on 123pan. `.`, `..` and empty names are
+/// refused, as is a name too long once encoded.
+pub fn encode_name(name: &str) -> Result<Cow<'_, str>> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(AppError::BadRequest(format!(
+            "Invalid file name: {:?}",
+            name
+        )));
+    }
+    // Trailing dots and spaces don't survive on 123pan
+    let kept = name.trim_end_matches(['.', ' ']).len();
+    let encoded = if kept == name.len() && !name.chars().any(needs_escape) {
+        Cow::Borrowed(name)
+    } else {
+        let mut encoded = String::with_capacity(name.len() + 8);
+        for (i, c) in name.char_indices() {
+            if needs_escape(c) || i >= kept {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    encoded.push_str(&format!("%{:02X}", byte));
+                }
+            } else {
+                encoded.push(c);
+            }
+        }
+        Cow::Owned(encoded)
+    };
+    validate_filename(&encoded)?;
+    Ok(encoded)
+}
+
+/// Name a 123pan entry stands for, the inverse of [`encode_name`]. Names
+/// without escape sequences it writes are returned as they are.
+pub fn decode_name(name: &str) -> Cow<'_, str> {
+    if !name.contains('%') {
+        return Cow::Borrowed(name);
+    }
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        match decode_escape(rest) {
+            Some((c, len))
+                if needs_escape(c)
+                    || (matches!(c, '.' | ' ') && only_escaped_trailer(&rest[len..])) =>
+            {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            _ => {
+                decoded.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if decoded == name {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(decoded)
+    }
+}
@@ -0,0 +1,20 @@
+use sea_orm::entity::prelude::*;
+
+/// File deleted by a client whose removal from 123pan is still queued. It is
+/// already gone from the cache; listings keep it out until it is removed.
+/// The table is defined by `crate::migration`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "pending_deletes")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repo: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub file_id: i64,
+    /// Unix time the deletion was queued
+    pub queued_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,48 @@
+//! Connection reuse of the HTTP clients talking to 123pan.
+//!
+//! Both the API client and the client for upload and download domains keep
+//! idle connections per host, so that on high-latency links consecutive
+//! requests skip the TCP and TLS handshakes. HTTP/2 is negotiated over TLS
+//! where the server offers it, multiplexing requests on one connection.
+
+use reqwest::ClientBuilder;
+use std::time::Duration;
+
+/// Connection pool settings of the HTTP clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Idle connections kept per host
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept
+    pub idle_timeout: Duration,
+    /// Negotiate HTTP/2; HTTP/1.1 only when off
+    pub http2: bool,
+    /// TCP keepalive probe interval of open connections (`None` disables)
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 32,
+            idle_timeout: Duration::from_secs(90),
+            http2: true,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl PoolOptions {
+    /// Apply the settings to a client under construction.
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        let builder = builder
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .pool_idle_timeout(self.idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+        if self.http2 {
+            builder
+        } else {
+            builder.http1_only()
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Checks run before the server starts listening.
+//!
+//! Bad credentials, a full account, an unusable repository path or a
+//! read-only cache database would otherwise only show up as errors on
+//! restic's first request; checking them up front lets the server refuse to
+//! start with a message saying what to fix.
+
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, TransactionTrait};
+
+use super::{tombstone, Pan123Client, UserInfoData};
+use crate::error::{AppError, Result};
+
+/// A failed [`Pan123Client::preflight`] check.
+#[derive(Debug, thiserror::Error)]
+pub enum PreflightError {
+    #[error(
+        "Could not get an access token from {base_url}: {source}. Check PAN123_CLIENT_ID and \
+         PAN123_CLIENT_SECRET, and that the API is reachable (PAN123_API_BASE_URL, PROXY_URL)"
+    )]
+    Credentials { base_url: String, source: AppError },
+
+    #[error(
+        "The 123pan account is full ({used} of {total} bytes used); free up space or \
+         extend the account before backing up"
+    )]
+    AccountFull { used: u64, total: u64 },
+
+    #[error(
+        "Repository path {path} is not reachable and could not be created: {source}. \
+         Check PAN123_REPO_PATH"
+    )]
+    RepoPath { path: String, source: AppError },
+
+    #[error(
+        "Cache database is not writable: {0}. Check the permissions of DB_PATH or the \
+         DATABASE_URL user"
+    )]
+    Database(AppError),
+
+    #[error("Preflight check failed: {0}")]
+    Other(#[from] AppError),
+}
+
+/// What a passed [`Pan123Client::preflight`] found.
+#[derive(Debug, Clone)]
+pub struct PreflightReport {
+    pub account: UserInfoData,
+    /// Whether the repository directory had to be created (as a repository
+    /// with `init_repository`)
+    pub repo_created: bool,
+}
+
+impl Pan123Client {
+    /// Get the account's user info, including its storage space.
+    pub async fn user_info(&self) -> Result<UserInfoData> {
+        let url = format!("{}/api/v1/user/info", self.token_manager.base_url());
+        let response: super::ApiResponse<UserInfoData> = self.get(&url).await?;
+        if !response.is_success() {
+            return Err(AppError::Pan123Api {
+                code: response.code,
+                message: response.message,
+            });
+        }
+        response
+            .data
+            .ok_or_else(|| AppError::Internal("No user info in response".to_string()))
+    }
+
+    /// Check, in order, that an access token can be obtained, that the
+    /// account has space left, that the repository directory exists and that
+    /// the cache database accepts writes. A missing repository directory is
+    /// created, with the whole repository structure if `init_repository`.
+    pub async fn preflight(
+        &self,
+        init_repository: bool,
+    ) -> std::result::Result<PreflightReport, PreflightError> {
+        self.check_token()
+            .await
+            .map_err(|source| PreflightError::Credentials {
+                base_url: self.token_manager.base_url().to_string(),
+                source,
+            })?;
+
+        let account = self.user_info().await?;
+        let total = account.space_total();
+        if total > 0 && account.space_used >= total {
+            return Err(PreflightError::AccountFull {
+                used: account.space_used,
+                total,
+            });
+        }
+
+        let repo_created = match self.find_path_id(&self.repo_path).await {
+            Ok(Some(_)) => false,
+            Ok(None) => {
+                let created = if init_repository {
+                    self.init_repository().await
+                } else {
+                    self.ensure_path(&self.repo_path).await.map(drop)
+                };
+                created.map_err(|source| PreflightError::RepoPath {
+                    path: self.repo_path.clone(),
+                    source,
+                })?;
+                true
+            }
+            Err(source) => {
+                return Err(PreflightError::RepoPath {
+                    path: self.repo_path.clone(),
+                    source,
+                })
+            }
+        };
+
+        self.check_db_writable()
+            .await
+            .map_err(PreflightError::Database)?;
+
+        Ok(PreflightReport {
+            account,
+            repo_created,
+        })
+    }
+
+    /// Run a write that changes nothing in a transaction that is rolled back;
+    /// read-only databases refuse it all the same.
+    async fn check_db_writable(&self) -> Result<()> {
+        let db_error = |e: sea_orm::DbErr| AppError::Internal(e.to_string());
+        let txn = self.db.begin().await.map_err(db_error)?;
+        tombstone::Entity::delete_many()
+            .filter(tombstone::Column::Repo.eq(self.namespace.as_str()))
+            .filter(tombstone::Column::FileId.eq(-1))
+            .exec(&txn)
+            .await
+            .map_err(db_error)?;
+        txn.rollback().await.map_err(db_error)
+    }
+}
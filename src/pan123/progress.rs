@@ -0,0 +1,127 @@
+//! Periodic progress logging for large transfers.
+//!
+//! Lines are logged from inside the request's tracing span, so they carry its
+//! request ID; a stalled upload shows as repeated lines without progress.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::throttle::Throttle;
+
+/// Size of the pieces an upload body is sent in, so a single large body
+/// doesn't overdraw the throttle bucket all at once and progress is seen.
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+struct State {
+    done: u64,
+    last_log: Instant,
+    logged: bool,
+}
+
+/// Progress of one transfer, shared by clones (e.g. parallel chunks).
+#[derive(Debug, Clone)]
+pub struct Progress {
+    verb: &'static str,
+    name: Arc<str>,
+    total: u64,
+    interval: Duration,
+    started: Instant,
+    state: Arc<Mutex<State>>,
+}
+
+impl Progress {
+    /// Track `total` bytes of `name`, logging at most every `interval`.
+    pub fn new(
+        verb: &'static str,
+        name: impl Into<Arc<str>>,
+        total: u64,
+        interval: Duration,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            verb,
+            name: name.into(),
+            total,
+            interval,
+            started: now,
+            state: Arc::new(Mutex::new(State {
+                done: 0,
+                last_log: now,
+                logged: false,
+            })),
+        }
+    }
+
+    /// Count `bytes` as transferred, logging if the interval has passed.
+    /// Completion is logged if any progress was.
+    pub fn advance(&self, bytes: usize) {
+        let mut state = self.state.lock();
+        state.done += bytes as u64;
+        let now = Instant::now();
+        let finished = state.done >= self.total;
+        if finished && !state.logged {
+            return;
+        }
+        if !finished && now.duration_since(state.last_log) < self.interval {
+            return;
+        }
+        state.last_log = now;
+        state.logged = true;
+
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let rate = state.done as f64 / elapsed.max(0.001) / (1024.0 * 1024.0);
+        if finished {
+            tracing::info!(
+                "{} '{}' finished: {} MiB in {:.0}s ({:.1} MiB/s)",
+                self.verb,
+                self.name,
+                self.total / (1024 * 1024),
+                elapsed,
+                rate
+            );
+        } else {
+            tracing::info!(
+                "{} '{}': {} of {} MiB ({}%), {:.1} MiB/s",
+                self.verb,
+                self.name,
+                state.done / (1024 * 1024),
+                self.total / (1024 * 1024),
+                state.done * 100 / self.total.max(1),
+                rate
+            );
+        }
+    }
+}
+
+/// Request body for `data`, sent in chunks when it is rate limited or its
+/// progress is tracked.
+pub(crate) fn upload_body(
+    data: Bytes,
+    throttle: Option<Throttle>,
+    progress: Option<Progress>,
+) -> reqwest::Body {
+    if throttle.is_none() && progress.is_none() {
+        return reqwest::Body::from(data);
+    }
+    let stream = futures_util::stream::unfold(data, move |mut rest| {
+        let throttle = throttle.clone();
+        let progress = progress.clone();
+        async move {
+            if rest.is_empty() {
+                return None;
+            }
+            let chunk = rest.split_to(rest.len().min(UPLOAD_CHUNK_SIZE));
+            if let Some(throttle) = throttle {
+                throttle.consume(chunk.len()).await;
+            }
+            if let Some(progress) = progress {
+                progress.advance(chunk.len());
+            }
+            Some((Ok::<_, std::io::Error>(chunk), rest))
+        }
+    });
+    reqwest::Body::wrap_stream(stream)
+}
@@ -0,0 +1,60 @@
+//! How long 123pan asks to wait after rate-limiting a request.
+//!
+//! The API answers throttled calls with code 429. The wait comes from a
+//! `Retry-After` header (seconds or an HTTP date), an `X-RateLimit-Reset`
+//! header (a Unix timestamp or seconds from now) or, failing both, a message
+//! such as "请求过于频繁，请10秒后再试".
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::Duration;
+
+use super::MAX_RATE_LIMIT_WAIT;
+
+/// Values of `X-RateLimit-Reset` above this are Unix timestamps, below it
+/// seconds from now.
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+/// The wait requested by a rate-limited response, capped at
+/// [`MAX_RATE_LIMIT_WAIT`]; `None` if it requests none.
+pub fn retry_after(headers: &HeaderMap, message: &str) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    header(RETRY_AFTER.as_str())
+        .and_then(parse_retry_after)
+        .or_else(|| header("x-ratelimit-reset").and_then(parse_reset))
+        .or_else(|| parse_message(message))
+        .map(|wait| wait.min(MAX_RATE_LIMIT_WAIT))
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(until(at.with_timezone(&Utc)))
+}
+
+fn parse_reset(value: &str) -> Option<Duration> {
+    let secs = value.trim().parse::<u64>().ok()?;
+    if secs < EPOCH_THRESHOLD {
+        return Some(Duration::from_secs(secs));
+    }
+    Some(until(DateTime::from_timestamp(secs as i64, 0)?))
+}
+
+/// The number of seconds in a message like "请10秒后再试".
+fn parse_message(message: &str) -> Option<Duration> {
+    let (before, _) = message.split_once('秒')?;
+    let digits: String = before
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    let secs = digits.chars().rev().collect::<String>().parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+fn until(at: DateTime<Utc>) -> Duration {
+    (at - Utc::now()).to_std().unwrap_or(Duration::ZERO)
+}
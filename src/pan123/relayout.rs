@@ -0,0 +1,107 @@
+//! Moving data packs into the shard directories of a layout.
+//!
+//! Repositories created before sharding keep their packs directly in `data/`,
+//! and a repository can be switched to another shard length or depth, or back
+//! to flat `data/` for backends that expect it. Packs are moved with the batch
+//! move API (nothing is downloaded), then the layout is recorded so later
+//! starts use it.
+
+use bytes::Bytes;
+use std::collections::BTreeMap;
+
+use sea_orm::{ColumnTrait, QueryFilter};
+
+use super::{entity, Pan123Client};
+use crate::error::{AppError, Result};
+use crate::restic::types::LAYOUT_FILE;
+
+/// Files per call accepted by the move API.
+const MOVE_BATCH: usize = 100;
+
+impl Pan123Client {
+    /// Move every data pack not stored where the client's layout puts it into
+    /// its shard directory, then record the layout in the repository.
+    /// Returns the number of packs moved.
+    pub async fn migrate_data_structure(&self) -> Result<usize> {
+        let Some(repo_id) = self.find_path_id(&self.repo_path).await? else {
+            return Err(AppError::NotFound(format!(
+                "Repository {} not found",
+                self.repo_path
+            )));
+        };
+
+        let mut targets: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+        for file in self.list_all_data_files().await? {
+            let target = self.get_data_file_dir_id(&file.filename).await?;
+            if file.parent_file_id != target {
+                targets.entry(target).or_default().push(file.file_id);
+            }
+        }
+
+        let total: usize = targets.values().map(Vec::len).sum();
+        tracing::info!("Moving {} data packs into layout {}", total, self.layout());
+        let mut moved = 0;
+        for (target, file_ids) in targets {
+            for batch in file_ids.chunks(MOVE_BATCH) {
+                self.move_files(batch.to_vec(), target).await?;
+                moved += batch.len();
+            }
+            tracing::info!("Moved {} of {} data packs", moved, total);
+        }
+
+        let data = Bytes::from(serde_json::to_vec(&self.layout())?);
+        self.upload_file(repo_id, LAYOUT_FILE, data).await?;
+        Ok(moved)
+    }
+
+    /// Move shard directories below `data/` that hold nothing to the trash,
+    /// deepest first, e.g. after migrating to a flat or shallower layout.
+    /// Returns the number of directories removed.
+    pub async fn remove_empty_shard_dirs(&self) -> Result<usize> {
+        let Some(data_id) = self
+            .find_path_id(&format!("{}/data", self.repo_path))
+            .await?
+        else {
+            return Ok(0);
+        };
+
+        // Every directory below data/, parents before their children
+        let mut dirs = Vec::new();
+        let mut pending = vec![data_id];
+        while let Some(dir_id) = pending.pop() {
+            self.ensure_loaded(dir_id).await?;
+            let children = self.child_dirs(dir_id).await?;
+            pending.extend(&children);
+            dirs.extend(children);
+        }
+
+        let mut removed = 0;
+        for &dir_id in dirs.iter().rev() {
+            let child = self
+                .nodes()
+                .filter(entity::Column::ParentId.eq(dir_id))
+                .one(&self.db)
+                .await
+                .map_err(|e| AppError::Internal(format!("DB error listing shard: {}", e)))?;
+            if child.is_none() {
+                self.trash_file(dir_id).await?;
+                removed += 1;
+            }
+        }
+        tracing::info!("Removed {} empty data shard directories", removed);
+        Ok(removed)
+    }
+
+    async fn child_dirs(&self, dir_id: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .nodes()
+            .filter(entity::Column::ParentId.eq(dir_id))
+            .filter(entity::Column::IsDir.eq(true))
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error listing shards: {}", e)))?
+            .into_iter()
+            .map(|n| n.file_id)
+            .collect())
+    }
+}
@@ -0,0 +1,204 @@
+//! Lease that keeps two servers from mutating the same repository.
+//!
+//! Each instance caches the repository tree; a second instance writing to the
+//! same `repo_path` makes both caches silently wrong. The instance holding
+//! the lease renews `{repo_path}/.server-lock` well before it expires, and
+//! others refuse to start or serve read-only until it lapses.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{FileInfo, Pan123Client};
+use crate::error::Result;
+
+/// Repository-relative name of the lease file.
+pub const SERVER_LOCK_FILE: &str = ".server-lock";
+
+/// Contents of the lease file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerLease {
+    /// Random identifier of the holding process
+    pub instance: String,
+    pub host: String,
+    pub pid: u32,
+    /// Unix time after which the lease may be taken over
+    pub expires_at: i64,
+}
+
+impl ServerLease {
+    /// Whether the holder has stopped renewing the lease.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= chrono::Utc::now().timestamp()
+    }
+}
+
+impl std::fmt::Display for ServerLease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let until = chrono::DateTime::from_timestamp(self.expires_at, 0)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_else(|| self.expires_at.to_string());
+        write!(
+            f,
+            "instance {} on {} (pid {}) until {}",
+            self.instance, self.host, self.pid, until
+        )
+    }
+}
+
+/// This process's claim on the repository lease.
+#[derive(Clone)]
+pub struct ServerLock {
+    client: Pan123Client,
+    instance: String,
+    host: String,
+    ttl: Duration,
+    /// Set while another instance holds the lease
+    read_only: Arc<AtomicBool>,
+}
+
+impl ServerLock {
+    pub fn new(client: Pan123Client, ttl: Duration) -> Self {
+        let host = std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+        let started = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        Self {
+            client,
+            instance: format!("{:x}-{:x}", std::process::id(), started),
+            host,
+            ttl,
+            read_only: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Identifier written into the lease file.
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// Flag that is set while writes must be refused.
+    pub fn read_only_flag(&self) -> Arc<AtomicBool> {
+        self.read_only.clone()
+    }
+
+    /// Take or renew the lease.
+    ///
+    /// Returns `None` once this instance holds it, or the lease of the other
+    /// live instance holding it.
+    pub async fn acquire(&self) -> Result<Option<ServerLease>> {
+        let dir_id = self
+            .client
+            .ensure_path(&self.client.repo_full_path(""))
+            .await?;
+        if let Some(current) = self.current(dir_id).await? {
+            if current.instance != self.instance && !current.is_expired() {
+                self.read_only.store(true, Ordering::Relaxed);
+                return Ok(Some(current));
+            }
+        }
+
+        let lease = ServerLease {
+            instance: self.instance.clone(),
+            host: self.host.clone(),
+            pid: std::process::id(),
+            expires_at: chrono::Utc::now().timestamp() + self.ttl.as_secs() as i64,
+        };
+        let data = Bytes::from(serde_json::to_vec(&lease)?);
+        self.client
+            .upload_file(dir_id, SERVER_LOCK_FILE, data)
+            .await?;
+
+        // Two instances starting together both overwrite the file; the one
+        // whose lease was written last wins
+        match self.current(dir_id).await? {
+            Some(current) if current.instance != self.instance => {
+                self.read_only.store(true, Ordering::Relaxed);
+                Ok(Some(current))
+            }
+            _ => {
+                self.read_only.store(false, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Delete the lease file if this instance holds it.
+    pub async fn release(&self) -> Result<()> {
+        let dir_id = self
+            .client
+            .ensure_path(&self.client.repo_full_path(""))
+            .await?;
+        let Some(file) = self.lease_file(dir_id).await? else {
+            return Ok(());
+        };
+        if self
+            .read(&file)
+            .await?
+            .is_some_and(|l| l.instance == self.instance)
+        {
+            self.client.trash_file(file.file_id).await?;
+            tracing::info!("Released repository lock");
+        }
+        Ok(())
+    }
+
+    /// Renew the lease (or keep trying to take it over) every third of its
+    /// lifetime, switching between read-only and read-write as it changes hands.
+    pub fn spawn_renewal(&self) -> tokio::task::JoinHandle<()> {
+        let lock = self.clone();
+        tokio::spawn(async move {
+            let period = (lock.ttl / 3).max(Duration::from_secs(1));
+            let mut ticker = tokio::time::interval(period);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let was_read_only = lock.read_only.load(Ordering::Relaxed);
+                match lock.acquire().await {
+                    Ok(None) if was_read_only => {
+                        tracing::info!("Acquired repository lock, accepting writes")
+                    }
+                    Ok(Some(holder)) if !was_read_only => {
+                        tracing::error!("Repository lock taken by {}, refusing writes", holder)
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Failed to renew repository lock: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Lease currently stored in `dir_id`, read past the cache.
+    async fn current(&self, dir_id: i64) -> Result<Option<ServerLease>> {
+        match self.lease_file(dir_id).await? {
+            Some(file) => self.read(&file).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn lease_file(&self, dir_id: i64) -> Result<Option<FileInfo>> {
+        Ok(self
+            .client
+            .fetch_files_from_api(dir_id)
+            .await?
+            .into_iter()
+            .find(|f| f.filename == SERVER_LOCK_FILE && !f.is_folder()))
+    }
+
+    async fn read(&self, file: &FileInfo) -> Result<Option<ServerLease>> {
+        let data = self.client.download_file(file.file_id, None).await?;
+        match serde_json::from_slice(&data) {
+            Ok(lease) => Ok(Some(lease)),
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable repository lock: {}", e);
+                Ok(None)
+            }
+        }
+    }
+}
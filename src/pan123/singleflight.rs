@@ -0,0 +1,107 @@
+//! Request coalescing: concurrent calls with the same key share one execution.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::error::{AppError, Result};
+
+/// Outcome shared with waiting callers (errors are passed on as text).
+type Shared<V> = std::result::Result<V, String>;
+
+/// Deduplicates concurrent executions of the same keyed operation.
+#[derive(Debug)]
+pub struct SingleFlight<K, V> {
+    inflight: Arc<Mutex<HashMap<K, broadcast::Sender<Shared<V>>>>>,
+}
+
+impl<K, V> Clone for SingleFlight<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inflight: self.inflight.clone(),
+        }
+    }
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+/// Removes the in-flight entry when the leading call finishes or is cancelled.
+struct LeaderGuard<'a, K: Eq + Hash, V> {
+    inflight: &'a Mutex<HashMap<K, broadcast::Sender<Shared<V>>>>,
+    key: &'a K,
+}
+
+impl<K: Eq + Hash, V> Drop for LeaderGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.inflight.lock().remove(self.key);
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Run `f` for `key`, or wait for the result of an identical call already
+    /// in progress. If the leading call is cancelled, a waiter takes over.
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> Result<V>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<V>>,
+    {
+        loop {
+            let receiver = {
+                let mut inflight = self.inflight.lock();
+                match inflight.get(&key) {
+                    Some(sender) => Some(sender.subscribe()),
+                    None => {
+                        let (sender, _) = broadcast::channel(1);
+                        inflight.insert(key.clone(), sender);
+                        None
+                    }
+                }
+            };
+
+            let Some(mut receiver) = receiver else {
+                return self.lead(&key, &f).await;
+            };
+
+            match receiver.recv().await {
+                Ok(shared) => return shared.map_err(AppError::Internal),
+                // Leader was cancelled before producing a result: try again
+                Err(_) => continue,
+            }
+        }
+    }
+
+    async fn lead<F, Fut>(&self, key: &K, f: &F) -> Result<V>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<V>>,
+    {
+        let guard = LeaderGuard {
+            inflight: &self.inflight,
+            key,
+        };
+        let result = f().await;
+
+        let sender = self.inflight.lock().remove(key);
+        std::mem::forget(guard);
+        if let Some(sender) = sender {
+            let _ = sender.send(match &result {
+                Ok(value) => Ok(value.clone()),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+        result
+    }
+}
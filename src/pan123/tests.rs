@@ -1,50 +1,664 @@
-#[cfg(test)]
-mod tests {
-    use crate::pan123::entity;
-    use crate::pan123::Pan123Client;
-    use sea_orm::prelude::*;
-    use sea_orm::EntityTrait;
-    use tempfile::NamedTempFile;
-
-    async fn setup_test_client() -> Pan123Client {
-        let db_file = NamedTempFile::new().unwrap();
-        let db_url = format!("sqlite:{}?mode=rwc", db_file.path().display());
-
-        Pan123Client::new(
-            "test_id".to_string(),
-            "test_secret".to_string(),
-            "/test_repo".to_string(),
-            &db_url,
-        )
-        .await
-        .expect("Failed to create client")
-    }
-
-    #[tokio::test]
-    async fn test_db_init() {
-        let client = setup_test_client().await;
-        // Verify table exists by querying it
-        let count = entity::Entity::find()
-            .count(&client.db)
+use crate::pan123::Pan123Client;
+use crate::pan123::{entity, loaded_dir};
+use sea_orm::prelude::*;
+use sea_orm::EntityTrait;
+use tempfile::NamedTempFile;
+
+async fn setup_test_client() -> Pan123Client {
+    let db_file = NamedTempFile::new().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", db_file.path().display());
+
+    Pan123Client::new(
+        "test_id".to_string(),
+        "test_secret".to_string(),
+        "/test_repo".to_string(),
+        &db_url,
+    )
+    .await
+    .expect("Failed to create client")
+}
+
+#[tokio::test]
+async fn test_db_init() {
+    let client = setup_test_client().await;
+    // Verify table exists by querying it
+    let count = entity::Entity::find()
+        .count(&client.db)
+        .await
+        .expect("Failed to query DB");
+    assert_eq!(count, 0);
+}
+
+/// Mark cached directories as listed so lookups don't go to the API.
+async fn mark_loaded(client: &Pan123Client, dir_ids: &[i64]) {
+    use sea_orm::Set;
+    for &dir_id in dir_ids {
+        loaded_dir::Entity::insert(loaded_dir::ActiveModel {
+            repo: Set(client.namespace.clone()),
+            dir_id: Set(dir_id),
+            loaded_at: Set(0),
+        })
+        .exec_without_returning(&client.db)
+        .await
+        .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_list_files_empty() {
+    let client = setup_test_client().await;
+    mark_loaded(&client, &[0]).await;
+    let files = client.list_files(0).await.expect("Failed to list files");
+    assert!(files.is_empty());
+}
+
+#[tokio::test]
+async fn test_find_path_id_not_found() {
+    let client = setup_test_client().await;
+    mark_loaded(&client, &[0]).await;
+    let id = client
+        .find_path_id("/nonexistent")
+        .await
+        .expect("Failed to find path");
+    assert!(id.is_none());
+}
+
+#[tokio::test]
+async fn test_health_before_warm_up() {
+    let client = setup_test_client().await;
+    client.ping_db().await.expect("DB should be reachable");
+    assert!(!client.is_cache_ready());
+}
+
+#[tokio::test]
+async fn test_single_flight_coalesces_concurrent_calls() {
+    use crate::pan123::singleflight::SingleFlight;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let flight: SingleFlight<&str, u32> = SingleFlight::default();
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let run = |flight: SingleFlight<&'static str, u32>, calls: Arc<AtomicUsize>| async move {
+        flight
+            .run("pack", || {
+                let calls = calls.clone();
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    Ok(7)
+                }
+            })
             .await
-            .expect("Failed to query DB");
-        assert_eq!(count, 0);
+    };
+
+    let (a, b) = tokio::join!(
+        run(flight.clone(), calls.clone()),
+        run(flight.clone(), calls.clone())
+    );
+    assert_eq!(a.unwrap(), 7);
+    assert_eq!(b.unwrap(), 7);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_throttle_limits_rate() {
+    use crate::pan123::throttle::Throttle;
+
+    assert!(Throttle::new(0).is_none());
+    let throttle = Throttle::new(100_000).unwrap();
+
+    // A full bucket passes a second's worth at once, then waits for refills
+    let start = std::time::Instant::now();
+    throttle.consume(100_000).await;
+    assert!(start.elapsed() < std::time::Duration::from_millis(100));
+    throttle.clone().consume(50_000).await;
+    assert!(start.elapsed() >= std::time::Duration::from_millis(400));
+}
+
+#[test]
+fn test_chunk_ranges() {
+    use crate::pan123::client::chunk_ranges;
+
+    assert_eq!(chunk_ranges(0, 9, 4), vec![(0, 3), (4, 7), (8, 9)]);
+    assert_eq!(chunk_ranges(10, 17, 4), vec![(10, 13), (14, 17)]);
+    assert_eq!(chunk_ranges(5, 5, 4), vec![(5, 5)]);
+}
+
+#[test]
+fn test_file_info_modified_at_is_utc() {
+    let file: crate::pan123::FileInfo = serde_json::from_str(
+        r#"{"fileId":1,"filename":"config","type":0,"size":155,"parentFileId":0,
+            "etag":"","updateAt":"2025-02-24 17:56:45"}"#,
+    )
+    .unwrap();
+    assert_eq!(file.etag, None);
+    assert_eq!(file.modified_at.unwrap().to_string(), "2025-02-24 09:56:45");
+}
+
+#[tokio::test]
+async fn test_filename_sanitization() {
+    use crate::pan123::validate_filename;
+
+    assert!(validate_filename("0123abcd").is_ok());
+    for name in ["", ".", "..", "a/b", "a\\b", "a:b", "a\nb", "a?b", "a<b"] {
+        assert!(validate_filename(name).is_err(), "{:?} accepted", name);
     }
 
-    #[tokio::test]
-    async fn test_list_files_empty() {
-        let client = setup_test_client().await;
-        let files = client.list_files(0).await.expect("Failed to list files");
-        assert!(files.is_empty());
+    let client = setup_test_client().await;
+    assert!(client.find_path_id("/repo/../etc").await.is_err());
+}
+
+#[test]
+fn test_name_encoding() {
+    use crate::pan123::{decode_name, encode_name};
+
+    for (name, stored) in [
+        ("0123abcd", "0123abcd"),
+        ("backups:home", "backups%3Ahome"),
+        ("v1.", "v1%2E"),
+        ("a. .", "a%2E%20%2E"),
+        ("100%", "100%"),
+        ("50%off", "50%off"),
+        ("%41", "%41"),
+        ("a%2Eb", "a%2Eb"),
+        ("tab\tname", "tab%09name"),
+        ("c1\u{85}", "c1%C2%85"),
+    ] {
+        assert_eq!(encode_name(name).unwrap(), stored);
+        assert_eq!(decode_name(stored), name);
     }
+    for name in ["", ".", ".."] {
+        assert!(encode_name(name).is_err(), "{:?} accepted", name);
+    }
+    assert!(encode_name(&":".repeat(100)).is_err());
+}
+
+#[test]
+fn test_unicode_normalization() {
+    use crate::pan123::Normalization;
+    use clap::ValueEnum;
+
+    let composed = "B\u{fc}cher";
+    let decomposed = "Bu\u{308}cher";
+    assert_eq!(Normalization::Nfc.apply(decomposed), composed);
+    assert_eq!(Normalization::Nfc.apply(composed), composed);
+    assert_eq!(Normalization::Nfd.apply(composed), decomposed);
+    assert_eq!(Normalization::None.apply(decomposed), decomposed);
+    assert_eq!(
+        Normalization::Nfc.variants(composed),
+        [composed, decomposed]
+    );
+    assert_eq!(Normalization::None.variants(composed), [composed]);
+    assert!(Normalization::Nfc.matches(composed, decomposed));
+    assert!(!Normalization::None.matches(composed, decomposed));
+    assert_eq!(
+        Normalization::from_str("nfd", false),
+        Ok(Normalization::Nfd)
+    );
+    assert!(Normalization::from_str("nfkc", false).is_err());
+}
+
+#[tokio::test]
+async fn test_credential_failover_on_rate_limit() {
+    let client = setup_test_client()
+        .await
+        .with_extra_credentials(vec![("id2".to_string(), "secret2".to_string())]);
+
+    let stats = client.credential_stats();
+    assert_eq!(stats.len(), 2);
+    assert!(stats[0].active);
+
+    assert!(client.token_manager.report_rate_limited());
+    let stats = client.credential_stats();
+    assert!(stats[1].active);
+    assert_eq!(stats[0].rate_limited, 1);
+}
+
+#[tokio::test]
+async fn test_token_refresh_lease_is_exclusive() {
+    use crate::pan123::auth::TokenManager;
+
+    let client = setup_test_client().await;
+    let first = TokenManager::new("id".to_string(), "secret".to_string(), client.db.clone());
+    let second = TokenManager::new("id".to_string(), "secret".to_string(), client.db.clone());
+
+    assert!(first.acquire_refresh_lease(0).await.unwrap());
+    assert!(!second.acquire_refresh_lease(0).await.unwrap());
+
+    first.release_refresh_lease(0).await.unwrap();
+    assert!(second.acquire_refresh_lease(0).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_invalid_proxy_url_rejected() {
+    let client = setup_test_client().await;
+    assert!(client
+        .clone()
+        .with_proxies(Some("socks5://127.0.0.1:1080"), None)
+        .is_ok());
+    assert!(client.with_proxies(Some("not a url"), None).is_err());
+}
+
+#[tokio::test]
+async fn test_custom_base_url() {
+    let client = setup_test_client()
+        .await
+        .with_base_url("http://127.0.0.1:9999/");
+    assert_eq!(client.token_manager.base_url(), "http://127.0.0.1:9999");
+}
+
+#[tokio::test]
+async fn test_builder_options() {
+    let db_file = NamedTempFile::new().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", db_file.path().display());
+
+    let client = Pan123Client::builder("id", "secret")
+        .repo_path("/custom")
+        .database_url(&db_url)
+        .base_url("http://127.0.0.1:9999")
+        .max_retries(7)
+        .page_size(50)
+        .data_shard_len(3)
+        .request_timeout(std::time::Duration::from_secs(5))
+        .pool(crate::pan123::PoolOptions {
+            http2: false,
+            tcp_keepalive: None,
+            ..Default::default()
+        })
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(client.repo_path, "/custom");
+    let timeouts = client.token_manager.timeouts();
+    assert_eq!(timeouts.api, std::time::Duration::from_secs(5));
+    assert_eq!(
+        timeouts.upload_for(3 * 1024 * 1024 + 1),
+        timeouts.upload + timeouts.upload_per_mib * 4
+    );
+    assert_eq!(client.max_retries, 7);
+    assert_eq!(client.page_size, 50);
+    assert_eq!(client.layout().data_shard_len, 3);
+    assert_eq!(client.token_manager.base_url(), "http://127.0.0.1:9999");
+
+    for builder in [
+        Pan123Client::builder("id", "secret").page_size(0),
+        Pan123Client::builder("id", "secret").page_size(101),
+        Pan123Client::builder("id", "secret").data_shard_len(65),
+        Pan123Client::builder("id", "secret").data_shard_depth(4),
+        Pan123Client::builder("id", "secret")
+            .data_shard_len(32)
+            .data_shard_depth(3),
+    ] {
+        assert!(matches!(
+            builder.database_url(&db_url).build().await,
+            Err(crate::error::AppError::BadRequest(_))
+        ));
+    }
+
+    let debug = format!("{:?}", Pan123Client::builder("id", "top-secret"));
+    assert!(!debug.contains("top-secret"));
+}
 
-    #[tokio::test]
-    async fn test_find_path_id_not_found() {
-        let client = setup_test_client().await;
-        let id = client
-            .find_path_id("/nonexistent")
+fn cached_dir(repo: &str, file_id: i64, parent_id: i64, name: &str) -> entity::ActiveModel {
+    use sea_orm::Set;
+    entity::ActiveModel {
+        repo: Set(repo.to_string()),
+        file_id: Set(file_id),
+        parent_id: Set(parent_id),
+        name: Set(name.to_string()),
+        is_dir: Set(true),
+        size: Set(0),
+        etag: Set(None),
+        updated_at: Set(chrono::Utc::now().naive_utc()),
+        modified_at: Set(None),
+    }
+}
+
+#[tokio::test]
+async fn test_cache_namespaces_share_database() {
+    let db_file = NamedTempFile::new().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", db_file.path().display());
+    let open = |namespace: &'static str| {
+        Pan123Client::builder("id", "secret")
+            .repo_path("/restic")
+            .database_url(&db_url)
+            .cache_namespace(namespace)
+            .build()
+    };
+    let alice = open("alice").await.unwrap();
+    let bob = open("bob").await.unwrap();
+
+    // Both accounts cache the same path under the root with different IDs
+    entity::Entity::insert_many([
+        cached_dir("alice", 1, 0, "restic"),
+        cached_dir("bob", 1, 0, "restic"),
+    ])
+    .exec(&alice.db)
+    .await
+    .unwrap();
+    entity::Entity::insert(cached_dir("bob", 2, 1, "keys"))
+        .exec(&bob.db)
+        .await
+        .unwrap();
+    mark_loaded(&alice, &[0]).await;
+    mark_loaded(&bob, &[0, 1]).await;
+
+    assert_eq!(bob.find_path_id("/restic/keys").await.unwrap(), Some(2));
+    assert_eq!(alice.nodes().count(&alice.db).await.unwrap(), 1);
+    assert_eq!(bob.nodes().count(&bob.db).await.unwrap(), 2);
+
+    // Dropping one namespace's entries leaves the other intact
+    entity::Entity::delete_by_id(("alice".to_string(), 1))
+        .exec(&alice.db)
+        .await
+        .unwrap();
+    assert_eq!(bob.find_path_id("/restic").await.unwrap(), Some(1));
+}
+
+#[tokio::test]
+async fn test_unnamespaced_cache_is_migrated() {
+    use sea_orm::{ConnectionTrait, Statement};
+
+    let db_file = NamedTempFile::new().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", db_file.path().display());
+    let db = sea_orm::Database::connect(&db_url).await.unwrap();
+    for sql in [
+        "CREATE TABLE file_nodes (file_id INTEGER PRIMARY KEY, parent_id INTEGER NOT NULL, \
+         name TEXT NOT NULL, is_dir BOOLEAN NOT NULL, size INTEGER NOT NULL, etag TEXT, \
+         updated_at TEXT NOT NULL)",
+        "CREATE UNIQUE INDEX idx_parent_name ON file_nodes (parent_id, name)",
+        "INSERT INTO file_nodes VALUES (7, 0, 'legacy', 1, 0, NULL, '2025-01-01 00:00:00')",
+    ] {
+        db.execute(Statement::from_string(db.get_database_backend(), sql))
+            .await
+            .unwrap();
+    }
+    db.close().await.unwrap();
+
+    let client = Pan123Client::builder("id", "secret")
+        .repo_path("/legacy")
+        .database_url(&db_url)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(client.find_path_id("/legacy").await.unwrap(), Some(7));
+    let node = entity::Entity::find_by_id(("/legacy".to_string(), 7))
+        .one(&client.db)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(node.name, "legacy");
+}
+
+fn lookup_node(file_id: i64, name: &str) -> crate::pan123::FileInfo {
+    crate::pan123::FileInfo {
+        file_id,
+        filename: name.to_string(),
+        file_type: 0,
+        size: 1,
+        parent_file_id: 1,
+        trashed: 0,
+        etag: None,
+        modified_at: None,
+    }
+}
+
+#[test]
+fn test_lookup_cache_eviction_and_invalidation() {
+    use crate::pan123::lookup_cache::LookupCache;
+
+    let cache = LookupCache::new(2);
+    cache.put(1, lookup_node(10, "a"));
+    cache.put(1, lookup_node(11, "b"));
+    assert_eq!(cache.get(1, "a").unwrap().file_id, 10);
+
+    // "b" is least recently used
+    cache.put(2, lookup_node(12, "c"));
+    assert!(cache.get(1, "b").is_err());
+    assert!(cache.get(1, "a").is_ok());
+
+    cache.remove_ids(&[10]);
+    assert!(cache.get(1, "a").is_err());
+    cache.remove_children(2);
+    assert!(cache.get(2, "c").is_err());
+
+    // A lookup that raced with a mutation doesn't insert what it read
+    let generation = cache.get(1, "d").unwrap_err();
+    cache.remove_children(1);
+    cache.fill(generation, 1, lookup_node(13, "d"));
+    assert!(cache.get(1, "d").is_err());
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.entries), (2, 0));
+
+    let disabled = LookupCache::new(0);
+    disabled.put(1, lookup_node(10, "a"));
+    assert!(disabled.get(1, "a").is_err());
+}
+
+#[tokio::test]
+async fn test_check_cache_finds_and_repairs_problems() {
+    use sea_orm::{ConnectionTrait, Statement};
+
+    let client = setup_test_client().await;
+    assert!(client.check_cache(false).await.unwrap().is_clean());
+
+    // An orphan, and a duplicate the unique index would normally prevent
+    client
+        .db
+        .execute(Statement::from_string(
+            client.db.get_database_backend(),
+            "DROP INDEX idx_repo_parent_name",
+        ))
+        .await
+        .unwrap();
+    entity::Entity::insert_many([
+        cached_dir("/test_repo", 1, 0, "repo"),
+        cached_dir("/test_repo", 2, 1, "keys"),
+        cached_dir("/test_repo", 3, 1, "keys"),
+        cached_dir("/test_repo", 4, 99, "lost"),
+    ])
+    .exec(&client.db)
+    .await
+    .unwrap();
+    mark_loaded(&client, &[0, 1]).await;
+
+    let report = client.check_cache(false).await.unwrap();
+    assert!(report.integrity_errors.is_empty());
+    assert_eq!(report.orphans, vec![4]);
+    assert_eq!(report.duplicates, vec![(1, "keys".to_string())]);
+    assert!(!report.repaired);
+
+    // Repair drops both, and the directory is listed again on next use
+    assert!(client.check_cache(true).await.unwrap().repaired);
+    assert!(client.check_cache(false).await.unwrap().is_clean());
+    assert!(!client.is_loaded(1).await.unwrap());
+    assert!(client.is_loaded(0).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_cleanup_orphans_removes_dead_subtrees() {
+    let client = setup_test_client().await;
+    // "gone" (id 2) was dropped from its parent's listing; its subtree remains
+    entity::Entity::insert_many([
+        cached_dir("/test_repo", 1, 0, "repo"),
+        cached_dir("/test_repo", 3, 2, "sub"),
+        cached_dir("/test_repo", 4, 3, "deeper"),
+        cached_dir("/test_repo", 5, 4, "deepest"),
+    ])
+    .exec(&client.db)
+    .await
+    .unwrap();
+    mark_loaded(&client, &[0, 1, 2, 3]).await;
+
+    assert_eq!(client.cleanup_orphans().await.unwrap(), 3 + 2);
+    assert_eq!(client.nodes().count(&client.db).await.unwrap(), 1);
+    assert!(client.is_loaded(1).await.unwrap());
+    assert!(!client.is_loaded(3).await.unwrap());
+    assert_eq!(client.cleanup_orphans().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_list_stream_pages_through_large_directories() {
+    use crate::storage::StorageBackend;
+    use futures_util::TryStreamExt;
+    use sea_orm::Set;
+
+    let client = setup_test_client().await;
+    let repo = client.namespace.clone();
+    let mut nodes = vec![
+        cached_dir(&repo, 1, 0, "test_repo"),
+        cached_dir(&repo, 2, 1, "data"),
+        cached_dir(&repo, 3, 2, "aa"),
+        cached_dir(&repo, 4, 2, "bb"),
+    ];
+    // Spread over two shards and more than two pages of rows
+    for i in 0..2500 {
+        let mut file = cached_dir(&repo, 100 + i, 3 + i % 2, &format!("pack-{}", i));
+        file.is_dir = Set(false);
+        file.size = Set(i);
+        nodes.push(file);
+    }
+    for chunk in nodes.chunks(500) {
+        entity::Entity::insert_many(chunk.to_vec())
+            .exec(&client.db)
             .await
-            .expect("Failed to find path");
-        assert!(id.is_none());
+            .unwrap();
     }
+    mark_loaded(&client, &[0, 1, 2, 3, 4]).await;
+
+    let objects: Vec<_> = client
+        .list_stream("data")
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(objects.len(), 2500);
+    let names: std::collections::HashSet<_> = objects.iter().map(|o| o.name.as_str()).collect();
+    assert_eq!(names.len(), 2500);
+    assert!(client.list("missing").await.unwrap().is_empty());
+}
+
+#[test]
+fn test_error_codes_map_to_http_statuses() {
+    use crate::error::AppError;
+    use crate::pan123::ErrorKind;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+
+    assert_eq!(ErrorKind::of(5066, "文件不存在"), ErrorKind::NotFound);
+    assert_eq!(ErrorKind::of(1, "网盘空间不足"), ErrorKind::QuotaExceeded);
+    assert_eq!(ErrorKind::of(1, "其他错误"), ErrorKind::Upstream);
+
+    let status = |code, message: &str| {
+        AppError::Pan123Api {
+            code,
+            message: message.to_string(),
+        }
+        .into_response()
+    };
+    assert_eq!(
+        status(1, "空间不足").status(),
+        StatusCode::INSUFFICIENT_STORAGE
+    );
+    assert_eq!(status(5066, "").status(), StatusCode::NOT_FOUND);
+    assert_eq!(status(403, "").status(), StatusCode::FORBIDDEN);
+    assert_eq!(status(2, "").status(), StatusCode::BAD_GATEWAY);
+    let limited = status(429, "操作频繁");
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(limited.headers().contains_key(header::RETRY_AFTER));
+}
+
+#[test]
+fn test_rate_limit_retry_after() {
+    use crate::pan123::rate_limit::retry_after;
+    use crate::pan123::MAX_RATE_LIMIT_WAIT;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use std::time::Duration;
+
+    let headers = |name: &'static str, value: String| {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(&value).unwrap());
+        headers
+    };
+    let none = HeaderMap::new();
+
+    assert_eq!(
+        retry_after(&headers("retry-after", "7".to_string()), ""),
+        Some(Duration::from_secs(7))
+    );
+    let date = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+    let wait = retry_after(&headers("retry-after", date), "").unwrap();
+    assert!(wait > Duration::from_secs(25) && wait <= Duration::from_secs(30));
+    let reset = (chrono::Utc::now().timestamp() + 20).to_string();
+    let wait = retry_after(&headers("x-ratelimit-reset", reset), "").unwrap();
+    assert!(wait > Duration::from_secs(15) && wait <= Duration::from_secs(20));
+    assert_eq!(
+        retry_after(&none, "请求过于频繁，请10秒后再试"),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(
+        retry_after(&headers("retry-after", "3600".to_string()), ""),
+        Some(MAX_RATE_LIMIT_WAIT)
+    );
+    assert_eq!(retry_after(&none, "操作频繁"), None);
+}
+
+#[tokio::test]
+async fn test_class_limits_are_independent() {
+    use crate::pan123::limits::ClassLimit;
+    use std::time::Duration;
+
+    let downloads = ClassLimit::new(1);
+    let api = ClassLimit::new(1);
+    let held = downloads.acquire().await;
+    assert!(held.is_some());
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), downloads.acquire())
+            .await
+            .is_err()
+    );
+    // A saturated class doesn't hold up another
+    assert!(api.acquire().await.is_some());
+    drop(held);
+    assert!(downloads.acquire().await.is_some());
+    assert!(ClassLimit::new(0).acquire().await.is_none());
+}
+
+#[tokio::test]
+async fn test_upstream_timings_follow_the_request() {
+    use crate::pan123::metrics::UpstreamTimings;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Calls outside a request are not recorded anywhere
+    UpstreamTimings::record("/api/v1/user/info", Duration::from_millis(5));
+
+    let timings = Arc::new(UpstreamTimings::default());
+    timings
+        .clone()
+        .scope(async {
+            UpstreamTimings::record("/api/v2/file/list", Duration::from_millis(20));
+            UpstreamTimings::record("/api/v2/file/list", Duration::from_millis(30));
+            // Spawned work counts towards the request that spawned it
+            tokio::spawn(UpstreamTimings::propagate(async {
+                let _call = UpstreamTimings::start("download");
+                tokio::time::sleep(Duration::from_millis(60)).await;
+            }))
+            .await
+            .unwrap();
+        })
+        .await;
+
+    assert_eq!(timings.calls(), 3);
+    assert!(timings.total() >= Duration::from_millis(110));
+    let summary = timings.summary();
+    assert!(summary.starts_with("download 1x "), "{}", summary);
+    assert!(
+        summary.ends_with(", /api/v2/file/list 2x 50ms"),
+        "{}",
+        summary
+    );
 }
@@ -0,0 +1,52 @@
+//! Bandwidth limits for transfers to and from 123pan.
+//!
+//! A token bucket shared by all transfers in one direction: each chunk of
+//! body takes its size in tokens, and a transfer that overdraws the bucket
+//! waits until it has refilled.
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Byte rate limit shared by clones.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    /// Bytes per second, also the burst size
+    rate: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl Throttle {
+    /// Limit to `bytes_per_sec`; `None` for 0 (unlimited).
+    pub fn new(bytes_per_sec: u64) -> Option<Self> {
+        (bytes_per_sec > 0).then(|| Self {
+            rate: bytes_per_sec as f64,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            })),
+        })
+    }
+
+    /// Take `bytes` from the bucket, waiting while it is overdrawn.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / self.rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
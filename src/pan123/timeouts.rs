@@ -0,0 +1,49 @@
+//! Timeouts of requests to 123pan.
+//!
+//! Connecting and waiting for the next bytes of a response are bounded for
+//! every request. The total time is bounded per kind of request: API calls
+//! get a fixed limit (listings of large directories are exempt), uploads a
+//! limit growing with their size, and each download request its own limit.
+
+use std::time::Duration;
+
+use super::REQUEST_TIMEOUT;
+
+/// Timeouts of the HTTP requests a [`super::Pan123Client`] makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Establishing a connection
+    pub connect: Duration,
+    /// Waiting for the next bytes of a response
+    pub read: Duration,
+    /// Total time of an API call
+    pub api: Duration,
+    /// Total time of an upload, before adding `upload_per_mib`
+    pub upload: Duration,
+    /// Added to the upload limit per MiB uploaded
+    pub upload_per_mib: Duration,
+    /// Total time of one download request (a chunk of ranged downloads)
+    pub download: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            read: REQUEST_TIMEOUT,
+            api: REQUEST_TIMEOUT,
+            upload: Duration::from_secs(60),
+            upload_per_mib: Duration::from_secs(2),
+            download: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Total time allowed for uploading `size` bytes.
+    pub fn upload_for(&self, size: u64) -> Duration {
+        let mib = size.div_ceil(1024 * 1024) as u32;
+        self.upload
+            .saturating_add(self.upload_per_mib.saturating_mul(mib))
+    }
+}
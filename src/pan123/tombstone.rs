@@ -0,0 +1,20 @@
+use sea_orm::entity::prelude::*;
+
+/// File deleted through this cache. Listings fetched shortly after a delete
+/// may still show the file; tombstones keep it from being cached again.
+/// The table is defined by `crate::migration`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "tombstones")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repo: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub file_id: i64,
+    /// Unix time of the deletion
+    pub deleted_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -15,6 +15,64 @@ impl<T> ApiResponse<T> {
     pub fn is_success(&self) -> bool {
         self.code == 0
     }
+
+    /// Whether the call failed because an entry with the same name exists
+    /// (e.g. 该目录下已经有同名文件夹,无法进行创建).
+    pub fn is_name_conflict(&self) -> bool {
+        self.code == 1 && self.message.contains("同名")
+    }
+}
+
+/// How a failed 123pan call is answered to restic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Account storage exhausted (507)
+    QuotaExceeded,
+    /// File or directory does not exist (404)
+    NotFound,
+    /// No permission for the file or operation (403)
+    Forbidden,
+    /// Request frequency limit hit (429)
+    RateLimited,
+    /// Anything else (502)
+    Upstream,
+}
+
+/// Business error codes of the 123pan open API with a meaning of their own.
+const ERROR_CODES: &[(i32, ErrorKind)] = &[
+    (403, ErrorKind::Forbidden),
+    (429, ErrorKind::RateLimited),
+    (5066, ErrorKind::NotFound),
+    // Traffic quota of the account used up
+    (5113, ErrorKind::QuotaExceeded),
+];
+
+/// Messages identifying errors whose code is not in [`ERROR_CODES`], mostly
+/// the generic code 1.
+const ERROR_MESSAGES: &[(&str, ErrorKind)] = &[
+    ("空间不足", ErrorKind::QuotaExceeded),
+    ("容量不足", ErrorKind::QuotaExceeded),
+    ("不存在", ErrorKind::NotFound),
+    ("没有权限", ErrorKind::Forbidden),
+    ("无权限", ErrorKind::Forbidden),
+    ("频繁", ErrorKind::RateLimited),
+];
+
+impl ErrorKind {
+    /// Classify a 123pan error by its code, falling back to its message.
+    pub fn of(code: i32, message: &str) -> Self {
+        ERROR_CODES
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, kind)| *kind)
+            .or_else(|| {
+                ERROR_MESSAGES
+                    .iter()
+                    .find(|(m, _)| message.contains(m))
+                    .map(|(_, kind)| *kind)
+            })
+            .unwrap_or(ErrorKind::Upstream)
+    }
 }
 
 // ============================================================================
@@ -37,6 +95,30 @@ pub struct AccessTokenData {
     pub expired_at: String,
 }
 
+// ============================================================================
+// Account
+// ============================================================================
+
+/// Response data for the account's user info (storage space in bytes).
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfoData {
+    pub uid: i64,
+    #[serde(default)]
+    pub space_used: u64,
+    #[serde(default)]
+    pub space_permanent: u64,
+    #[serde(default)]
+    pub space_temp: u64,
+}
+
+impl UserInfoData {
+    /// Total space of the account, 0 if unknown.
+    pub fn space_total(&self) -> u64 {
+        self.space_permanent + self.space_temp
+    }
+}
+
 // ============================================================================
 // File Operations
 // ============================================================================
@@ -50,10 +132,35 @@ pub struct FileInfo {
     #[serde(rename = "type")]
     pub file_type: i32, // 0 = file, 1 = folder
     pub size: i64,
-    #[allow(dead_code)]
     pub parent_file_id: i64,
     #[serde(default)]
     pub trashed: i32, // 0 = not trashed, 1 = trashed
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub etag: Option<String>, // MD5 of file content (empty for folders)
+    #[serde(default, rename = "updateAt", deserialize_with = "china_time_as_utc")]
+    pub modified_at: Option<chrono::NaiveDateTime>, // Last modification time (UTC)
+}
+
+/// Deserialize an empty string as `None`.
+fn empty_string_as_none<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.filter(|s| !s.is_empty()))
+}
+
+/// Deserialize a 123pan timestamp (`YYYY-MM-DD HH:MM:SS`, China Standard Time) as UTC.
+fn china_time_as_utc<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<chrono::NaiveDateTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value
+        .and_then(|s| chrono::NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S").ok())
+        .map(|local| local - chrono::Duration::hours(8)))
 }
 
 impl FileInfo {
@@ -77,6 +184,8 @@ impl From<crate::pan123::entity::Model> for FileInfo {
             size: model.size,
             parent_file_id: model.parent_id,
             trashed: 0,
+            etag: model.etag,
+            modified_at: model.modified_at,
         }
     }
 }
@@ -113,6 +222,19 @@ pub struct DownloadInfoData {
     pub download_url: String,
 }
 
+/// Response data for the details of one file.
+#[derive(Debug, Deserialize)]
+pub struct FileDetailData {
+    #[serde(rename = "fileID")]
+    pub file_id: i64,
+    pub filename: String,
+    pub size: i64,
+    #[serde(rename = "parentFileID")]
+    pub parent_file_id: i64,
+    #[serde(default)]
+    pub trashed: i32,
+}
+
 /// Request body for moving files to trash.
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -147,3 +269,48 @@ pub struct SingleUploadData {
     pub file_id: i64,
     pub completed: bool,
 }
+
+/// Request body for creating a slice upload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateUploadRequest {
+    #[serde(rename = "parentFileID")]
+    pub parent_file_id: i64,
+    pub filename: String,
+    pub etag: String,
+    pub size: i64,
+    pub duplicate: i32,
+}
+
+/// Response data for creating a slice upload. With `reuse`, 123pan already
+/// has the content and `file_id` is the finished file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateUploadData {
+    #[serde(rename = "fileID", default)]
+    pub file_id: i64,
+    #[serde(rename = "preuploadID", default)]
+    pub preupload_id: String,
+    #[serde(default)]
+    pub reuse: bool,
+    #[serde(default)]
+    pub slice_size: i64,
+    #[serde(default)]
+    pub servers: Vec<String>,
+}
+
+/// Request body for finishing a slice upload.
+#[derive(Debug, Serialize)]
+pub struct UploadCompleteRequest {
+    #[serde(rename = "preuploadID")]
+    pub preupload_id: String,
+}
+
+/// Response data for finishing a slice upload.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadCompleteData {
+    pub completed: bool,
+    #[serde(rename = "fileID", default)]
+    pub file_id: i64,
+}
@@ -0,0 +1,114 @@
+//! Upload domains handed out by 123pan.
+//!
+//! `/upload/v2/file/domain` returns several domains. The first is used until
+//! uploads to it fail [`MAX_DOMAIN_FAILURES`] times in a row, then the next.
+//! The list is fetched again once it is older than its TTL, once every
+//! domain has failed, and at once when a domain answers with something other
+//! than the upload API (a sign 123pan retired it).
+
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Consecutive failed uploads after which the next domain is used.
+pub const MAX_DOMAIN_FAILURES: u32 = 2;
+
+#[derive(Debug)]
+struct DomainList {
+    domains: Vec<String>,
+    /// Index of the domain in use
+    current: usize,
+    /// Consecutive failed uploads to the domain in use
+    failures: u32,
+    fetched_at: Instant,
+}
+
+/// Upload domains with failover, shared by clones.
+#[derive(Debug, Clone)]
+pub struct UploadDomains {
+    list: Arc<RwLock<Option<DomainList>>>,
+    /// Age after which the list is fetched again
+    ttl: Duration,
+}
+
+impl UploadDomains {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            list: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// The domain to upload to, `None` if the list has to be fetched.
+    pub fn current(&self) -> Option<String> {
+        let list = self.list.read();
+        let list = list.as_ref()?;
+        if list.fetched_at.elapsed() >= self.ttl {
+            return None;
+        }
+        list.domains.get(list.current).cloned()
+    }
+
+    /// Replace the list with freshly fetched `domains`.
+    pub fn set(&self, domains: Vec<String>) {
+        *self.list.write() = Some(DomainList {
+            domains,
+            current: 0,
+            failures: 0,
+            fetched_at: Instant::now(),
+        });
+    }
+
+    /// Note an upload to `domain` that succeeded.
+    pub fn report_success(&self, domain: &str) {
+        if let Some(list) = self.list.write().as_mut() {
+            if list.domains.get(list.current).is_some_and(|d| d == domain) {
+                list.failures = 0;
+            }
+        }
+    }
+
+    /// Note an upload to `domain` that failed, moving on to the next domain
+    /// after repeated failures and dropping the list once all have failed.
+    pub fn report_failure(&self, domain: &str) {
+        let mut guard = self.list.write();
+        let Some(list) = guard.as_mut() else {
+            return;
+        };
+        if list.domains.get(list.current).is_none_or(|d| d != domain) {
+            return;
+        }
+        list.failures += 1;
+        if list.failures < MAX_DOMAIN_FAILURES {
+            return;
+        }
+        if list.current + 1 < list.domains.len() {
+            list.current += 1;
+            list.failures = 0;
+            tracing::warn!(
+                "Uploads to {} keep failing, switching to upload domain {}",
+                domain,
+                list.domains[list.current]
+            );
+        } else {
+            tracing::warn!("Uploads to every upload domain keep failing, fetching the list again");
+            *guard = None;
+        }
+    }
+
+    /// Drop the list because `domain` no longer serves uploads, so the next
+    /// upload fetches it again.
+    pub fn invalidate(&self, domain: &str) {
+        let mut guard = self.list.write();
+        if guard
+            .as_ref()
+            .is_some_and(|list| list.domains.iter().any(|d| d == domain))
+        {
+            tracing::warn!(
+                "Upload domain {} does not serve uploads, fetching the domain list again",
+                domain
+            );
+            *guard = None;
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use sea_orm::entity::prelude::*;
+
+/// Slice upload in progress. Parts already accepted by 123pan are skipped
+/// when the same file is uploaded again, e.g. after a restart.
+/// The table is defined by `crate::migration`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "upload_sessions")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repo: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub parent_id: i64,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub name: String,
+    /// MD5 of the whole file
+    pub etag: String,
+    pub size: i64,
+    pub preupload_id: String,
+    pub slice_size: i64,
+    /// Upload server the slices are sent to
+    pub server: String,
+    /// Comma-separated numbers of the uploaded slices
+    pub completed_parts: String,
+    /// Unix time the upload was created
+    pub created_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,183 @@
+//! Keeping secrets out of logs and error messages.
+//!
+//! Download URLs are presigned through their query string and API requests
+//! carry bearer tokens; both can end up in transport errors and in logged API
+//! responses. [`redact`] masks them in any text, and [`RedactingWriter`]
+//! applies it to every log line before it leaves the process.
+
+use std::borrow::Cow;
+use std::io;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// What a secret is replaced with.
+const REDACTED: &str = "<redacted>";
+
+/// JSON fields whose values are secrets, matched with or without escaping.
+const SECRET_FIELDS: &[&str] = &["accessToken", "clientSecret", "refreshToken"];
+
+/// Mask URL query strings and credentials, bearer tokens and secret JSON
+/// fields in `text`. Scheme, host and path of URLs are kept so messages stay
+/// useful.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let has_secrets = text.contains("://")
+        || text.contains("Bearer ")
+        || SECRET_FIELDS.iter().any(|f| text.contains(f));
+    if !has_secrets {
+        return Cow::Borrowed(text);
+    }
+    let mut text = redact_urls(text);
+    text = redact_after(&text, "Bearer ", |_| true);
+    for field in SECRET_FIELDS {
+        text = redact_after(&text, field, is_json_value_start);
+    }
+    Cow::Owned(text)
+}
+
+/// Characters ending a URL embedded in text, plain or JSON-escaped.
+fn is_url_end(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '<' | '>' | '(' | ')' | '`')
+}
+
+/// Replace the query and fragment and any `user:password@` of every URL.
+fn redact_urls(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find("://") {
+        let start = pos + 3;
+        let end = rest[start..]
+            .find(is_url_end)
+            .map_or(rest.len(), |i| start + i);
+        out.push_str(&rest[..start]);
+        let url = &rest[start..end];
+        let (address, secret_part) = match url.find(['?', '#']) {
+            Some(i) => (&url[..i], Some(&url[i..=i])),
+            None => (url, None),
+        };
+        let authority = address.find('/').unwrap_or(address.len());
+        match address[..authority].rfind('@') {
+            Some(at) => {
+                out.push_str(REDACTED);
+                out.push_str(&address[at..]);
+            }
+            None => out.push_str(address),
+        }
+        if let Some(separator) = secret_part {
+            out.push_str(separator);
+            out.push_str(REDACTED);
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Replace the token following each `marker`, provided what sits between
+/// them passes `accept`.
+fn redact_after(text: &str, marker: &str, accept: fn(&str) -> bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(marker) {
+        let after = pos + marker.len();
+        // Skip the separator between a field name and its value
+        let value_start = after
+            + rest[after..]
+                .find(|c: char| !matches!(c, '"' | '\\' | ':' | ' ' | '='))
+                .unwrap_or(rest.len() - after);
+        let value_end = value_start
+            + rest[value_start..]
+                .find(|c: char| is_url_end(c) || matches!(c, ',' | '}' | ';'))
+                .unwrap_or(rest.len() - value_start);
+        out.push_str(&rest[..value_start]);
+        if value_end > value_start && accept(&rest[after..value_start]) {
+            out.push_str(REDACTED);
+        } else {
+            out.push_str(&rest[value_start..value_end]);
+        }
+        rest = &rest[value_end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether a field name is followed by a JSON or `key=value` separator, so
+/// words merely containing a field name are left alone.
+fn is_json_value_start(separator: &str) -> bool {
+    separator.contains(':') || separator.contains('=')
+}
+
+/// [`MakeWriter`] passing every formatted log line through [`redact`].
+pub struct RedactingWriter<M>(M);
+
+impl<M> RedactingWriter<M> {
+    pub fn new(make_writer: M) -> Self {
+        Self(make_writer)
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingWriter<M> {
+    type Writer = Redacting<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redacting(self.0.make_writer())
+    }
+}
+
+/// Writer of [`RedactingWriter`]. The formatter writes each event in one
+/// call, so secrets are never split across writes.
+pub struct Redacting<W>(W);
+
+impl<W: io::Write> io::Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match std::str::from_utf8(buf) {
+            Ok(text) => {
+                self.0.write_all(redact(text).as_bytes())?;
+                Ok(buf.len())
+            }
+            Err(_) => self.0.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(
+                "error sending request for url (https://dl.123pan.cn/a/b.bin?auth_key=123-abc&x=1)"
+            ),
+            "error sending request for url (https://dl.123pan.cn/a/b.bin?<redacted>)"
+        );
+        assert_eq!(
+            redact("proxy http://user:pw@proxy:3128/ failed"),
+            "proxy http://<redacted>@proxy:3128/ failed"
+        );
+        assert_eq!(
+            redact("Authorization: Bearer eyJhbGciOi.x-y_z, next"),
+            "Authorization: Bearer <redacted>, next"
+        );
+        assert_eq!(
+            redact(r#"{"data":{"accessToken":"secret","expiredAt":"2030"}}"#),
+            r#"{"data":{"accessToken":"<redacted>","expiredAt":"2030"}}"#
+        );
+        // As escaped in JSON log lines
+        assert_eq!(
+            redact(r#"{"message":"{\"clientSecret\":\"s3\"}"}"#),
+            r#"{"message":"{\"clientSecret\":\"<redacted>\"}"}"#
+        );
+        assert_eq!(
+            redact("accessToken expired, refreshing"),
+            "accessToken expired, refreshing"
+        );
+        assert!(matches!(
+            redact("GET /data/abc 200"),
+            Cow::Borrowed("GET /data/abc 200")
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+//! Admission control for upstream transfers and buffered request bodies.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::{AppError, Result};
+
+/// Seconds clients are asked to wait before retrying a rejected request.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/// Bounded concurrency limiter with a bounded wait queue.
+///
+/// Up to `max_concurrent` operations run at once and up to `queue_size` more
+/// wait for a slot; anything beyond that is rejected with 503 + Retry-After.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    name: &'static str,
+    semaphore: Option<Arc<Semaphore>>,
+    waiting: Arc<AtomicUsize>,
+    queue_size: usize,
+}
+
+/// Permit held for the duration of an admitted operation.
+#[derive(Debug)]
+pub struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Decrements the waiting counter when a queued acquire finishes or is cancelled.
+struct WaitGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ConcurrencyLimiter {
+    /// Create a limiter. `max_concurrent == 0` disables limiting.
+    pub fn new(name: &'static str, max_concurrent: usize, queue_size: usize) -> Self {
+        Self {
+            name,
+            semaphore: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            queue_size,
+        }
+    }
+
+    /// Wait for a slot, or fail immediately if the wait queue is full.
+    pub async fn acquire(&self) -> Result<Permit> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(Permit { _permit: None });
+        };
+
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Permit {
+                _permit: Some(permit),
+            });
+        }
+
+        if self.waiting.fetch_add(1, Ordering::AcqRel) >= self.queue_size {
+            self.waiting.fetch_sub(1, Ordering::AcqRel);
+            tracing::warn!("Too many concurrent {}, rejecting request", self.name);
+            return Err(AppError::Unavailable {
+                message: format!("Too many concurrent {}, retry later", self.name),
+                retry_after: RETRY_AFTER_SECS,
+            });
+        }
+        let _guard = WaitGuard(&self.waiting);
+
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| AppError::Internal(format!("Semaphore closed: {}", e)))?;
+
+        Ok(Permit {
+            _permit: Some(permit),
+        })
+    }
+}
+
+/// Granularity of memory budget reservations (semaphore permits are `u32`).
+const BUDGET_UNIT: u64 = 1024;
+
+/// Cap on the bytes of request bodies buffered in memory at once.
+///
+/// Bodies reserve their Content-Length before being read; once the budget is
+/// used up, further bodies are not read until enough is released. A body
+/// larger than the whole budget waits until it can take all of it.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    semaphore: Option<Arc<Semaphore>>,
+    units: u32,
+    in_flight: Arc<AtomicU64>,
+}
+
+/// Reservation held while a buffered body is in memory.
+#[derive(Debug)]
+pub struct Reservation {
+    _permit: Option<OwnedSemaphorePermit>,
+    bytes: u64,
+    in_flight: Arc<AtomicU64>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+impl MemoryBudget {
+    /// Create a budget of `max_bytes`. `0` disables the cap, but buffered
+    /// bytes are still counted.
+    pub fn new(max_bytes: u64) -> Self {
+        let units = max_bytes.div_ceil(BUDGET_UNIT).min(u32::MAX as u64) as u32;
+        Self {
+            semaphore: (units > 0).then(|| Arc::new(Semaphore::new(units as usize))),
+            units,
+            in_flight: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Reserve memory for a body of `bytes`, waiting while the budget is
+    /// used up. A body of unknown length reserves the whole budget.
+    pub async fn reserve(&self, bytes: Option<u64>) -> Result<Reservation> {
+        let permit = match &self.semaphore {
+            None => None,
+            Some(semaphore) => {
+                let units = bytes
+                    .map_or(self.units, |b| {
+                        b.div_ceil(BUDGET_UNIT).min(self.units as u64) as u32
+                    })
+                    .max(1);
+                let permit = match semaphore.clone().try_acquire_many_owned(units) {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        tracing::debug!(
+                            "Body memory budget used up ({} bytes buffered), waiting",
+                            self.in_flight()
+                        );
+                        semaphore
+                            .clone()
+                            .acquire_many_owned(units)
+                            .await
+                            .map_err(|e| AppError::Internal(format!("Semaphore closed: {}", e)))?
+                    }
+                };
+                Some(permit)
+            }
+        };
+        let bytes = bytes.unwrap_or(self.units as u64 * BUDGET_UNIT);
+        self.in_flight.fetch_add(bytes, Ordering::AcqRel);
+        Ok(Reservation {
+            _permit: permit,
+            bytes,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// Bytes of bodies currently reserved.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
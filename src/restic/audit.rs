@@ -0,0 +1,248 @@
+//! Audit log of requests that modify the repository.
+//!
+//! Every upload, delete and repository creation is recorded with the client
+//! address, the HTTP user and the response status, so "what deleted my
+//! snapshots?" can be answered after the fact (`GET /admin/audit`).
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::Response,
+};
+use base64::Engine;
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::handler::AppState;
+use crate::error::{AppError, Result};
+
+/// Entries returned by a query when no limit is given.
+const DEFAULT_QUERY_LIMIT: u64 = 100;
+
+/// Largest number of entries returned by one query.
+const MAX_QUERY_LIMIT: u64 = 1000;
+
+/// Rows of the `audit_log` table (defined by `crate::migration`).
+pub mod entry {
+    use sea_orm::entity::prelude::*;
+    use serde::Serialize;
+
+    /// One request that modified (or tried to modify) the repository.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize)]
+    #[sea_orm(table_name = "audit_log")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i64,
+        /// Cache namespace of the repository
+        #[serde(skip)]
+        pub repo: String,
+        /// Unix time in milliseconds
+        pub at: i64,
+        /// `upload`, `delete` or `mkdir`
+        pub operation: String,
+        /// Restic file type, empty for the repository itself
+        pub file_type: String,
+        pub name: String,
+        /// Request body size in bytes (uploads)
+        pub size: Option<i64>,
+        pub client_ip: Option<String>,
+        /// User name of HTTP basic authentication
+        pub user: Option<String>,
+        /// HTTP status of the response
+        pub status: i32,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+/// Filters of `GET /admin/audit`.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries at or after this Unix time (seconds)
+    pub since: Option<i64>,
+    pub operation: Option<String>,
+    #[serde(rename = "type")]
+    pub file_type: Option<String>,
+    pub name: Option<String>,
+    pub limit: Option<u64>,
+}
+
+/// Audit log stored in the cache database.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    db: DatabaseConnection,
+    repo: String,
+}
+
+impl AuditLog {
+    /// Record entries for `repo` (the cache namespace) in `db`.
+    pub fn new(db: DatabaseConnection, repo: impl Into<String>) -> Self {
+        Self {
+            db,
+            repo: repo.into(),
+        }
+    }
+
+    /// Store one entry; `id`, `repo` and `at` are filled in.
+    pub async fn record(&self, entry: entry::Model) -> Result<()> {
+        let model = entry::ActiveModel {
+            repo: Set(self.repo.clone()),
+            at: Set(chrono::Utc::now().timestamp_millis()),
+            operation: Set(entry.operation),
+            file_type: Set(entry.file_type),
+            name: Set(entry.name),
+            size: Set(entry.size),
+            client_ip: Set(entry.client_ip),
+            user: Set(entry.user),
+            status: Set(entry.status),
+            ..Default::default()
+        };
+        entry::Entity::insert(model)
+            .exec(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to record audit entry: {}", e)))?;
+        Ok(())
+    }
+
+    /// Matching entries, newest first.
+    pub async fn query(&self, query: &AuditQuery) -> Result<Vec<entry::Model>> {
+        let mut select = entry::Entity::find().filter(entry::Column::Repo.eq(self.repo.as_str()));
+        if let Some(since) = query.since {
+            select = select.filter(entry::Column::At.gte(since.saturating_mul(1000)));
+        }
+        if let Some(operation) = &query.operation {
+            select = select.filter(entry::Column::Operation.eq(operation.as_str()));
+        }
+        if let Some(file_type) = &query.file_type {
+            select = select.filter(entry::Column::FileType.eq(file_type.as_str()));
+        }
+        if let Some(name) = &query.name {
+            select = select.filter(entry::Column::Name.eq(name.as_str()));
+        }
+        select
+            .order_by_desc(entry::Column::Id)
+            .limit(
+                query
+                    .limit
+                    .unwrap_or(DEFAULT_QUERY_LIMIT)
+                    .min(MAX_QUERY_LIMIT),
+            )
+            .all(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("DB error reading audit log: {}", e)))
+    }
+
+    /// Delete entries older than `retention`, returning how many were removed.
+    pub async fn prune(&self, retention: Duration) -> Result<u64> {
+        let cutoff = chrono::Utc::now().timestamp_millis() - retention.as_millis() as i64;
+        let result = entry::Entity::delete_many()
+            .filter(entry::Column::Repo.eq(self.repo.as_str()))
+            .filter(entry::Column::At.lt(cutoff))
+            .exec(&self.db)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to prune audit log: {}", e)))?;
+        Ok(result.rows_affected)
+    }
+
+    /// Prune entries older than `retention` daily in the background.
+    pub fn spawn_pruning(&self, retention: Duration) -> tokio::task::JoinHandle<()> {
+        let log = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match log.prune(retention).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Pruned {} audit log entries", n),
+                    Err(e) => tracing::error!("Audit log pruning failed: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// Record requests that modify the repository once they are answered.
+pub async fn audit_mutations(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(audit) = state.audit.clone() else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path().to_string();
+    let Some((operation, file_type, name)) = classify(req.method(), &path) else {
+        return next.run(req).await;
+    };
+    let size = (operation == "upload")
+        .then(|| {
+            req.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<i64>().ok())
+        })
+        .flatten();
+    let client_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let user = basic_auth_user(&req);
+
+    let response = next.run(req).await;
+
+    let entry = entry::Model {
+        id: 0,
+        repo: String::new(),
+        at: 0,
+        operation: operation.to_string(),
+        file_type,
+        name,
+        size,
+        client_ip,
+        user,
+        status: response.status().as_u16() as i32,
+    };
+    if let Err(e) = audit.record(entry).await {
+        tracing::error!("{}", e);
+    }
+    response
+}
+
+/// Operation, file type and name of a request that modifies the repository.
+fn classify(method: &Method, path: &str) -> Option<(&'static str, String, String)> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        (&Method::POST, [""]) => Some(("mkdir", String::new(), String::new())),
+        (&Method::DELETE, [""]) => Some(("delete", String::new(), String::new())),
+        (&Method::POST, ["config"]) => Some(("upload", "config".into(), "config".into())),
+        (&Method::POST, [file_type, name]) if *file_type != "admin" => {
+            Some(("upload", file_type.to_string(), name.to_string()))
+        }
+        (&Method::DELETE, [file_type, name]) => {
+            Some(("delete", file_type.to_string(), name.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// User name sent with HTTP basic authentication, if any.
+fn basic_auth_user(req: &Request) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    let user = credentials.split(':').next()?;
+    (!user.is_empty()).then(|| user.to_string())
+}
@@ -1,24 +1,158 @@
 //! Restic REST API v2 handlers.
 
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::{Path, Query, State},
-    http::{header, HeaderMap, StatusCode},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
-    routing::{get, head, post},
-    Router,
+    routing::{get, head, post, MethodRouter},
+    Json, Router,
 };
+use futures_util::stream::{self, StreamExt, TryStreamExt};
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tracing::Instrument;
 
-use super::types::{FileEntryV2, ResticFileType};
+use super::admission::{ConcurrencyLimiter, MemoryBudget};
+use super::audit::{self, audit_mutations, AuditLog, AuditQuery};
+use super::middleware::{
+    access_log, deadline, reject_writes, require_admin, ModeSwitch, ServerMode,
+};
+use super::read_cache::{MetadataCache, PackCache};
+use super::readahead::{verify_pack, ReadPlan, Readahead};
+use super::spool::WriteBackSpool;
+use super::types::{FileEntryV2, RepoLayout, ResticFileType, LAYOUT_FILE};
 use crate::error::{AppError, Result};
-use crate::pan123::Pan123Client;
+use crate::pan123::metrics::CacheMetrics;
+use crate::pan123::validate_filename;
+use crate::storage::{ObjectInfo, StorageBackend};
+
+/// Tunable behaviour of the REST API.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Maximum concurrent upstream uploads (0 = unlimited)
+    pub max_concurrent_uploads: usize,
+    /// Maximum uploads waiting for a slot before rejecting with 503
+    pub upload_queue_size: usize,
+    /// Bytes of upload bodies buffered in memory at once (0 = unlimited)
+    pub body_memory_budget: u64,
+    /// Write-back spool; when set, uploads are acknowledged once spooled locally
+    pub spool: Option<WriteBackSpool>,
+    /// Spool every upload (write-back) rather than only those that failed
+    pub write_back: bool,
+    /// Local disk cache for config/index/snapshot/key contents
+    pub metadata_cache: Option<MetadataCache>,
+    /// Size-capped local disk cache for data packs
+    pub pack_cache: Option<PackCache>,
+    /// Answer ranged reads of uncached packs directly and fetch the whole
+    /// pack into the pack cache in the background
+    pub pack_readahead: bool,
+    /// Arrangement of objects in the repository directory
+    pub layout: RepoLayout,
+    /// While set, requests that modify the repository get 403
+    pub read_only: Arc<AtomicBool>,
+    /// Refuse deletes of anything but locks
+    pub append_only: bool,
+    /// Refuse uploads replacing data, snapshots or keys
+    pub immutable: bool,
+    /// Object types whose HEAD is confirmed with the backend rather than
+    /// answered from its cache
+    pub verified_heads: Vec<ResticFileType>,
+    /// Maintenance mode switched through `/admin/mode`
+    pub mode: ModeSwitch,
+    /// Audit log of requests that modify the repository
+    pub audit: Option<AuditLog>,
+    /// Requests taking longer are logged with their 123pan call timings
+    pub slow_request: Option<std::time::Duration>,
+    /// Requests without a response after this long get 504
+    pub deadline: Option<std::time::Duration>,
+    /// Bytes fetched ahead of sequential pack reads (0 disables)
+    pub readahead_window: u64,
+    /// Who may use the `/admin/*` routes
+    pub admin: AdminAccess,
+}
+
+/// Who may use the `/admin/*` routes, which can switch the server read-only
+/// or draining.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AdminAccess {
+    /// Anyone who can connect; for listeners only this host can reach
+    #[default]
+    Open,
+    /// Requests with `Authorization: Bearer <token>`
+    Token(String),
+    /// Not served at all
+    Disabled,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent_uploads: 4,
+            upload_queue_size: 16,
+            body_memory_budget: 0,
+            spool: None,
+            write_back: true,
+            metadata_cache: None,
+            pack_cache: None,
+            pack_readahead: false,
+            layout: RepoLayout::default(),
+            read_only: Arc::default(),
+            append_only: false,
+            immutable: false,
+            verified_heads: Vec::new(),
+            mode: ModeSwitch::default(),
+            audit: None,
+            slow_request: None,
+            deadline: None,
+            readahead_window: 0,
+            admin: AdminAccess::default(),
+        }
+    }
+}
 
 /// Application state shared across handlers.
 #[derive(Clone)]
 pub struct AppState {
-    pub client: Pan123Client,
+    pub backend: Arc<dyn StorageBackend>,
+    /// Admission control for uploads (acquired before buffering the body)
+    pub uploads: ConcurrencyLimiter,
+    /// Memory reserved by upload bodies (acquired before buffering the body)
+    pub bodies: MemoryBudget,
+    /// Write-back spool for pending uploads
+    pub spool: Option<WriteBackSpool>,
+    /// Spool every upload rather than only those that failed
+    pub write_back: bool,
+    /// Local disk cache for metadata object contents
+    pub metadata_cache: Option<MetadataCache>,
+    /// Local disk cache for data packs
+    pub pack_cache: Option<PackCache>,
+    /// Prefetch whole packs after ranged reads that miss the pack cache
+    pub pack_readahead: bool,
+    /// Hits and misses of the metadata and pack caches
+    pub read_cache_metrics: CacheMetrics,
+    /// Arrangement of objects in the repository directory
+    pub layout: RepoLayout,
+    /// Set while another instance holds the repository lock
+    pub read_only: Arc<AtomicBool>,
+    /// Refuse deletes of anything but locks
+    pub append_only: bool,
+    /// Refuse uploads replacing data, snapshots or keys
+    pub immutable: bool,
+    /// Object types whose HEAD is confirmed with the backend
+    pub verified_heads: Vec<ResticFileType>,
+    /// Maintenance mode switched through `/admin/mode`
+    pub mode: ModeSwitch,
+    /// Audit log of requests that modify the repository
+    pub audit: Option<AuditLog>,
+    /// Requests taking longer are logged with their 123pan call timings
+    pub slow_request: Option<std::time::Duration>,
+    /// Readahead for sequential and bursty pack reads
+    pub readahead: Option<Arc<Readahead>>,
+    /// Who may use the `/admin/*` routes
+    pub admin: AdminAccess,
 }
 
 /// Query parameters for repository creation.
@@ -32,30 +166,259 @@ pub struct CreateQuery {
 const V2_CONTENT_TYPE: &str = "application/vnd.x.restic.rest.v2";
 
 /// Create the Axum router with all routes.
-pub fn create_router(client: Pan123Client) -> Router {
-    let state = Arc::new(AppState { client });
+pub fn create_router(backend: Arc<dyn StorageBackend>, options: ServerOptions) -> Router {
+    let state = Arc::new(AppState {
+        backend,
+        uploads: ConcurrencyLimiter::new(
+            "uploads",
+            options.max_concurrent_uploads,
+            options.upload_queue_size,
+        ),
+        bodies: MemoryBudget::new(options.body_memory_budget),
+        spool: options.spool,
+        write_back: options.write_back,
+        metadata_cache: options.metadata_cache,
+        pack_cache: options.pack_cache,
+        pack_readahead: options.pack_readahead,
+        read_cache_metrics: CacheMetrics::default(),
+        layout: options.layout,
+        read_only: options.read_only,
+        append_only: options.append_only,
+        immutable: options.immutable,
+        verified_heads: options.verified_heads,
+        mode: options.mode,
+        audit: options.audit,
+        slow_request: options.slow_request,
+        readahead: (options.readahead_window > 0)
+            .then(|| Arc::new(Readahead::new(options.readahead_window))),
+        admin: options.admin,
+    });
 
-    Router::new()
+    let mut router = Router::new()
+        // Health probes
+        .route("/healthz", allow(get(healthz), "GET, HEAD, OPTIONS"))
+        .route("/readyz", allow(get(readyz), "GET, HEAD, OPTIONS"))
+        .route("/metrics", allow(get(metrics), "GET, HEAD, OPTIONS"))
+        // Administration
+        .merge(admin_routes(&state))
         // Repository operations
-        .route("/", post(create_repository).delete(delete_repository))
+        .route(
+            "/",
+            allow(
+                post(create_repository).delete(delete_repository),
+                "POST, DELETE, OPTIONS",
+            ),
+        )
         // Config operations
         .route(
             "/config",
-            head(head_config).get(get_config).post(post_config),
+            allow(
+                head(head_config).get(get_config).post(post_config),
+                "GET, HEAD, POST, OPTIONS",
+            ),
         )
         // Type directory listing
-        .route("/:type/", get(list_files))
+        .route("/:type/", allow(get(list_files), "GET, HEAD, OPTIONS"))
         // Individual file operations
         .route(
             "/:type/:name",
-            head(head_file)
-                .get(get_file)
-                .post(post_file)
-                .delete(delete_file),
+            allow(
+                head(head_file)
+                    .get(get_file)
+                    .post(post_file)
+                    .delete(delete_file),
+                "GET, HEAD, POST, DELETE, OPTIONS",
+            ),
         )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            reject_writes,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            audit_mutations,
+        ));
+    // Inside the access log, so a timed-out request is logged with its 504
+    // rather than as cancelled
+    if let Some(limit) = options.deadline {
+        router = router.layer(axum::middleware::from_fn_with_state(limit, deadline));
+    }
+    router
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            access_log,
+        ))
         .with_state(state)
 }
 
+/// The `/admin/*` routes, behind [`require_admin`]; none if administration
+/// is disabled.
+fn admin_routes(state: &Arc<AppState>) -> Router<Arc<AppState>> {
+    if state.admin == AdminAccess::Disabled {
+        return Router::new();
+    }
+    Router::new()
+        .route(
+            "/admin/maintenance",
+            allow(post(run_maintenance), "POST, OPTIONS"),
+        )
+        .route(
+            "/admin/audit",
+            allow(get(query_audit), "GET, HEAD, OPTIONS"),
+        )
+        .route(
+            "/admin/uploads",
+            allow(get(pending_uploads), "GET, HEAD, OPTIONS"),
+        )
+        .route(
+            "/admin/mode",
+            allow(get(get_mode).put(set_mode), "GET, HEAD, PUT, OPTIONS"),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_admin,
+        ))
+}
+
+/// Answer OPTIONS on a route with the `methods` it serves, and methods it
+/// doesn't serve with 405 and the same `Allow` header.
+fn allow(route: MethodRouter<Arc<AppState>>, methods: &'static str) -> MethodRouter<Arc<AppState>> {
+    route
+        .options(move || async move { (StatusCode::NO_CONTENT, [(header::ALLOW, methods)]) })
+        .fallback(move |method: Method| async move {
+            (
+                StatusCode::METHOD_NOT_ALLOWED,
+                [(header::ALLOW, methods)],
+                Json(serde_json::json!({
+                    "error": format!("Method {} not allowed", method)
+                })),
+            )
+        })
+}
+
+// ============================================================================
+// Health Probes
+// ============================================================================
+
+/// GET /healthz - Liveness probe (process is up and serving).
+async fn healthz() -> impl IntoResponse {
+    StatusCode::OK
+}
+
+/// GET /readyz - Readiness probe (backend checks, e.g. DB, token and cache warm-up).
+async fn readyz(State(state): State<Arc<AppState>>) -> Response {
+    let readiness = state.backend.readiness().await;
+    // A draining server asks load balancers to send traffic elsewhere
+    let status = if readiness.ready && state.mode.get() != ServerMode::Drain {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness.details)).into_response()
+}
+
+/// GET /metrics - Backend counters (e.g. 123pan API usage) for Prometheus.
+async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    let mut body = state.backend.metrics();
+    body += &format!(
+        "# HELP restic_buffered_body_bytes Bytes of upload bodies held in memory\n\
+         # TYPE restic_buffered_body_bytes gauge\n\
+         restic_buffered_body_bytes {}\n",
+        state.bodies.in_flight()
+    );
+    if state.metadata_cache.is_some() || state.pack_cache.is_some() {
+        body += &state.read_cache_metrics.render_prometheus(
+            "restic_read_cache_requests_total",
+            "Downloads served from the local metadata or pack cache (hit) or from the backend (miss)",
+        );
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+// ============================================================================
+// Administration
+// ============================================================================
+
+/// POST /admin/maintenance - Run backend housekeeping now (e.g. SQLite
+/// checkpoint, vacuum and ANALYZE) instead of waiting for the schedule.
+async fn run_maintenance(State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>> {
+    Ok(Json(state.backend.maintain().await?))
+}
+
+/// GET /admin/audit - Recorded repository mutations, newest first, filtered
+/// by `since`, `operation`, `type`, `name` and `limit`.
+async fn query_audit(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<audit::entry::Model>>> {
+    let audit = state
+        .audit
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound("Audit log is disabled".to_string()))?;
+    Ok(Json(audit.query(&query).await?))
+}
+
+/// Body of `PUT /admin/mode`.
+#[derive(Debug, Deserialize)]
+pub struct ModeRequest {
+    pub mode: ServerMode,
+}
+
+/// GET /admin/mode - Current maintenance mode, and whether writes are
+/// refused because another instance holds the repository lock.
+async fn get_mode(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "mode": state.mode.get(),
+        "locked_elsewhere": state.read_only.load(Ordering::Relaxed),
+    }))
+}
+
+/// PUT /admin/mode - Switch between `normal`, `read-only` and `drain`.
+async fn set_mode(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ModeRequest>,
+) -> Json<serde_json::Value> {
+    let previous = state.mode.get();
+    state.mode.set(request.mode);
+    if previous != request.mode {
+        tracing::warn!(
+            "Server mode changed from {:?} to {:?}",
+            previous,
+            request.mode
+        );
+    }
+    get_mode(State(state)).await
+}
+
+/// GET /admin/uploads - Uploads waiting in the spool, with those that keep
+/// failing counted as stuck.
+async fn pending_uploads(State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>> {
+    let entries = match &state.spool {
+        Some(spool) => spool.pending().await?,
+        None => Vec::new(),
+    };
+    let stuck = entries.iter().filter(|e| e.is_stuck()).count();
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|e| {
+            serde_json::json!({
+                "type": e.file_type.dirname(),
+                "name": e.name,
+                "size": e.size,
+                "attempts": e.attempts,
+                "retry_at": e.retry_at,
+                "stuck": e.is_stuck(),
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({
+        "pending": entries.len(),
+        "stuck": stuck,
+        "entries": entries,
+    })))
+}
+
 // ============================================================================
 // Repository Operations
 // ============================================================================
@@ -72,7 +435,21 @@ async fn create_repository(
     }
 
     tracing::info!("Creating repository");
-    state.client.init_repository().await?;
+    state.backend.ensure_dir("").await?;
+    for file_type in [
+        ResticFileType::Data,
+        ResticFileType::Keys,
+        ResticFileType::Locks,
+        ResticFileType::Snapshots,
+        ResticFileType::Index,
+    ] {
+        state.backend.ensure_dir(file_type.dir_path()).await?;
+    }
+    // Record the layout so later starts keep using it whatever they're configured with
+    if state.backend.head(LAYOUT_FILE).await?.is_none() {
+        let layout = serde_json::to_vec(&state.layout)?;
+        state.backend.put(LAYOUT_FILE, Bytes::from(layout)).await?;
+    }
 
     Ok(StatusCode::OK)
 }
@@ -87,52 +464,68 @@ async fn delete_repository() -> impl IntoResponse {
 // ============================================================================
 
 /// HEAD /config - Check if config exists.
-async fn head_config(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse> {
-    let dir_id = state.client.get_type_dir_id(ResticFileType::Config).await?;
+async fn head_config(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Response> {
+    if let Some(size) = spooled_size(&state, ResticFileType::Config, "config").await? {
+        return Ok((StatusCode::OK, content_length_headers(size)).into_response());
+    }
 
-    match state.client.get_file_info(dir_id, "config").await? {
-        Some(file) => {
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                header::CONTENT_LENGTH,
-                file.size.to_string().parse().unwrap(),
-            );
-            Ok((StatusCode::OK, headers))
-        }
+    match head_object(&state, ResticFileType::Config, "config").await? {
+        Some(file) => Ok(head_response(&file, &headers)),
         None => Err(AppError::NotFound("config".to_string())),
     }
 }
 
 /// GET /config - Get config file.
-async fn get_config(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse> {
-    let dir_id = state.client.get_type_dir_id(ResticFileType::Config).await?;
+async fn get_config(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Response> {
+    if let Some(data) = read_spooled(&state, ResticFileType::Config, "config").await? {
+        return Ok(spooled_response(data, &headers));
+    }
 
     let file = state
-        .client
-        .get_file_info(dir_id, "config")
+        .backend
+        .head("config")
         .await?
         .ok_or_else(|| AppError::NotFound("config".to_string()))?;
 
-    let data = state.client.download_file(file.file_id, None).await?;
+    if let Some(response) = not_modified_response(&file, &headers) {
+        return Ok(response);
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        "application/octet-stream".parse().unwrap(),
-    );
-    headers.insert(
-        header::CONTENT_LENGTH,
-        data.len().to_string().parse().unwrap(),
-    );
+    let response = if let Some(cache) = &state.metadata_cache {
+        serve_cached(
+            &state,
+            cache,
+            ResticFileType::Config,
+            "config",
+            &file,
+            &headers,
+        )
+        .await?
+    } else {
+        let data = state.backend.get_range("config", None).await?;
+        data_response(data, &headers)
+    };
 
-    Ok((headers, data))
+    Ok(with_validators(response, &file))
+}
+
+/// Declared length of a request body.
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
 }
 
 /// POST /config - Save config file.
 async fn post_config(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     body: axum::body::Body,
 ) -> Result<impl IntoResponse> {
+    let _permit = state.uploads.acquire().await?;
+    let _reservation = state.bodies.reserve(content_length(&headers)).await?;
+
     // Convert body to Bytes with 1GB limit
     let body = axum::body::to_bytes(body, 1024 * 1024 * 1024)
         .await
@@ -140,10 +533,7 @@ async fn post_config(
 
     tracing::info!("Saving config ({} bytes)", body.len());
 
-    let dir_id = state.client.get_type_dir_id(ResticFileType::Config).await?;
-
-    // With duplicate=2, upload will overwrite existing file atomically
-    state.client.upload_file(dir_id, "config", body).await?;
+    store_object(&state, ResticFileType::Config, "config", body).await?;
 
     Ok(StatusCode::OK)
 }
@@ -166,23 +556,41 @@ async fn list_files(
         ));
     }
 
-    // For data files, use the aggregated listing across all subdirectories
-    let files = if file_type == ResticFileType::Data {
-        state.client.list_all_data_files().await?
-    } else {
-        let dir_id = state.client.get_type_dir_id(file_type).await?;
-        state.client.list_files(dir_id).await?
-    };
-
-    // Always return v2 format (name + size)
-    let entries: Vec<FileEntryV2> = files.iter().map(FileEntryV2::from).collect();
+    // Spooled objects shadow remote ones
+    let mut spooled: BTreeMap<String, FileEntryV2> = BTreeMap::new();
+    if let Some(spool) = &state.spool {
+        for entry in spool.list(file_type).await? {
+            spooled.insert(
+                entry.name.clone(),
+                FileEntryV2 {
+                    name: entry.name,
+                    size: entry.size as u64,
+                },
+            );
+        }
+    }
 
-    let body = serde_json::to_string(&entries)?;
+    // Data listings include all shard subdirectories; the v2 format (name +
+    // size) is serialized entry by entry as rows are read
+    let remote = state.backend.list_stream(file_type.dir_path()).await?;
+    let shadowed: HashSet<String> = spooled.keys().cloned().collect();
+    let entries = remote
+        .try_filter(move |f| std::future::ready(!shadowed.contains(&f.name)))
+        .map_ok(|f| FileEntryV2::from(&f))
+        .chain(stream::iter(spooled.into_values().map(Ok)));
+    let body = entries.enumerate().map(|(index, entry)| {
+        let mut chunk = if index == 0 { Vec::new() } else { vec![b','] };
+        serde_json::to_writer(&mut chunk, &entry?)?;
+        Ok::<_, AppError>(Bytes::from(chunk))
+    });
+    let body = stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(body)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
 
     Ok(Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE, V2_CONTENT_TYPE)
-        .body(Body::from(body))
+        .body(Body::from_stream(body))
         .unwrap())
 }
 
@@ -194,26 +602,22 @@ async fn list_files(
 async fn head_file(
     State(state): State<Arc<AppState>>,
     Path((type_str, name)): Path<(String, String)>,
-) -> Result<impl IntoResponse> {
-    let file_type = ResticFileType::from_str(&type_str)
-        .ok_or_else(|| AppError::BadRequest(format!("Invalid type: {}", type_str)))?;
+    headers: HeaderMap,
+) -> Result<Response> {
+    let file_type = object_type(&type_str, &name)?;
 
-    // For data files, use the subdirectory based on filename prefix
-    let dir_id = if file_type == ResticFileType::Data {
-        state.client.get_data_file_dir_id(&name).await?
-    } else {
-        state.client.get_type_dir_id(file_type).await?
-    };
+    if let Some(size) = spooled_size(&state, file_type, &name).await? {
+        return Ok((StatusCode::OK, content_length_headers(size)).into_response());
+    }
 
-    match state.client.get_file_info(dir_id, &name).await? {
-        Some(file) => {
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                header::CONTENT_LENGTH,
-                file.size.to_string().parse().unwrap(),
-            );
-            Ok((StatusCode::OK, headers))
-        }
+    match head_object(
+        &state,
+        file_type,
+        &state.layout.object_path(file_type, &name),
+    )
+    .await?
+    {
+        Some(file) => Ok(head_response(&file, &headers)),
         None => Err(AppError::NotFound(name)),
     }
 }
@@ -253,23 +657,54 @@ async fn get_file(
     State(state): State<Arc<AppState>>,
     Path((type_str, name)): Path<(String, String)>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse> {
-    let file_type = ResticFileType::from_str(&type_str)
-        .ok_or_else(|| AppError::BadRequest(format!("Invalid type: {}", type_str)))?;
+) -> Result<Response> {
+    let file_type = object_type(&type_str, &name)?;
 
-    // For data files, use the subdirectory based on filename prefix
-    let dir_id = if file_type == ResticFileType::Data {
-        state.client.get_data_file_dir_id(&name).await?
-    } else {
-        state.client.get_type_dir_id(file_type).await?
-    };
+    if let Some(data) = read_spooled(&state, file_type, &name).await? {
+        return Ok(spooled_response(data, &headers));
+    }
 
+    let path = state.layout.object_path(file_type, &name);
     let file = state
-        .client
-        .get_file_info(dir_id, &name)
+        .backend
+        .head(&path)
         .await?
         .ok_or_else(|| AppError::NotFound(name.clone()))?;
 
+    if let Some(response) = not_modified_response(&file, &headers) {
+        return Ok(response);
+    }
+
+    let response = if let Some(cache) = state
+        .metadata_cache
+        .as_ref()
+        .filter(|_| MetadataCache::caches(file_type))
+    {
+        serve_cached(&state, cache, file_type, &name, &file, &headers).await?
+    } else if let Some(response) =
+        serve_readahead(&state, file_type, &name, &file, &headers).await?
+    {
+        response
+    } else if let Some(cache) = state
+        .pack_cache
+        .as_ref()
+        .filter(|_| file_type == ResticFileType::Data)
+    {
+        serve_pack_cached(&state, cache, &name, &file, &headers).await?
+    } else {
+        download_response(&state, &path, &file, &headers).await?
+    };
+
+    Ok(with_validators(response, &file))
+}
+
+/// Download from the backend, passing a Range request through natively.
+async fn download_response(
+    state: &AppState,
+    path: &str,
+    file: &ObjectInfo,
+    headers: &HeaderMap,
+) -> Result<Response> {
     let file_size = file.size as u64;
 
     // Check for Range header
@@ -279,29 +714,12 @@ async fn get_file(
         .and_then(|r| parse_range(r, file_size));
 
     if let Some((start, end)) = range {
-        // Use native range download from 123pan
-        let data = state
-            .client
-            .download_file(file.file_id, Some((start, end)))
-            .await?;
-
-        let content_range = format!("bytes {}-{}/{}", start, end, file_size);
-
-        let mut resp_headers = HeaderMap::new();
-        resp_headers.insert(
-            header::CONTENT_TYPE,
-            "application/octet-stream".parse().unwrap(),
-        );
-        resp_headers.insert(
-            header::CONTENT_LENGTH,
-            data.len().to_string().parse().unwrap(),
-        );
-        resp_headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
-
-        Ok((StatusCode::PARTIAL_CONTENT, resp_headers, data).into_response())
+        // Use the backend's native range download
+        let data = state.backend.get_range(path, Some((start, end))).await?;
+        Ok(range_response(data, start, end, file_size))
     } else {
         // Full file download
-        let data = state.client.download_file(file.file_id, None).await?;
+        let data = state.backend.get_range(path, None).await?;
 
         let mut resp_headers = HeaderMap::new();
         resp_headers.insert(
@@ -321,27 +739,26 @@ async fn get_file(
 async fn post_file(
     State(state): State<Arc<AppState>>,
     Path((type_str, name)): Path<(String, String)>,
+    headers: HeaderMap,
     body: axum::body::Body,
 ) -> Result<impl IntoResponse> {
+    let file_type = object_type(&type_str, &name)?;
+
+    let _permit = state.uploads.acquire().await?;
+    let _reservation = state.bodies.reserve(content_length(&headers)).await?;
+
     // Convert body to Bytes with 1GB limit
     let body = axum::body::to_bytes(body, 1024 * 1024 * 1024)
         .await
         .map_err(|e| AppError::BadRequest(format!("Failed to read request body: {}", e)))?;
 
-    let file_type = ResticFileType::from_str(&type_str)
-        .ok_or_else(|| AppError::BadRequest(format!("Invalid type: {}", type_str)))?;
-
     tracing::info!("Uploading {}/{} ({} bytes)", type_str, name, body.len());
 
-    // For data files, use the subdirectory based on filename prefix
-    let dir_id = if file_type == ResticFileType::Data {
-        state.client.get_data_file_dir_id(&name).await?
+    if state.immutable && file_type.is_immutable() {
+        create_object(&state, file_type, &name, body).await?;
     } else {
-        state.client.get_type_dir_id(file_type).await?
-    };
-
-    // With duplicate=2, upload will overwrite existing file atomically
-    state.client.upload_file(dir_id, &name, body).await?;
+        store_object(&state, file_type, &name, body).await?;
+    }
 
     Ok(StatusCode::OK)
 }
@@ -351,22 +768,533 @@ async fn delete_file(
     State(state): State<Arc<AppState>>,
     Path((type_str, name)): Path<(String, String)>,
 ) -> Result<impl IntoResponse> {
-    let file_type = ResticFileType::from_str(&type_str)
-        .ok_or_else(|| AppError::BadRequest(format!("Invalid type: {}", type_str)))?;
+    let file_type = object_type(&type_str, &name)?;
 
     tracing::info!("Deleting {}/{}", type_str, name);
 
-    // For data files, use the subdirectory based on filename prefix
-    let dir_id = if file_type == ResticFileType::Data {
-        state.client.get_data_file_dir_id(&name).await?
+    if let Some(spool) = &state.spool {
+        spool.remove(file_type, &name).await?;
+    }
+    if let Some(cache) = &state.metadata_cache {
+        cache.invalidate(file_type, &name).await?;
+    }
+    if let Some(cache) = state
+        .pack_cache
+        .as_ref()
+        .filter(|_| file_type == ResticFileType::Data)
+    {
+        cache.invalidate(&name).await;
+    }
+    if let Some(readahead) = &state.readahead {
+        readahead.forget(&name);
+    }
+
+    // Idempotent: return OK even if file doesn't exist
+    state
+        .backend
+        .delete(&state.layout.object_path(file_type, &name))
+        .await?;
+
+    Ok(StatusCode::OK)
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Parse the object type and validate the object name.
+fn object_type(type_str: &str, name: &str) -> Result<ResticFileType> {
+    validate_filename(name)?;
+    let file_type = ResticFileType::from_str(type_str)
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid type: {}", type_str)))?;
+    if !file_type.is_valid_name(name) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid {} name: {}",
+            type_str, name
+        )));
+    }
+    Ok(file_type)
+}
+
+/// Metadata for a HEAD request, confirmed with the backend for the types
+/// configured in [`ServerOptions::verified_heads`].
+async fn head_object(
+    state: &AppState,
+    file_type: ResticFileType,
+    path: &str,
+) -> Result<Option<ObjectInfo>> {
+    if state.verified_heads.contains(&file_type) {
+        state.backend.head_verified(path).await
     } else {
-        state.client.get_type_dir_id(file_type).await?
+        state.backend.head(path).await
+    }
+}
+
+/// Headers for a HEAD response advertising `size` bytes.
+fn content_length_headers(size: i64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_LENGTH, size.to_string().parse().unwrap());
+    headers
+}
+
+/// Quoted entity tag for an object (its content MD5), if known.
+fn entity_tag(file: &ObjectInfo) -> Option<String> {
+    file.etag.as_ref().map(|etag| format!("\"{}\"", etag))
+}
+
+/// Whether an `If-None-Match` header matches `etag`.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(value) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
     };
+    value.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
 
-    // Idempotent: return OK even if file doesn't exist
-    if let Some(file) = state.client.get_file_info(dir_id, &name).await? {
-        state.client.delete_file(dir_id, file.file_id).await?;
+/// 304 response if the client already holds the current version of `file`.
+fn not_modified_response(file: &ObjectInfo, headers: &HeaderMap) -> Option<Response> {
+    let etag = entity_tag(file)?;
+    etag_matches(headers, &etag)
+        .then(|| with_validators(StatusCode::NOT_MODIFIED.into_response(), file))
+}
+
+/// Attach cache validators (ETag, Last-Modified) for `file` to a response.
+fn with_validators(mut response: Response, file: &ObjectInfo) -> Response {
+    if let Some(value) = entity_tag(file).and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        response.headers_mut().insert(header::ETAG, value);
     }
+    if let Some(modified_at) = file.modified_at {
+        let http_date = modified_at
+            .and_utc()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        if let Ok(value) = HeaderValue::from_str(&http_date) {
+            response.headers_mut().insert(header::LAST_MODIFIED, value);
+        }
+    }
+    response
+}
 
-    Ok(StatusCode::OK)
+/// HEAD response for a stored object, honoring `If-None-Match`.
+fn head_response(file: &ObjectInfo, headers: &HeaderMap) -> Response {
+    if let Some(response) = not_modified_response(file, headers) {
+        return response;
+    }
+    with_validators(
+        (StatusCode::OK, content_length_headers(file.size)).into_response(),
+        file,
+    )
+}
+
+/// Store an uploaded object through the spool (write-back) or the backend,
+/// queueing it in the spool if the backend fails.
+async fn store_object(
+    state: &AppState,
+    file_type: ResticFileType,
+    name: &str,
+    data: Bytes,
+) -> Result<()> {
+    let Some(spool) = &state.spool else {
+        return state
+            .backend
+            .put(&state.layout.object_path(file_type, name), data)
+            .await;
+    };
+    if state.write_back {
+        return spool.put(file_type, name, data).await;
+    }
+
+    match state
+        .backend
+        .put(&state.layout.object_path(file_type, name), data.clone())
+        .await
+    {
+        Err(e) if !matches!(e, AppError::BadRequest(_) | AppError::NotFound(_)) => {
+            tracing::warn!(
+                "Upload of {}/{} failed, queueing it for retry: {}",
+                file_type.dirname(),
+                name,
+                e
+            );
+            spool.put(file_type, name, data).await
+        }
+        result => {
+            // A stale queued version must not overwrite this one later
+            if result.is_ok() {
+                spool.remove(file_type, name).await?;
+            }
+            result
+        }
+    }
+}
+
+/// Store an object that must not replace an existing one (immutable-data
+/// mode). Uploading the same content again succeeds, so restic can retry an
+/// upload whose response got lost; different content gets 403.
+async fn create_object(
+    state: &AppState,
+    file_type: ResticFileType,
+    name: &str,
+    data: Bytes,
+) -> Result<()> {
+    let path = state.layout.object_path(file_type, name);
+    let etag = format!("{:x}", md5::compute(&data));
+    let existing = match read_spooled(state, file_type, name).await? {
+        Some(spooled) => Some(format!("{:x}", md5::compute(&spooled))),
+        None => match state.backend.head(&path).await? {
+            Some(file) if file.size == data.len() as i64 && file.etag.is_none() => {
+                let stored = state.backend.get_range(&path, None).await?;
+                Some(format!("{:x}", md5::compute(&stored)))
+            }
+            Some(file) => Some(file.etag.unwrap_or_default()),
+            None => None,
+        },
+    };
+    match existing {
+        Some(existing) if existing == etag => {
+            tracing::info!("{} already stored with the same content", path);
+            return Ok(());
+        }
+        Some(_) => {
+            tracing::warn!("Refusing to overwrite {} in immutable-data mode", path);
+            return Err(AppError::Forbidden(format!(
+                "Server is immutable-data: {} already exists",
+                path
+            )));
+        }
+        None => {}
+    }
+    if let (Some(spool), true) = (&state.spool, state.write_back) {
+        return spool.put(file_type, name, data).await;
+    }
+    match state.backend.create(&path, data.clone()).await {
+        Err(e)
+            if !matches!(
+                e,
+                AppError::BadRequest(_) | AppError::NotFound(_) | AppError::Forbidden(_)
+            ) =>
+        {
+            let Some(spool) = &state.spool else {
+                return Err(e);
+            };
+            tracing::warn!(
+                "Upload of {}/{} failed, queueing it for retry: {}",
+                file_type.dirname(),
+                name,
+                e
+            );
+            spool.put(file_type, name, data).await
+        }
+        result => result,
+    }
+}
+
+/// Serve spooled content, tagged with its MD5 like 123pan's etag.
+fn spooled_response(data: Bytes, headers: &HeaderMap) -> Response {
+    let etag = format!("\"{:x}\"", md5::compute(&data));
+    let mut response = if etag_matches(headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        data_response(data, headers)
+    };
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    response
+}
+
+/// Size of an object pending in the write-back spool, if any.
+async fn spooled_size(
+    state: &AppState,
+    file_type: ResticFileType,
+    name: &str,
+) -> Result<Option<i64>> {
+    let Some(spool) = &state.spool else {
+        return Ok(None);
+    };
+    Ok(spool.get(file_type, name).await?.map(|entry| entry.size))
+}
+
+/// Content of an object pending in the write-back spool, if any.
+async fn read_spooled(
+    state: &AppState,
+    file_type: ResticFileType,
+    name: &str,
+) -> Result<Option<Bytes>> {
+    let Some(spool) = &state.spool else {
+        return Ok(None);
+    };
+    match spool.get(file_type, name).await? {
+        Some(entry) => spool.read(&entry).await,
+        None => Ok(None),
+    }
+}
+
+/// Serve an object through the metadata read cache, downloading it in full on a miss.
+async fn serve_cached(
+    state: &AppState,
+    cache: &MetadataCache,
+    file_type: ResticFileType,
+    name: &str,
+    file: &ObjectInfo,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let path = state.layout.object_path(file_type, name);
+    let cached = match cache.get(file_type, name, file).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            tracing::warn!("Metadata cache read failed for {}: {}", name, e);
+            None
+        }
+    };
+    state
+        .read_cache_metrics
+        .record("metadata", &path, cached.is_some());
+    if let Some(data) = cached {
+        return Ok(data_response(data, headers));
+    }
+
+    let data = state.backend.get_range(&path, None).await?;
+    if let Err(e) = cache.put(file_type, name, file, &data).await {
+        tracing::warn!("Metadata cache write failed for {}: {}", name, e);
+    }
+    Ok(data_response(data, headers))
+}
+
+/// Serve a data pack through the pack cache. A miss downloads the whole pack,
+/// so later range reads of the same pack are served locally; with readahead,
+/// a ranged miss is answered first and the pack is fetched in the background.
+async fn serve_pack_cached(
+    state: &AppState,
+    cache: &PackCache,
+    name: &str,
+    file: &ObjectInfo,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let path = state.layout.object_path(ResticFileType::Data, name);
+    let cached = match cache.get(name, file.size).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            tracing::warn!("Pack cache read failed for {}: {}", name, e);
+            None
+        }
+    };
+    state
+        .read_cache_metrics
+        .record("pack", &path, cached.is_some());
+    if let Some(data) = cached {
+        return Ok(data_response(data, headers));
+    }
+
+    let ranged = headers.contains_key(header::RANGE);
+    if state.pack_readahead && ranged {
+        let prefetch = cache.start_prefetch(name, file.size).map(|guard| {
+            let backend = state.backend.clone();
+            let cache = cache.clone();
+            let name = name.to_string();
+            let path = path.clone();
+            let task = tokio::spawn(
+                async move {
+                    let _guard = guard;
+                    let result = match backend.get_range(&path, None).await {
+                        Ok(data) => cache.put(&name, &data).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => tracing::debug!("Prefetched pack {}", name),
+                        Err(e) => tracing::warn!("Prefetching pack {} failed: {}", name, e),
+                    }
+                }
+                .in_current_span(),
+            );
+            // Restic giving up on this read (timeout, ctrl-C) drops this
+            // future, cancelling the prefetch along with the read
+            AbortOnDrop(Some(task.abort_handle()))
+        });
+        let response = download_response(state, &path, file, headers).await;
+        if let Some(prefetch) = prefetch {
+            prefetch.disarm();
+        }
+        return response;
+    }
+
+    let data = state.backend.get_range(&path, None).await?;
+    if let Err(e) = cache.put(name, &data).await {
+        tracing::warn!("Pack cache write failed for {}: {}", name, e);
+    }
+    Ok(data_response(data, headers))
+}
+
+/// Aborts a background task when dropped, unless disarmed first.
+struct AbortOnDrop(Option<tokio::task::AbortHandle>);
+
+impl AbortOnDrop {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            handle.abort();
+        }
+    }
+}
+
+/// Serve a data pack read as part of a sequential scan or a burst of
+/// whole-pack reads through [`Readahead`]; `None` leaves the read to the
+/// usual path.
+async fn serve_readahead(
+    state: &AppState,
+    file_type: ResticFileType,
+    name: &str,
+    file: &ObjectInfo,
+    headers: &HeaderMap,
+) -> Result<Option<Response>> {
+    let Some(readahead) = state
+        .readahead
+        .as_ref()
+        .filter(|_| file_type == ResticFileType::Data)
+    else {
+        return Ok(None);
+    };
+    let path = state.layout.object_path(file_type, name);
+    let file_size = file.size as u64;
+
+    let Some(range) = headers.get(header::RANGE) else {
+        if !readahead.full_read() {
+            return Ok(None);
+        }
+        let data = fetch_pack(state, &path, name, file).await?;
+        return Ok(Some(data_response(data, headers)));
+    };
+    let Some((start, end)) = range.to_str().ok().and_then(|r| parse_range(r, file_size)) else {
+        return Ok(None);
+    };
+    match readahead.plan(name, start, end, file_size) {
+        ReadPlan::Pass => Ok(None),
+        ReadPlan::Hit(data) => Ok(Some(range_response(data, start, end, file_size))),
+        ReadPlan::Fetch(window_start, window_end) => {
+            let data = if window_start == 0 && window_end + 1 == file_size {
+                fetch_pack(state, &path, name, file).await?
+            } else {
+                state
+                    .backend
+                    .get_range(&path, Some((window_start, window_end)))
+                    .await?
+            };
+            tracing::debug!(
+                "Read ahead {} bytes of pack {} from {}",
+                data.len(),
+                name,
+                window_start
+            );
+            // A truncated transfer, or a pack that shrank since it was listed
+            if data.len() as u64 != window_end - window_start + 1 {
+                readahead.forget(name);
+                return Err(AppError::Internal(format!(
+                    "Pack {} returned {} bytes for {}-{}",
+                    name,
+                    data.len(),
+                    window_start,
+                    window_end
+                )));
+            }
+            let slice = data.slice((start - window_start) as usize..=(end - window_start) as usize);
+            readahead.store(name, window_start, data);
+            Ok(Some(range_response(slice, start, end, file_size)))
+        }
+    }
+}
+
+/// Fetch a whole pack through the pack cache, if any. A pack not matching its
+/// ID is fetched again; if it still doesn't, it is damaged in the repository
+/// and served as is, for restic to report.
+async fn fetch_pack(state: &AppState, path: &str, name: &str, file: &ObjectInfo) -> Result<Bytes> {
+    if let Some(cache) = &state.pack_cache {
+        let cached = cache.get(name, file.size).await.unwrap_or_else(|e| {
+            tracing::warn!("Pack cache read failed for {}: {}", name, e);
+            None
+        });
+        state
+            .read_cache_metrics
+            .record("pack", path, cached.is_some());
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+    }
+
+    let mut data = state.backend.get_range(path, None).await?;
+    if !verify_pack(name, &data) {
+        tracing::warn!(
+            "Pack {} came back with other content, fetching it again",
+            name
+        );
+        data = state.backend.get_range(path, None).await?;
+        if !verify_pack(name, &data) {
+            tracing::warn!("Pack {} does not match its ID", name);
+            return Ok(data);
+        }
+    }
+    if let Some(cache) = &state.pack_cache {
+        if let Err(e) = cache.put(name, &data).await {
+            tracing::warn!("Pack cache write failed for {}: {}", name, e);
+        }
+    }
+    Ok(data)
+}
+
+/// Build a full or partial (Range) response from locally held data.
+fn data_response(data: Bytes, headers: &HeaderMap) -> Response {
+    let file_size = data.len() as u64;
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|r| parse_range(r, file_size));
+
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(
+        header::CONTENT_TYPE,
+        "application/octet-stream".parse().unwrap(),
+    );
+
+    match range {
+        Some((start, end)) => {
+            let slice = data.slice(start as usize..=end as usize);
+            range_response(slice, start, end, file_size)
+        }
+        None => {
+            resp_headers.insert(
+                header::CONTENT_LENGTH,
+                data.len().to_string().parse().unwrap(),
+            );
+            (StatusCode::OK, resp_headers, data).into_response()
+        }
+    }
+}
+
+/// Build a 206 response carrying bytes `start..=end` of an object of
+/// `file_size` bytes.
+fn range_response(data: Bytes, start: u64, end: u64, file_size: u64) -> Response {
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(
+        header::CONTENT_TYPE,
+        "application/octet-stream".parse().unwrap(),
+    );
+    resp_headers.insert(
+        header::CONTENT_LENGTH,
+        data.len().to_string().parse().unwrap(),
+    );
+    resp_headers.insert(
+        header::CONTENT_RANGE,
+        format!("bytes {}-{}/{}", start, end, file_size)
+            .parse()
+            .unwrap(),
+    );
+    (StatusCode::PARTIAL_CONTENT, resp_headers, data).into_response()
 }
@@ -0,0 +1,442 @@
+//! HTTP middleware for the Restic REST API.
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+use super::handler::{AdminAccess, AppState};
+use crate::error::AppError;
+use crate::pan123::metrics::UpstreamTimings;
+
+/// Header used to propagate the request ID to and from clients.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Monotonic counter used to generate request IDs.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Request ID assigned to each incoming request (stored in request extensions).
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Use the client-supplied request ID if present, otherwise generate one.
+fn request_id_for(req: &Request) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:08x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// Logs a request dropped before it produced a response because the client
+/// went away, cancelling the 123pan transfers it was making. Uploads already
+/// handed to the write-back spool and queued deletes are not undone: both are
+/// handed over only once the request is complete, and its response is due.
+struct Cancellation {
+    span: tracing::Span,
+    method: Method,
+    path: String,
+    start: Instant,
+    finished: bool,
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let duration = self.start.elapsed();
+        self.span.in_scope(|| {
+            // 499 as in nginx: client closed request
+            tracing::info!(
+                target: "access_log",
+                status = 499,
+                duration_ms = duration.as_millis() as u64,
+                "{} {} cancelled after {} ms: client went away",
+                self.method,
+                self.path,
+                duration.as_millis()
+            );
+        });
+    }
+}
+
+/// Assign a request ID, run the request inside a tracing span carrying it,
+/// and emit an access-log line once the response is produced, or with status
+/// 499 if the request is cancelled first. Requests taking longer than
+/// [`AppState::slow_request`] are also logged as a warning with the 123pan
+/// calls they waited on.
+pub async fn access_log(
+    State(state): State<Arc<AppState>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let request_id = request_id_for(&req);
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| path.clone(), |p| p.as_str().to_string());
+    let bytes_in = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    // Scoped to the request's hub when error reporting is enabled
+    sentry::configure_scope(|scope| scope.set_tag("request_id", &request_id));
+
+    let span = tracing::info_span!("request", id = %request_id, %method, %path);
+    let start = Instant::now();
+    let mut cancellation = Cancellation {
+        span: span.clone(),
+        method: method.clone(),
+        path: path.clone(),
+        start,
+        finished: false,
+    };
+
+    let timings = Arc::new(UpstreamTimings::default());
+    let mut response = match state.slow_request {
+        Some(_) => {
+            timings
+                .clone()
+                .scope(next.run(req).instrument(span.clone()))
+                .await
+        }
+        None => next.run(req).instrument(span.clone()).await,
+    };
+    cancellation.finished = true;
+    let duration = start.elapsed();
+
+    // HEAD responses advertise the object size but carry no body
+    let bytes_out = if method == Method::HEAD {
+        0
+    } else {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .or_else(|| response.body().size_hint().exact())
+            .unwrap_or(0)
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    span.in_scope(|| {
+        tracing::info!(
+            target: "access_log",
+            status = response.status().as_u16(),
+            duration_ms = duration.as_millis() as u64,
+            bytes_in,
+            bytes_out,
+            "{} {} {}",
+            method,
+            path,
+            response.status().as_u16()
+        );
+        if state
+            .slow_request
+            .is_some_and(|threshold| duration > threshold)
+        {
+            tracing::warn!(
+                target: "slow_request",
+                request_id = %request_id,
+                %route,
+                status = response.status().as_u16(),
+                duration_ms = duration.as_millis() as u64,
+                upstream_calls = timings.calls(),
+                upstream_ms = timings.total().as_millis() as u64,
+                upstream = %timings.summary(),
+                "Slow request: {} {} took {} ms",
+                method,
+                path,
+                duration.as_millis()
+            );
+        }
+    });
+
+    response
+}
+
+// ============================================================================
+// Rate Limiting
+// ============================================================================
+
+/// Number of tracked clients above which idle buckets are pruned.
+const RATE_LIMIT_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Token bucket for a single client.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per-client-IP token bucket rate limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    inner: Mutex<RateLimiterState>,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    /// Requests per second; 0 disables limiting
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` requests per second with bursts of up
+    /// to `burst`. A `rate` of 0 disables limiting.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            inner: Mutex::new(RateLimiterState {
+                rate,
+                burst: f64::from(burst.max(1)),
+                buckets: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Change the rate and burst, e.g. on configuration reload.
+    pub fn update(&self, rate: f64, burst: u32) {
+        let mut inner = self.inner.lock();
+        inner.rate = rate;
+        inner.burst = f64::from(burst.max(1));
+        inner.buckets.clear();
+    }
+
+    /// Try to take a token for `ip`. On rejection returns how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        let RateLimiterState {
+            rate,
+            burst,
+            buckets,
+        } = &mut *inner;
+        let (rate, burst) = (*rate, *burst);
+        if rate <= 0.0 {
+            return Ok(());
+        }
+
+        if buckets.len() > RATE_LIMIT_PRUNE_THRESHOLD {
+            let full_after = Duration::from_secs_f64(burst / rate);
+            buckets.retain(|_, b| now.duration_since(b.last_refill) < full_after);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Reject requests exceeding the per-client-IP rate with 429 and Retry-After.
+/// Health probes are never limited.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    if path == "/healthz" || path == "/readyz" {
+        return next.run(req).await;
+    }
+
+    let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        return next.run(req).await;
+    };
+
+    match limiter.check(addr.ip()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            tracing::warn!("Rate limit exceeded for {}", addr.ip());
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Answer requests that have no response after `deadline` with 504. Only the
+/// time until the response starts counts; streaming a body is not cut off.
+pub async fn deadline(State(deadline): State<Duration>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    match tokio::time::timeout(deadline, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => AppError::Timeout(format!(
+            "{} {} got no response within {:?}",
+            method, path, deadline
+        ))
+        .into_response(),
+    }
+}
+
+/// Seconds clients are asked to wait before retrying while the server drains.
+const DRAIN_RETRY_AFTER: u64 = 60;
+
+/// Operating mode switched at runtime through `PUT /admin/mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerMode {
+    /// Serve everything
+    Normal,
+    /// Refuse requests that modify the repository with 403
+    ReadOnly,
+    /// Answer new requests that modify the repository with 503 + Retry-After
+    /// and report not ready, letting in-flight uploads finish
+    Drain,
+}
+
+/// Current [`ServerMode`], shared by clones.
+#[derive(Debug, Clone, Default)]
+pub struct ModeSwitch(Arc<AtomicU8>);
+
+impl ModeSwitch {
+    pub fn get(&self) -> ServerMode {
+        match self.0.load(Ordering::Relaxed) {
+            1 => ServerMode::ReadOnly,
+            2 => ServerMode::Drain,
+            _ => ServerMode::Normal,
+        }
+    }
+
+    pub fn set(&self, mode: ServerMode) {
+        let value = match mode {
+            ServerMode::Normal => 0,
+            ServerMode::ReadOnly => 1,
+            ServerMode::Drain => 2,
+        };
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Refuse `/admin/*` requests without the admin token when one is required.
+pub async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let AdminAccess::Token(token) = &state.admin else {
+        return next.run(req).await;
+    };
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if presented.is_some_and(|presented| tokens_match(presented, token)) {
+        return next.run(req).await;
+    }
+    tracing::warn!(
+        "Refusing {} {} without the admin token",
+        req.method(),
+        req.uri().path()
+    );
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        axum::Json(serde_json::json!({ "error": "Admin token required" })),
+    )
+        .into_response()
+}
+
+/// Compare tokens in time independent of where they differ.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Refuse requests that modify the repository while the server is read-only
+/// (another instance holds the repository lock, or `ServerMode::ReadOnly`)
+/// or draining, and deletes other than of locks in append-only mode.
+pub async fn reject_writes(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let modifies = matches!(*req.method(), Method::POST | Method::DELETE)
+        && !req.uri().path().starts_with("/admin/");
+    if !modifies {
+        return next.run(req).await;
+    }
+    if state.append_only
+        && *req.method() == Method::DELETE
+        && !req.uri().path().starts_with("/locks/")
+    {
+        tracing::warn!("Refusing DELETE {} in append-only mode", req.uri().path());
+        return (
+            StatusCode::FORBIDDEN,
+            axum::Json(serde_json::json!({
+                "error": "Server is append-only: only locks can be deleted"
+            })),
+        )
+            .into_response();
+    }
+    let reason = if state.read_only.load(Ordering::Relaxed) {
+        "another instance holds the repository lock"
+    } else {
+        match state.mode.get() {
+            ServerMode::Normal => return next.run(req).await,
+            ServerMode::ReadOnly => "maintenance mode",
+            ServerMode::Drain => {
+                return AppError::Unavailable {
+                    message: "Server is draining for maintenance".to_string(),
+                    retry_after: DRAIN_RETRY_AFTER,
+                }
+                .into_response()
+            }
+        }
+    };
+    tracing::warn!(
+        "Refusing {} {} in read-only mode",
+        req.method(),
+        req.uri().path()
+    );
+    (
+        StatusCode::FORBIDDEN,
+        axum::Json(serde_json::json!({
+            "error": format!("Server is read-only: {}", reason)
+        })),
+    )
+        .into_response()
+}
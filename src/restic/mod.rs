@@ -1,7 +1,16 @@
 //! Restic REST API module.
 
+pub mod admission;
+pub mod audit;
 pub mod handler;
+pub mod middleware;
+pub mod read_cache;
+pub mod readahead;
+pub mod spool;
 pub mod types;
 
-pub use handler::create_router;
-pub use types::ResticFileType;
+#[cfg(test)]
+mod tests;
+
+pub use handler::{create_router, AdminAccess, ServerOptions};
+pub use types::{RepoLayout, ResticFileType};
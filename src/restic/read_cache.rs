@@ -0,0 +1,322 @@
+//! Local disk read caches.
+//!
+//! restic repeatedly fetches config, index, snapshot and key files. Their
+//! contents are cached on local disk keyed by backend object ID and etag, so a
+//! cached copy is only served while it matches the current remote version.
+//!
+//! Data packs are content-addressed (the name is the SHA-256 of the content),
+//! so they are cached by name alone in a size-capped LRU cache. With
+//! readahead, a ranged read of an uncached pack is answered directly while the
+//! whole pack is fetched into the cache in the background.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::types::ResticFileType;
+use crate::error::Result;
+use crate::storage::ObjectInfo;
+
+/// On-disk cache for config/index/snapshot/key objects.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+impl MetadataCache {
+    /// Open (or create) the cache in `dir`.
+    pub async fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await?;
+        tracing::info!("Metadata read cache at {}", dir.display());
+        Ok(Self { dir })
+    }
+
+    /// Whether objects of this type are cached.
+    pub fn caches(file_type: ResticFileType) -> bool {
+        matches!(
+            file_type,
+            ResticFileType::Config
+                | ResticFileType::Index
+                | ResticFileType::Snapshots
+                | ResticFileType::Keys
+        )
+    }
+
+    fn type_dir(&self, file_type: ResticFileType) -> PathBuf {
+        self.dir.join(file_type.dirname())
+    }
+
+    /// File name prefix shared by all cached versions of an object.
+    fn name_prefix(name: &str) -> String {
+        format!("{:x}-", md5::compute(name))
+    }
+
+    /// Cache path for the given remote version of an object.
+    fn entry_path(&self, file_type: ResticFileType, name: &str, file: &ObjectInfo) -> PathBuf {
+        let version = file
+            .etag
+            .clone()
+            .unwrap_or_else(|| format!("s{}", file.size));
+        self.type_dir(file_type).join(format!(
+            "{}{}-{}",
+            Self::name_prefix(name),
+            file.id,
+            version
+        ))
+    }
+
+    /// Return the cached content if it matches the remote version `file`.
+    pub async fn get(
+        &self,
+        file_type: ResticFileType,
+        name: &str,
+        file: &ObjectInfo,
+    ) -> Result<Option<Bytes>> {
+        let path = self.entry_path(file_type, name, file);
+        match tokio::fs::read(&path).await {
+            Ok(data) if data.len() as i64 == file.size => {
+                tracing::debug!("Metadata cache hit: {}/{}", file_type.dirname(), name);
+                Ok(Some(Bytes::from(data)))
+            }
+            Ok(_) => {
+                tracing::warn!(
+                    "Metadata cache entry {} has wrong size, discarding",
+                    path.display()
+                );
+                let _ = tokio::fs::remove_file(&path).await;
+                Ok(None)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Store the content of remote version `file`, replacing older versions.
+    pub async fn put(
+        &self,
+        file_type: ResticFileType,
+        name: &str,
+        file: &ObjectInfo,
+        data: &Bytes,
+    ) -> Result<()> {
+        let type_dir = self.type_dir(file_type);
+        tokio::fs::create_dir_all(&type_dir).await?;
+
+        self.invalidate(file_type, name).await?;
+
+        let path = self.entry_path(file_type, name, file);
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    /// Drop all cached versions of an object.
+    pub async fn invalidate(&self, file_type: ResticFileType, name: &str) -> Result<()> {
+        remove_with_prefix(&self.type_dir(file_type), &Self::name_prefix(name)).await
+    }
+}
+
+// ============================================================================
+// Pack Cache
+// ============================================================================
+
+/// Size-capped on-disk cache for data packs with LRU eviction.
+#[derive(Debug, Clone)]
+pub struct PackCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    lru: Arc<Mutex<Lru>>,
+    /// Packs being fetched in the background
+    prefetching: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Marks a pack as being prefetched until dropped.
+#[derive(Debug)]
+pub struct PrefetchGuard {
+    name: String,
+    prefetching: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for PrefetchGuard {
+    fn drop(&mut self) {
+        self.prefetching.lock().remove(&self.name);
+    }
+}
+
+/// In-memory recency index of cached packs.
+#[derive(Debug, Default)]
+struct Lru {
+    /// name -> (size, last use tick)
+    entries: HashMap<String, (u64, u64)>,
+    /// last use tick -> name, oldest first
+    order: BTreeMap<u64, String>,
+    total_bytes: u64,
+    tick: u64,
+}
+
+impl Lru {
+    /// Mark `name` as most recently used, inserting it if needed.
+    fn touch(&mut self, name: &str, size: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((old_size, old_tick)) = self.entries.insert(name.to_string(), (size, tick)) {
+            self.order.remove(&old_tick);
+            self.total_bytes -= old_size;
+        }
+        self.order.insert(tick, name.to_string());
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some((size, tick)) = self.entries.remove(name) {
+            self.order.remove(&tick);
+            self.total_bytes -= size;
+        }
+    }
+
+    /// Drop least recently used entries until the total fits in `max_bytes`.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_bytes > max_bytes {
+            let Some((_, name)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.entries.remove(&name) {
+                self.total_bytes -= size;
+            }
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+impl PackCache {
+    /// Open (or create) the cache in `dir`, indexing existing entries by mtime.
+    pub async fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut existing = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".tmp") {
+                let _ = tokio::fs::remove_file(entry.path()).await;
+                continue;
+            }
+            let meta = entry.metadata().await?;
+            if meta.is_file() {
+                let mtime = meta.modified().unwrap_or(std::time::UNIX_EPOCH);
+                existing.push((mtime, name, meta.len()));
+            }
+        }
+        existing.sort();
+
+        let mut lru = Lru::default();
+        for (_, name, size) in existing {
+            lru.touch(&name, size);
+        }
+        let cache = Self {
+            dir,
+            max_bytes,
+            lru: Arc::new(Mutex::new(lru)),
+            prefetching: Arc::default(),
+        };
+        cache.evict().await;
+
+        let lru = cache.lru.lock();
+        tracing::info!(
+            "Pack cache at {}: {} packs, {} of {} bytes",
+            cache.dir.display(),
+            lru.entries.len(),
+            lru.total_bytes,
+            max_bytes
+        );
+        drop(lru);
+        Ok(cache)
+    }
+
+    /// Return the cached pack if present with the expected size.
+    pub async fn get(&self, name: &str, size: i64) -> Result<Option<Bytes>> {
+        if !self.lru.lock().entries.contains_key(name) {
+            return Ok(None);
+        }
+        match tokio::fs::read(self.dir.join(name)).await {
+            Ok(data) if data.len() as i64 == size => {
+                self.lru.lock().touch(name, data.len() as u64);
+                tracing::debug!("Pack cache hit: {}", name);
+                Ok(Some(Bytes::from(data)))
+            }
+            Ok(_) => {
+                tracing::warn!("Pack cache entry {} has wrong size, discarding", name);
+                self.invalidate(name).await;
+                Ok(None)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.lru.lock().remove(name);
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Store a pack, evicting least recently used packs beyond the size cap.
+    pub async fn put(&self, name: &str, data: &Bytes) -> Result<()> {
+        if data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let path = self.dir.join(name);
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        self.lru.lock().touch(name, data.len() as u64);
+        self.evict().await;
+        Ok(())
+    }
+
+    /// Claim the background fetch of a pack of `size` bytes, unless one is
+    /// already running or the pack would not be kept.
+    pub fn start_prefetch(&self, name: &str, size: i64) -> Option<PrefetchGuard> {
+        if size as u64 > self.max_bytes || !self.prefetching.lock().insert(name.to_string()) {
+            return None;
+        }
+        Some(PrefetchGuard {
+            name: name.to_string(),
+            prefetching: self.prefetching.clone(),
+        })
+    }
+
+    /// Drop a pack from the cache.
+    pub async fn invalidate(&self, name: &str) {
+        self.lru.lock().remove(name);
+        let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+    }
+
+    async fn evict(&self) {
+        let evicted = self.lru.lock().evict(self.max_bytes);
+        for name in evicted {
+            tracing::debug!("Evicting pack {} from cache", name);
+            let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+        }
+    }
+}
+
+/// Remove all files in `dir` whose name starts with `prefix`.
+async fn remove_with_prefix(dir: &Path, prefix: &str) -> Result<()> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with(prefix) {
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Readahead for bursts of pack reads, as made by `restic check --read-data`
+//! and `restic prune`.
+//!
+//! Ranged reads moving forward through a pack are detected per pack; once a
+//! pack is read sequentially, the next read fetches a whole window from the
+//! backend in one go (split into parallel chunks by the 123pan client) and
+//! the reads after it are answered from memory. Whole-pack reads arriving in
+//! quick succession are fetched, checked against the pack ID (the SHA-256 of
+//! the pack) and fetched again once if a transfer came back corrupted.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Forward reads of a pack after which its next read fetches a window.
+const SEQUENTIAL_READS: u32 = 2;
+
+/// Packs whose read position is tracked; the least recently read is dropped
+/// beyond this, bounding the memory held in windows.
+const MAX_STREAMS: usize = 16;
+
+/// Reads of a pack further apart than this start over.
+const STREAM_IDLE: Duration = Duration::from_secs(60);
+
+/// Whole-pack reads within [`BURST_INTERVAL`] that make a burst.
+const BURST_READS: usize = 3;
+
+const BURST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Read position in one pack.
+#[derive(Debug)]
+struct Stream {
+    /// Offset following the last read
+    next_offset: u64,
+    /// Consecutive forward reads
+    sequential: u32,
+    last_read: Instant,
+    /// Fetched window: offset of its first byte and content
+    window: Option<(u64, Bytes)>,
+}
+
+/// What to do with a ranged read, see [`Readahead::plan`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReadPlan {
+    /// Answered from a window fetched earlier
+    Hit(Bytes),
+    /// Fetch this range, a window starting at the read, and pass it to
+    /// [`Readahead::store`]
+    Fetch(u64, u64),
+    /// Not sequential (yet): read as usual
+    Pass,
+}
+
+/// Sequential read detection and the windows fetched for it.
+#[derive(Debug)]
+pub struct Readahead {
+    /// Bytes fetched ahead of a sequential read
+    window: u64,
+    streams: Mutex<HashMap<String, Stream>>,
+    /// Times of recent whole-pack reads
+    full_reads: Mutex<VecDeque<Instant>>,
+}
+
+impl Readahead {
+    /// Read `window` bytes ahead once a pack is read sequentially.
+    pub fn new(window: u64) -> Self {
+        Self {
+            window: window.max(1),
+            streams: Mutex::new(HashMap::new()),
+            full_reads: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Plan the read of bytes `start..=end` of pack `name`, which has `size`
+    /// bytes.
+    pub fn plan(&self, name: &str, start: u64, end: u64, size: u64) -> ReadPlan {
+        let now = Instant::now();
+        let mut streams = self.streams.lock();
+        let stream = match streams.get_mut(name) {
+            Some(stream) if now.duration_since(stream.last_read) < STREAM_IDLE => stream,
+            _ => {
+                if streams.len() >= MAX_STREAMS {
+                    let oldest = streams
+                        .iter()
+                        .min_by_key(|(_, s)| s.last_read)
+                        .map(|(name, _)| name.clone());
+                    if let Some(oldest) = oldest {
+                        streams.remove(&oldest);
+                    }
+                }
+                streams.insert(
+                    name.to_string(),
+                    Stream {
+                        next_offset: end + 1,
+                        sequential: 1,
+                        last_read: now,
+                        window: None,
+                    },
+                );
+                return ReadPlan::Pass;
+            }
+        };
+
+        // Skipping ahead within a window still counts, e.g. over blobs
+        // prune drops
+        let forward = start >= stream.next_offset && start - stream.next_offset < self.window;
+        stream.sequential = if forward { stream.sequential + 1 } else { 1 };
+        stream.next_offset = end + 1;
+        stream.last_read = now;
+
+        if let Some((offset, data)) = &stream.window {
+            let window_end = offset + data.len() as u64;
+            if start >= *offset && end < window_end {
+                return ReadPlan::Hit(
+                    data.slice((start - offset) as usize..=(end - offset) as usize),
+                );
+            }
+        }
+        stream.window = None;
+        if stream.sequential < SEQUENTIAL_READS {
+            return ReadPlan::Pass;
+        }
+        ReadPlan::Fetch(start, (start + self.window).max(end + 1).min(size) - 1)
+    }
+
+    /// Keep a window fetched for [`ReadPlan::Fetch`], starting at `offset`.
+    pub fn store(&self, name: &str, offset: u64, data: Bytes) {
+        if let Some(stream) = self.streams.lock().get_mut(name) {
+            stream.window = Some((offset, data));
+        }
+    }
+
+    /// Note a whole-pack read; returns whether it is part of a burst.
+    pub fn full_read(&self) -> bool {
+        let now = Instant::now();
+        let mut reads = self.full_reads.lock();
+        while reads
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > BURST_INTERVAL)
+        {
+            reads.pop_front();
+        }
+        reads.push_back(now);
+        if reads.len() > BURST_READS {
+            reads.pop_front();
+        }
+        reads.len() >= BURST_READS
+    }
+
+    /// Forget the position in and window of a pack, e.g. when it is deleted.
+    pub fn forget(&self, name: &str) {
+        self.streams.lock().remove(name);
+    }
+}
+
+/// Whether `data` is the pack named `name`, i.e. hashes to its ID.
+pub fn verify_pack(name: &str, data: &[u8]) -> bool {
+    format!("{:x}", Sha256::digest(data)) == name
+}
@@ -0,0 +1,462 @@
+//! Local write-back spool with a journal of pending uploads.
+//!
+//! When enabled, POSTed objects are written durably to a local spool
+//! directory and recorded in an `upload_journal` table before the request is
+//! acknowledged. A background worker uploads them to the storage backend with retries and
+//! removes them from the spool once stored. Reads consult the spool first so
+//! restic always sees its own writes.
+//!
+//! Without write-back, the spool only takes uploads that failed after all
+//! retries, so they are not lost while the backend is unreachable.
+
+use bytes::Bytes;
+use sea_orm::{
+    sea_query::{Expr, OnConflict, Order, Query},
+    ConnectionTrait, DatabaseConnection,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify};
+
+use super::types::{RepoLayout, ResticFileType};
+use crate::error::{AppError, Result};
+use crate::storage::StorageBackend;
+
+const JOURNAL_TABLE: &str = "upload_journal";
+const JOURNAL_FILE_TYPE: &str = "file_type";
+const JOURNAL_NAME: &str = "name";
+const JOURNAL_SEQ: &str = "seq";
+const JOURNAL_SIZE: &str = "size";
+const JOURNAL_ATTEMPTS: &str = "attempts";
+const JOURNAL_RETRY_AT: &str = "retry_at";
+
+/// Interval at which the worker rescans the journal when idle.
+const WORKER_IDLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum backoff between upload attempts for one entry.
+const MAX_BACKOFF_SECS: i64 = 300;
+
+/// Failed attempts after which an entry is reported as stuck.
+pub const STUCK_ATTEMPTS: i64 = 10;
+
+/// A spooled object waiting to be uploaded.
+#[derive(Debug, Clone)]
+pub struct SpoolEntry {
+    pub file_type: ResticFileType,
+    pub name: String,
+    pub seq: i64,
+    pub size: i64,
+    pub attempts: i64,
+    /// Unix time of the next upload attempt
+    pub retry_at: i64,
+    pub path: PathBuf,
+}
+
+impl SpoolEntry {
+    /// Whether uploading keeps failing (see [`STUCK_ATTEMPTS`]).
+    pub fn is_stuck(&self) -> bool {
+        self.attempts >= STUCK_ATTEMPTS
+    }
+}
+
+/// Write-back spool backed by a local directory and a journal table.
+#[derive(Clone)]
+pub struct WriteBackSpool {
+    dir: PathBuf,
+    db: DatabaseConnection,
+    backend: Arc<dyn StorageBackend>,
+    layout: RepoLayout,
+    next_seq: Arc<AtomicU64>,
+    /// Serializes journal mutations so the worker never removes a newer version
+    lock: Arc<Mutex<()>>,
+    notify: Arc<Notify>,
+}
+
+impl WriteBackSpool {
+    /// Open (or create) the spool in `dir`, keeping the journal table in `db`
+    /// and removing spool files that never made it into the journal.
+    pub async fn open(
+        dir: impl Into<PathBuf>,
+        db: DatabaseConnection,
+        backend: Arc<dyn StorageBackend>,
+    ) -> Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let spool = Self {
+            dir,
+            db,
+            backend,
+            layout: RepoLayout::default(),
+            next_seq: Arc::new(AtomicU64::new(1)),
+            lock: Arc::new(Mutex::new(())),
+            notify: Arc::new(Notify::new()),
+        };
+
+        let entries = spool.pending().await?;
+        let max_seq = entries.iter().map(|e| e.seq).max().unwrap_or(0);
+        spool.next_seq.store(max_seq as u64 + 1, Ordering::Release);
+
+        spool.remove_orphan_files(&entries).await?;
+
+        tracing::info!(
+            "Write-back spool at {} has {} pending uploads",
+            spool.dir.display(),
+            entries.len()
+        );
+        Ok(spool)
+    }
+
+    /// Spool file path for a given version of an object.
+    fn spool_path(&self, file_type: ResticFileType, name: &str, seq: i64) -> PathBuf {
+        let key = md5::compute(format!("{}/{}", file_type.dirname(), name));
+        self.dir.join(format!("{:x}-{}.obj", key, seq))
+    }
+
+    /// Upload to the repository paths of `layout` instead of the default layout.
+    pub fn with_layout(mut self, layout: RepoLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Durably store an object and journal it for upload.
+    pub async fn put(&self, file_type: ResticFileType, name: &str, data: Bytes) -> Result<()> {
+        let _lock = self.lock.lock().await;
+
+        let seq = self.next_seq.fetch_add(1, Ordering::AcqRel) as i64;
+        let path = self.spool_path(file_type, name, seq);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&tmp_path, &path).await?;
+        sync_dir(&self.dir).await?;
+
+        let previous = self.get(file_type, name).await?;
+
+        let builder = self.db.get_database_backend();
+        let stmt = Query::insert()
+            .into_table(JOURNAL_TABLE)
+            .columns([
+                JOURNAL_FILE_TYPE,
+                JOURNAL_NAME,
+                JOURNAL_SEQ,
+                JOURNAL_SIZE,
+                JOURNAL_ATTEMPTS,
+                JOURNAL_RETRY_AT,
+            ])
+            .values_panic([
+                file_type.dirname().into(),
+                name.into(),
+                seq.into(),
+                (data.len() as i64).into(),
+                0i64.into(),
+                0i64.into(),
+            ])
+            .on_conflict(
+                OnConflict::columns([JOURNAL_FILE_TYPE, JOURNAL_NAME])
+                    .update_columns([
+                        JOURNAL_SEQ,
+                        JOURNAL_SIZE,
+                        JOURNAL_ATTEMPTS,
+                        JOURNAL_RETRY_AT,
+                    ])
+                    .to_owned(),
+            )
+            .to_owned();
+
+        self.db
+            .execute(builder.build(&stmt))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to journal upload: {}", e)))?;
+
+        if let Some(previous) = previous {
+            let _ = tokio::fs::remove_file(&previous.path).await;
+        }
+
+        tracing::debug!(
+            "Spooled {}/{} ({} bytes, seq {})",
+            file_type.dirname(),
+            name,
+            data.len(),
+            seq
+        );
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Look up a pending object.
+    pub async fn get(&self, file_type: ResticFileType, name: &str) -> Result<Option<SpoolEntry>> {
+        let stmt = Self::select()
+            .and_where(Expr::col(JOURNAL_FILE_TYPE).eq(file_type.dirname()))
+            .and_where(Expr::col(JOURNAL_NAME).eq(name))
+            .to_owned();
+        Ok(self.query(stmt).await?.into_iter().next())
+    }
+
+    /// Read a pending object's content. Returns `None` if the entry was
+    /// uploaded and removed from the spool in the meantime.
+    pub async fn read(&self, entry: &SpoolEntry) -> Result<Option<Bytes>> {
+        match tokio::fs::read(&entry.path).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// List pending objects of a type.
+    pub async fn list(&self, file_type: ResticFileType) -> Result<Vec<SpoolEntry>> {
+        let stmt = Self::select()
+            .and_where(Expr::col(JOURNAL_FILE_TYPE).eq(file_type.dirname()))
+            .to_owned();
+        self.query(stmt).await
+    }
+
+    /// All pending objects in journal order.
+    pub async fn pending(&self) -> Result<Vec<SpoolEntry>> {
+        self.query(Self::select()).await
+    }
+
+    /// Drop a pending object (e.g. on DELETE). Returns whether it existed.
+    pub async fn remove(&self, file_type: ResticFileType, name: &str) -> Result<bool> {
+        let _lock = self.lock.lock().await;
+        let Some(entry) = self.get(file_type, name).await? else {
+            return Ok(false);
+        };
+        self.delete_entry(&entry).await?;
+        Ok(true)
+    }
+
+    /// Remove the journal row and spool file, if the row still holds this version.
+    async fn delete_entry(&self, entry: &SpoolEntry) -> Result<bool> {
+        let builder = self.db.get_database_backend();
+        let stmt = Query::delete()
+            .from_table(JOURNAL_TABLE)
+            .and_where(Expr::col(JOURNAL_FILE_TYPE).eq(entry.file_type.dirname()))
+            .and_where(Expr::col(JOURNAL_NAME).eq(entry.name.as_str()))
+            .and_where(Expr::col(JOURNAL_SEQ).eq(entry.seq))
+            .to_owned();
+
+        let result =
+            self.db.execute(builder.build(&stmt)).await.map_err(|e| {
+                AppError::Internal(format!("Failed to remove journal entry: {}", e))
+            })?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        let _ = tokio::fs::remove_file(&entry.path).await;
+        Ok(true)
+    }
+
+    fn select() -> sea_orm::sea_query::SelectStatement {
+        Query::select()
+            .columns([
+                JOURNAL_FILE_TYPE,
+                JOURNAL_NAME,
+                JOURNAL_SEQ,
+                JOURNAL_SIZE,
+                JOURNAL_ATTEMPTS,
+                JOURNAL_RETRY_AT,
+            ])
+            .from(JOURNAL_TABLE)
+            .order_by(JOURNAL_SEQ, Order::Asc)
+            .to_owned()
+    }
+
+    async fn query(&self, stmt: sea_orm::sea_query::SelectStatement) -> Result<Vec<SpoolEntry>> {
+        let builder = self.db.get_database_backend();
+        let rows =
+            self.db.query_all(builder.build(&stmt)).await.map_err(|e| {
+                AppError::Internal(format!("Failed to query upload journal: {}", e))
+            })?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            let read_err = |e: sea_orm::DbErr| {
+                AppError::Internal(format!("Failed to read journal entry: {}", e))
+            };
+            let type_str: String = row.try_get("", JOURNAL_FILE_TYPE).map_err(read_err)?;
+            let name: String = row.try_get("", JOURNAL_NAME).map_err(read_err)?;
+            let seq: i64 = row.try_get("", JOURNAL_SEQ).map_err(read_err)?;
+            let size: i64 = row.try_get("", JOURNAL_SIZE).map_err(read_err)?;
+            let attempts: i64 = row.try_get("", JOURNAL_ATTEMPTS).map_err(read_err)?;
+            let retry_at: i64 = row.try_get("", JOURNAL_RETRY_AT).map_err(read_err)?;
+
+            let Some(file_type) = ResticFileType::from_str(&type_str) else {
+                tracing::warn!("Ignoring journal entry with unknown type '{}'", type_str);
+                continue;
+            };
+            let path = self.spool_path(file_type, &name, seq);
+            entries.push(SpoolEntry {
+                file_type,
+                name,
+                seq,
+                size,
+                attempts,
+                retry_at,
+                path,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Delete spool files that have no journal entry (crash before journaling).
+    async fn remove_orphan_files(&self, entries: &[SpoolEntry]) -> Result<()> {
+        let known: std::collections::HashSet<&Path> =
+            entries.iter().map(|e| e.path.as_path()).collect();
+
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(item) = dir.next_entry().await? {
+            let path = item.path();
+            let is_spool_file = matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("obj") | Some("tmp")
+            );
+            if is_spool_file && !known.contains(path.as_path()) {
+                tracing::warn!("Removing orphaned spool file {}", path.display());
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawn the background upload worker, uploading up to `concurrency`
+    /// entries at a time.
+    pub fn spawn_worker(&self, concurrency: usize) -> tokio::task::JoinHandle<()> {
+        let spool = self.clone();
+        let concurrency = concurrency.max(1);
+        tokio::spawn(async move {
+            loop {
+                let uploaded = match spool.upload_due(concurrency).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        tracing::error!("Write-back worker error: {}", e);
+                        0
+                    }
+                };
+                if uploaded == 0 {
+                    let _ =
+                        tokio::time::timeout(WORKER_IDLE_INTERVAL, spool.notify.notified()).await;
+                }
+            }
+        })
+    }
+
+    /// Upload entries whose retry time has passed. Returns the number of
+    /// entries processed.
+    async fn upload_due(&self, concurrency: usize) -> Result<usize> {
+        let now = chrono::Utc::now().timestamp();
+        let stmt = Self::select()
+            .and_where(Expr::col(JOURNAL_RETRY_AT).lte(now))
+            .limit(concurrency as u64)
+            .to_owned();
+        let due = self.query(stmt).await?;
+        let count = due.len();
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for entry in due {
+            let spool = self.clone();
+            tasks.spawn(async move {
+                if let Err(e) = spool.upload_entry(&entry).await {
+                    tracing::warn!(
+                        "Write-back upload of {}/{} failed (attempt {}): {}",
+                        entry.file_type.dirname(),
+                        entry.name,
+                        entry.attempts + 1,
+                        e
+                    );
+                    if let Err(e) = spool.schedule_retry(&entry).await {
+                        tracing::error!("Failed to schedule upload retry: {}", e);
+                    }
+                }
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        Ok(count)
+    }
+
+    async fn upload_entry(&self, entry: &SpoolEntry) -> Result<()> {
+        let Some(data) = self.read(entry).await? else {
+            // Superseded or deleted while queued
+            return Ok(());
+        };
+        let path = self.layout.object_path(entry.file_type, &entry.name);
+        self.backend.put(&path, data).await?;
+
+        let _lock = self.lock.lock().await;
+        if self.delete_entry(entry).await? {
+            tracing::info!(
+                "Write-back upload of {}/{} completed",
+                entry.file_type.dirname(),
+                entry.name
+            );
+        } else if self.get(entry.file_type, &entry.name).await?.is_none() {
+            // Object was deleted by a client while we were uploading it
+            tracing::info!(
+                "{}/{} was deleted during write-back upload, removing it",
+                entry.file_type.dirname(),
+                entry.name
+            );
+            self.backend.delete(&path).await?;
+        }
+        Ok(())
+    }
+
+    async fn schedule_retry(&self, entry: &SpoolEntry) -> Result<()> {
+        let backoff = 2i64
+            .saturating_pow(entry.attempts.min(16) as u32)
+            .min(MAX_BACKOFF_SECS);
+        let retry_at = chrono::Utc::now().timestamp() + backoff;
+
+        let builder = self.db.get_database_backend();
+        let stmt = Query::update()
+            .table(JOURNAL_TABLE)
+            .value(JOURNAL_ATTEMPTS, entry.attempts + 1)
+            .value(JOURNAL_RETRY_AT, retry_at)
+            .and_where(Expr::col(JOURNAL_FILE_TYPE).eq(entry.file_type.dirname()))
+            .and_where(Expr::col(JOURNAL_NAME).eq(entry.name.as_str()))
+            .and_where(Expr::col(JOURNAL_SEQ).eq(entry.seq))
+            .to_owned();
+
+        self.db
+            .execute(builder.build(&stmt))
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to update journal entry: {}", e)))?;
+        if entry.attempts + 1 == STUCK_ATTEMPTS {
+            tracing::error!(
+                "Upload of {}/{} still failing after {} attempts",
+                entry.file_type.dirname(),
+                entry.name,
+                STUCK_ATTEMPTS
+            );
+        }
+        Ok(())
+    }
+}
+
+/// fsync a directory so a rename inside it is durable.
+async fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = tokio::fs::File::open(dir).await?;
+        dir.sync_all().await?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+impl std::fmt::Debug for WriteBackSpool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteBackSpool")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
@@ -0,0 +1,1338 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use tower::ServiceExt;
+
+use crate::error::AppError;
+use crate::pan123::Pan123Client;
+use crate::restic::admission::{ConcurrencyLimiter, MemoryBudget};
+use crate::restic::audit::AuditLog;
+use crate::restic::middleware::RateLimiter;
+use crate::restic::read_cache::{MetadataCache, PackCache};
+use crate::restic::spool::WriteBackSpool;
+use crate::restic::{create_router, AdminAccess, ResticFileType, ServerOptions};
+use crate::storage::{LocalBackend, ObjectInfo, StorageBackend};
+
+async fn setup_test_client() -> Pan123Client {
+    let db_file = NamedTempFile::new().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", db_file.path().display());
+
+    Pan123Client::new(
+        "test_id".to_string(),
+        "test_secret".to_string(),
+        "/test_repo".to_string(),
+        &db_url,
+    )
+    .await
+    .expect("Failed to create client")
+}
+
+async fn setup_test_router() -> Router {
+    create_router(
+        Arc::new(setup_test_client().await),
+        ServerOptions::default(),
+    )
+}
+
+#[tokio::test]
+async fn test_healthz() {
+    let app = setup_test_router().await;
+    let response = app
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_request_id_propagation() {
+    let app = setup_test_router().await;
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(response.headers().contains_key("x-request-id"));
+
+    let response = app
+        .oneshot(
+            Request::get("/healthz")
+                .header("x-request-id", "abc123")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"], "abc123");
+}
+
+#[tokio::test]
+async fn test_admin_maintenance_endpoint() {
+    let app = setup_test_router().await;
+    let response = app
+        .oneshot(
+            Request::post("/admin/maintenance")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["converted"], true);
+}
+
+#[tokio::test]
+async fn test_request_deadline_returns_504() {
+    use crate::restic::middleware::deadline;
+    use std::time::Duration;
+
+    let app = Router::new()
+        .route(
+            "/slow",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "done"
+            }),
+        )
+        .route("/fast", axum::routing::get(|| async { "done" }))
+        .layer(axum::middleware::from_fn_with_state(
+            Duration::from_millis(50),
+            deadline,
+        ));
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    let response = app
+        .oneshot(Request::get("/fast").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn test_rate_limiter_burst() {
+    let limiter = RateLimiter::new(1.0, 2);
+    let a: IpAddr = "10.0.0.1".parse().unwrap();
+    let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+    assert!(limiter.check(a).is_ok());
+    assert!(limiter.check(a).is_ok());
+    assert!(limiter.check(a).is_err());
+    // Other clients have their own bucket
+    assert!(limiter.check(b).is_ok());
+}
+
+#[tokio::test]
+async fn test_concurrency_limiter_rejects_when_queue_full() {
+    let limiter = ConcurrencyLimiter::new("uploads", 1, 0);
+    let permit = limiter.acquire().await.expect("first acquire succeeds");
+
+    match limiter.acquire().await {
+        Err(AppError::Unavailable { retry_after, .. }) => assert!(retry_after > 0),
+        other => panic!("expected Unavailable, got {:?}", other.map(|_| ())),
+    }
+
+    drop(permit);
+    limiter.acquire().await.expect("slot freed");
+}
+
+#[tokio::test]
+async fn test_memory_budget_delays_bodies() {
+    let budget = MemoryBudget::new(100 * 1024);
+    let first = budget.reserve(Some(60 * 1024)).await.unwrap();
+    assert_eq!(budget.in_flight(), 60 * 1024);
+
+    // A body that doesn't fit waits until enough is released
+    let waiting = tokio::spawn({
+        let budget = budget.clone();
+        async move { budget.reserve(Some(50 * 1024)).await.map(|_| ()) }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+    drop(first);
+    waiting.await.unwrap().unwrap();
+    assert_eq!(budget.in_flight(), 0);
+
+    // Bodies larger than the budget still pass, one at a time
+    let large = budget.reserve(Some(1024 * 1024)).await.unwrap();
+    assert_eq!(budget.in_flight(), 1024 * 1024);
+    drop(large);
+}
+
+#[tokio::test]
+async fn test_spooled_upload_is_readable() {
+    let client = setup_test_client().await;
+    let spool_dir = tempfile::tempdir().unwrap();
+    let backend: Arc<dyn StorageBackend> = Arc::new(client.clone());
+    let spool = WriteBackSpool::open(spool_dir.path(), client.database(), backend.clone())
+        .await
+        .unwrap();
+    let app = create_router(
+        backend,
+        ServerOptions {
+            spool: Some(spool.clone()),
+            ..ServerOptions::default()
+        },
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/keys/abcdef")
+                .body(Body::from("hello world"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(spool.pending().await.unwrap().len(), 1);
+
+    let response = app
+        .clone()
+        .oneshot(Request::head("/keys/abcdef").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-length"], "11");
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get("/keys/abcdef")
+                .header("range", "bytes=6-10")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"world");
+
+    // The spooled copy is tagged with its MD5, like 123pan's etag
+    let etag = "\"5eb63bbbe01eeed093cb22bb8f5acdc3\"";
+    let response = app
+        .clone()
+        .oneshot(Request::get("/keys/abcdef").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["etag"], etag);
+
+    let response = app
+        .oneshot(
+            Request::get("/keys/abcdef")
+                .header("if-none-match", etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    assert!(spool.remove(ResticFileType::Keys, "abcdef").await.unwrap());
+    assert!(spool.pending().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_metadata_cache_keyed_by_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = MetadataCache::open(dir.path()).await.unwrap();
+    let mut file = ObjectInfo {
+        id: 42,
+        name: "abcdef".to_string(),
+        size: 5,
+        etag: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+        modified_at: None,
+    };
+
+    let data = bytes::Bytes::from_static(b"hello");
+    cache
+        .put(ResticFileType::Index, "abcdef", &file, &data)
+        .await
+        .unwrap();
+    let hit = cache
+        .get(ResticFileType::Index, "abcdef", &file)
+        .await
+        .unwrap();
+    assert_eq!(hit, Some(data));
+
+    // A new remote version must not be served from the old entry
+    file.id = 43;
+    let miss = cache
+        .get(ResticFileType::Index, "abcdef", &file)
+        .await
+        .unwrap();
+    assert_eq!(miss, None);
+}
+
+#[tokio::test]
+async fn test_pack_cache_evicts_least_recently_used() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = PackCache::open(dir.path(), 10).await.unwrap();
+
+    cache
+        .put("aa", &bytes::Bytes::from_static(b"1234"))
+        .await
+        .unwrap();
+    cache
+        .put("bb", &bytes::Bytes::from_static(b"5678"))
+        .await
+        .unwrap();
+    // Touch "aa" so "bb" becomes the eviction candidate
+    assert!(cache.get("aa", 4).await.unwrap().is_some());
+    cache
+        .put("cc", &bytes::Bytes::from_static(b"9012"))
+        .await
+        .unwrap();
+
+    assert!(cache.get("aa", 4).await.unwrap().is_some());
+    assert!(cache.get("bb", 4).await.unwrap().is_none());
+    assert!(cache.get("cc", 4).await.unwrap().is_some());
+    assert!(!dir.path().join("bb").exists());
+
+    // Entries survive a restart
+    let reopened = PackCache::open(dir.path(), 10).await.unwrap();
+    assert!(reopened.get("cc", 4).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_pack_readahead_after_ranged_get() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache_dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let cache = PackCache::open(cache_dir.path(), 1024).await.unwrap();
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            pack_cache: Some(cache.clone()),
+            pack_readahead: true,
+            ..ServerOptions::default()
+        },
+    );
+
+    let id = "cd".repeat(32);
+    let uri = format!("/data/{}", id);
+    let response = app
+        .clone()
+        .oneshot(Request::post(&uri).body(Body::from("pack data")).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The range is answered directly, the whole pack lands in the cache
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get(&uri)
+                .header("range", "bytes=0-3")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"pack");
+    let mut cached = None;
+    for _ in 0..50 {
+        cached = cache.get(&id, 9).await.unwrap();
+        if cached.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(cached.as_deref(), Some(&b"pack data"[..]));
+
+    // A pack already being fetched is not fetched twice
+    let guard = cache.start_prefetch("ef", 4).unwrap();
+    assert!(cache.start_prefetch("ef", 4).is_none());
+    drop(guard);
+    assert!(cache.start_prefetch("ef", 4).is_some());
+    assert!(cache.start_prefetch("ff", 2048).is_none());
+}
+
+#[test]
+fn test_object_name_validation() {
+    let id = "a".repeat(64);
+    assert!(ResticFileType::Data.is_valid_name(&id));
+    assert!(ResticFileType::Snapshots.is_valid_name(&id));
+    assert!(ResticFileType::Keys.is_valid_name("abcdef"));
+
+    assert!(!ResticFileType::Data.is_valid_name("abcdef"));
+    assert!(!ResticFileType::Index.is_valid_name(&"A".repeat(64)));
+    assert!(!ResticFileType::Locks.is_valid_name("../config"));
+    assert!(!ResticFileType::Keys.is_valid_name(""));
+    assert!(!ResticFileType::Config.is_valid_name("config"));
+}
+
+#[tokio::test]
+async fn test_invalid_object_name_rejected() {
+    let app = setup_test_router().await;
+
+    let response = app
+        .oneshot(
+            Request::post("/data/not-a-pack-id")
+                .body(Body::from("x"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_rest_api_on_local_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(Arc::new(backend), ServerOptions::default());
+
+    let response = app
+        .clone()
+        .oneshot(Request::post("/?create=true").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(dir.path().join("snapshots").is_dir());
+
+    let id = "ab".repeat(32);
+    let uri = format!("/data/{}", id);
+    let response = app
+        .clone()
+        .oneshot(Request::post(&uri).body(Body::from("pack data")).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(dir.path().join("data/ab").join(&id).is_file());
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/data/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listing, serde_json::json!([{ "name": id, "size": 9 }]));
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get(&uri)
+                .header("range", "bytes=5-8")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"data");
+
+    let response = app
+        .clone()
+        .oneshot(Request::delete(&uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .oneshot(Request::head(&uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_repo_layout_object_paths() {
+    let id = "abcdef".repeat(10) + "abcd";
+    let layout = crate::restic::RepoLayout::default();
+    assert_eq!(
+        layout.object_path(ResticFileType::Config, "config"),
+        "config"
+    );
+    assert_eq!(
+        layout.object_path(ResticFileType::Keys, "01"),
+        "keys/01".to_string()
+    );
+    assert_eq!(
+        layout.object_path(ResticFileType::Data, &id),
+        format!("data/ab/{}", id)
+    );
+
+    let unsharded = crate::restic::RepoLayout {
+        data_shard_len: 0,
+        ..Default::default()
+    };
+    assert_eq!(
+        unsharded.object_path(ResticFileType::Data, &id),
+        format!("data/{}", id)
+    );
+
+    let deep = crate::restic::RepoLayout {
+        data_shard_len: 1,
+        data_shard_depth: 3,
+    };
+    assert_eq!(
+        deep.object_path(ResticFileType::Data, &id),
+        format!("data/a/b/c/{}", id)
+    );
+    assert_eq!(deep.to_string(), "data/x/x/x/<id>");
+    assert!(crate::restic::RepoLayout {
+        data_shard_depth: 4,
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+
+    // Layout files written before depths were configurable mean one level
+    let recorded: crate::restic::RepoLayout =
+        serde_json::from_str(r#"{"data_shard_len":3}"#).unwrap();
+    assert_eq!(recorded.data_shard_depth, 1);
+}
+
+#[tokio::test]
+async fn test_audit_log_records_mutations() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = NamedTempFile::new().unwrap();
+    let db = crate::db::connect(&format!("sqlite:{}?mode=rwc", db_file.path().display()))
+        .await
+        .unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            audit: Some(AuditLog::new(db, "repo")),
+            ..ServerOptions::default()
+        },
+    );
+
+    let id = "cd".repeat(32);
+    let requests = [
+        Request::post("/?create=true").body(Body::empty()).unwrap(),
+        Request::post(format!("/snapshots/{}", id))
+            .header("content-length", "4")
+            // alice:secret
+            .header("authorization", "Basic YWxpY2U6c2VjcmV0")
+            .body(Body::from("snap"))
+            .unwrap(),
+        Request::get(format!("/snapshots/{}", id))
+            .body(Body::empty())
+            .unwrap(),
+        Request::delete(format!("/snapshots/{}", id))
+            .body(Body::empty())
+            .unwrap(),
+        Request::post("/snapshots/not-hex")
+            .body(Body::empty())
+            .unwrap(),
+    ];
+    for request in requests {
+        app.clone().oneshot(request).await.unwrap();
+    }
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/admin/audit").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    let summary: Vec<(&str, &str, u64)> = entries
+        .iter()
+        .map(|e| {
+            (
+                e["operation"].as_str().unwrap(),
+                e["name"].as_str().unwrap(),
+                e["status"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("upload", "not-hex", 400),
+            ("delete", id.as_str(), 200),
+            ("upload", id.as_str(), 200),
+            ("mkdir", "", 200),
+        ]
+    );
+    assert_eq!(entries[2]["user"], "alice");
+    assert_eq!(entries[2]["size"], 4);
+
+    let response = app
+        .oneshot(
+            Request::get("/admin/audit?operation=delete&type=snapshots")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["name"], id.as_str());
+}
+
+/// Local backend whose uploads fail while `failing` is set.
+struct FlakyBackend {
+    inner: LocalBackend,
+    failing: std::sync::atomic::AtomicBool,
+}
+
+#[async_trait::async_trait]
+impl StorageBackend for FlakyBackend {
+    async fn list(&self, dir: &str) -> crate::error::Result<Vec<ObjectInfo>> {
+        self.inner.list(dir).await
+    }
+
+    async fn head(&self, path: &str) -> crate::error::Result<Option<ObjectInfo>> {
+        self.inner.head(path).await
+    }
+
+    async fn get_range(
+        &self,
+        path: &str,
+        range: Option<(u64, u64)>,
+    ) -> crate::error::Result<bytes::Bytes> {
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put(&self, path: &str, data: bytes::Bytes) -> crate::error::Result<()> {
+        if self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(AppError::Internal("upstream down".to_string()));
+        }
+        self.inner.put(path, data).await
+    }
+
+    async fn delete(&self, path: &str) -> crate::error::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn ensure_dir(&self, path: &str) -> crate::error::Result<()> {
+        self.inner.ensure_dir(path).await
+    }
+
+    async fn readiness(&self) -> crate::storage::Readiness {
+        self.inner.readiness().await
+    }
+}
+
+#[test]
+fn test_errors_reported_to_sentry_with_request_id() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let events = sentry::test::with_captured_events(|| {
+        let subscriber =
+            tracing_subscriber::registry().with(sentry::integrations::tracing::layer());
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+        // On this thread, so the request reports to the captured hub
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let backend = FlakyBackend {
+                inner: LocalBackend::open(dir.path()).await.unwrap(),
+                failing: true.into(),
+            };
+            let app = create_router(Arc::new(backend), ServerOptions::default());
+            let response = app
+                .oneshot(
+                    Request::post("/keys/abcdef")
+                        .header("x-request-id", "req-1")
+                        .body(Body::from("key"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        });
+    });
+
+    let event = events
+        .iter()
+        .find(|e| e.level == sentry::Level::Error)
+        .expect("error reported");
+    assert_eq!(
+        event.tags.get("request_id").map(String::as_str),
+        Some("req-1")
+    );
+}
+
+#[tokio::test]
+async fn test_failed_uploads_are_queued_for_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool_dir = tempfile::tempdir().unwrap();
+    let db_file = NamedTempFile::new().unwrap();
+    let db = crate::db::connect(&format!("sqlite:{}?mode=rwc", db_file.path().display()))
+        .await
+        .unwrap();
+    let backend = Arc::new(FlakyBackend {
+        inner: LocalBackend::open(dir.path()).await.unwrap(),
+        failing: true.into(),
+    });
+    let spool = WriteBackSpool::open(spool_dir.path(), db, backend.clone())
+        .await
+        .unwrap();
+    let app = create_router(
+        backend.clone(),
+        ServerOptions {
+            spool: Some(spool.clone()),
+            write_back: false,
+            ..ServerOptions::default()
+        },
+    );
+    let upload = |name: &str| {
+        Request::post(format!("/keys/{}", name))
+            .body(Body::from("key"))
+            .unwrap()
+    };
+
+    // The failed upload is acknowledged, queued and readable
+    let response = app.clone().oneshot(upload("aa")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!dir.path().join("keys/aa").exists());
+    let response = app
+        .clone()
+        .oneshot(Request::get("/keys/aa").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/admin/uploads").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["pending"], 1);
+    assert_eq!(status["stuck"], 0);
+    assert_eq!(status["entries"][0]["name"], "aa");
+
+    // Once the backend is back, uploads go straight through and the worker
+    // drains the queue
+    backend
+        .failing
+        .store(false, std::sync::atomic::Ordering::Relaxed);
+    let response = app.clone().oneshot(upload("bb")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(dir.path().join("keys/bb").exists());
+    assert_eq!(spool.pending().await.unwrap().len(), 1);
+
+    spool.spawn_worker(1);
+    for _ in 0..100 {
+        if spool.pending().await.unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(spool.pending().await.unwrap().is_empty());
+    assert!(dir.path().join("keys/aa").exists());
+}
+
+#[tokio::test]
+async fn test_maintenance_mode_switch() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(Arc::new(backend), ServerOptions::default());
+    let send = |request: Request<Body>| app.clone().oneshot(request);
+    let set_mode = |mode: &str| {
+        Request::put("/admin/mode")
+            .header("content-type", "application/json")
+            .body(Body::from(format!(r#"{{"mode":"{}"}}"#, mode)))
+            .unwrap()
+    };
+    let upload = || {
+        Request::post("/keys/abcdef")
+            .body(Body::from("key"))
+            .unwrap()
+    };
+    assert_eq!(send(upload()).await.unwrap().status(), StatusCode::OK);
+
+    // Read-only: writes are refused, reads still served
+    let response = send(set_mode("read-only")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        send(upload()).await.unwrap().status(),
+        StatusCode::FORBIDDEN
+    );
+    let response = send(Request::get("/keys/abcdef").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Drain: writes get 503 with Retry-After and the server reports not ready
+    send(set_mode("drain")).await.unwrap();
+    let response = send(upload()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+    let response = send(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = send(Request::get("/admin/mode").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["mode"], "drain");
+
+    send(set_mode("normal")).await.unwrap();
+    assert_eq!(send(upload()).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(
+        send(set_mode("bogus")).await.unwrap().status(),
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+}
+
+#[tokio::test]
+async fn test_config_upload_waits_for_body_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            body_memory_budget: 1024 * 1024,
+            ..ServerOptions::default()
+        },
+    );
+
+    // A body of unknown length holds the whole budget while it streams
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(1);
+    let streaming = tokio::spawn(
+        app.clone().oneshot(
+            Request::post("/keys/abcdef")
+                .body(Body::from_stream(futures_util::stream::unfold(
+                    rx,
+                    |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) },
+                )))
+                .unwrap(),
+        ),
+    );
+    tx.send(Ok(b"key".to_vec())).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let config = tokio::spawn(
+        app.oneshot(
+            Request::post("/config")
+                .header("content-length", "6")
+                .body(Body::from("config"))
+                .unwrap(),
+        ),
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(!config.is_finished());
+
+    drop(tx);
+    assert_eq!(streaming.await.unwrap().unwrap().status(), StatusCode::OK);
+    assert_eq!(config.await.unwrap().unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_admin_access() {
+    let dir = tempfile::tempdir().unwrap();
+    let router = |admin: AdminAccess| {
+        let backend = LocalBackend::open(dir.path());
+        async move {
+            create_router(
+                Arc::new(backend.await.unwrap()),
+                ServerOptions {
+                    admin,
+                    ..ServerOptions::default()
+                },
+            )
+        }
+    };
+    let get_mode = |token: Option<&str>| {
+        let mut request = Request::get("/admin/mode");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    // With a token, only requests carrying it are served
+    let app = router(AdminAccess::Token("s3cret".to_string())).await;
+    for token in [None, Some("wrong"), Some("s3cre")] {
+        let response = app.clone().oneshot(get_mode(token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", token);
+    }
+    let response = app.clone().oneshot(get_mode(Some("s3cret"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Disabled, the path is just an unknown object type; the repository is
+    // still served
+    let app = router(AdminAccess::Disabled).await;
+    let response = app.clone().oneshot(get_mode(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_options_and_method_not_allowed() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(Arc::new(backend), ServerOptions::default());
+    let send = |method: &str, path: &str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let allow = |response: &axum::response::Response| {
+        response.headers()["allow"].to_str().unwrap().to_string()
+    };
+
+    let response = send("OPTIONS", "/config").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(allow(&response), "GET, HEAD, POST, OPTIONS");
+    let response = send("OPTIONS", "/data/").await.unwrap();
+    assert_eq!(allow(&response), "GET, HEAD, OPTIONS");
+
+    let response = send("PUT", "/keys/abcdef").await.unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(allow(&response), "GET, HEAD, POST, DELETE, OPTIONS");
+    let response = send("DELETE", "/data/").await.unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(allow(&response), "GET, HEAD, OPTIONS");
+}
+
+#[tokio::test]
+async fn test_append_only_refuses_deletes() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            append_only: true,
+            ..ServerOptions::default()
+        },
+    );
+    let send = |request: Request<Body>| app.clone().oneshot(request);
+    for path in ["/keys/abcdef", "/locks/abcdef"] {
+        let request = Request::post(path).body(Body::from("data")).unwrap();
+        assert_eq!(send(request).await.unwrap().status(), StatusCode::OK);
+    }
+
+    let response = send(Request::delete("/keys/abcdef").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(Request::get("/keys/abcdef").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send(
+        Request::delete("/locks/abcdef")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_immutable_data_refuses_overwrites() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            immutable: true,
+            ..ServerOptions::default()
+        },
+    );
+    let send = |request: Request<Body>| app.clone().oneshot(request);
+    let upload = |path: &str, body: &'static str| {
+        Request::post(path.to_string())
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    assert_eq!(
+        send(upload("/keys/abcdef", "key")).await.unwrap().status(),
+        StatusCode::OK
+    );
+    // Retrying the same upload is fine, replacing the content is not
+    assert_eq!(
+        send(upload("/keys/abcdef", "key")).await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        send(upload("/keys/abcdef", "other"))
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::FORBIDDEN
+    );
+    let response = send(Request::get("/keys/abcdef").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"key");
+
+    // Locks and the config may still be replaced
+    for path in ["/locks/abcdef", "/config"] {
+        for body in ["one", "two"] {
+            assert_eq!(
+                send(upload(path, body)).await.unwrap().status(),
+                StatusCode::OK
+            );
+        }
+    }
+}
+
+/// Local backend reporting objects larger than they are, as a cache does for
+/// a file that shrank since it was listed.
+struct StaleSizeBackend(LocalBackend);
+
+#[async_trait::async_trait]
+impl StorageBackend for StaleSizeBackend {
+    async fn list(&self, dir: &str) -> crate::error::Result<Vec<ObjectInfo>> {
+        self.0.list(dir).await
+    }
+
+    async fn head(&self, path: &str) -> crate::error::Result<Option<ObjectInfo>> {
+        Ok(self.0.head(path).await?.map(|mut info| {
+            info.size += 100;
+            info
+        }))
+    }
+
+    async fn get_range(
+        &self,
+        path: &str,
+        range: Option<(u64, u64)>,
+    ) -> crate::error::Result<bytes::Bytes> {
+        self.0.get_range(path, range).await
+    }
+
+    async fn put(&self, path: &str, data: bytes::Bytes) -> crate::error::Result<()> {
+        self.0.put(path, data).await
+    }
+
+    async fn delete(&self, path: &str) -> crate::error::Result<()> {
+        self.0.delete(path).await
+    }
+
+    async fn ensure_dir(&self, path: &str) -> crate::error::Result<()> {
+        self.0.ensure_dir(path).await
+    }
+
+    async fn readiness(&self) -> crate::storage::Readiness {
+        self.0.readiness().await
+    }
+}
+
+#[tokio::test]
+async fn test_short_readahead_window_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = StaleSizeBackend(LocalBackend::open(dir.path()).await.unwrap());
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            readahead_window: 1024,
+            ..ServerOptions::default()
+        },
+    );
+    let id = "ab".repeat(32);
+    let uri = format!("/data/{}", id);
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post(&uri)
+                .body(Body::from(vec![7u8; 100]))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let get = |range: &str| {
+        Request::get(&uri)
+            .header("range", format!("bytes={}", range))
+            .body(Body::empty())
+            .unwrap()
+    };
+    app.clone().oneshot(get("0-9")).await.unwrap();
+    // The window comes back short of the read: an error, not a panic
+    let response = app.clone().oneshot(get("95-104")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn test_sequential_reads_are_read_ahead() {
+    use crate::restic::readahead::Readahead;
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let backend = Arc::new(LocalBackend::open(dir.path()).await.unwrap());
+    let app = create_router(
+        backend.clone(),
+        ServerOptions {
+            readahead_window: 1024,
+            ..ServerOptions::default()
+        },
+    );
+
+    let content: Vec<u8> = (0..100u8).collect();
+    let id = format!("{:x}", Sha256::digest(&content));
+    let uri = format!("/data/{}", id);
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post(&uri)
+                .body(Body::from(content.clone()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let get = |range: &str| {
+        Request::get(&uri)
+            .header("range", format!("bytes={}", range))
+            .body(Body::empty())
+            .unwrap()
+    };
+    for (range, expected) in [("0-9", 0..10), ("10-19", 10..20)] {
+        let response = app.clone().oneshot(get(range)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], &content[expected]);
+    }
+
+    // The second forward read fetched the rest of the pack, so later reads
+    // don't reach the backend
+    backend
+        .put(
+            &crate::restic::RepoLayout::default().object_path(ResticFileType::Data, &id),
+            bytes::Bytes::from(vec![0u8; content.len()]),
+        )
+        .await
+        .unwrap();
+    let response = app.clone().oneshot(get("30-39")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()["content-range"],
+        format!("bytes 30-39/{}", content.len())
+    );
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], &content[30..40]);
+
+    // Whole-pack reads in quick succession make a burst
+    let readahead = Readahead::new(1024);
+    assert!(!readahead.full_read());
+    assert!(!readahead.full_read());
+    assert!(readahead.full_read());
+}
+
+/// Backend whose reads never finish, counting those still running.
+struct HangingReads {
+    inner: LocalBackend,
+    reads: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+/// Counts a running read until dropped.
+struct ReadInFlight(Arc<std::sync::atomic::AtomicUsize>);
+
+impl Drop for ReadInFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageBackend for HangingReads {
+    async fn list(&self, dir: &str) -> crate::error::Result<Vec<ObjectInfo>> {
+        self.inner.list(dir).await
+    }
+
+    async fn head(&self, path: &str) -> crate::error::Result<Option<ObjectInfo>> {
+        self.inner.head(path).await
+    }
+
+    async fn get_range(
+        &self,
+        _path: &str,
+        _range: Option<(u64, u64)>,
+    ) -> crate::error::Result<bytes::Bytes> {
+        self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let _read = ReadInFlight(self.reads.clone());
+        std::future::pending().await
+    }
+
+    async fn put(&self, path: &str, data: bytes::Bytes) -> crate::error::Result<()> {
+        self.inner.put(path, data).await
+    }
+
+    async fn delete(&self, path: &str) -> crate::error::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn ensure_dir(&self, path: &str) -> crate::error::Result<()> {
+        self.inner.ensure_dir(path).await
+    }
+
+    async fn readiness(&self) -> crate::storage::Readiness {
+        self.inner.readiness().await
+    }
+}
+
+#[tokio::test]
+async fn test_cancelled_read_cancels_pack_prefetch() {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let cache_dir = tempfile::tempdir().unwrap();
+    let reads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let backend = HangingReads {
+        inner: LocalBackend::open(dir.path()).await.unwrap(),
+        reads: reads.clone(),
+    };
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            pack_cache: Some(PackCache::open(cache_dir.path(), 1024).await.unwrap()),
+            pack_readahead: true,
+            ..ServerOptions::default()
+        },
+    );
+    let uri = format!("/data/{}", "ef".repeat(32));
+    let response = app
+        .clone()
+        .oneshot(Request::post(&uri).body(Body::from("pack data")).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The client gives up while the range and the whole pack are being fetched
+    let request = Request::get(&uri)
+        .header("range", "bytes=0-3")
+        .body(Body::empty())
+        .unwrap();
+    let read = tokio::spawn(app.oneshot(request));
+    for _ in 0..50 {
+        if reads.load(Ordering::SeqCst) == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(reads.load(Ordering::SeqCst), 2);
+    read.abort();
+    let _ = read.await;
+
+    for _ in 0..50 {
+        if reads.load(Ordering::SeqCst) == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        reads.load(Ordering::SeqCst),
+        0,
+        "123pan downloads still running after the client went away"
+    );
+}
+
+/// Collects formatted log output.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_request_deadline_logged_as_timeout() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = HangingReads {
+        inner: LocalBackend::open(dir.path()).await.unwrap(),
+        reads: Default::default(),
+    };
+    let app = create_router(
+        Arc::new(backend),
+        ServerOptions {
+            deadline: Some(std::time::Duration::from_millis(50)),
+            ..ServerOptions::default()
+        },
+    );
+    let uri = format!("/keys/{}", "ab".repeat(32));
+    let response = app
+        .clone()
+        .oneshot(Request::post(&uri).body(Body::from("key")).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _subscriber = tracing::subscriber::set_default(subscriber);
+    let response = app
+        .oneshot(Request::get(&uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("status=504"), "{}", logs);
+    assert!(!logs.contains("status=499"), "{}", logs);
+}
@@ -31,6 +31,7 @@ impl ResticFileType {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
             "config" => Some(ResticFileType::Config),
@@ -46,13 +47,144 @@ impl ResticFileType {
     pub fn is_config(&self) -> bool {
         matches!(self, ResticFileType::Config)
     }
+
+    /// Whether objects of this type are never rewritten under the same name
+    /// (refused in immutable-data mode). Index files are left out: `restic
+    /// repair index` may write one again.
+    pub fn is_immutable(&self) -> bool {
+        matches!(
+            self,
+            ResticFileType::Data | ResticFileType::Snapshots | ResticFileType::Keys
+        )
+    }
+
+    /// Repository directory holding objects of this type (config lives at the root).
+    pub fn dir_path(&self) -> &'static str {
+        if self.is_config() {
+            ""
+        } else {
+            self.dirname()
+        }
+    }
+
+    /// Check that `name` is a valid object name for this type.
+    ///
+    /// Data, index and snapshot objects are named by their SHA-256 ID (64
+    /// lowercase hex characters). Keys and locks may use shorter lowercase hex
+    /// IDs. Config has no named objects.
+    pub fn is_valid_name(&self, name: &str) -> bool {
+        let is_hex = |s: &str| s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        match self {
+            ResticFileType::Config => false,
+            ResticFileType::Data | ResticFileType::Index | ResticFileType::Snapshots => {
+                name.len() == OBJECT_ID_LEN && is_hex(name)
+            }
+            ResticFileType::Keys | ResticFileType::Locks => {
+                (1..=OBJECT_ID_LEN).contains(&name.len()) && is_hex(name)
+            }
+        }
+    }
+}
+
+/// Length of a hex-encoded restic object ID.
+pub const OBJECT_ID_LEN: usize = 64;
+
+/// Default length of the ID prefix naming data subdirectories.
+pub const DEFAULT_DATA_SHARD_LEN: usize = 2;
+
+/// Default number of data subdirectory levels.
+pub const DEFAULT_DATA_SHARD_DEPTH: usize = 1;
+
+/// Deepest supported nesting of data subdirectories.
+pub const MAX_DATA_SHARD_DEPTH: usize = 3;
+
+/// Repository-relative name of the file recording the layout a repository
+/// was created with.
+pub const LAYOUT_FILE: &str = ".layout";
+
+/// How objects are arranged in the repository directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepoLayout {
+    /// Data packs are stored in subdirectories named by consecutive
+    /// `data_shard_len`-character pieces of their ID (0 = no subdirectories)
+    pub data_shard_len: usize,
+    /// Levels of data subdirectories, e.g. `data/ab/cd/<id>` for 2 (0 = none)
+    #[serde(default = "default_data_shard_depth")]
+    pub data_shard_depth: usize,
+}
+
+fn default_data_shard_depth() -> usize {
+    DEFAULT_DATA_SHARD_DEPTH
+}
+
+impl Default for RepoLayout {
+    fn default() -> Self {
+        Self {
+            data_shard_len: DEFAULT_DATA_SHARD_LEN,
+            data_shard_depth: DEFAULT_DATA_SHARD_DEPTH,
+        }
+    }
+}
+
+impl std::fmt::Display for RepoLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "data/")?;
+        if self.data_shard_len > 0 {
+            for _ in 0..self.data_shard_depth {
+                write!(f, "{}/", "x".repeat(self.data_shard_len))?;
+            }
+        }
+        write!(f, "<id>")
+    }
+}
+
+impl RepoLayout {
+    /// Check that the layout fits restic object IDs.
+    pub fn validate(&self) -> crate::error::Result<()> {
+        if self.data_shard_depth > MAX_DATA_SHARD_DEPTH {
+            return Err(crate::error::AppError::BadRequest(format!(
+                "Data shard depth must not exceed {}, got {}",
+                MAX_DATA_SHARD_DEPTH, self.data_shard_depth
+            )));
+        }
+        if self.data_shard_len * self.data_shard_depth.max(1) > OBJECT_ID_LEN {
+            return Err(crate::error::AppError::BadRequest(format!(
+                "Data shards must not use more than the {} characters of an ID, got {} levels of {}",
+                OBJECT_ID_LEN, self.data_shard_depth, self.data_shard_len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Subdirectory path below `data/` holding the pack `name`, if sharded
+    /// (e.g. `ab/cd` with two levels).
+    pub fn data_shard(&self, name: &str) -> Option<String> {
+        let levels: Vec<&str> = (0..self.data_shard_depth)
+            .map(|i| {
+                let start = (i * self.data_shard_len).min(name.len());
+                let end = (start + self.data_shard_len).min(name.len());
+                name.get(start..end).unwrap_or_default()
+            })
+            .filter(|level| !level.is_empty())
+            .collect();
+        (!levels.is_empty()).then(|| levels.join("/"))
+    }
+
+    /// Repository-relative path of an object.
+    pub fn object_path(&self, file_type: ResticFileType, name: &str) -> String {
+        match (file_type, self.data_shard(name)) {
+            (ResticFileType::Config, _) => "config".to_string(),
+            (ResticFileType::Data, Some(shard)) => format!("data/{}/{}", shard, name),
+            _ => format!("{}/{}", file_type.dirname(), name),
+        }
+    }
 }
 
-impl From<&crate::pan123::FileInfo> for FileEntryV2 {
-    fn from(file: &crate::pan123::FileInfo) -> Self {
+impl From<&crate::storage::ObjectInfo> for FileEntryV2 {
+    fn from(object: &crate::storage::ObjectInfo) -> Self {
         Self {
-            name: file.filename.clone(),
-            size: file.size as u64,
+            name: object.name.clone(),
+            size: object.size as u64,
         }
     }
 }
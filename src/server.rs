@@ -0,0 +1,771 @@
+//! HTTP server: listener setup and connection serving with graceful shutdown.
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::ServiceExt;
+
+/// Address the server listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// TCP `host:port`
+    Tcp(String),
+    /// Unix domain socket path (`unix:/path/to.sock`)
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Unix socket path must not be empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        if addr.is_empty() {
+            return Err("Listen address must not be empty".to_string());
+        }
+        // `::1:8000` could be a port on ::1 or the address ::1:8000
+        if !addr.starts_with('[') && addr.matches(':').count() > 1 {
+            return Err(format!(
+                "IPv6 addresses must be in brackets, e.g. [::]:8000 instead of {}",
+                addr
+            ));
+        }
+        // rest-server's `:port` listens on all interfaces
+        if addr.starts_with(':') {
+            return Ok(ListenAddr::Tcp(format!("0.0.0.0{}", addr)));
+        }
+        Ok(ListenAddr::Tcp(addr.to_string()))
+    }
+}
+
+impl ListenAddr {
+    /// Whether only this host can connect: Unix sockets and TCP on loopback
+    /// addresses or `localhost`.
+    pub fn is_local(&self) -> bool {
+        match self {
+            ListenAddr::Unix(_) => true,
+            ListenAddr::Tcp(addr) => {
+                let host = addr
+                    .rsplit_once(':')
+                    .map_or(addr.as_str(), |(host, _)| host);
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                host.eq_ignore_ascii_case("localhost")
+                    || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A bound listener accepting client connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        /// Socket file to remove on shutdown (None when owned by systemd)
+        path: Option<PathBuf>,
+    },
+}
+
+impl Listener {
+    /// Whether only this host can connect, see [`ListenAddr::is_local`].
+    pub fn is_local(&self) -> bool {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .is_ok_and(|addr| addr.ip().is_loopback()),
+            #[cfg(unix)]
+            Listener::Unix { .. } => true,
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    /// The address actually bound, and for IPv6 whether IPv4 clients are
+    /// accepted too.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener) => {
+                let Ok(addr) = listener.local_addr() else {
+                    return write!(f, "TCP socket");
+                };
+                let stack = if addr.is_ipv4() {
+                    "IPv4 only"
+                } else if socket2::SockRef::from(listener).only_v6().unwrap_or(true) {
+                    "IPv6 only"
+                } else {
+                    "IPv6 and IPv4"
+                };
+                write!(f, "http://{} ({})", addr, stack)
+            }
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => match path {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "Unix socket"),
+            },
+        }
+    }
+}
+
+/// Bind to the first address `addr` resolves to that can be bound. IPv6
+/// sockets accept IPv4 clients too unless `ipv6_only`, whatever the OS
+/// default (Linux is dual-stack, Windows and the BSDs are not).
+async fn bind_tcp(addr: &str, ipv6_only: bool) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match bind_socket(addr, ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} resolves to no address", addr),
+        )
+    }))
+}
+
+fn bind_socket(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // As tokio's own bind does, so restarts don't wait for TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// First file descriptor passed by systemd socket activation (SD_LISTEN_FDS_START).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+impl Listener {
+    /// Bind to the given address. IPv6 addresses accept IPv4 clients as well
+    /// unless `ipv6_only`. Unix sockets are created with `socket_mode`
+    /// permissions, replacing a stale socket file left by a previous run.
+    pub async fn bind(
+        addr: &ListenAddr,
+        socket_mode: u32,
+        ipv6_only: bool,
+    ) -> std::io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(bind_tcp(addr, ipv6_only).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                let listener = tokio::net::UnixListener::bind(path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(socket_mode))?;
+                Ok(Listener::Unix {
+                    listener,
+                    path: Some(path.clone()),
+                })
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                let _ = socket_mode;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix domain sockets are not supported on this platform",
+                ))
+            }
+        }
+    }
+
+    /// Take over a listener passed by systemd socket activation
+    /// (`LISTEN_PID`/`LISTEN_FDS`, see sd_listen_fds(3)). Returns `None` when
+    /// the process was not socket-activated and fails on a malformed
+    /// `LISTEN_FDS`.
+    #[cfg(unix)]
+    pub fn from_systemd() -> std::io::Result<Option<Self>> {
+        use std::os::fd::{FromRawFd, IntoRawFd};
+
+        let pid_matches = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == std::process::id());
+        if !pid_matches {
+            return Ok(None);
+        }
+        let fds = match std::env::var("LISTEN_FDS") {
+            Ok(n) => n.parse::<i32>().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid LISTEN_FDS from systemd: {:?}", n),
+                )
+            })?,
+            Err(_) => 0,
+        };
+        if fds < 1 {
+            return Ok(None);
+        }
+        if fds > 1 {
+            tracing::warn!("systemd passed {} sockets, only the first one is used", fds);
+        }
+
+        // Don't leak the activation variables to child processes
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        // SAFETY: systemd guarantees SD_LISTEN_FDS_START is an open listening
+        // socket owned by this process, and nothing else takes ownership of it.
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            return Ok(Some(Listener::Tcp(TcpListener::from_std(tcp)?)));
+        }
+
+        // Not an inet socket: treat it as a Unix domain socket
+        // SAFETY: the fd was just released from the TcpListener above.
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        unix.local_addr()?;
+        unix.set_nonblocking(true)?;
+        Ok(Some(Listener::Unix {
+            listener: tokio::net::UnixListener::from_std(unix)?,
+            path: None,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_systemd() -> std::io::Result<Option<Self>> {
+        Ok(None)
+    }
+}
+
+/// How [`serve`] treats client connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// Close connections that don't send a complete request head in time
+    pub header_read_timeout: Option<Duration>,
+    /// Close connections with no request in progress and no traffic for this long
+    pub idle_timeout: Option<Duration>,
+    /// Connections served at once; further ones wait to be accepted (0 = unlimited)
+    pub max_connections: usize,
+}
+
+/// Serve `app` on `listener` until `shutdown` resolves, then stop accepting
+/// connections and wait up to `drain_timeout` for in-flight requests.
+pub async fn serve<F>(
+    listener: Listener,
+    app: Router,
+    shutdown: F,
+    drain_timeout: Duration,
+    options: ConnectionOptions,
+) -> std::io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let graceful = GracefulShutdown::new();
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(options.header_read_timeout);
+    let slots =
+        (options.max_connections > 0).then(|| Arc::new(Semaphore::new(options.max_connections)));
+    tokio::pin!(shutdown);
+
+    loop {
+        // Wait for a free slot before accepting, leaving further clients in
+        // the listen backlog
+        let permit = match &slots {
+            Some(slots) => tokio::select! {
+                permit = slots.clone().acquire_owned() => permit.ok(),
+                _ = &mut shutdown => break,
+            },
+            None => None,
+        };
+        tokio::select! {
+            accepted = accept(&listener) => {
+                match accepted {
+                    Ok((stream, remote)) => {
+                        spawn_connection(
+                            &graceful,
+                            &builder,
+                            &app,
+                            stream,
+                            remote,
+                            options.idle_timeout,
+                            permit,
+                        );
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Stop accepting new connections
+    #[cfg(unix)]
+    if let Listener::Unix {
+        path: Some(path), ..
+    } = &listener
+    {
+        let _ = std::fs::remove_file(path);
+    }
+    drop(listener);
+
+    tokio::select! {
+        _ = graceful.shutdown() => {
+            tracing::info!("All connections drained");
+        }
+        _ = tokio::time::sleep(drain_timeout) => {
+            tracing::warn!(
+                "In-flight requests did not finish within {:?}, forcing shutdown",
+                drain_timeout
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Boxed client connection stream.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Connection for T {}
+
+/// Accept one connection, returning the peer address for TCP connections.
+async fn accept(listener: &Listener) -> std::io::Result<(Box<dyn Connection>, Option<SocketAddr>)> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let (stream, remote) = listener.accept().await?;
+            Ok((Box::new(stream), Some(remote)))
+        }
+        #[cfg(unix)]
+        Listener::Unix { listener, .. } => {
+            let (stream, _) = listener.accept().await?;
+            Ok((Box::new(stream), None))
+        }
+    }
+}
+
+fn spawn_connection(
+    graceful: &GracefulShutdown,
+    builder: &Builder<TokioExecutor>,
+    app: &Router,
+    stream: Box<dyn Connection>,
+    remote: Option<SocketAddr>,
+    idle_timeout: Option<Duration>,
+    permit: Option<OwnedSemaphorePermit>,
+) {
+    let activity = Arc::new(Activity::new());
+    let requests = activity.clone();
+    let service = app
+        .clone()
+        .map_request(move |mut req: axum::http::Request<Incoming>| {
+            if let Some(remote) = remote {
+                req.extensions_mut().insert(ConnectInfo(remote));
+            }
+            req
+        })
+        .map_future(move |response| {
+            let request = requests.begin_request();
+            async move {
+                let _request = request;
+                response.await
+            }
+        });
+
+    let stream = Tracked {
+        inner: stream,
+        activity: activity.clone(),
+    };
+    let conn = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+        .into_owned();
+    let conn = graceful.watch(conn);
+
+    tokio::spawn(async move {
+        let _permit = permit;
+        let idle = async {
+            match idle_timeout {
+                Some(timeout) => activity.idle(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = conn => {
+                if let Err(e) = result {
+                    tracing::debug!("Connection error: {}", e);
+                }
+            }
+            _ = idle => tracing::debug!("Closing idle connection"),
+        }
+    });
+}
+
+/// Traffic and requests in progress on one connection.
+struct Activity {
+    last: Mutex<Instant>,
+    requests: AtomicUsize,
+}
+
+/// Marks a request in progress until dropped.
+struct RequestGuard(Arc<Activity>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.requests.fetch_sub(1, Ordering::AcqRel);
+        self.0.touch();
+    }
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock() = Instant::now();
+    }
+
+    fn begin_request(self: &Arc<Self>) -> RequestGuard {
+        self.requests.fetch_add(1, Ordering::AcqRel);
+        RequestGuard(self.clone())
+    }
+
+    /// Resolve once the connection has been idle for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let idle_for = if self.requests.load(Ordering::Acquire) > 0 {
+                Duration::ZERO
+            } else {
+                self.last.lock().elapsed()
+            };
+            if idle_for >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle_for).await;
+        }
+    }
+}
+
+/// Connection stream noting its traffic in an [`Activity`].
+struct Tracked {
+    inner: Box<dyn Connection>,
+    activity: Arc<Activity>,
+}
+
+impl AsyncRead for Tracked {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Tracked {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(result, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            "127.0.0.1:8000".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("127.0.0.1:8000".to_string())
+        );
+        assert_eq!(
+            "unix:/run/restic-123pan.sock"
+                .parse::<ListenAddr>()
+                .unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/restic-123pan.sock"))
+        );
+        assert_eq!(
+            ":8000".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("0.0.0.0:8000".to_string())
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert_eq!(
+            "[::]:8000".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("[::]:8000".to_string())
+        );
+        assert!("::1:8000".parse::<ListenAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_ipv6_dual_stack() {
+        // Skipped where the host has no IPv6
+        let Ok(listener) = Listener::bind(&"[::]:0".parse().unwrap(), 0, false).await else {
+            return;
+        };
+        assert!(listener.to_string().ends_with("(IPv6 and IPv4)"));
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let port = tcp.local_addr().unwrap().port();
+        let accepted = tokio::spawn(async move {
+            let Listener::Tcp(tcp) = &listener else {
+                unreachable!()
+            };
+            tcp.accept().await.unwrap().1
+        });
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        assert!(accepted.await.unwrap().ip().to_canonical().is_ipv4());
+
+        let listener = Listener::bind(&"[::]:0".parse().unwrap(), 0, true)
+            .await
+            .unwrap();
+        assert!(listener.to_string().ends_with("(IPv6 only)"));
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let port = tcp.local_addr().unwrap().port();
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err());
+    }
+
+    /// Serializes tests that set the socket activation variables.
+    #[cfg(unix)]
+    static ACTIVATION_ENV: Mutex<()> = Mutex::new(());
+
+    #[cfg(unix)]
+    #[test]
+    fn test_from_systemd_without_activation() {
+        let _env = ACTIVATION_ENV.lock();
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        assert!(Listener::from_systemd().unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_from_systemd_for_another_process() {
+        let _env = ACTIVATION_ENV.lock();
+        std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+        std::env::set_var("LISTEN_FDS", "1");
+        assert!(Listener::from_systemd().unwrap().is_none());
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_from_systemd_malformed_fds() {
+        let _env = ACTIVATION_ENV.lock();
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        std::env::set_var("LISTEN_FDS", "one");
+        let Err(err) = Listener::from_systemd() else {
+            panic!("malformed LISTEN_FDS accepted");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+    }
+
+    #[test]
+    fn test_listen_addr_is_local() {
+        for local in [
+            "127.0.0.1:8000",
+            "localhost:8000",
+            "[::1]:8000",
+            "unix:/run/r.sock",
+        ] {
+            assert!(local.parse::<ListenAddr>().unwrap().is_local(), "{}", local);
+        }
+        for exposed in [
+            ":8000",
+            "0.0.0.0:8000",
+            "[::]:8000",
+            "192.168.1.2:8000",
+            "nas:8000",
+        ] {
+            assert!(
+                !exposed.parse::<ListenAddr>().unwrap().is_local(),
+                "{}",
+                exposed
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone()), 0o600, false)
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let app = Router::new().route("/healthz", get(|| async { "ok" }));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            app,
+            async {
+                let _ = rx.await;
+            },
+            Duration::from_secs(1),
+            ConnectionOptions::default(),
+        ));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_are_closed() {
+        let listener = Listener::bind(&ListenAddr::Tcp("127.0.0.1:0".to_string()), 0, false)
+            .await
+            .unwrap();
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let addr = tcp.local_addr().unwrap();
+        let app = Router::new().route("/healthz", get(|| async { "ok" }));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            app,
+            async {
+                let _ = rx.await;
+            },
+            Duration::from_secs(1),
+            ConnectionOptions {
+                idle_timeout: Some(Duration::from_millis(200)),
+                max_connections: 1,
+                ..Default::default()
+            },
+        ));
+
+        // A kept-alive connection is served, then closed once idle
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+
+        // The only slot is taken, so a second client waits
+        let mut waiting = tokio::net::TcpStream::connect(addr).await.unwrap();
+        waiting
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            waiting.read_to_string(&mut response)
+        )
+        .await
+        .is_err());
+
+        let closed = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(closed, 0);
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            waiting.read_to_string(&mut response),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
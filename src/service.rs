@@ -0,0 +1,340 @@
+//! Running in the background: as a Unix daemon with a pidfile, or as a
+//! Windows service.
+//!
+//! A stop request from the Windows Service Control Manager ends in the same
+//! graceful shutdown as SIGTERM, through [`stop_requested`].
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::Notify;
+
+/// Name the Windows service is registered under.
+pub const SERVICE_NAME: &str = "restic-123pan";
+
+static STOP: Notify = Notify::const_new();
+
+/// Resolves once the service manager asked the server to stop.
+pub async fn stop_requested() {
+    STOP.notified().await
+}
+
+/// Ask the server to stop; a request made before anyone waits is kept.
+pub fn request_stop() {
+    STOP.notify_one()
+}
+
+/// File holding the server's process ID, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write this process's ID to `path`, refusing if the file names another
+    /// process that is still running. The file is created exclusively, so of
+    /// two servers starting at once only one gets it; a stale file is replaced.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let failed =
+            |e: std::io::Error| anyhow::anyhow!("Failed to write {}: {}", path.display(), e);
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(mut file) => {
+                    writeln!(file, "{}", std::process::id()).map_err(failed)?;
+                    return Ok(Self(path.to_path_buf()));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let content = std::fs::read_to_string(path).unwrap_or_default();
+                    if let Ok(pid) = content.trim().parse::<u32>() {
+                        if pid != std::process::id() && process_alive(pid) {
+                            anyhow::bail!(
+                                "Already running as process {} (see {})",
+                                pid,
+                                path.display()
+                            );
+                        }
+                    }
+                    match std::fs::remove_file(path) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(failed(e)),
+                    }
+                }
+                Err(e) => return Err(failed(e)),
+            }
+        }
+        anyhow::bail!(
+            "{} was created by another process meanwhile",
+            path.display()
+        )
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Detach from the terminal: fork twice around a new session, so the daemon
+/// can't reacquire a controlling terminal, and point standard input and
+/// output at `/dev/null`. The working directory is kept, so relative paths in
+/// the configuration stay valid.
+///
+/// Must be called before any thread is started, i.e. before the Tokio
+/// runtime.
+#[cfg(unix)]
+pub fn daemonize() -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    fn fork() -> anyhow::Result<()> {
+        // SAFETY: the process is still single-threaded
+        match unsafe { libc::fork() } {
+            -1 => Err(std::io::Error::last_os_error().into()),
+            0 => Ok(()),
+            _ => std::process::exit(0),
+        }
+    }
+
+    fork()?;
+    // SAFETY: plain system call without arguments
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    fork()?;
+
+    let null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        // SAFETY: both descriptors are valid
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub use windows::{install, run, uninstall};
+
+/// Windows service support, through the Service Control Manager API.
+#[cfg(windows)]
+mod windows {
+    use parking_lot::Mutex;
+    use std::ffi::c_void;
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+    use std::time::Duration;
+    use windows_sys::Win32::Foundation::{
+        ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+    };
+    use windows_sys::Win32::System::Services::{
+        RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+        SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_STATUS,
+        SERVICE_STATUS_CURRENT_STATE, SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW,
+        SERVICE_WIN32_OWN_PROCESS,
+    };
+
+    use super::{request_stop, SERVICE_NAME};
+
+    type Serve = Box<dyn FnOnce() -> anyhow::Result<()> + Send>;
+
+    /// The server, handed from [`run`] to the service main function
+    static SERVE: Mutex<Option<Serve>> = Mutex::new(None);
+    static RESULT: Mutex<Option<anyhow::Result<()>>> = Mutex::new(None);
+    static STATUS_HANDLE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+    /// Milliseconds the Service Control Manager is asked to wait for a stop
+    static STOP_WAIT_MS: AtomicU32 = AtomicU32::new(30_000);
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+        let status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: if state == SERVICE_RUNNING {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            } else {
+                0
+            },
+            dwWin32ExitCode: exit_code,
+            dwServiceSpecificExitCode: u32::from(exit_code != NO_ERROR),
+            dwCheckPoint: 0,
+            dwWaitHint: if state == SERVICE_STOP_PENDING {
+                STOP_WAIT_MS.load(Ordering::Relaxed)
+            } else {
+                0
+            },
+        };
+        // SAFETY: the handle came from RegisterServiceCtrlHandlerExW
+        unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::Acquire), &status) };
+    }
+
+    unsafe extern "system" fn handler(
+        control: u32,
+        _event_type: u32,
+        _event_data: *mut c_void,
+        _context: *mut c_void,
+    ) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                set_status(SERVICE_STOP_PENDING, NO_ERROR);
+                request_stop();
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+        let name = wide(SERVICE_NAME);
+        let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), std::ptr::null());
+        if handle.is_null() {
+            *RESULT.lock() = Some(Err(std::io::Error::last_os_error().into()));
+            return;
+        }
+        STATUS_HANDLE.store(handle, Ordering::Release);
+        set_status(SERVICE_RUNNING, NO_ERROR);
+
+        let result = match SERVE.lock().take() {
+            Some(serve) => serve(),
+            None => Ok(()),
+        };
+        let exit_code = if result.is_ok() {
+            NO_ERROR
+        } else {
+            ERROR_SERVICE_SPECIFIC_ERROR
+        };
+        *RESULT.lock() = Some(result);
+        set_status(SERVICE_STOPPED, exit_code);
+    }
+
+    /// Run `serve` as the service, returning when it stopped. Only works when
+    /// started by the Service Control Manager; `stop_wait` is how long a stop
+    /// may take.
+    pub fn run(
+        serve: impl FnOnce() -> anyhow::Result<()> + Send + 'static,
+        stop_wait: Duration,
+    ) -> anyhow::Result<()> {
+        *SERVE.lock() = Some(Box::new(serve));
+        STOP_WAIT_MS.store(
+            u32::try_from(stop_wait.as_millis()).unwrap_or(u32::MAX),
+            Ordering::Relaxed,
+        );
+        let mut name = wide(SERVICE_NAME);
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_mut_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: std::ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // SAFETY: the table is terminated by a null entry and outlives the call
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            anyhow::bail!(
+                "Not started by the Service Control Manager ({}); register the service \
+                 with `service install` and start it with `sc start {}`",
+                std::io::Error::last_os_error(),
+                SERVICE_NAME
+            );
+        }
+        RESULT.lock().take().unwrap_or(Ok(()))
+    }
+
+    fn sc(args: &[&str]) -> anyhow::Result<()> {
+        let output = Command::new("sc.exe").args(args).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "sc.exe {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Register the service to start at boot, serving with the settings in
+    /// `config_file`.
+    pub fn install(config_file: &Path) -> anyhow::Result<()> {
+        let exe = std::env::current_exe()?;
+        let config_file = std::path::absolute(config_file)?;
+        let bin_path = format!(
+            "\"{}\" --config-file \"{}\" service run",
+            exe.display(),
+            config_file.display()
+        );
+        sc(&[
+            "create",
+            SERVICE_NAME,
+            "binPath=",
+            &bin_path,
+            "start=",
+            "auto",
+            "DisplayName=",
+            SERVICE_NAME,
+        ])?;
+        sc(&[
+            "description",
+            SERVICE_NAME,
+            "Restic REST server backed by 123pan cloud storage",
+        ])
+    }
+
+    /// Remove the service registration; a running service is stopped first.
+    pub fn uninstall() -> anyhow::Result<()> {
+        let _ = sc(&["stop", SERVICE_NAME]);
+        sc(&["delete", SERVICE_NAME])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("restic-123pan.pid");
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // A stale file is taken over, one naming a running process is not
+        std::fs::write(&path, "999999999\n").unwrap();
+        drop(PidFile::create(&path).unwrap());
+        std::fs::write(&path, "1\n").unwrap();
+        assert!(PidFile::create(&path).is_err());
+    }
+}
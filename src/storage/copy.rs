@@ -0,0 +1,156 @@
+//! Copying a repository between backends.
+//!
+//! Used to export a repository from 123pan to a local directory, import one
+//! and mirror it to another path or account. Objects already at the
+//! destination with the same size (and MD5, where both backends know it) are
+//! skipped, so an interrupted copy resumes where it stopped and a repeated
+//! one only copies what is new or changed.
+
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::{repository_objects, StorageBackend};
+use crate::error::Result;
+use crate::restic::{RepoLayout, ResticFileType};
+
+/// How [`copy_repository`] copies.
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Objects copied at the same time
+    pub parallelism: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self { parallelism: 4 }
+    }
+}
+
+/// Outcome of [`copy_repository`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CopyReport {
+    /// Objects copied
+    pub copied: u64,
+    /// Bytes copied
+    pub bytes: u64,
+    /// Objects already at the destination
+    pub skipped: u64,
+    /// Objects that could not be copied, with the error
+    pub failed: Vec<(String, String)>,
+}
+
+impl CopyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Copy every object of the repository at `src` (see
+/// [`repository_objects`]) to `dst`, placing data packs as `dst_layout` does.
+pub async fn copy_repository(
+    src: &dyn StorageBackend,
+    src_layout: RepoLayout,
+    dst: &dyn StorageBackend,
+    dst_layout: RepoLayout,
+    options: &CopyOptions,
+) -> Result<CopyReport> {
+    let mut report = CopyReport::default();
+    for file_type in [
+        ResticFileType::Keys,
+        ResticFileType::Locks,
+        ResticFileType::Snapshots,
+        ResticFileType::Index,
+        ResticFileType::Data,
+    ] {
+        dst.ensure_dir(file_type.dirname()).await?;
+    }
+
+    let objects = repository_objects(src, src_layout, true).await?;
+    let total = objects.len();
+    tracing::info!("Copying {} objects", total);
+    let mut results = stream::iter(objects)
+        .map(|(file_type, src_path, object)| async move {
+            let dst_path = dst_layout.object_path(file_type, &object.name);
+            let result: Result<Option<u64>> = async {
+                if let Some(existing) = dst.head(&dst_path).await? {
+                    let same_md5 = match (&existing.etag, &object.etag) {
+                        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+                        _ => true,
+                    };
+                    if existing.size == object.size && same_md5 {
+                        return Ok(None);
+                    }
+                }
+                let data = src.get_range(&src_path, None).await?;
+                let size = data.len() as u64;
+                dst.put(&dst_path, data).await?;
+                Ok(Some(size))
+            }
+            .await;
+            (dst_path, result)
+        })
+        .buffer_unordered(options.parallelism.max(1));
+    let mut done = 0;
+    while let Some((path, result)) = results.next().await {
+        match result {
+            Ok(Some(size)) => {
+                report.copied += 1;
+                report.bytes += size;
+            }
+            Ok(None) => report.skipped += 1,
+            Err(e) => {
+                tracing::warn!("Failed to copy {}: {}", path, e);
+                report.failed.push((path, e.to_string()));
+            }
+        }
+        done += 1;
+        if done % 1000 == 0 {
+            tracing::info!("Copied {} of {} objects", done, total);
+        }
+    }
+    report.failed.sort();
+    Ok(report)
+}
+
+/// Outcome of [`check_copy`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CopyCheck {
+    /// Objects at the source
+    pub objects: u64,
+    /// Their total size
+    pub bytes: u64,
+    /// Source objects missing at the destination or of another size there
+    pub mismatched: Vec<String>,
+}
+
+impl CopyCheck {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty()
+    }
+}
+
+/// Compare the object lists of a copy with its source by name and size.
+pub async fn check_copy(
+    src: &dyn StorageBackend,
+    src_layout: RepoLayout,
+    dst: &dyn StorageBackend,
+    dst_layout: RepoLayout,
+) -> Result<CopyCheck> {
+    let copied: HashMap<String, i64> = repository_objects(dst, dst_layout, true)
+        .await?
+        .into_iter()
+        .map(|(_, path, object)| (path, object.size))
+        .collect();
+    let mut check = CopyCheck::default();
+    for (file_type, _, object) in repository_objects(src, src_layout, true).await? {
+        check.objects += 1;
+        check.bytes += object.size.max(0) as u64;
+        let path = dst_layout.object_path(file_type, &object.name);
+        if copied.get(&path) != Some(&object.size) {
+            check.mismatched.push(path);
+        }
+    }
+    check.mismatched.sort();
+    Ok(check)
+}
@@ -0,0 +1,91 @@
+//! Comparing the object lists of two repositories.
+//!
+//! Used to check a mirror, export or migration against its source without
+//! reading any content: objects are matched by type and name, whatever data
+//! layout either side uses, and compared by size and, where both backends
+//! know it, MD5.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use super::{repository_objects, ObjectInfo, StorageBackend};
+use crate::error::Result;
+use crate::restic::RepoLayout;
+
+/// An object present on both sides with different content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffEntry {
+    /// Path under the default layout, e.g. `data/<id>` or `config`
+    pub name: String,
+    pub problem: String,
+}
+
+/// Outcome of [`diff_repositories`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepoDiff {
+    /// Objects on both sides with the same size and MD5
+    pub same: u64,
+    /// Objects of the first repository missing from the second
+    pub missing: Vec<String>,
+    /// Objects of the second repository missing from the first
+    pub extra: Vec<String>,
+    pub differing: Vec<DiffEntry>,
+}
+
+impl RepoDiff {
+    pub fn is_identical(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// Compare the objects (see [`repository_objects`]) of the repository at
+/// `a` with those at `b`.
+pub async fn diff_repositories(
+    a: &dyn StorageBackend,
+    a_layout: RepoLayout,
+    b: &dyn StorageBackend,
+    b_layout: RepoLayout,
+) -> Result<RepoDiff> {
+    let mut others = objects_by_name(b, b_layout).await?;
+    let mut diff = RepoDiff::default();
+    for (name, object) in objects_by_name(a, a_layout).await? {
+        match others.remove(&name) {
+            None => diff.missing.push(name),
+            Some(other) => match compare(&object, &other) {
+                None => diff.same += 1,
+                Some(problem) => diff.differing.push(DiffEntry { name, problem }),
+            },
+        }
+    }
+    diff.extra = others.into_keys().collect();
+    Ok(diff)
+}
+
+/// Objects keyed by their type directory and name, in order.
+async fn objects_by_name(
+    backend: &dyn StorageBackend,
+    layout: RepoLayout,
+) -> Result<BTreeMap<String, ObjectInfo>> {
+    Ok(repository_objects(backend, layout, true)
+        .await?
+        .into_iter()
+        .map(|(file_type, _, object)| {
+            let name = if file_type.is_config() {
+                "config".to_string()
+            } else {
+                format!("{}/{}", file_type.dirname(), object.name)
+            };
+            (name, object)
+        })
+        .collect())
+}
+
+fn compare(a: &ObjectInfo, b: &ObjectInfo) -> Option<String> {
+    if a.size != b.size {
+        return Some(format!("size {} vs {}", a.size, b.size));
+    }
+    match (&a.etag, &b.etag) {
+        (Some(x), Some(y)) if !x.eq_ignore_ascii_case(y) => Some(format!("MD5 {} vs {}", x, y)),
+        _ => None,
+    }
+}
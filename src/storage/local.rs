@@ -0,0 +1,178 @@
+//! Storage backend on a local directory.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{ObjectInfo, Readiness, StorageBackend};
+use crate::error::{AppError, Result};
+use crate::pan123::validate_filename;
+
+/// Distinguishes concurrent temporary files for the same object.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Stores objects as plain files under a root directory.
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    /// Use `root` as the repository directory, creating it if missing.
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    /// Resolve a repository path, rejecting components that could escape the root.
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let mut resolved = self.root.clone();
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            validate_filename(part)?;
+            resolved.push(part);
+        }
+        Ok(resolved)
+    }
+}
+
+/// Object metadata from a file's filesystem metadata.
+fn object_info(name: String, metadata: &std::fs::Metadata) -> ObjectInfo {
+    ObjectInfo {
+        id: 0,
+        name,
+        size: metadata.len() as i64,
+        etag: None,
+        modified_at: metadata
+            .modified()
+            .ok()
+            .map(|t| chrono::DateTime::<chrono::Utc>::from(t).naive_utc()),
+    }
+}
+
+/// Temporary files are hidden (dot-prefixed) and skipped by listings.
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+async fn list_dir(dir: &Path, objects: &mut Vec<ObjectInfo>) -> Result<()> {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_hidden(&name) {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                objects.push(object_info(name, &metadata));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn list(&self, dir: &str) -> Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        list_dir(&self.resolve(dir)?, &mut objects).await?;
+        Ok(objects)
+    }
+
+    async fn head(&self, path: &str) -> Result<Option<ObjectInfo>> {
+        let resolved = self.resolve(path)?;
+        match tokio::fs::metadata(&resolved).await {
+            Ok(metadata) if metadata.is_file() => {
+                let (_, name) = super::split_path(path);
+                Ok(Some(object_info(name.to_string(), &metadata)))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_range(&self, path: &str, range: Option<(u64, u64)>) -> Result<Bytes> {
+        let resolved = self.resolve(path)?;
+        let mut file = match tokio::fs::File::open(&resolved).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(AppError::NotFound(path.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let Some((start, end)) = range else {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).await?;
+            return Ok(Bytes::from(data));
+        };
+
+        let size = file.metadata().await?.len();
+        if start > end || start >= size {
+            return Err(AppError::BadRequest(format!(
+                "Range {}-{} not satisfiable for {} ({} bytes)",
+                start, end, path, size
+            )));
+        }
+        let len = (end.min(size - 1) - start + 1) as usize;
+        let mut data = vec![0u8; len];
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        file.read_exact(&mut data).await?;
+        Ok(Bytes::from(data))
+    }
+
+    async fn put(&self, path: &str, data: Bytes) -> Result<()> {
+        let resolved = self.resolve(path)?;
+        let (parent, name) = super::split_path(path);
+        let dir = self.resolve(parent)?;
+        tokio::fs::create_dir_all(&dir).await?;
+
+        // Write to a hidden temporary file, then rename into place atomically
+        let tmp_path = dir.join(format!(
+            ".{}.{}.tmp",
+            name,
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&tmp_path, &data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp_path, &resolved).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.resolve(path)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn ensure_dir(&self, path: &str) -> Result<()> {
+        tokio::fs::create_dir_all(self.resolve(path)?).await?;
+        Ok(())
+    }
+
+    async fn readiness(&self) -> Readiness {
+        let root = tokio::fs::metadata(&self.root)
+            .await
+            .is_ok_and(|m| m.is_dir());
+        Readiness {
+            ready: root,
+            details: json!({ "root": root }),
+        }
+    }
+}
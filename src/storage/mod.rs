@@ -0,0 +1,160 @@
+//! Storage backends behind the restic REST layer.
+//!
+//! Backends address objects by `/`-separated paths relative to the repository
+//! root (e.g. `config`, `keys/<id>`, `data/ab/<id>`); the restic layout is
+//! decided by the REST layer, caching and spooling sit on top of the trait.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+
+use crate::error::{AppError, Result};
+use crate::pan123::{decode_name, FileInfo};
+use crate::restic::{RepoLayout, ResticFileType};
+
+pub mod copy;
+pub mod diff;
+mod local;
+pub mod verify;
+
+#[cfg(test)]
+mod tests;
+
+pub use copy::{check_copy, copy_repository, CopyCheck, CopyOptions, CopyReport};
+pub use diff::{diff_repositories, DiffEntry, RepoDiff};
+pub use local::LocalBackend;
+pub use verify::{verify, VerifyOptions, VerifyReport};
+
+/// Metadata of a stored object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// Backend-specific object ID (0 if the backend has none)
+    pub id: i64,
+    pub name: String,
+    pub size: i64,
+    /// MD5 of the content, if known
+    pub etag: Option<String>,
+    /// Last modification time (UTC)
+    pub modified_at: Option<chrono::NaiveDateTime>,
+}
+
+impl From<FileInfo> for ObjectInfo {
+    fn from(file: FileInfo) -> Self {
+        Self {
+            id: file.file_id,
+            name: decode_name(&file.filename).into_owned(),
+            size: file.size,
+            etag: file.etag,
+            modified_at: file.modified_at,
+        }
+    }
+}
+
+/// Readiness report for `/readyz`.
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Backend-specific check results, returned as the response body
+    pub details: serde_json::Value,
+}
+
+/// Object storage operations used by the REST layer.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// List objects under `dir`, including those in its subdirectories.
+    /// A missing directory lists as empty.
+    async fn list(&self, dir: &str) -> Result<Vec<ObjectInfo>>;
+
+    /// Like [`Self::list`], but yield objects as they are read, so a large
+    /// listing is never held in memory at once. Errors before the first
+    /// object (e.g. a missing warm-up) are returned up front.
+    async fn list_stream(&self, dir: &str) -> Result<BoxStream<'static, Result<ObjectInfo>>> {
+        let objects = self.list(dir).await?;
+        Ok(stream::iter(objects.into_iter().map(Ok)).boxed())
+    }
+
+    /// Metadata of the object at `path`, if it exists.
+    async fn head(&self, path: &str) -> Result<Option<ObjectInfo>>;
+
+    /// Like [`Self::head`], but confirmed with the storage service rather
+    /// than answered from a cache. Backends without a cache just head.
+    async fn head_verified(&self, path: &str) -> Result<Option<ObjectInfo>> {
+        self.head(path).await
+    }
+
+    /// Read the object at `path`, or only the inclusive byte `range` of it.
+    async fn get_range(&self, path: &str, range: Option<(u64, u64)>) -> Result<Bytes>;
+
+    /// Create or replace the object at `path`, creating parent directories.
+    async fn put(&self, path: &str, data: Bytes) -> Result<()>;
+
+    /// Create the object at `path` like [`Self::put`], but fail with
+    /// [`AppError::Forbidden`] if it already exists. Backends that can refuse
+    /// the overwrite as part of the upload override this; the default checks
+    /// first and leaves a window for a concurrent writer.
+    async fn create(&self, path: &str, data: Bytes) -> Result<()> {
+        if self.head(path).await?.is_some() {
+            return Err(AppError::Forbidden(format!("{} already exists", path)));
+        }
+        self.put(path, data).await
+    }
+
+    /// Delete the object at `path`. Deleting a missing object succeeds.
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    /// Create directory `path` and its parents if missing (`""` is the root).
+    async fn ensure_dir(&self, path: &str) -> Result<()>;
+
+    /// Whether the backend is ready to serve requests.
+    async fn readiness(&self) -> Readiness;
+
+    /// Run housekeeping on backend-local state (e.g. the cache database),
+    /// returning a report. Backends without any do nothing.
+    async fn maintain(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::Value::Null)
+    }
+
+    /// Backend counters in the Prometheus text format, served at `/metrics`.
+    fn metrics(&self) -> String {
+        String::new()
+    }
+}
+
+/// Every object of the repository at `backend`, with its path under
+/// `layout`: the config, keys, snapshots, index and, `with_data`, data packs.
+/// Locks are left out, they come and go while restic runs.
+pub async fn repository_objects(
+    backend: &dyn StorageBackend,
+    layout: RepoLayout,
+    with_data: bool,
+) -> Result<Vec<(ResticFileType, String, ObjectInfo)>> {
+    let mut objects = Vec::new();
+    if let Some(config) = backend.head("config").await? {
+        objects.push((ResticFileType::Config, "config".to_string(), config));
+    }
+    for file_type in [
+        ResticFileType::Keys,
+        ResticFileType::Snapshots,
+        ResticFileType::Index,
+        ResticFileType::Data,
+    ] {
+        if file_type == ResticFileType::Data && !with_data {
+            continue;
+        }
+        for object in backend.list(file_type.dirname()).await? {
+            objects.push((
+                file_type,
+                layout.object_path(file_type, &object.name),
+                object,
+            ));
+        }
+    }
+    Ok(objects)
+}
+
+/// Split a path into its parent directory and final component.
+pub(crate) fn split_path(path: &str) -> (&str, &str) {
+    path.trim_matches('/')
+        .rsplit_once('/')
+        .unwrap_or(("", path.trim_matches('/')))
+}
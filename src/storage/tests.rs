@@ -0,0 +1,270 @@
+use bytes::Bytes;
+
+use sha2::{Digest, Sha256};
+
+use super::{
+    copy_repository, diff_repositories, split_path, verify, CopyOptions, DiffEntry, LocalBackend,
+    StorageBackend, VerifyOptions,
+};
+use crate::error::AppError;
+use crate::restic::{RepoLayout, ResticFileType};
+
+#[test]
+fn test_split_path() {
+    assert_eq!(split_path("config"), ("", "config"));
+    assert_eq!(split_path("keys/abc"), ("keys", "abc"));
+    assert_eq!(split_path("/data/ab/abcd/"), ("data/ab", "abcd"));
+}
+
+#[tokio::test]
+async fn test_local_backend_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+
+    backend.ensure_dir("data").await.unwrap();
+    assert!(backend.list("data").await.unwrap().is_empty());
+    assert!(backend.list("missing").await.unwrap().is_empty());
+
+    backend
+        .put("data/ab/abcd", Bytes::from_static(b"0123456789"))
+        .await
+        .unwrap();
+    backend
+        .put("data/cd/cdef", Bytes::from_static(b"xyz"))
+        .await
+        .unwrap();
+
+    let mut names: Vec<String> = backend
+        .list("data")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|o| o.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["abcd", "cdef"]);
+
+    let object = backend.head("data/ab/abcd").await.unwrap().unwrap();
+    assert_eq!(object.name, "abcd");
+    assert_eq!(object.size, 10);
+    assert!(backend.head("data/ab").await.unwrap().is_none());
+
+    assert_eq!(
+        backend
+            .get_range("data/ab/abcd", Some((2, 5)))
+            .await
+            .unwrap(),
+        Bytes::from_static(b"2345")
+    );
+    assert_eq!(
+        backend
+            .get_range("data/ab/abcd", Some((8, 20)))
+            .await
+            .unwrap(),
+        Bytes::from_static(b"89")
+    );
+
+    // Overwrite replaces the content
+    backend
+        .put("data/ab/abcd", Bytes::from_static(b"new"))
+        .await
+        .unwrap();
+    assert_eq!(
+        backend.get_range("data/ab/abcd", None).await.unwrap(),
+        Bytes::from_static(b"new")
+    );
+
+    backend.delete("data/ab/abcd").await.unwrap();
+    backend.delete("data/ab/abcd").await.unwrap();
+    assert!(matches!(
+        backend.get_range("data/ab/abcd", None).await,
+        Err(AppError::NotFound(_))
+    ));
+    assert!(backend.readiness().await.ready);
+}
+
+#[tokio::test]
+async fn test_local_backend_rejects_escaping_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path().join("repo")).await.unwrap();
+
+    assert!(matches!(
+        backend.put("../outside", Bytes::from_static(b"x")).await,
+        Err(AppError::BadRequest(_))
+    ));
+    assert!(!dir.path().join("outside").exists());
+}
+
+#[tokio::test]
+async fn test_verify_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = LocalBackend::open(dir.path()).await.unwrap();
+    let layout = RepoLayout::default();
+    let put = |file_type: ResticFileType, content: &'static [u8]| {
+        let name = format!("{:x}", Sha256::digest(content));
+        let path = layout.object_path(file_type, &name);
+        let backend = &backend;
+        async move {
+            backend
+                .put(&path, Bytes::from_static(content))
+                .await
+                .unwrap();
+            path
+        }
+    };
+    backend
+        .put("config", Bytes::from_static(b"config"))
+        .await
+        .unwrap();
+    put(ResticFileType::Keys, b"key").await;
+    put(ResticFileType::Index, b"index").await;
+    put(ResticFileType::Data, b"pack one").await;
+    let damaged = put(ResticFileType::Data, b"pack two").await;
+    backend
+        .put(&damaged, Bytes::from_static(b"bit rot"))
+        .await
+        .unwrap();
+
+    let report = verify(&backend, layout, &VerifyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(report.checked, 5);
+    assert!(report.is_ok());
+
+    let options = VerifyOptions {
+        check_sha256: true,
+        ..VerifyOptions::default()
+    };
+    let report = verify(&backend, layout, &options).await.unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].path, damaged);
+
+    let options = VerifyOptions {
+        metadata_only: true,
+        check_sha256: true,
+        ..VerifyOptions::default()
+    };
+    let report = verify(&backend, layout, &options).await.unwrap();
+    assert_eq!((report.checked, report.skipped), (3, 0));
+    assert!(report.is_ok());
+
+    let options = VerifyOptions {
+        data_sample_percent: 0,
+        ..VerifyOptions::default()
+    };
+    let report = verify(&backend, layout, &options).await.unwrap();
+    assert_eq!((report.checked, report.skipped), (3, 2));
+}
+
+#[tokio::test]
+async fn test_copy_repository_between_layouts() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let dst_dir = tempfile::tempdir().unwrap();
+    let src = LocalBackend::open(src_dir.path()).await.unwrap();
+    let dst = LocalBackend::open(dst_dir.path()).await.unwrap();
+    let flat = RepoLayout {
+        data_shard_len: 0,
+        data_shard_depth: 0,
+    };
+    let pack = "ab".repeat(32);
+    src.put("config", Bytes::from_static(b"config"))
+        .await
+        .unwrap();
+    src.put("keys/0123", Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+    src.put("locks/4567", Bytes::from_static(b"lock"))
+        .await
+        .unwrap();
+    src.put(&format!("data/{}", pack), Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+
+    let report = copy_repository(
+        &src,
+        flat,
+        &dst,
+        RepoLayout::default(),
+        &CopyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert!(report.is_ok());
+    assert_eq!((report.copied, report.bytes, report.skipped), (3, 13, 0));
+    assert_eq!(
+        dst.get_range(&format!("data/ab/{}", pack), None)
+            .await
+            .unwrap(),
+        Bytes::from_static(b"pack")
+    );
+    // Locks are not copied, but their directory exists for restic
+    assert!(dst.list("locks").await.unwrap().is_empty());
+    assert!(dst_dir.path().join("locks").is_dir());
+
+    // A repeated copy only fills in what is missing
+    dst.delete("keys/0123").await.unwrap();
+    let report = copy_repository(
+        &src,
+        flat,
+        &dst,
+        RepoLayout::default(),
+        &CopyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!((report.copied, report.skipped), (1, 2));
+}
+
+#[tokio::test]
+async fn test_diff_repositories() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let a = LocalBackend::open(a_dir.path()).await.unwrap();
+    let b = LocalBackend::open(b_dir.path()).await.unwrap();
+    let flat = RepoLayout {
+        data_shard_len: 0,
+        data_shard_depth: 0,
+    };
+    let pack = "ab".repeat(32);
+    a.put("config", Bytes::from_static(b"config"))
+        .await
+        .unwrap();
+    a.put(&format!("data/{}", pack), Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+    a.put("keys/0123", Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+    // The same pack in another layout, a changed config and an extra index
+    b.put(&format!("data/ab/{}", pack), Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+    b.put("config", Bytes::from_static(b"config-2"))
+        .await
+        .unwrap();
+    b.put("index/4567", Bytes::from_static(b"index"))
+        .await
+        .unwrap();
+    b.put("locks/89ab", Bytes::from_static(b"lock"))
+        .await
+        .unwrap();
+
+    let diff = diff_repositories(&a, flat, &b, RepoLayout::default())
+        .await
+        .unwrap();
+    assert!(!diff.is_identical());
+    assert_eq!(diff.same, 1);
+    assert_eq!(diff.missing, vec!["keys/0123".to_string()]);
+    assert_eq!(diff.extra, vec!["index/4567".to_string()]);
+    assert_eq!(
+        diff.differing,
+        vec![DiffEntry {
+            name: "config".to_string(),
+            problem: "size 6 vs 8".to_string(),
+        }]
+    );
+
+    let diff = diff_repositories(&a, flat, &a, flat).await.unwrap();
+    assert!(diff.is_identical());
+    assert_eq!(diff.same, 3);
+}
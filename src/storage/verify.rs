@@ -0,0 +1,152 @@
+//! Integrity check of a whole repository, independent of restic.
+//!
+//! Every object is read back and its length and MD5 compared with what the
+//! listing reports; restic names objects by the SHA-256 of their content, so
+//! that can be checked against the name as well.
+
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::{repository_objects, ObjectInfo, StorageBackend};
+use crate::error::Result;
+use crate::restic::types::OBJECT_ID_LEN;
+use crate::restic::{RepoLayout, ResticFileType};
+
+/// What [`verify`] reads and checks.
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// Skip data packs
+    pub metadata_only: bool,
+    /// Percentage of data packs read (a different sample each run)
+    pub data_sample_percent: u32,
+    /// Compare the SHA-256 of each named object with its name
+    pub check_sha256: bool,
+    /// Objects read at the same time
+    pub parallelism: usize,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            metadata_only: false,
+            data_sample_percent: 100,
+            check_sha256: false,
+            parallelism: 4,
+        }
+    }
+}
+
+/// An object that failed a check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VerifyProblem {
+    /// Repository-relative path
+    pub path: String,
+    pub problem: String,
+}
+
+/// Outcome of [`verify`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    /// Objects read
+    pub checked: u64,
+    /// Bytes read
+    pub bytes: u64,
+    /// Data packs left out of the sample
+    pub skipped: u64,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Read every object of the repository (see [`VerifyOptions`]) and check it.
+pub async fn verify(
+    backend: &dyn StorageBackend,
+    layout: RepoLayout,
+    options: &VerifyOptions,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let sample_offset = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+
+    let mut objects = repository_objects(backend, layout, !options.metadata_only).await?;
+    objects.retain(|(file_type, _, object)| {
+        let keep = *file_type != ResticFileType::Data
+            || sampled(&object.name, options.data_sample_percent, sample_offset);
+        if !keep {
+            report.skipped += 1;
+        }
+        keep
+    });
+
+    tracing::info!("Verifying {} objects", objects.len());
+    let mut results = stream::iter(objects)
+        .map(|(file_type, path, object)| async move {
+            let result = check(backend, file_type, &path, &object, options.check_sha256).await;
+            (path, object.size, result)
+        })
+        .buffer_unordered(options.parallelism.max(1));
+    while let Some((path, size, result)) = results.next().await {
+        report.checked += 1;
+        report.bytes += size.max(0) as u64;
+        if let Err(problem) = result {
+            tracing::warn!("{}: {}", path, problem);
+            report.problems.push(VerifyProblem { path, problem });
+        }
+        if report.checked % 1000 == 0 {
+            tracing::info!("Verified {} objects", report.checked);
+        }
+    }
+    report.problems.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
+
+/// Read one object and describe what is wrong with it.
+async fn check(
+    backend: &dyn StorageBackend,
+    file_type: ResticFileType,
+    path: &str,
+    object: &ObjectInfo,
+    check_sha256: bool,
+) -> std::result::Result<(), String> {
+    let data = backend
+        .get_range(path, None)
+        .await
+        .map_err(|e| format!("read failed: {}", e))?;
+    if data.len() as i64 != object.size {
+        return Err(format!(
+            "length {} does not match the listed size {}",
+            data.len(),
+            object.size
+        ));
+    }
+    if let Some(etag) = &object.etag {
+        let md5 = format!("{:x}", md5::compute(&data));
+        if !md5.eq_ignore_ascii_case(etag) {
+            return Err(format!("MD5 {} does not match the etag {}", md5, etag));
+        }
+    }
+    if check_sha256 && !file_type.is_config() && object.name.len() == OBJECT_ID_LEN {
+        let sha256 = format!("{:x}", Sha256::digest(&data));
+        if sha256 != object.name {
+            return Err(format!("SHA-256 {} does not match the name", sha256));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the pack `name` is in this run's sample of `percent` percent.
+fn sampled(name: &str, percent: u32, offset: u32) -> bool {
+    if percent >= 100 {
+        return true;
+    }
+    let hash = name
+        .bytes()
+        .fold(offset, |h, b| h.wrapping_mul(31).wrapping_add(b as u32));
+    hash % 100 < percent
+}
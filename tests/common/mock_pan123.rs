@@ -0,0 +1,754 @@
+//! In-process mock of the 123pan Open Platform API.
+//!
+//! Emulates the endpoints used by `Pan123Client` (token, list, mkdir, upload
+//! domain, single and slice upload, download_info, download, trash, delete,
+//! move, user info) on
+//! top of an in-memory file tree, so client and handler behavior can be
+//! tested deterministically without credentials or network access.
+
+#![allow(dead_code)]
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::StreamExt;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Number of entries returned per list page.
+const PAGE_SIZE: usize = 100;
+
+/// A file or directory in the mock tree.
+#[derive(Debug, Clone)]
+pub struct MockNode {
+    pub id: i64,
+    pub parent_id: i64,
+    pub name: String,
+    pub is_dir: bool,
+    pub data: Bytes,
+    pub trashed: bool,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    next_id: i64,
+    nodes: BTreeMap<i64, MockNode>,
+    /// Requests served per path
+    requests: HashMap<String, usize>,
+    /// Number of upcoming API calls answered with code 429
+    rate_limit_next: usize,
+    /// Whether listings still show trashed and deleted files as live
+    lag_listings: bool,
+    /// Deleted nodes still shown while listings lag
+    ghosts: BTreeMap<i64, MockNode>,
+    /// Slice size handed out for slice uploads
+    slice_size: usize,
+    /// Slice uploads in progress by preupload ID
+    preuploads: HashMap<String, MockPreupload>,
+    /// Slices accepted before every further slice upload fails
+    fail_slices_after: Option<usize>,
+    /// Used and total account space reported by user info
+    space: (u64, u64),
+    /// Upload domains handed out instead of the mock's own URL
+    upload_domains: Option<Vec<String>>,
+    /// Upload completions answered with `completed: false` before finalizing
+    pending_completions: usize,
+    /// Client secrets refused by the token endpoint
+    revoked_secrets: Vec<String>,
+    /// Upcoming downloads whose body is cut off halfway
+    cut_downloads: usize,
+    /// Range header of every download served, `None` for whole files
+    download_ranges: Vec<Option<String>>,
+}
+
+/// A slice upload in progress.
+#[derive(Debug, Clone)]
+struct MockPreupload {
+    parent_id: i64,
+    filename: String,
+    etag: String,
+    slices: BTreeMap<i64, Bytes>,
+}
+
+/// Handle to a running mock server.
+#[derive(Clone)]
+pub struct MockPan123 {
+    pub base_url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockPan123 {
+    /// Start the mock on an ephemeral local port.
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(MockState {
+            next_id: 1000,
+            slice_size: 16 * 1024 * 1024,
+            ..MockState::default()
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let mock = Self { base_url, state };
+        let app = Router::new()
+            .route("/api/v1/access_token", post(access_token))
+            .route("/api/v1/user/info", get(user_info))
+            .route("/api/v2/file/list", get(list))
+            .route("/upload/v1/file/mkdir", post(mkdir))
+            .route("/upload/v2/file/domain", get(upload_domain))
+            .route("/upload/v2/file/single/create", post(single_upload))
+            .route("/upload/v2/file/create", post(create_upload))
+            .route("/upload/v2/file/slice", post(upload_slice))
+            .route("/upload/v2/file/upload_complete", post(upload_complete))
+            .route("/api/v1/file/download_info", get(download_info))
+            .route("/api/v1/file/detail", get(file_detail))
+            .route("/download/:id", get(download))
+            .route("/api/v1/file/trash", post(trash))
+            .route("/api/v1/file/delete", post(delete))
+            .route("/api/v1/file/move", post(move_files))
+            .with_state(mock.clone());
+
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        mock
+    }
+
+    /// Find a live (non-trashed) node by path, e.g. `/repo/keys/abc`.
+    pub fn find(&self, path: &str) -> Option<MockNode> {
+        let state = self.state.lock();
+        let mut parent_id = 0;
+        let mut found = None;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            let node = state
+                .nodes
+                .values()
+                .find(|n| n.parent_id == parent_id && n.name == part && !n.trashed)?;
+            parent_id = node.id;
+            found = Some(node.clone());
+        }
+        found
+    }
+
+    /// Add a file to the directory at `dir` without replacing files of the
+    /// same name, as racing or interrupted uploads can leave behind.
+    pub fn insert_file(&self, dir: &str, name: &str, data: &'static [u8]) -> i64 {
+        let parent_id = self.find(dir).expect("directory exists").id;
+        let mut state = self.state.lock();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.nodes.insert(
+            id,
+            MockNode {
+                id,
+                parent_id,
+                name: name.to_string(),
+                is_dir: false,
+                data: Bytes::from_static(data),
+                trashed: false,
+            },
+        );
+        id
+    }
+
+    /// Delete the node at `path` directly, as another client would.
+    pub fn remove(&self, path: &str) {
+        let id = self.find(path).expect("node exists").id;
+        self.state.lock().nodes.remove(&id);
+    }
+
+    /// Set the used and total account space reported by user info.
+    pub fn set_space(&self, used: u64, total: u64) {
+        self.state.lock().space = (used, total);
+    }
+
+    /// Hand out `domains` as upload domains instead of the mock's own URL.
+    pub fn set_upload_domains(&self, domains: Vec<String>) {
+        self.state.lock().upload_domains = Some(domains);
+    }
+
+    /// Answer the next `n` upload completions with `completed: false`, as
+    /// 123pan does while it verifies a file asynchronously.
+    pub fn set_pending_completions(&self, n: usize) {
+        self.state.lock().pending_completions = n;
+    }
+
+    /// Refuse access tokens for `secret`, as after rotating it on 123pan.
+    pub fn revoke_secret(&self, secret: &str) {
+        self.state.lock().revoked_secrets.push(secret.to_string());
+    }
+
+    /// Cut the body of the next `n` downloads off halfway, as a dropped
+    /// connection does.
+    pub fn cut_downloads(&self, n: usize) {
+        self.state.lock().cut_downloads = n;
+    }
+
+    /// Range headers of the downloads served so far.
+    pub fn download_ranges(&self) -> Vec<Option<String>> {
+        self.state.lock().download_ranges.clone()
+    }
+
+    /// Number of requests served for an API path.
+    pub fn request_count(&self, path: &str) -> usize {
+        self.state.lock().requests.get(path).copied().unwrap_or(0)
+    }
+
+    /// Answer the next `n` API calls with a 429 rate-limit error.
+    pub fn rate_limit_next(&self, n: usize) {
+        self.state.lock().rate_limit_next = n;
+    }
+
+    /// Emulate the listing index lagging behind deletions: while enabled,
+    /// trashed and deleted files are still listed as live.
+    pub fn lag_listings(&self, lag: bool) {
+        let mut state = self.state.lock();
+        state.lag_listings = lag;
+        if !lag {
+            state.ghosts.clear();
+        }
+    }
+
+    /// Slice size handed out for slice uploads.
+    pub fn set_slice_size(&self, size: usize) {
+        self.state.lock().slice_size = size;
+    }
+
+    /// Accept `n` more slices, then fail every slice upload with a server
+    /// error until `None` is set.
+    pub fn fail_slices_after(&self, n: Option<usize>) {
+        self.state.lock().fail_slices_after = n;
+    }
+
+    /// Record a request and return a 429 response if one is pending.
+    fn begin(&self, path: &str) -> Option<Response> {
+        let mut state = self.state.lock();
+        *state.requests.entry(path.to_string()).or_default() += 1;
+        if state.rate_limit_next > 0 {
+            state.rate_limit_next -= 1;
+            return Some(api_error(429, "rate limited"));
+        }
+        None
+    }
+}
+
+fn api_ok(data: Value) -> Response {
+    Json(json!({ "code": 0, "message": "ok", "data": data })).into_response()
+}
+
+fn api_error(code: i32, message: &str) -> Response {
+    Json(json!({ "code": code, "message": message, "data": null })).into_response()
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("Bearer mock-token-"))
+}
+
+fn file_json(node: &MockNode) -> Value {
+    json!({
+        "fileId": node.id,
+        "filename": node.name,
+        "type": if node.is_dir { 1 } else { 0 },
+        "size": node.data.len(),
+        "parentFileId": node.parent_id,
+        "trashed": if node.trashed { 1 } else { 0 },
+        "etag": if node.is_dir { String::new() } else { format!("{:x}", md5::compute(&node.data)) },
+        "updateAt": "2025-01-01 08:00:00",
+    })
+}
+
+async fn user_info(State(mock): State<MockPan123>, headers: HeaderMap) -> Response {
+    if let Some(response) = mock.begin("/api/v1/user/info") {
+        return response;
+    }
+    if !authorized(&headers) {
+        return api_error(401, "unauthorized");
+    }
+    let (used, total) = mock.state.lock().space;
+    api_ok(json!({ "uid": 1, "spaceUsed": used, "spacePermanent": total, "spaceTemp": 0 }))
+}
+
+async fn access_token(State(mock): State<MockPan123>, Json(body): Json<Value>) -> Response {
+    if let Some(response) = mock.begin("/api/v1/access_token") {
+        return response;
+    }
+    let secret = body["clientSecret"].as_str().unwrap_or_default();
+    if mock
+        .state
+        .lock()
+        .revoked_secrets
+        .iter()
+        .any(|s| s == secret)
+    {
+        return api_error(401, "invalid client secret");
+    }
+    let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let token = format!("mock-token-{}", mock.request_count("/api/v1/access_token"));
+    api_ok(json!({ "accessToken": token, "expiredAt": expires_at.to_rfc3339() }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    parent_file_id: i64,
+    last_file_id: Option<i64>,
+}
+
+async fn list(
+    State(mock): State<MockPan123>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    if let Some(response) = mock.begin("/api/v2/file/list") {
+        return response;
+    }
+    if !authorized(&headers) {
+        return api_error(401, "unauthorized");
+    }
+
+    let state = mock.state.lock();
+    let after = query.last_file_id.unwrap_or(i64::MIN);
+    let mut candidates: BTreeMap<i64, MockNode> = state.nodes.clone();
+    if state.lag_listings {
+        candidates.extend(state.ghosts.clone());
+        for node in candidates.values_mut() {
+            node.trashed = false;
+        }
+    }
+    let page: Vec<&MockNode> = candidates
+        .values()
+        .filter(|n| n.parent_id == query.parent_file_id && n.id > after)
+        .take(PAGE_SIZE)
+        .collect();
+    let last_file_id = if page.len() < PAGE_SIZE {
+        -1
+    } else {
+        page.last().map_or(-1, |n| n.id)
+    };
+    api_ok(json!({
+        "lastFileId": last_file_id,
+        "fileList": page.into_iter().map(file_json).collect::<Vec<_>>(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct MkdirRequest {
+    name: String,
+    #[serde(rename = "parentID")]
+    parent_id: i64,
+}
+
+async fn mkdir(State(mock): State<MockPan123>, Json(request): Json<MkdirRequest>) -> Response {
+    if let Some(response) = mock.begin("/upload/v1/file/mkdir") {
+        return response;
+    }
+
+    let mut state = mock.state.lock();
+    if state
+        .nodes
+        .values()
+        .any(|n| n.parent_id == request.parent_id && n.name == request.name && !n.trashed)
+    {
+        return api_error(1, "该目录下已经有同名文件夹,无法进行创建");
+    }
+    state.next_id += 1;
+    let id = state.next_id;
+    state.nodes.insert(
+        id,
+        MockNode {
+            id,
+            parent_id: request.parent_id,
+            name: request.name,
+            is_dir: true,
+            data: Bytes::new(),
+            trashed: false,
+        },
+    );
+    api_ok(json!({ "dirID": id }))
+}
+
+async fn upload_domain(State(mock): State<MockPan123>) -> Response {
+    if let Some(response) = mock.begin("/upload/v2/file/domain") {
+        return response;
+    }
+    let domains = mock.state.lock().upload_domains.clone();
+    api_ok(json!(domains.unwrap_or_else(|| vec![mock.base_url.clone()])))
+}
+
+async fn single_upload(State(mock): State<MockPan123>, mut multipart: Multipart) -> Response {
+    if let Some(response) = mock.begin("/upload/v2/file/single/create") {
+        return response;
+    }
+
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut data = Bytes::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            data = field.bytes().await.unwrap_or_default();
+        } else {
+            fields.insert(name, field.text().await.unwrap_or_default());
+        }
+    }
+
+    let parent_id: i64 = fields["parentFileID"].parse().unwrap();
+    let filename = fields["filename"].clone();
+    if fields["etag"] != format!("{:x}", md5::compute(&data)) {
+        return api_error(1, "etag mismatch");
+    }
+
+    let mut state = mock.state.lock();
+    // duplicate=1 refuses an existing name instead of overwriting
+    if fields.get("duplicate").map(String::as_str) == Some("1")
+        && state
+            .nodes
+            .values()
+            .any(|n| n.parent_id == parent_id && n.name == filename && !n.trashed)
+    {
+        return api_error(1, "该目录下已经有同名文件");
+    }
+    match store_file(&mut state, parent_id, filename, data) {
+        Ok(id) => api_ok(json!({ "fileID": id, "completed": true })),
+        Err(message) => api_error(1, message),
+    }
+}
+
+/// Create a file with duplicate=2 semantics: an existing file of the same
+/// name is overwritten, a folder of the same name is an error.
+fn store_file(
+    state: &mut MockState,
+    parent_id: i64,
+    filename: String,
+    data: Bytes,
+) -> Result<i64, &'static str> {
+    if state
+        .nodes
+        .values()
+        .any(|n| n.parent_id == parent_id && n.name == filename && n.is_dir && !n.trashed)
+    {
+        return Err("该目录下已经有同名文件夹");
+    }
+    let existing: Vec<i64> = state
+        .nodes
+        .values()
+        .filter(|n| n.parent_id == parent_id && n.name == filename && !n.trashed)
+        .map(|n| n.id)
+        .collect();
+    for id in existing {
+        state.nodes.remove(&id);
+    }
+
+    state.next_id += 1;
+    let id = state.next_id;
+    state.nodes.insert(
+        id,
+        MockNode {
+            id,
+            parent_id,
+            name: filename,
+            is_dir: false,
+            data,
+            trashed: false,
+        },
+    );
+    Ok(id)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateUploadRequest {
+    #[serde(rename = "parentFileID")]
+    parent_file_id: i64,
+    filename: String,
+    etag: String,
+}
+
+async fn create_upload(
+    State(mock): State<MockPan123>,
+    headers: HeaderMap,
+    Json(request): Json<CreateUploadRequest>,
+) -> Response {
+    if let Some(response) = mock.begin("/upload/v2/file/create") {
+        return response;
+    }
+    if !authorized(&headers) {
+        return api_error(401, "unauthorized");
+    }
+    let mut state = mock.state.lock();
+    if state.nodes.values().any(|n| {
+        n.parent_id == request.parent_file_id
+            && n.name == request.filename
+            && n.is_dir
+            && !n.trashed
+    }) {
+        return api_error(1, "该目录下已经有同名文件夹");
+    }
+    state.next_id += 1;
+    let preupload_id = format!("preupload-{}", state.next_id);
+    state.preuploads.insert(
+        preupload_id.clone(),
+        MockPreupload {
+            parent_id: request.parent_file_id,
+            filename: request.filename,
+            etag: request.etag,
+            slices: BTreeMap::new(),
+        },
+    );
+    api_ok(json!({
+        "fileID": 0,
+        "preuploadID": preupload_id,
+        "reuse": false,
+        "sliceSize": state.slice_size,
+        "servers": [mock.base_url],
+    }))
+}
+
+async fn upload_slice(State(mock): State<MockPan123>, mut multipart: Multipart) -> Response {
+    if let Some(response) = mock.begin("/upload/v2/file/slice") {
+        return response;
+    }
+
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut data = Bytes::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "slice" {
+            data = field.bytes().await.unwrap_or_default();
+        } else {
+            fields.insert(name, field.text().await.unwrap_or_default());
+        }
+    }
+
+    let mut state = mock.state.lock();
+    match state.fail_slices_after {
+        Some(0) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Some(n) => state.fail_slices_after = Some(n - 1),
+        None => {}
+    }
+    if fields["sliceMD5"] != format!("{:x}", md5::compute(&data)) {
+        return api_error(1, "slice md5 mismatch");
+    }
+    let slice_no: i64 = fields["sliceNo"].parse().unwrap();
+    let Some(preupload) = state.preuploads.get_mut(&fields["preuploadID"]) else {
+        return api_error(1, "preupload not found");
+    };
+    preupload.slices.insert(slice_no, data);
+    api_ok(Value::Null)
+}
+
+#[derive(Deserialize)]
+struct UploadCompleteRequest {
+    #[serde(rename = "preuploadID")]
+    preupload_id: String,
+}
+
+async fn upload_complete(
+    State(mock): State<MockPan123>,
+    Json(request): Json<UploadCompleteRequest>,
+) -> Response {
+    if let Some(response) = mock.begin("/upload/v2/file/upload_complete") {
+        return response;
+    }
+    let mut state = mock.state.lock();
+    if !state.preuploads.contains_key(&request.preupload_id) {
+        return api_error(1, "preupload not found");
+    }
+    if state.pending_completions > 0 {
+        state.pending_completions -= 1;
+        return api_ok(json!({ "completed": false, "fileID": 0 }));
+    }
+    let preupload = state.preuploads.remove(&request.preupload_id).unwrap();
+    let data: Vec<u8> = preupload.slices.values().flatten().copied().collect();
+    if preupload.etag != format!("{:x}", md5::compute(&data)) {
+        return api_error(1, "etag mismatch");
+    }
+    match store_file(
+        &mut state,
+        preupload.parent_id,
+        preupload.filename,
+        Bytes::from(data),
+    ) {
+        Ok(id) => api_ok(json!({ "completed": true, "fileID": id })),
+        Err(message) => api_error(1, message),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadInfoQuery {
+    file_id: i64,
+}
+
+async fn download_info(
+    State(mock): State<MockPan123>,
+    Query(query): Query<DownloadInfoQuery>,
+) -> Response {
+    if let Some(response) = mock.begin("/api/v1/file/download_info") {
+        return response;
+    }
+    if !mock.state.lock().nodes.contains_key(&query.file_id) {
+        return api_error(5066, "file not found");
+    }
+    api_ok(json!({ "downloadUrl": format!("{}/download/{}", mock.base_url, query.file_id) }))
+}
+
+#[derive(Deserialize)]
+struct FileDetailQuery {
+    #[serde(rename = "fileID")]
+    file_id: i64,
+}
+
+async fn file_detail(
+    State(mock): State<MockPan123>,
+    Query(query): Query<FileDetailQuery>,
+) -> Response {
+    if let Some(response) = mock.begin("/api/v1/file/detail") {
+        return response;
+    }
+    let state = mock.state.lock();
+    let Some(node) = state.nodes.get(&query.file_id) else {
+        return api_error(5066, "file not found");
+    };
+    api_ok(json!({
+        "fileID": node.id,
+        "filename": node.name,
+        "type": i32::from(node.is_dir),
+        "size": node.data.len(),
+        "etag": format!("{:x}", md5::compute(&node.data)),
+        "parentFileID": node.parent_id,
+        "trashed": i32::from(node.trashed),
+    }))
+}
+
+async fn download(
+    State(mock): State<MockPan123>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    mock.begin("/download");
+    let Some(data) = mock.state.lock().nodes.get(&id).map(|n| n.data.clone()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let range_header = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let range = range_header
+        .as_deref()
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.split_once('-'))
+        .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?)));
+    let cut = {
+        let mut state = mock.state.lock();
+        state.download_ranges.push(range_header);
+        let cut = state.cut_downloads > 0;
+        state.cut_downloads = state.cut_downloads.saturating_sub(1);
+        cut
+    };
+
+    let mut response = match range {
+        Some((start, end)) if start < data.len() => {
+            let end = end.min(data.len() - 1);
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, data.len()),
+                )],
+                data.slice(start..=end),
+            )
+                .into_response()
+        }
+        _ => data.into_response(),
+    };
+    if cut {
+        // Announce the full length, send half, then drop the connection
+        let body = std::mem::take(response.body_mut());
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let half = body.slice(..body.len() / 2);
+        let stream =
+            futures_util::stream::iter([Ok(half), Err(std::io::Error::other("connection reset"))])
+                .then(|item| async {
+                    // Let the first half go out before the connection drops
+                    if item.is_err() {
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    }
+                    item
+                });
+        *response.body_mut() = Body::from_stream(stream);
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, body.len().into());
+    }
+    response
+}
+
+#[derive(Deserialize)]
+struct FileIdsRequest {
+    #[serde(rename = "fileIDs")]
+    file_ids: Vec<i64>,
+    #[serde(rename = "toParentFileID")]
+    to_parent_file_id: Option<i64>,
+}
+
+async fn trash(State(mock): State<MockPan123>, Json(request): Json<FileIdsRequest>) -> Response {
+    if let Some(response) = mock.begin("/api/v1/file/trash") {
+        return response;
+    }
+    let mut state = mock.state.lock();
+    for id in request.file_ids {
+        if let Some(node) = state.nodes.get_mut(&id) {
+            node.trashed = true;
+        }
+    }
+    api_ok(Value::Null)
+}
+
+async fn delete(State(mock): State<MockPan123>, Json(request): Json<FileIdsRequest>) -> Response {
+    if let Some(response) = mock.begin("/api/v1/file/delete") {
+        return response;
+    }
+    let mut state = mock.state.lock();
+    for id in request.file_ids {
+        if state.nodes.get(&id).is_some_and(|n| !n.trashed) {
+            return api_error(1, "file must be trashed before deletion");
+        }
+        if let Some(node) = state.nodes.remove(&id) {
+            if state.lag_listings {
+                state.ghosts.insert(id, node);
+            }
+        }
+    }
+    api_ok(Value::Null)
+}
+
+async fn move_files(
+    State(mock): State<MockPan123>,
+    Json(request): Json<FileIdsRequest>,
+) -> Response {
+    if let Some(response) = mock.begin("/api/v1/file/move") {
+        return response;
+    }
+    let Some(to_parent) = request.to_parent_file_id else {
+        return api_error(1, "missing toParentFileID");
+    };
+    let mut state = mock.state.lock();
+    for id in request.file_ids {
+        if let Some(node) = state.nodes.get_mut(&id) {
+            node.parent_id = to_parent;
+        }
+    }
+    api_ok(Value::Null)
+}
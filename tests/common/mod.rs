@@ -0,0 +1,27 @@
+//! Shared helpers for offline tests.
+
+pub mod mock_pan123;
+
+use restic_123pan::pan123::Pan123Client;
+use tempfile::TempDir;
+
+pub use mock_pan123::MockPan123;
+
+/// Create a client pointed at `mock`, backed by a fresh SQLite database.
+///
+/// The returned `TempDir` holds the database and must outlive the client.
+#[allow(dead_code)]
+pub async fn mock_client(mock: &MockPan123, repo_path: &str) -> (Pan123Client, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let client = Pan123Client::new(
+        "mock-id".to_string(),
+        "mock-secret".to_string(),
+        repo_path.to_string(),
+        &db_url,
+    )
+    .await
+    .unwrap()
+    .with_base_url(&mock.base_url);
+    (client, dir)
+}
@@ -17,7 +17,7 @@ use std::env;
 use std::fs;
 use std::io::{BufRead, BufReader, Write};
 use std::net::TcpListener;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Output, Stdio};
 use std::thread;
 use std::time::Duration;
@@ -63,19 +63,15 @@ fn start_server(client_id: &str, client_secret: &str, port: u16, repo_path: &str
 
     // Forward stdout
     thread::spawn(move || {
-        for line in stdout_reader.lines() {
-            if let Ok(line) = line {
-                println!("[SERVER] {}", line);
-            }
+        for line in stdout_reader.lines().map_while(Result::ok) {
+            println!("[SERVER] {}", line);
         }
     });
 
     // Forward stderr
     thread::spawn(move || {
-        for line in stderr_reader.lines() {
-            if let Ok(line) = line {
-                eprintln!("[SERVER] {}", line);
-            }
+        for line in stderr_reader.lines().map_while(Result::ok) {
+            eprintln!("[SERVER] {}", line);
         }
     });
 
@@ -115,11 +111,9 @@ fn run_restic_with_output(
         let stdout_handle = thread::spawn(move || {
             let reader = BufReader::new(stdout);
             let mut collected = Vec::new();
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    println!("[RESTIC] {}", line);
-                    collected.push(line);
-                }
+            for line in reader.lines().map_while(Result::ok) {
+                println!("[RESTIC] {}", line);
+                collected.push(line);
             }
             collected
         });
@@ -128,11 +122,9 @@ fn run_restic_with_output(
         let stderr_handle = thread::spawn(move || {
             let reader = BufReader::new(stderr);
             let mut collected = Vec::new();
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    eprintln!("[RESTIC] {}", line);
-                    collected.push(line);
-                }
+            for line in reader.lines().map_while(Result::ok) {
+                eprintln!("[RESTIC] {}", line);
+                collected.push(line);
             }
             collected
         });
@@ -189,6 +181,12 @@ fn run_restic_with_output(
     }
 }
 
+/// Kill the server and reap the child process.
+fn stop_server(server: &mut Child) {
+    server.kill().ok();
+    server.wait().ok();
+}
+
 /// Wait for the server to be ready.
 fn wait_for_server(port: u16, timeout: Duration) -> bool {
     let start = std::time::Instant::now();
@@ -207,7 +205,7 @@ fn wait_for_server(port: u16, timeout: Duration) -> bool {
 }
 
 /// Create test files in a directory.
-fn create_test_files(dir: &PathBuf) {
+fn create_test_files(dir: &Path) {
     // Create some test files
     let mut file1 = fs::File::create(dir.join("test1.txt")).expect("Failed to create file");
     writeln!(file1, "This is test file 1").expect("Failed to write");
@@ -306,7 +304,7 @@ fn test_e2e_backup_and_restore() {
 
     // Wait for server to be ready
     if !wait_for_server(port, Duration::from_secs(10)) {
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Server failed to start within 10 seconds");
     }
 
@@ -326,7 +324,7 @@ fn test_e2e_backup_and_restore() {
     if !init_output.status.success() {
         let stderr = String::from_utf8_lossy(&init_output.stderr);
         let stdout = String::from_utf8_lossy(&init_output.stdout);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!(
             "restic init failed:\nstdout: {}\nstderr: {}",
             stdout, stderr
@@ -346,7 +344,7 @@ fn test_e2e_backup_and_restore() {
     if !backup_output.status.success() {
         let stderr = String::from_utf8_lossy(&backup_output.stderr);
         let stdout = String::from_utf8_lossy(&backup_output.stdout);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!(
             "restic backup failed:\nstdout: {}\nstderr: {}",
             stdout, stderr
@@ -368,7 +366,7 @@ fn test_e2e_backup_and_restore() {
 
     if !snapshots_output.status.success() {
         let stderr = String::from_utf8_lossy(&snapshots_output.stderr);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("restic snapshots failed: {}", stderr);
     }
 
@@ -395,7 +393,7 @@ fn test_e2e_backup_and_restore() {
     if !restore_output.status.success() {
         let stderr = String::from_utf8_lossy(&restore_output.stderr);
         let stdout = String::from_utf8_lossy(&restore_output.stdout);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!(
             "restic restore failed:\nstdout: {}\nstderr: {}",
             stdout, stderr
@@ -408,7 +406,7 @@ fn test_e2e_backup_and_restore() {
     );
 
     // Stop server
-    server.kill().ok();
+    stop_server(&mut server);
 
     // Find the restored source directory (restic restores with full path)
     let restored_source = walkdir::WalkDir::new(&restore_dir)
@@ -464,13 +462,13 @@ fn test_server_startup() {
 
     // Wait for server to be ready
     if !wait_for_server(port, Duration::from_secs(10)) {
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Server failed to start");
     }
 
     // Test HEAD on config (should return 404 for non-existent repo)
     let response = reqwest::blocking::Client::new()
-        .head(&format!("http://127.0.0.1:{}/config", port))
+        .head(format!("http://127.0.0.1:{}/config", port))
         .send()
         .expect("Failed to send request");
 
@@ -483,7 +481,7 @@ fn test_server_startup() {
         response.status()
     );
 
-    server.kill().ok();
+    stop_server(&mut server);
     println!("Server startup test passed");
 }
 
@@ -494,7 +492,7 @@ fn test_server_startup() {
 /// Create test files of a specific total size using truly random, incompressible data.
 /// Generates a mix of small, medium, and large files to simulate real workloads.
 /// Uses /dev/urandom for fast random data generation.
-fn create_large_test_files(dir: &PathBuf, total_size_mb: usize) {
+fn create_large_test_files(dir: &Path, total_size_mb: usize) {
     use std::io::{BufWriter, Read};
 
     // Open /dev/urandom for fast random data reading
@@ -670,7 +668,7 @@ fn test_e2e_large_scale_100mb() {
 
     // Wait for server to be ready
     if !wait_for_server(port, Duration::from_secs(15)) {
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Server failed to start within 15 seconds");
     }
 
@@ -687,7 +685,7 @@ fn test_e2e_large_scale_100mb() {
     if !init_success {
         let stderr = String::from_utf8_lossy(&init_output.stderr);
         let stdout = String::from_utf8_lossy(&init_output.stdout);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!(
             "restic init failed:\nstdout: {}\nstderr: {}",
             stdout, stderr
@@ -707,7 +705,7 @@ fn test_e2e_large_scale_100mb() {
     if !backup_success {
         let stderr = String::from_utf8_lossy(&backup_output.stderr);
         let stdout = String::from_utf8_lossy(&backup_output.stdout);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!(
             "restic backup failed:\nstdout: {}\nstderr: {}",
             stdout, stderr
@@ -754,7 +752,7 @@ fn test_e2e_large_scale_100mb() {
     if !restore_success {
         let stderr = String::from_utf8_lossy(&restore_output.stderr);
         let stdout = String::from_utf8_lossy(&restore_output.stdout);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!(
             "restic restore failed:\nstdout: {}\nstderr: {}",
             stdout, stderr
@@ -765,7 +763,7 @@ fn test_e2e_large_scale_100mb() {
     println!("\nRestore completed in {:?}", restore_time);
 
     // Stop server
-    server.kill().ok();
+    stop_server(&mut server);
 
     // Find the restored source directory
     let restored_source = walkdir::WalkDir::new(&restore_dir)
@@ -839,7 +837,7 @@ fn test_e2e_incremental_backup() {
     let mut server = start_server(&client_id, &client_secret, port, &repo_path);
 
     if !wait_for_server(port, Duration::from_secs(15)) {
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Server failed to start");
     }
 
@@ -856,7 +854,7 @@ fn test_e2e_incremental_backup() {
 
     if !init_output.status.success() {
         let stderr = String::from_utf8_lossy(&init_output.stderr);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("restic init failed: {}", stderr);
     }
 
@@ -874,7 +872,7 @@ fn test_e2e_incremental_backup() {
 
     if !backup1.status.success() {
         let stderr = String::from_utf8_lossy(&backup1.stderr);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("First backup failed: {}", stderr);
     }
     println!("First backup took {:?}", start_backup1.elapsed());
@@ -908,7 +906,7 @@ fn test_e2e_incremental_backup() {
 
     if !backup2.status.success() {
         let stderr = String::from_utf8_lossy(&backup2.stderr);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Second backup failed: {}", stderr);
     }
     println!(
@@ -948,7 +946,7 @@ fn test_e2e_incremental_backup() {
 
     if !backup3.status.success() {
         let stderr = String::from_utf8_lossy(&backup3.stderr);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Third backup failed: {}", stderr);
     }
     println!(
@@ -984,11 +982,11 @@ fn test_e2e_incremental_backup() {
 
     if !restore_output.status.success() {
         let stderr = String::from_utf8_lossy(&restore_output.stderr);
-        server.kill().ok();
+        stop_server(&mut server);
         panic!("Restore failed: {}", stderr);
     }
 
-    server.kill().ok();
+    stop_server(&mut server);
 
     // Verify
     let restored_source = walkdir::WalkDir::new(&restore_dir)
@@ -0,0 +1,1855 @@
+//! Offline tests against the in-process mock 123pan server.
+//!
+//! Unlike `integration_test.rs`, these need no credentials or network access
+//! and always run.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+};
+use bytes::Bytes;
+use common::{mock_client, MockPan123};
+use restic_123pan::error::AppError;
+use restic_123pan::pan123::gc::GarbageReason;
+use restic_123pan::pan123::metrics::CacheStats;
+use restic_123pan::pan123::preflight::PreflightError;
+use restic_123pan::pan123::server_lock::ServerLock;
+use restic_123pan::pan123::{DirectBackend, Normalization, Pan123Client};
+use restic_123pan::restic::{create_router, RepoLayout, ResticFileType, ServerOptions};
+use restic_123pan::storage::{
+    check_copy, copy_repository, CopyOptions, LocalBackend, StorageBackend,
+};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tower::ServiceExt;
+
+const REPO: &str = "/mock-repo";
+
+fn object_name(seed: u8) -> String {
+    format!("{:02x}", seed).repeat(32)
+}
+
+async fn body_bytes(response: axum::response::Response) -> Bytes {
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_mock_upload_download_delete() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+
+    client.init_repository().await.unwrap();
+    assert!(mock.find("/mock-repo/keys").is_some());
+
+    let name = object_name(0xab);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    let data = Bytes::from_static(b"0123456789abcdef");
+    let file_id = client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .unwrap();
+
+    let stored = mock.find(&format!("/mock-repo/data/ab/{}", name)).unwrap();
+    assert_eq!(stored.id, file_id);
+    assert_eq!(stored.data, data);
+
+    let files = client.list_files(dir_id).await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].filename, name);
+    assert_eq!(files[0].size, 16);
+
+    // Repeated lookups are answered from the in-memory cache
+    let hits = client.lookup_cache_stats().hits;
+    assert!(client.get_file_info(dir_id, &name).await.unwrap().is_some());
+    assert!(client.lookup_cache_stats().hits > hits);
+
+    assert_eq!(client.download_file(file_id, None).await.unwrap(), data);
+    assert_eq!(
+        client.download_file(file_id, Some((4, 7))).await.unwrap(),
+        Bytes::from_static(b"4567")
+    );
+
+    client.delete_file(dir_id, file_id).await.unwrap();
+    assert!(mock.find(&format!("/mock-repo/data/ab/{}", name)).is_none());
+    assert!(client.get_file_info(dir_id, &name).await.unwrap().is_none());
+    assert!(matches!(
+        client.download_file(file_id, None).await,
+        Err(AppError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_mock_retries_rate_limited_calls() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+
+    mock.rate_limit_next(1);
+    client.init_repository().await.unwrap();
+
+    assert!(mock.find("/mock-repo/index").is_some());
+
+    // A retried upload sends the whole body again
+    let name = object_name(0x12);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    let uploads = mock.request_count("/upload/v2/file/single/create");
+    let data = Bytes::from_static(b"retried upload body");
+    mock.rate_limit_next(1);
+    client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        mock.request_count("/upload/v2/file/single/create"),
+        uploads + 2
+    );
+    assert_eq!(
+        mock.find(&format!("/mock-repo/data/12/{}", name))
+            .unwrap()
+            .data,
+        data
+    );
+}
+
+#[tokio::test]
+async fn test_mock_api_metrics() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+
+    mock.rate_limit_next(1);
+    client.init_repository().await.unwrap();
+    let name = object_name(0x42);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    // init_repository uploads the layout marker
+    let uploaded = client.api_metrics().snapshot().bytes_uploaded;
+    let data = Bytes::from_static(b"0123456789abcdef");
+    let file_id = client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .unwrap();
+    client.download_file(file_id, None).await.unwrap();
+
+    let snapshot = client.api_metrics().snapshot();
+    assert_eq!(snapshot.bytes_uploaded, uploaded + 16);
+    assert_eq!(snapshot.bytes_downloaded, 16);
+    let total = |field: fn(&restic_123pan::pan123::metrics::EndpointStats) -> u64| {
+        snapshot.endpoints.values().map(field).sum::<u64>()
+    };
+    assert_eq!(total(|s| s.rate_limited), 1);
+    assert_eq!(total(|s| s.retries), 1);
+    assert!(total(|s| s.calls) > 2);
+    let create = &snapshot.endpoints["/upload/v1/file/mkdir"];
+    assert!(create.calls > 0);
+    assert!(create.errors.is_empty());
+
+    let text = client.api_metrics().render_prometheus();
+    assert!(text.contains("pan123_api_calls_total{endpoint=\"/upload/v1/file/mkdir\"}"));
+    assert!(text.contains("pan123_downloaded_bytes_total 16"));
+}
+
+#[tokio::test]
+async fn test_mock_warm_cache_from_remote() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x11);
+    {
+        let (client, _dir) = mock_client(&mock, REPO).await;
+        client.init_repository().await.unwrap();
+        let dir_id = client
+            .get_type_dir_id(restic_123pan::restic::ResticFileType::Snapshots)
+            .await
+            .unwrap();
+        client
+            .upload_file(dir_id, &name, Bytes::from_static(b"snapshot"))
+            .await
+            .unwrap();
+    }
+
+    // A fresh database learns the tree from upstream listings
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+    assert!(client.is_cache_ready());
+
+    let lists_before = mock.request_count("/api/v2/file/list");
+    let dir_id = client
+        .find_path_id("/mock-repo/snapshots")
+        .await
+        .unwrap()
+        .unwrap();
+    let file = client.get_file_info(dir_id, &name).await.unwrap().unwrap();
+    assert_eq!(file.size, 8);
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists_before);
+}
+
+#[tokio::test]
+async fn test_mock_lazy_shard_loading() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x33);
+    {
+        let (client, _dir) = mock_client(&mock, REPO).await;
+        client.init_repository().await.unwrap();
+        let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+        client
+            .upload_file(dir_id, &name, Bytes::from_static(b"pack"))
+            .await
+            .unwrap();
+    }
+
+    // Warm-up stops at the type directories
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+    let lists = mock.request_count("/api/v2/file/list");
+    let shard_id = client
+        .find_path_id("/mock-repo/data/33")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists);
+
+    // The shard is listed on first use only
+    let file = client
+        .get_file_info(shard_id, &name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(file.size, 4);
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists + 1);
+    assert_eq!(client.list_all_data_files().await.unwrap().len(), 1);
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists + 1);
+
+    // Everything is loaded now, so a full crawl is answered from the cache
+    client.crawl_cache().await.unwrap();
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists + 1);
+}
+
+#[tokio::test]
+async fn test_mock_cache_metrics() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x34);
+    {
+        let (client, _dir) = mock_client(&mock, REPO).await;
+        client.init_repository().await.unwrap();
+        let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+        client
+            .upload_file(dir_id, &name, Bytes::from_static(b"pack"))
+            .await
+            .unwrap();
+    }
+
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+    let path = format!("data/34/{}", name);
+    // The shard is listed on the first lookup only
+    assert!(client.head(&path).await.unwrap().is_some());
+    assert!(client.head(&path).await.unwrap().is_some());
+    assert!(client.list("keys").await.unwrap().is_empty());
+
+    let lookups = client.cache_metrics().stats("lookup");
+    assert_eq!(lookups["data"], CacheStats { hits: 1, misses: 1 });
+    let listings = client.cache_metrics().stats("listing");
+    assert_eq!(listings["keys"], CacheStats { hits: 1, misses: 0 });
+    assert!(client
+        .metrics()
+        .contains("pan123_cache_requests_total{cache=\"lookup\",type=\"data\",result=\"miss\"} 1"));
+}
+
+#[tokio::test]
+async fn test_mock_background_cache_crawl() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x44);
+    {
+        let (client, _dir) = mock_client(&mock, REPO).await;
+        client.init_repository().await.unwrap();
+        let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+        client
+            .upload_file(dir_id, &name, Bytes::from_static(b"pack"))
+            .await
+            .unwrap();
+    }
+
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+    client.spawn_cache_crawl().await.unwrap();
+
+    // Shards crawled in the background are served without listing
+    let lists = mock.request_count("/api/v2/file/list");
+    let shard_id = client
+        .find_path_id("/mock-repo/data/44")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(client
+        .get_file_info(shard_id, &name)
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists);
+}
+
+#[tokio::test]
+async fn test_mock_full_crawl_saves_listings_in_batches() {
+    let mock = MockPan123::start().await;
+    {
+        let (client, _dir) = mock_client(&mock, REPO).await;
+        client.init_repository().await.unwrap();
+        for seed in 0..40 {
+            let name = object_name(seed);
+            let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+            client
+                .upload_file(dir_id, &name, Bytes::from_static(b"pack"))
+                .await
+                .unwrap();
+        }
+    }
+
+    // More shards than fit in one transaction, all cached by the crawl
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache_fully(false).await.unwrap();
+    let lists = mock.request_count("/api/v2/file/list");
+    assert_eq!(client.list_all_data_files().await.unwrap().len(), 40);
+    for seed in [0, 39] {
+        let shard_id = client
+            .find_path_id(&format!("/mock-repo/data/{:02x}", seed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(client
+            .get_file_info(shard_id, &object_name(seed))
+            .await
+            .unwrap()
+            .is_some());
+    }
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists);
+}
+
+#[tokio::test]
+async fn test_mock_directory_ttl_revalidation() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x55);
+    let (writer, _writer_dir) = mock_client(&mock, REPO).await;
+    writer.init_repository().await.unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let expiring = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .directory_ttl(std::time::Duration::ZERO)
+        .build()
+        .await
+        .unwrap();
+    let (stale, _stale_dir) = mock_client(&mock, REPO).await;
+    for client in [&expiring, &stale] {
+        client.warm_cache(false).await.unwrap();
+    }
+
+    // Another server writes a key behind their backs
+    let keys_id = writer
+        .get_type_dir_id(restic_123pan::restic::ResticFileType::Keys)
+        .await
+        .unwrap();
+    writer
+        .upload_file(keys_id, &name, Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+
+    // Expired listings are fetched again on access
+    assert!(expiring
+        .get_file_info(keys_id, &name)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(stale.get_file_info(keys_id, &name).await.unwrap().is_none());
+
+    // ... or by a background revalidation pass
+    assert!(
+        stale
+            .revalidate_expired(std::time::Duration::ZERO)
+            .await
+            .unwrap()
+            > 0
+    );
+    assert!(stale.get_file_info(keys_id, &name).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_mock_tombstones_hide_lagging_deletes() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x66);
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let dir_id = client
+        .get_type_dir_id(restic_123pan::restic::ResticFileType::Locks)
+        .await
+        .unwrap();
+    let file_id = client
+        .upload_file(dir_id, &name, Bytes::from_static(b"lock"))
+        .await
+        .unwrap();
+
+    // Listings fetched right after the delete still show the file
+    mock.lag_listings(true);
+    client.delete_file(dir_id, file_id).await.unwrap();
+    client
+        .revalidate_expired(std::time::Duration::ZERO)
+        .await
+        .unwrap();
+    assert!(client.get_file_info(dir_id, &name).await.unwrap().is_none());
+    assert!(client.list_files(dir_id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_mock_reconcile_external_changes() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x77);
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    let (other, _other_dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+    let locks = client.get_type_dir_id(ResticFileType::Locks).await.unwrap();
+    let snapshots = client
+        .get_type_dir_id(ResticFileType::Snapshots)
+        .await
+        .unwrap();
+    client.list_files(locks).await.unwrap();
+
+    // Another client creates a shard directory and a file next to it; mkdir
+    // reports the conflict and the whole directory is listed again
+    let shard = other.get_data_file_dir_id(&name).await.unwrap();
+    let data = other
+        .find_path_id("/mock-repo/data")
+        .await
+        .unwrap()
+        .unwrap();
+    let external = object_name(0x78);
+    other
+        .upload_file(data, &external, Bytes::from_static(b"x"))
+        .await
+        .unwrap();
+    assert_eq!(client.get_data_file_dir_id(&name).await.unwrap(), shard);
+    assert!(client.find_file(data, &external).await.unwrap().is_some());
+
+    // An upload clashing with a folder created elsewhere re-lists the parent
+    other
+        .ensure_path(&format!("/mock-repo/locks/{}", name))
+        .await
+        .unwrap();
+    let err = client
+        .upload_file(locks, &name, Bytes::from_static(b"lock"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AppError::Pan123Api { code: 1, .. }));
+    assert!(client
+        .find_file(locks, &name)
+        .await
+        .unwrap()
+        .unwrap()
+        .is_folder());
+
+    // A file moved elsewhere is picked up under its new parent
+    let moved = object_name(0x79);
+    let file_id = client
+        .upload_file(snapshots, &moved, Bytes::from_static(b"snap"))
+        .await
+        .unwrap();
+    other.move_files(vec![file_id], locks).await.unwrap();
+    client.reconcile_dir(locks).await.unwrap();
+    assert_eq!(
+        client
+            .find_file(locks, &moved)
+            .await
+            .unwrap()
+            .unwrap()
+            .file_id,
+        file_id
+    );
+    assert!(client.find_file(snapshots, &moved).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_mock_mkdir_conflict_refreshes_large_directory() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    let (other, _other_dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+    let locks = client.get_type_dir_id(ResticFileType::Locks).await.unwrap();
+    client.list_files(locks).await.unwrap();
+
+    // Entries created elsewhere, more than one insert batch's worth
+    let other_locks = other.get_type_dir_id(ResticFileType::Locks).await.unwrap();
+    for seed in 0..120 {
+        other
+            .upload_file(other_locks, &object_name(seed), Bytes::from_static(b"x"))
+            .await
+            .unwrap();
+    }
+    other.ensure_path("/mock-repo/locks/sub").await.unwrap();
+
+    // mkdir reports the conflict and the listing is saved in one batch
+    let sub = client.ensure_path("/mock-repo/locks/sub").await.unwrap();
+    assert!(client
+        .find_file(locks, "sub")
+        .await
+        .unwrap()
+        .is_some_and(|dir| dir.file_id == sub && dir.is_folder()));
+    let lists = mock.request_count("/api/v2/file/list");
+    assert_eq!(client.list_files(locks).await.unwrap().len(), 121);
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists);
+}
+
+#[tokio::test]
+async fn test_mock_server_lock() {
+    let mock = MockPan123::start().await;
+    let (first, _first_dir) = mock_client(&mock, REPO).await;
+    let (second, _second_dir) = mock_client(&mock, REPO).await;
+    let ttl = std::time::Duration::from_secs(60);
+    let first = ServerLock::new(first, ttl);
+    let second = ServerLock::new(second, ttl);
+
+    assert!(first.acquire().await.unwrap().is_none());
+    assert!(mock.find("/mock-repo/.server-lock").is_some());
+    // Renewing our own lease succeeds
+    assert!(first.acquire().await.unwrap().is_none());
+
+    let holder = second.acquire().await.unwrap().unwrap();
+    assert_eq!(holder.instance, first.instance());
+    assert!(second.read_only_flag().load(Ordering::Relaxed));
+
+    // Releasing by the instance not holding the lease is a no-op
+    second.release().await.unwrap();
+    assert!(mock.find("/mock-repo/.server-lock").is_some());
+
+    first.release().await.unwrap();
+    assert!(second.acquire().await.unwrap().is_none());
+    assert!(!second.read_only_flag().load(Ordering::Relaxed));
+    assert!(first.acquire().await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_mock_server_lock_expires() {
+    let mock = MockPan123::start().await;
+    let (first, _first_dir) = mock_client(&mock, REPO).await;
+    let (second, _second_dir) = mock_client(&mock, REPO).await;
+    let first = ServerLock::new(first, std::time::Duration::ZERO);
+    let second = ServerLock::new(second, std::time::Duration::from_secs(60));
+
+    assert!(first.acquire().await.unwrap().is_none());
+    // A lease that was not renewed in time is taken over
+    assert!(second.acquire().await.unwrap().is_none());
+    assert_eq!(
+        first.acquire().await.unwrap().unwrap().instance,
+        second.instance()
+    );
+}
+
+#[tokio::test]
+async fn test_mock_read_only_rejects_writes() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+    let read_only = Arc::new(AtomicBool::new(true));
+    let app = create_router(
+        Arc::new(client),
+        ServerOptions {
+            read_only: read_only.clone(),
+            ..ServerOptions::default()
+        },
+    );
+
+    let post = || {
+        Request::post("/keys/abcdef")
+            .body(Body::from("key"))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(post()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app
+        .clone()
+        .oneshot(Request::get("/keys/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    read_only.store(false, Ordering::Relaxed);
+    let response = app.oneshot(post()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_mock_cache_backup_and_restore() {
+    let mock = MockPan123::start().await;
+    let name = object_name(0x22);
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    client
+        .upload_file(dir_id, &name, Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+
+    let snapshot = client.backup_cache().await.unwrap();
+    assert!(mock
+        .find(&format!("/mock-repo/.cache-backup/{}", snapshot))
+        .is_some());
+
+    // A new host seeds its empty cache from the snapshot instead of crawling
+    let (restored, _dir) = mock_client(&mock, REPO).await;
+    assert_eq!(restored.restore_cache().await.unwrap(), Some(snapshot));
+    let lists_before = mock.request_count("/api/v2/file/list");
+    let dir_id = restored
+        .find_path_id("/mock-repo/data/22")
+        .await
+        .unwrap()
+        .unwrap();
+    let file = restored
+        .get_file_info(dir_id, &name)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(file.size, 4);
+    assert_eq!(mock.request_count("/api/v2/file/list"), lists_before);
+
+    // Without snapshots there is nothing to restore
+    let other = MockPan123::start().await;
+    let (empty, _dir) = mock_client(&other, REPO).await;
+    assert_eq!(empty.restore_cache().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_mock_rest_api_roundtrip() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    let app = create_router(Arc::new(client.clone()), ServerOptions::default());
+
+    // Paths not cached yet are deferred until warm-up completes
+    let response = app
+        .clone()
+        .oneshot(Request::get("/keys/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    client.warm_cache(false).await.unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(Request::post("/?create=true").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let name = object_name(0x42);
+    let uri = format!("/keys/{}", name);
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post(&uri)
+                .body(Body::from("key material"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(mock.find(&format!("/mock-repo/keys/{}", name)).is_some());
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/keys/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let listing: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(listing[0]["name"], name);
+    assert_eq!(listing[0]["size"], 12);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get(&uri)
+                .header(header::RANGE, "bytes=4-11")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body_bytes(response).await, Bytes::from_static(b"material"));
+
+    let response = app
+        .clone()
+        .oneshot(Request::delete(&uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(mock.find(&format!("/mock-repo/keys/{}", name)).is_none());
+
+    let response = app
+        .oneshot(Request::head(&uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_mock_builder_page_size_and_retries() {
+    let mock = MockPan123::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let client = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .page_size(2)
+        .max_retries(0)
+        .data_shard_len(0)
+        .build()
+        .await
+        .unwrap();
+
+    client.init_repository().await.unwrap();
+    let dir_id = client.get_data_file_dir_id(&object_name(0)).await.unwrap();
+    for seed in 0..5 {
+        client
+            .upload_file(dir_id, &object_name(seed), Bytes::from_static(b"x"))
+            .await
+            .unwrap();
+    }
+    assert!(mock
+        .find(&format!("/mock-repo/data/{}", object_name(0)))
+        .is_some());
+
+    // A rebuild pages through the listing two entries at a time
+    let lists_before = mock.request_count("/api/v2/file/list");
+    client.warm_cache(true).await.unwrap();
+    assert!(mock.request_count("/api/v2/file/list") - lists_before >= 3);
+    assert_eq!(client.list_all_data_files().await.unwrap().len(), 5);
+
+    // Without retries a rate-limited call fails immediately
+    let file_id = mock
+        .find(&format!("/mock-repo/data/{}", object_name(1)))
+        .unwrap()
+        .id;
+    mock.rate_limit_next(1);
+    assert!(matches!(
+        client.get_download_url(file_id).await,
+        Err(AppError::Pan123Api { code: 429, .. })
+    ));
+    assert!(client.get_download_url(file_id).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_mock_progress_logged_transfers() {
+    let mock = MockPan123::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let client = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .parallel_download(64 * 1024, 4)
+        .progress_logging(1024, std::time::Duration::ZERO)
+        .build()
+        .await
+        .unwrap();
+
+    // Tracked bodies are streamed in chunks and arrive intact
+    client.init_repository().await.unwrap();
+    let name = object_name(0x51);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    let data: Bytes = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    let file_id = client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        mock.find(&format!("/mock-repo/data/51/{}", name))
+            .unwrap()
+            .data,
+        data
+    );
+    assert_eq!(client.download_file(file_id, None).await.unwrap(), data);
+    assert_eq!(
+        client
+            .download_file(file_id, Some((1000, 200_000)))
+            .await
+            .unwrap(),
+        data.slice(1000..=200_000)
+    );
+}
+
+#[tokio::test]
+async fn test_mock_in_memory_database() {
+    let mock = MockPan123::start().await;
+    let client = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url("sqlite::memory:")
+        .base_url(&mock.base_url)
+        .build()
+        .await
+        .unwrap();
+
+    client.init_repository().await.unwrap();
+    let name = object_name(0x33);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    client
+        .upload_file(dir_id, &name, Bytes::from_static(b"memory"))
+        .await
+        .unwrap();
+    client.warm_cache(true).await.unwrap();
+
+    let file = client.get_file_info(dir_id, &name).await.unwrap().unwrap();
+    assert_eq!(file.size, 6);
+}
+
+#[tokio::test]
+async fn test_mock_sliced_upload_resumes() {
+    let mock = MockPan123::start().await;
+    mock.set_slice_size(1000);
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let builder = || {
+        Pan123Client::builder("mock-id", "mock-secret")
+            .repo_path(REPO)
+            .database_url(&db_url)
+            .base_url(&mock.base_url)
+            .max_retries(0)
+            .multipart_threshold(1024)
+    };
+
+    let client = builder().build().await.unwrap();
+    client.init_repository().await.unwrap();
+    let name = object_name(0x42);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    let data: Bytes = (0..4500u32).map(|i| (i % 251) as u8).collect();
+
+    // The connection drops after two of the five slices
+    mock.fail_slices_after(Some(2));
+    assert!(client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .is_err());
+    assert_eq!(mock.request_count("/upload/v2/file/slice"), 3);
+    drop(client);
+
+    // After a restart only the missing slices are sent
+    mock.fail_slices_after(None);
+    let client = builder().build().await.unwrap();
+    client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .unwrap();
+    assert_eq!(mock.request_count("/upload/v2/file/create"), 1);
+    assert_eq!(mock.request_count("/upload/v2/file/slice"), 6);
+
+    let node = mock
+        .find(&format!("/mock-repo/data/{}/{}", &name[..2], name))
+        .unwrap();
+    assert_eq!(node.data, data);
+    let file = client.get_file_info(dir_id, &name).await.unwrap().unwrap();
+    assert_eq!(file.size, 4500);
+
+    // Small files still go up in a single request
+    let small = object_name(0x43);
+    let small_dir = client.get_data_file_dir_id(&small).await.unwrap();
+    client
+        .upload_file(small_dir, &small, Bytes::from_static(b"small"))
+        .await
+        .unwrap();
+    assert_eq!(mock.request_count("/upload/v2/file/create"), 1);
+}
+
+#[tokio::test]
+async fn test_mock_repository_keeps_its_data_layout() {
+    let mock = MockPan123::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let creator = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .data_shard_depth(2)
+        .build()
+        .await
+        .unwrap();
+    creator.init_repository().await.unwrap();
+    assert!(mock.find("/mock-repo/.layout").is_some());
+
+    let name = object_name(0x7a);
+    let dir_id = creator.get_data_file_dir_id(&name).await.unwrap();
+    creator
+        .upload_file(dir_id, &name, Bytes::from_static(b"deep"))
+        .await
+        .unwrap();
+    assert!(mock
+        .find(&format!(
+            "/mock-repo/data/{}/{}/{}",
+            &name[..2],
+            &name[2..4],
+            name
+        ))
+        .is_some());
+
+    // A server configured with the default layout adopts the recorded one
+    let (mut client, _client_dir) = mock_client(&mock, REPO).await;
+    assert_eq!(client.layout().data_shard_depth, 1);
+    let recorded = client.load_layout().await.unwrap().unwrap();
+    assert_eq!(recorded.data_shard_depth, 2);
+    assert_eq!(client.layout(), recorded);
+    assert_eq!(client.get_data_file_dir_id(&name).await.unwrap(), dir_id);
+
+    let files = client.list_all_data_files().await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].filename, name);
+}
+
+#[tokio::test]
+async fn test_mock_migrate_flat_data_into_shards() {
+    let mock = MockPan123::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let flat = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .data_shard_len(0)
+        .build()
+        .await
+        .unwrap();
+    flat.init_repository().await.unwrap();
+    let names: Vec<String> = [0x11, 0x12, 0x9a].into_iter().map(object_name).collect();
+    for name in &names {
+        let dir_id = flat.get_data_file_dir_id(name).await.unwrap();
+        flat.upload_file(dir_id, name, Bytes::from_static(b"pack"))
+            .await
+            .unwrap();
+        assert!(mock.find(&format!("/mock-repo/data/{}", name)).is_some());
+    }
+
+    let (client, _client_dir) = mock_client(&mock, REPO).await;
+    assert_eq!(client.migrate_data_structure().await.unwrap(), 3);
+    for name in &names {
+        assert!(mock.find(&format!("/mock-repo/data/{}", name)).is_none());
+        assert!(mock
+            .find(&format!("/mock-repo/data/{}/{}", &name[..2], name))
+            .is_some());
+    }
+    // Nothing is left to move, and later starts use the new layout
+    assert_eq!(client.migrate_data_structure().await.unwrap(), 0);
+    let (mut restarted, _restarted_dir) = mock_client(&mock, REPO).await;
+    let recorded = restarted.load_layout().await.unwrap().unwrap();
+    assert_eq!(recorded, client.layout());
+}
+
+#[tokio::test]
+async fn test_mock_migrate_sharded_data_back_to_flat() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let names: Vec<String> = [0x11, 0x12, 0x9a].into_iter().map(object_name).collect();
+    for name in &names {
+        let dir_id = client.get_data_file_dir_id(name).await.unwrap();
+        client
+            .upload_file(dir_id, name, Bytes::from_static(b"pack"))
+            .await
+            .unwrap();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let flat = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .data_shard_len(0)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(flat.migrate_data_structure().await.unwrap(), 3);
+    assert_eq!(flat.remove_empty_shard_dirs().await.unwrap(), 3);
+    for name in &names {
+        assert!(mock.find(&format!("/mock-repo/data/{}", name)).is_some());
+        assert!(mock
+            .find(&format!("/mock-repo/data/{}", &name[..2]))
+            .is_none());
+    }
+    assert_eq!(flat.remove_empty_shard_dirs().await.unwrap(), 0);
+    let (mut restarted, _restarted_dir) = mock_client(&mock, REPO).await;
+    let recorded = restarted.load_layout().await.unwrap().unwrap();
+    assert_eq!(recorded.data_shard_len, 0);
+}
+
+#[tokio::test]
+async fn test_mock_gc_trashes_leftovers() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+
+    let pack = object_name(0xab);
+    let dir_id = client.get_data_file_dir_id(&pack).await.unwrap();
+    let kept = client
+        .upload_file(dir_id, &pack, Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+    let shard = format!("/mock-repo/data/{}", &pack[..2]);
+    let duplicate = mock.insert_file(&shard, &pack, b"");
+    let misplaced = mock.insert_file("/mock-repo/data", &pack, b"pack");
+    let empty = mock.insert_file("/mock-repo/keys", &object_name(0x01), b"");
+    // Not copied to its shard yet, so it is left for migrate
+    let stray = object_name(0xcd);
+    mock.insert_file("/mock-repo/data", &stray, b"stray");
+
+    let garbage = client.find_garbage().await.unwrap();
+    let found: Vec<_> = garbage.iter().map(|g| (g.file_id, g.reason)).collect();
+    assert_eq!(
+        found,
+        vec![
+            (duplicate, GarbageReason::Duplicate),
+            (misplaced, GarbageReason::Misplaced),
+            (empty, GarbageReason::Empty),
+        ]
+    );
+
+    client.collect_garbage(&garbage).await.unwrap();
+    assert_eq!(mock.find(&format!("{}/{}", shard, pack)).unwrap().id, kept);
+    assert!(mock.find(&format!("/mock-repo/data/{}", pack)).is_none());
+    assert!(mock.find(&format!("/mock-repo/data/{}", stray)).is_some());
+    let key_path = format!("keys/{}", object_name(0x01));
+    assert!(client.head(&key_path).await.unwrap().is_none());
+    let pack_path = client.layout().object_path(ResticFileType::Data, &pack);
+    assert_eq!(client.head(&pack_path).await.unwrap().unwrap().id, kept);
+    assert!(client.find_garbage().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_mock_dedupe_keeps_copy_matching_its_name() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+
+    let pack = format!("{:x}", Sha256::digest(b"pack"));
+    let dir_id = client.get_data_file_dir_id(&pack).await.unwrap();
+    let kept_pack = client
+        .upload_file(dir_id, &pack, Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+    // Newer, but its content doesn't match the name
+    let shard = format!("/mock-repo/data/{}", &pack[..2]);
+    let corrupt = mock.insert_file(&shard, &pack, b"junk");
+    // Keys aren't checked against their name, so the newest copy is kept
+    let key = object_name(0x01);
+    let keys_id = client.get_type_dir_id(ResticFileType::Keys).await.unwrap();
+    let old_key = client
+        .upload_file(keys_id, &key, Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+    let new_key = mock.insert_file("/mock-repo/keys", &key, b"key2");
+
+    let mut found: Vec<_> = client
+        .find_duplicates()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|g| (g.file_id, g.reason))
+        .collect();
+    found.sort_by_key(|(file_id, _)| *file_id);
+    assert_eq!(
+        found,
+        vec![
+            (corrupt, GarbageReason::Duplicate),
+            (old_key, GarbageReason::Duplicate),
+        ]
+    );
+
+    let duplicates = client.find_duplicates().await.unwrap();
+    client.collect_garbage(&duplicates).await.unwrap();
+    assert_eq!(
+        mock.find(&format!("{}/{}", shard, pack)).unwrap().id,
+        kept_pack
+    );
+    let pack_path = client.layout().object_path(ResticFileType::Data, &pack);
+    assert_eq!(
+        client.head(&pack_path).await.unwrap().unwrap().id,
+        kept_pack
+    );
+    let key_path = format!("keys/{}", key);
+    assert_eq!(client.head(&key_path).await.unwrap().unwrap().id, new_key);
+    assert!(client.find_duplicates().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_mock_import_local_repository() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+    client.init_repository().await.unwrap();
+
+    let local_dir = tempfile::tempdir().unwrap();
+    let local = LocalBackend::open(local_dir.path()).await.unwrap();
+    let pack = object_name(0x5e);
+    local
+        .put("config", Bytes::from_static(b"config"))
+        .await
+        .unwrap();
+    local
+        .put(
+            &format!("keys/{}", object_name(0x01)),
+            Bytes::from_static(b"key"),
+        )
+        .await
+        .unwrap();
+    local
+        .put(&format!("data/5e/{}", pack), Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+
+    let report = copy_repository(
+        &local,
+        RepoLayout::default(),
+        &client,
+        client.layout(),
+        &CopyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!((report.copied, report.skipped), (3, 0));
+    assert!(mock.find(&format!("/mock-repo/data/5e/{}", pack)).is_some());
+
+    let check = check_copy(&local, RepoLayout::default(), &client, client.layout())
+        .await
+        .unwrap();
+    assert!(check.is_ok());
+    assert_eq!((check.objects, check.bytes), (3, 13));
+
+    // An object lost on the way is reported, and a rerun uploads only it
+    client
+        .delete(&client.layout().object_path(ResticFileType::Data, &pack))
+        .await
+        .unwrap();
+    let check = check_copy(&local, RepoLayout::default(), &client, client.layout())
+        .await
+        .unwrap();
+    assert_eq!(check.mismatched, [format!("data/5e/{}", pack)]);
+    let report = copy_repository(
+        &local,
+        RepoLayout::default(),
+        &client,
+        client.layout(),
+        &CopyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!((report.copied, report.skipped), (1, 2));
+}
+
+#[tokio::test]
+async fn test_mock_mirror_copies_new_and_changed_objects() {
+    let mock = MockPan123::start().await;
+    let (source, _source_dir) = mock_client(&mock, REPO).await;
+    let (target, _target_dir) = mock_client(&mock, "/mirror").await;
+    for client in [&source, &target] {
+        client.warm_cache(false).await.unwrap();
+        client.init_repository().await.unwrap();
+    }
+    let put = |name: String, data: &'static [u8]| {
+        let source = &source;
+        async move {
+            let path = source.layout().object_path(ResticFileType::Data, &name);
+            source.put(&path, Bytes::from_static(data)).await.unwrap();
+        }
+    };
+    source
+        .put("config", Bytes::from_static(b"config-1"))
+        .await
+        .unwrap();
+    put(object_name(0x01), b"first").await;
+    let options = CopyOptions::default();
+    let mirror = || copy_repository(&source, source.layout(), &target, target.layout(), &options);
+    let report = mirror().await.unwrap();
+    assert_eq!((report.copied, report.skipped), (2, 0));
+
+    // Only the new pack and the config, changed at the same size, are copied
+    put(object_name(0x02), b"second").await;
+    source
+        .put("config", Bytes::from_static(b"config-2"))
+        .await
+        .unwrap();
+    let report = mirror().await.unwrap();
+    assert_eq!((report.copied, report.skipped), (2, 1));
+    let config = mock.find("/mirror/config").unwrap();
+    assert_eq!(config.data, Bytes::from_static(b"config-2"));
+    assert!(mock
+        .find(&format!("/mirror/data/02/{}", object_name(0x02)))
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_mock_preflight_creates_missing_repository() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+
+    // A missing repository is created in full
+    assert!(client.preflight(true).await.unwrap().repo_created);
+    for dir in ["data", "keys", "locks", "snapshots", "index"] {
+        assert!(mock.find(&format!("{}/{}", REPO, dir)).is_some(), "{}", dir);
+    }
+
+    // An existing one is left alone
+    mock.remove(&format!("{}/locks", REPO));
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    assert!(!client.preflight(true).await.unwrap().repo_created);
+    assert!(mock.find(&format!("{}/locks", REPO)).is_none());
+
+    // Without init_repository only the directory is created
+    let (client, _dir) = mock_client(&mock, "/other-repo").await;
+    assert!(client.preflight(false).await.unwrap().repo_created);
+    assert!(mock.find("/other-repo").is_some());
+    assert!(mock.find("/other-repo/keys").is_none());
+}
+
+#[tokio::test]
+async fn test_mock_preflight() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    mock.set_space(1024, 4096);
+
+    let report = client.preflight(false).await.unwrap();
+    assert!(report.repo_created);
+    assert_eq!(report.account.space_total(), 4096);
+    assert!(mock.find(REPO).is_some());
+    assert!(!client.preflight(false).await.unwrap().repo_created);
+
+    mock.set_space(4096, 4096);
+    assert!(matches!(
+        client.preflight(false).await,
+        Err(PreflightError::AccountFull {
+            used: 4096,
+            total: 4096
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_mock_doctor() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    mock.set_space(1024, 4096);
+
+    let report = client.doctor().await;
+    let names: Vec<_> = report.checks.iter().map(|c| c.name).collect();
+    assert_eq!(
+        names,
+        vec!["token", "api latency", "clock", "space", "round trip"]
+    );
+    assert!(report.is_ok(), "{:?}", report);
+    // The scratch directory is cleaned up
+    assert!(mock.find(REPO).is_some());
+    assert!(mock.find(&format!("{}/.doctor", REPO)).is_none());
+
+    mock.set_space(4096, 4096);
+    let report = client.doctor().await;
+    assert!(!report.is_ok());
+    let space = report.checks.iter().find(|c| c.name == "space").unwrap();
+    assert!(!space.ok);
+    assert!(space.diagnosis.as_deref().unwrap().contains("full"));
+}
+
+#[tokio::test]
+async fn test_mock_direct_backend_bypasses_cache() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+    let direct = DirectBackend::new(client.clone());
+
+    let key = format!("keys/{}", object_name(0x01));
+    direct.put(&key, Bytes::from_static(b"key")).await.unwrap();
+    assert_eq!(
+        direct.get_range(&key, None).await.unwrap(),
+        Bytes::from_static(b"key")
+    );
+
+    // Changes made behind the cache's back are seen at once
+    let other = object_name(0x02);
+    mock.insert_file("/mock-repo/keys", &other, b"other");
+    assert!(client
+        .head(&format!("keys/{}", other))
+        .await
+        .unwrap()
+        .is_none());
+    let direct_head = direct.head(&format!("keys/{}", other)).await.unwrap();
+    assert_eq!(direct_head.unwrap().size, 5);
+    let mut names: Vec<_> = direct
+        .list("keys")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|o| o.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec![object_name(0x01), other]);
+
+    // Data packs in shard directories are listed too
+    let pack = object_name(0xab);
+    let pack_path = client.layout().object_path(ResticFileType::Data, &pack);
+    direct
+        .put(&pack_path, Bytes::from_static(b"pack"))
+        .await
+        .unwrap();
+    assert_eq!(direct.list("data").await.unwrap().len(), 1);
+
+    direct.delete(&key).await.unwrap();
+    assert!(direct.head(&key).await.unwrap().is_none());
+    assert!(direct.readiness().await.ready);
+}
+
+#[tokio::test]
+async fn test_mock_upload_domain_failover() {
+    let mock = MockPan123::start().await;
+    let (setup, _setup_dir) = mock_client(&mock, REPO).await;
+    setup.init_repository().await.unwrap();
+
+    // Nothing listens on port 1, so uploads to the first domain are refused
+    mock.set_upload_domains(vec![
+        "http://127.0.0.1:1".to_string(),
+        mock.base_url.clone(),
+    ]);
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+
+    for i in 0..2 {
+        let path = format!("keys/{}", object_name(i));
+        assert!(matches!(
+            client.put(&path, Bytes::from_static(b"key")).await,
+            Err(AppError::HttpClient(_))
+        ));
+    }
+    let path = format!("keys/{}", object_name(0x10));
+    client.put(&path, Bytes::from_static(b"key")).await.unwrap();
+    assert!(mock.find(&format!("{}/{}", REPO, path)).is_some());
+    assert_eq!(mock.request_count("/upload/v2/file/domain"), 2);
+}
+
+#[tokio::test]
+async fn test_mock_upload_domain_refetched_when_retired_or_expired() {
+    let mock = MockPan123::start().await;
+    let (setup, _setup_dir) = mock_client(&mock, REPO).await;
+    setup.init_repository().await.unwrap();
+
+    // A retired domain answers with something other than the upload API
+    mock.set_upload_domains(vec![format!("{}/retired", mock.base_url)]);
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.warm_cache(false).await.unwrap();
+    let path = format!("keys/{}", object_name(0x01));
+    assert!(client.put(&path, Bytes::from_static(b"key")).await.is_err());
+    mock.set_upload_domains(vec![mock.base_url.clone()]);
+    client.put(&path, Bytes::from_static(b"key")).await.unwrap();
+    assert_eq!(mock.request_count("/upload/v2/file/domain"), 3);
+
+    // An expired list is fetched again before the next upload
+    let dir = tempfile::tempdir().unwrap();
+    let client = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(format!(
+            "sqlite:{}?mode=rwc",
+            dir.path().join("cache.db").display()
+        ))
+        .base_url(&mock.base_url)
+        .upload_domain_ttl(std::time::Duration::ZERO)
+        .build()
+        .await
+        .unwrap();
+    client.warm_cache(false).await.unwrap();
+    for i in 0..2 {
+        let path = format!("keys/{}", object_name(0x10 + i));
+        client.put(&path, Bytes::from_static(b"key")).await.unwrap();
+    }
+    assert_eq!(mock.request_count("/upload/v2/file/domain"), 5);
+}
+
+#[tokio::test]
+async fn test_mock_sliced_upload_waits_for_async_completion() {
+    let mock = MockPan123::start().await;
+    mock.set_slice_size(1000);
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let builder = |timeout| {
+        Pan123Client::builder("mock-id", "mock-secret")
+            .repo_path(REPO)
+            .database_url(&db_url)
+            .base_url(&mock.base_url)
+            .multipart_threshold(1024)
+            .upload_complete_timeout(timeout)
+    };
+    let data: Bytes = (0..2500u32).map(|i| (i % 251) as u8).collect();
+
+    // 123pan never finalizes within the wait: a clear error, nothing cached
+    let client = builder(std::time::Duration::ZERO).build().await.unwrap();
+    client.init_repository().await.unwrap();
+    let name = object_name(0x42);
+    let dir_id = client.get_data_file_dir_id(&name).await.unwrap();
+    mock.set_pending_completions(usize::MAX);
+    assert!(matches!(
+        client.upload_file(dir_id, &name, data.clone()).await,
+        Err(AppError::Timeout(_))
+    ));
+    assert!(client.get_file_info(dir_id, &name).await.unwrap().is_none());
+
+    // Finalized on the second poll; the slices are not sent again
+    mock.set_pending_completions(1);
+    let client = builder(std::time::Duration::from_secs(30))
+        .build()
+        .await
+        .unwrap();
+    client
+        .upload_file(dir_id, &name, data.clone())
+        .await
+        .unwrap();
+    assert_eq!(mock.request_count("/upload/v2/file/slice"), 3);
+    assert_eq!(mock.request_count("/upload/v2/file/upload_complete"), 3);
+    let file = client.get_file_info(dir_id, &name).await.unwrap().unwrap();
+    assert_eq!(file.size, 2500);
+}
+
+#[tokio::test]
+async fn test_mock_credential_rotation() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let credentials = |secret: &str| vec![("mock-id".to_string(), secret.to_string())];
+
+    // Unchanged credentials are left alone
+    assert_eq!(
+        client
+            .rotate_credentials(credentials("mock-secret"))
+            .await
+            .unwrap(),
+        0
+    );
+
+    // A refused secret keeps the working one
+    mock.revoke_secret("leaked");
+    assert!(matches!(
+        client.rotate_credentials(credentials("leaked")).await,
+        Err(AppError::Auth(_))
+    ));
+
+    // The old secret is revoked; the new one gets a token right away
+    mock.revoke_secret("mock-secret");
+    let tokens = mock.request_count("/api/v1/access_token");
+    assert_eq!(
+        client.rotate_credentials(credentials("new")).await.unwrap(),
+        1
+    );
+    assert_eq!(mock.request_count("/api/v1/access_token"), tokens + 1);
+    assert!(client.user_info().await.is_ok());
+
+    assert!(matches!(
+        client
+            .rotate_credentials(vec![
+                ("a".to_string(), "b".to_string()),
+                ("c".to_string(), "d".to_string()),
+            ])
+            .await,
+        Err(AppError::BadRequest(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_mock_batched_deletes() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let names: Vec<String> = (0x70..0x73).map(object_name).collect();
+    for name in &names {
+        client
+            .put(&format!("locks/{}", name), Bytes::from_static(b"lock"))
+            .await
+            .unwrap();
+    }
+
+    // Deletes are acknowledged and hidden right away, also from listings
+    // fetched again from 123pan, but not carried out yet
+    let batcher = client.spawn_delete_batching(std::time::Duration::from_secs(3600));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    for name in &names {
+        client.delete(&format!("locks/{}", name)).await.unwrap();
+    }
+    assert_eq!(client.pending_deletes().await.unwrap(), 3);
+    assert!(mock
+        .find(&format!("/mock-repo/locks/{}", names[0]))
+        .is_some());
+    client
+        .revalidate_expired(std::time::Duration::ZERO)
+        .await
+        .unwrap();
+    assert!(client.list("locks").await.unwrap().is_empty());
+    assert!(client
+        .head(&format!("locks/{}", names[1]))
+        .await
+        .unwrap()
+        .is_none());
+
+    // All of them go in one trash and one delete call
+    let trash_calls = mock.request_count("/api/v1/file/trash");
+    assert_eq!(client.flush_deletes().await.unwrap(), 3);
+    assert_eq!(mock.request_count("/api/v1/file/trash"), trash_calls + 1);
+    assert!(names
+        .iter()
+        .all(|name| mock.find(&format!("/mock-repo/locks/{}", name)).is_none()));
+    assert_eq!(client.pending_deletes().await.unwrap(), 0);
+    batcher.abort();
+}
+
+#[tokio::test]
+async fn test_mock_rate_limited_deletes_stay_queued() {
+    let mock = MockPan123::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    let client = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path(REPO)
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .max_retries(0)
+        .build()
+        .await
+        .unwrap();
+    client.init_repository().await.unwrap();
+    let name = object_name(0x74);
+    client
+        .put(&format!("locks/{}", name), Bytes::from_static(b"lock"))
+        .await
+        .unwrap();
+    let batcher = client.spawn_delete_batching(std::time::Duration::from_secs(3600));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    client.delete(&format!("locks/{}", name)).await.unwrap();
+
+    // A rate-limited delete is not a file already gone
+    mock.rate_limit_next(1);
+    assert!(matches!(
+        client.flush_deletes().await,
+        Err(AppError::Pan123Api { code: 429, .. })
+    ));
+    assert_eq!(client.pending_deletes().await.unwrap(), 1);
+    assert!(mock.find(&format!("/mock-repo/locks/{}", name)).is_some());
+
+    assert_eq!(client.flush_deletes().await.unwrap(), 1);
+    assert!(mock.find(&format!("/mock-repo/locks/{}", name)).is_none());
+    assert_eq!(client.pending_deletes().await.unwrap(), 0);
+    batcher.abort();
+}
+
+#[tokio::test]
+async fn test_mock_create_refuses_existing_files() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let key = format!("keys/{}", object_name(0x80));
+    client
+        .create(&key, Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.create(&key, Bytes::from_static(b"other")).await,
+        Err(AppError::Forbidden(_))
+    ));
+
+    // A file the cache doesn't know about yet is refused by 123pan
+    let snapshot = object_name(0x81);
+    assert!(client.list("snapshots").await.unwrap().is_empty());
+    mock.insert_file(&format!("{}/snapshots", REPO), &snapshot, b"snapshot");
+    assert!(matches!(
+        client
+            .create(
+                &format!("snapshots/{}", snapshot),
+                Bytes::from_static(b"forged")
+            )
+            .await,
+        Err(AppError::Forbidden(_))
+    ));
+    let node = mock
+        .find(&format!("{}/snapshots/{}", REPO, snapshot))
+        .unwrap();
+    assert_eq!(&node.data[..], b"snapshot");
+}
+
+#[tokio::test]
+async fn test_mock_verified_head() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let key = format!("keys/{}", object_name(0x90));
+    client.put(&key, Bytes::from_static(b"key")).await.unwrap();
+
+    let verified = client.head_verified(&key).await.unwrap().unwrap();
+    assert_eq!(verified.size, 3);
+    assert_eq!(mock.request_count("/api/v1/file/detail"), 1);
+
+    // A key removed behind the cache's back is still cached, but not
+    // confirmed, and is dropped from the cache
+    mock.remove(&format!("{}/{}", REPO, key));
+    assert!(client.head(&key).await.unwrap().is_some());
+    assert!(client.head_verified(&key).await.unwrap().is_none());
+    assert!(client.head(&key).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_mock_repo_path_names_are_encoded() {
+    let mock = MockPan123::start().await;
+    let repo = "/backups: home/restic.";
+    let (client, _dir) = mock_client(&mock, repo).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+    let key = object_name(0xa0);
+    client
+        .put(&format!("keys/{}", key), Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+
+    // Stored under names 123pan accepts, and found again through them
+    assert!(mock
+        .find(&format!("/backups%3A home/restic%2E/keys/{}", key))
+        .is_some());
+    let keys = client.list("keys").await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].name, key);
+    assert_eq!(
+        client
+            .get_range(&format!("keys/{}", key), None)
+            .await
+            .unwrap(),
+        Bytes::from_static(b"key")
+    );
+}
+
+#[tokio::test]
+async fn test_mock_repo_path_unicode_normalization() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, "/B\u{fc}cher/restic").await;
+    client.init_repository().await.unwrap();
+
+    // The same path typed decomposed, as on macOS, finds the folder
+    let (client, _dir) = mock_client(&mock, "/Bu\u{308}cher/restic").await;
+    assert!(client
+        .find_path_id("/Bu\u{308}cher/restic")
+        .await
+        .unwrap()
+        .is_some());
+    client.init_repository().await.unwrap();
+    assert!(mock.find("/Bu\u{308}cher").is_none());
+    assert!(mock.find("/B\u{fc}cher/restic/keys").is_some());
+}
+
+#[tokio::test]
+async fn test_mock_download_resumes_cut_off_body() {
+    let mock = MockPan123::start().await;
+    let (client, _dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    let path = format!("keys/{}", object_name(0xb0));
+    client.put(&path, Bytes::from(data.clone())).await.unwrap();
+
+    // A whole-file download resumes from where the body broke off
+    mock.cut_downloads(1);
+    assert_eq!(client.get_range(&path, None).await.unwrap(), data);
+    assert_eq!(
+        mock.download_ranges(),
+        vec![None, Some("bytes=5000-9999".to_string())]
+    );
+
+    // So does a ranged one, relative to its start
+    mock.cut_downloads(1);
+    assert_eq!(
+        client.get_range(&path, Some((1000, 2999))).await.unwrap(),
+        &data[1000..3000]
+    );
+    assert_eq!(
+        mock.download_ranges()[2..],
+        [
+            Some("bytes=1000-2999".to_string()),
+            Some("bytes=2000-2999".to_string())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_mock_legacy_percent_names_are_kept() {
+    let mock = MockPan123::start().await;
+    let repo = "/legacy%41";
+    let (client, _dir) = mock_client(&mock, repo).await;
+    client.init_repository().await.unwrap();
+    client.warm_cache(false).await.unwrap();
+    let key = object_name(0xa1);
+    client
+        .put(&format!("keys/{}", key), Bytes::from_static(b"key"))
+        .await
+        .unwrap();
+
+    // Names 123pan accepts are stored as they are, escapes and all
+    assert!(mock.find(&format!("/legacy%41/keys/{}", key)).is_some());
+    assert!(mock.find("/legacy%2541").is_none());
+    mock.insert_file("/legacy%41/keys", "old%41", b"old");
+    let (client, _dir) = mock_client(&mock, repo).await;
+    client.warm_cache(false).await.unwrap();
+    let mut names: Vec<_> = client
+        .list("keys")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|info| info.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, [key.clone(), "old%41".to_string()]);
+    assert_eq!(
+        client.get_range("keys/old%41", None).await.unwrap(),
+        Bytes::from_static(b"old")
+    );
+}
+
+#[tokio::test]
+async fn test_mock_decomposed_names_on_123pan_are_kept() {
+    let mock = MockPan123::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let db_url = format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display());
+    // A repository created with its path decomposed, as on macOS
+    let creator = Pan123Client::builder("mock-id", "mock-secret")
+        .repo_path("/Bu\u{308}cher/restic")
+        .database_url(&db_url)
+        .base_url(&mock.base_url)
+        .unicode_normalization(Normalization::None)
+        .build()
+        .await
+        .unwrap();
+    creator.init_repository().await.unwrap();
+    let keys = "/Bu\u{308}cher/restic/keys";
+    mock.insert_file(keys, "Bu\u{308}cher", b"decomposed");
+    mock.insert_file(keys, "B\u{fc}cher", b"composed");
+    mock.insert_file(keys, "Gru\u{308}n", b"old");
+
+    let (client, _dir) = mock_client(&mock, "/B\u{fc}cher/restic").await;
+    client.warm_cache(false).await.unwrap();
+    let mut names: Vec<_> = client
+        .list("keys")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|info| info.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["Bu\u{308}cher", "B\u{fc}cher", "Gru\u{308}n"]);
+
+    // Siblings differing only in form are told apart, an exact match winning
+    for (name, data) in [("B\u{fc}cher", "composed"), ("Bu\u{308}cher", "decomposed")] {
+        assert_eq!(
+            client
+                .get_range(&format!("keys/{}", name), None)
+                .await
+                .unwrap(),
+            Bytes::from(data)
+        );
+    }
+
+    // Writing under the composed name replaces the decomposed file
+    client
+        .put("keys/Gr\u{fc}n", Bytes::from_static(b"new"))
+        .await
+        .unwrap();
+    let node = mock.find(&format!("{}/Gru\u{308}n", keys)).unwrap();
+    assert_eq!(node.data, Bytes::from_static(b"new"));
+    assert!(mock.find(&format!("{}/Gr\u{fc}n", keys)).is_none());
+    assert!(mock.find("/B\u{fc}cher").is_none());
+}
+
+/// Run the `restic-123pan` binary against `mock`, sharing the cache
+/// database in `dir` with the test's client.
+async fn run_cli(mock: &MockPan123, dir: &tempfile::TempDir, args: &[&str]) -> Vec<u8> {
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_restic-123pan"))
+        .args(args)
+        .env_clear()
+        .env("PAN123_CLIENT_ID", "mock-id")
+        .env("PAN123_CLIENT_SECRET", "mock-secret")
+        .env("PAN123_API_BASE_URL", &mock.base_url)
+        .env("PAN123_REPO_PATH", REPO)
+        .env(
+            "DATABASE_URL",
+            format!("sqlite:{}?mode=rwc", dir.path().join("cache.db").display()),
+        )
+        .env("RUST_LOG", "off")
+        .output()
+        .await
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+/// Number of cached nodes named `name` in the cache database in `dir`.
+async fn cached_nodes(dir: &tempfile::TempDir, name: &str) -> i64 {
+    let db =
+        sea_orm::Database::connect(format!("sqlite:{}", dir.path().join("cache.db").display()))
+            .await
+            .unwrap();
+    sea_orm::ConnectionTrait::query_one(
+        &db,
+        sea_orm::Statement::from_sql_and_values(
+            sea_orm::DatabaseBackend::Sqlite,
+            "SELECT COUNT(*) AS n FROM file_nodes WHERE name = ?",
+            [name.into()],
+        ),
+    )
+    .await
+    .unwrap()
+    .unwrap()
+    .try_get("", "n")
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_mock_object_commands() {
+    let mock = MockPan123::start().await;
+    let (client, dir) = mock_client(&mock, REPO).await;
+    client.init_repository().await.unwrap();
+    let name = object_name(0x76);
+    let path = format!("snapshots/{}", name);
+    client
+        .put(&path, Bytes::from_static(b"snapshot"))
+        .await
+        .unwrap();
+
+    // ls lists the objects of the type with their size
+    let listing = String::from_utf8(run_cli(&mock, &dir, &["ls", "snapshots"]).await).unwrap();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", listing);
+    assert!(lines[0].trim_start().starts_with("8 "));
+    assert!(lines[0].ends_with(&name));
+
+    // cat writes the stored bytes
+    assert_eq!(
+        run_cli(&mock, &dir, &["cat", "snapshots", &name]).await,
+        b"snapshot"
+    );
+
+    // rm deletes the object on 123pan and from the cache
+    assert_eq!(cached_nodes(&dir, &name).await, 1);
+    run_cli(&mock, &dir, &["rm", "snapshots", &name, "--yes"]).await;
+    assert!(mock
+        .find(&format!("/mock-repo/snapshots/{}", name))
+        .is_none());
+    assert_eq!(cached_nodes(&dir, &name).await, 0);
+    assert!(
+        String::from_utf8(run_cli(&mock, &dir, &["ls", "snapshots"]).await)
+            .unwrap()
+            .is_empty()
+    );
+}